@@ -0,0 +1,144 @@
+//! Collecting the kernel images of a build into one directory with
+//! `--out-dir`, named so that builds for several platforms and profiles can
+//! share it.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use clap::Args;
+
+use crate::{image::ImageOptions, platforms::Platform};
+
+const PLACEHOLDERS: [&str; 6] = ["name", "platform", "arch", "profile", "target", "ext"];
+
+#[derive(Debug, Clone, Args)]
+pub struct ArtifactOptions {
+    /// Name of the images copied to `--out-dir`, with `{name}`, `{platform}`,
+    /// `{arch}`, `{profile}`, `{target}` and `{ext}` replaced
+    #[arg(
+        long,
+        default_value = "{name}-{platform}-{profile}.{ext}",
+        value_parser = parse_pattern,
+        value_name = "PATTERN",
+        help_heading = "Compilation Options"
+    )]
+    artifact_name: String,
+}
+
+fn parse_pattern(s: &str) -> anyhow::Result<String> {
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("`{{` is not closed");
+        };
+        let placeholder = &rest[start + 1..start + len];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            bail!(
+                "unknown placeholder `{{{}}}`, expected one of {}",
+                placeholder,
+                PLACEHOLDERS.map(|p| format!("`{{{}}}`", p)).join(", ")
+            );
+        }
+        rest = &rest[start + len + 1..];
+    }
+    Ok(s.to_string())
+}
+
+/// Where the images of a build go, and what they are called.
+#[derive(Debug)]
+pub struct Collector {
+    dir: PathBuf,
+    pattern: String,
+    platform: Platform,
+    target: &'static str,
+    profile: String,
+    image: ImageOptions,
+}
+
+impl ArtifactOptions {
+    pub fn collector(
+        &self,
+        dir: PathBuf,
+        platform: Platform,
+        target: &'static str,
+        profile: &str,
+        image: &ImageOptions,
+    ) -> Collector {
+        Collector {
+            dir,
+            pattern: self.artifact_name.clone(),
+            platform,
+            target,
+            profile: profile.to_string(),
+            image: image.clone(),
+        }
+    }
+}
+
+impl Collector {
+    /// Returns the name of the copy of `executable`, whose image has the
+    /// extension `ext`.
+    fn name(&self, executable: &Path, ext: &str) -> String {
+        let arch = crate::platforms::Arch::from(self.platform);
+        let mut name = self.pattern.clone();
+        for (placeholder, value) in [
+            ("name", executable.file_stem().unwrap().to_string_lossy()),
+            ("platform", self.platform.as_ref().into()),
+            ("arch", arch.as_ref().into()),
+            ("profile", self.profile.as_str().into()),
+            ("target", self.target.into()),
+            ("ext", ext.into()),
+        ] {
+            name = name.replace(&format!("{{{}}}", placeholder), &value);
+        }
+        name
+    }
+
+    /// Turns `executables` into images, padded, checksummed and compressed as
+    /// the image options ask for, and copies them into the directory,
+    /// returning the copies.
+    pub fn collect(&self, executables: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+        let mut sources: HashMap<PathBuf, &Path> = HashMap::new();
+        let mut copies = vec![];
+        for executable in executables {
+            let prepared = crate::image::prepare(self.platform, &self.image, executable.clone())?;
+            let image = crate::image::compress(&self.image, prepared.clone())?;
+            // A compressed image keeps the extension of the raw one, like
+            // `bin.gz`.
+            let ext = match (prepared.extension(), image.extension()) {
+                (Some(raw), Some(compressed)) if image != prepared => {
+                    format!("{}.{}", raw.display(), compressed.display())
+                }
+                (_, ext) => ext.map_or("elf".to_string(), |ext| ext.display().to_string()),
+            };
+            let copy = self.dir.join(self.name(executable, &ext));
+            if let Some(other) = sources.insert(copy.clone(), executable) {
+                bail!(
+                    "`{}` and `{}` would both be copied to `{}`, add `{{name}}` to `--artifact-name`",
+                    other.display(),
+                    executable.display(),
+                    copy.display()
+                );
+            }
+
+            if let Some(parent) = copy.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create `{}`", parent.display()))?;
+            }
+            fs::copy(&image, &copy).with_context(|| {
+                format!(
+                    "failed to copy `{}` to `{}`",
+                    image.display(),
+                    copy.display()
+                )
+            })?;
+            crate::info("Copied", format!("`{}`", copy.display()));
+            copies.push(copy);
+        }
+        Ok(copies)
+    }
+}
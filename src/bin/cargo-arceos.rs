@@ -1,5 +1,7 @@
 use clap::Parser;
 
 fn main() {
-    cargo_arceos::Cli::parse().run()
+    cargo_arceos::rustc_wrapper::run_if_invoked();
+    cargo_arceos::envvars::load_files();
+    cargo_arceos::Cli::parse_from(cargo_arceos::args_os()).run()
 }
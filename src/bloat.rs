@@ -0,0 +1,136 @@
+//! The functions and crates that take the most space in a kernel, for `cargo
+//! arceos bloat`, like `cargo bloat` does for hosted binaries.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::Context;
+
+use crate::size::{Symbol, SymbolKind};
+
+/// Returns the name of `symbol` without the hash of the legacy mangling, and
+/// with what nm left half-demangled of it decoded.
+fn display_name(symbol: &Symbol) -> String {
+    let mut name = symbol.name.as_str();
+    if let Some((path, hash)) = name.rsplit_once("::h")
+        && hash.len() == 16
+        && hash.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        name = path;
+    }
+    if !name.contains('$') {
+        return name.to_string();
+    }
+    let mut name = name.strip_prefix('_').unwrap_or(name).to_string();
+    for (escape, c) in [
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$u20$", " "),
+        ("$C$", ","),
+        ("$RF$", "&"),
+        ("$BP$", "*"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("..", "::"),
+    ] {
+        name = name.replace(escape, c);
+    }
+    name
+}
+
+/// Returns `size` in bytes with a binary unit.
+fn human(size: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if size < 1024 {
+        return format!("{}B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+/// Prints the `top` largest functions of the kernel `elf`, or the crates
+/// with the most code if `crates`.
+pub fn print(elf: &Path, top: usize, crates: bool) -> anyhow::Result<()> {
+    let data = fs::read(elf).with_context(|| format!("failed to read `{}`", elf.display()))?;
+    let text: u64 = crate::memmap::sections(&data)
+        .with_context(|| format!("failed to read sections of `{}`", elf.display()))?
+        .iter()
+        .filter(|section| section.name.starts_with(".text"))
+        .map(|section| section.size)
+        .sum();
+    let file = data.len() as u64;
+
+    let symbols = crate::size::symbols(elf)?;
+    let functions = symbols
+        .iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Text);
+    let mut rows: Vec<(u64, String, String)> = if crates {
+        let mut sizes: HashMap<&str, u64> = HashMap::new();
+        for symbol in functions {
+            *sizes.entry(symbol.crate_name()).or_default() += symbol.size;
+        }
+        sizes
+            .into_iter()
+            .map(|(name, size)| (size, name.to_string(), String::new()))
+            .collect()
+    } else {
+        functions
+            .map(|symbol| {
+                (
+                    symbol.size,
+                    symbol.crate_name().to_string(),
+                    display_name(symbol),
+                )
+            })
+            .collect()
+    };
+    rows.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| (&a.1, &a.2).cmp(&(&b.1, &b.2))));
+
+    let percent = |size: u64, of: u64| {
+        if of == 0 {
+            0.0
+        } else {
+            size as f64 * 100.0 / of as f64
+        }
+    };
+    let row = |size: u64, crate_name: &str, name: &str| {
+        let line = format!(
+            "{:>5.1}% {:>5.1}% {:>9} {:<16} {}",
+            percent(size, file),
+            percent(size, text),
+            human(size),
+            crate_name,
+            name
+        );
+        println!("{}", line.trim_end());
+    };
+    if crates {
+        println!("  File  .text      Size Crate");
+    } else {
+        println!("  File  .text      Size Crate            Name");
+    }
+    for (size, crate_name, name) in rows.iter().take(top) {
+        row(*size, crate_name, name);
+    }
+    if rows.len() > top {
+        let rest = &rows[top..];
+        let kind = if crates { "crates" } else { "functions" };
+        row(
+            rest.iter().map(|(size, ..)| size).sum(),
+            "",
+            &format!("And {} smaller {}", rest.len(), kind),
+        );
+    }
+    row(
+        text,
+        "",
+        &format!(".text section size, the file size is {}", human(file)),
+    );
+    Ok(())
+}
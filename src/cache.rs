@@ -0,0 +1,119 @@
+//! Compilation caching through sccache.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum BuildCache {
+    /// Use sccache if it is installed and no `RUSTC_WRAPPER` is set
+    Auto,
+    /// Use sccache
+    Sccache,
+}
+
+/// The hits and misses that sccache has counted so far.
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    hits: u64,
+    misses: u64,
+}
+
+/// An sccache server that the build goes through.
+#[derive(Debug)]
+pub struct Sccache {
+    program: PathBuf,
+    before: Stats,
+}
+
+impl BuildCache {
+    /// Makes cargo compile through the cache, returning it so that the cache
+    /// statistics can be reported after the build.
+    pub fn apply(self, command: &mut Command) -> anyhow::Result<Option<Sccache>> {
+        let program = match self {
+            BuildCache::Auto => {
+                if env::var_os("RUSTC_WRAPPER").is_some_and(|wrapper| !wrapper.is_empty()) {
+                    return Ok(None);
+                }
+                match crate::find_program("sccache") {
+                    Some(program) => program,
+                    None => return Ok(None),
+                }
+            }
+            BuildCache::Sccache => {
+                crate::find_program("sccache").context("sccache is not found in PATH")?
+            }
+        };
+
+        // The statistics are kept by the server for all builds, so only the
+        // difference is reported.
+        let before = stats(&program)?;
+        command.env("RUSTC_WRAPPER", &program);
+        Ok(Some(Sccache { program, before }))
+    }
+}
+
+impl Sccache {
+    /// Prints the cache hits and misses of the build.
+    pub fn report(&self) {
+        let after = match stats(&self.program) {
+            Ok(after) => after,
+            Err(e) => {
+                crate::warn(format!("failed to get sccache statistics: {}", e));
+                return;
+            }
+        };
+
+        let hits = after.hits.saturating_sub(self.before.hits);
+        let misses = after.misses.saturating_sub(self.before.misses);
+        let total = hits + misses;
+        if total == 0 {
+            crate::info("Cache", "nothing compiled");
+        } else {
+            crate::info(
+                "Cache",
+                format!(
+                    "{} hits, {} misses ({:.0}% hit rate)",
+                    hits,
+                    misses,
+                    hits as f64 * 100.0 / total as f64
+                ),
+            );
+        }
+    }
+}
+
+fn stats(program: &Path) -> anyhow::Result<Stats> {
+    let output = Command::new(program)
+        .args(["--show-stats", "--stats-format", "json"])
+        .output()
+        .with_context(|| format!("failed to execute `{}`", program.display()))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "`{} --show-stats` failed: {}",
+            program.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let info: serde_json::Value =
+        serde_json::from_slice(&output.stdout).context("invalid sccache statistics")?;
+    let stats = info.get("stats").unwrap_or(&info);
+    // Counts are kept per language.
+    let count = |key: &str| -> u64 {
+        stats[key]["counts"]
+            .as_object()
+            .map(|counts| counts.values().filter_map(|v| v.as_u64()).sum())
+            .unwrap_or(0)
+    };
+    Ok(Stats {
+        hits: count("cache_hits"),
+        misses: count("cache_misses"),
+    })
+}
@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, bail};
+use cargo_metadata::Metadata;
+use clap::Args;
+
+use crate::{features, platforms::Arch};
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "C App Options")]
+pub struct CAppOptions {
+    /// Build the C app in DIR against axlibc
+    #[arg(long, value_name = "DIR")]
+    c_app: Option<PathBuf>,
+
+    /// Prefix of the musl cross toolchain [default: `<ARCH>-linux-musl-`]
+    #[arg(
+        long,
+        env = "ARCEOS_CROSS_COMPILE",
+        requires = "c_app",
+        value_name = "PREFIX"
+    )]
+    cross_compile: Option<String>,
+}
+
+/// A C app that is compiled and linked once axlibc has been built.
+#[derive(Debug)]
+pub struct CApp {
+    dir: PathBuf,
+    name: String,
+    toolchain: String,
+    cflags: Vec<String>,
+    axlibc_id: String,
+    axlibc_dir: PathBuf,
+    binary_dir: PathBuf,
+    linker_script: PathBuf,
+    pie: bool,
+}
+
+impl CAppOptions {
+    /// Makes `command` build axlibc for the C app, if there is one.
+    pub fn apply(
+        &self,
+        ctx: &features::Context,
+        metadata: &Metadata,
+        binary_dir: &Path,
+        profile: &str,
+        command: &mut Command,
+    ) -> anyhow::Result<Option<CApp>> {
+        let Some(dir) = &self.c_app else {
+            return Ok(None);
+        };
+        if !dir.is_dir() {
+            bail!("C app `{}` is not a directory", dir.display());
+        }
+
+        let axlibc = metadata
+            .packages
+            .iter()
+            .find(|p| p.name == "axlibc")
+            .context("package `axlibc` is not found in the workspace")?;
+        let axlibc_dir = axlibc.manifest_path.parent().unwrap().as_std_path();
+
+        // Like the ArceOS Makefile, features of a C app are listed in its
+        // `features.txt`.
+        command.args(["--package", "axlibc"]);
+        let features_path = dir.join("features.txt");
+        if features_path.exists() {
+            let features = fs::read_to_string(&features_path)
+                .with_context(|| format!("failed to read `{}`", features_path.display()))?;
+            for feature in features.split_whitespace() {
+                command.args(["--features", &format!("axlibc/{}", feature)]);
+            }
+        }
+
+        let mut cflags: Vec<String> = ["-nostdinc", "-fno-builtin", "-ffreestanding", "-Wall"]
+            .map(String::from)
+            .into();
+        cflags.push(format!("-I{}", axlibc_dir.join("include").display()));
+        if profile == "release" {
+            cflags.push("-O3".to_string());
+        }
+        let arch_flags: &[&str] = match ctx.arch {
+            Arch::Riscv64 => &["-march=rv64gc", "-mabi=lp64d", "-mcmodel=medany"],
+            Arch::X86_64 => &["-mno-sse"],
+            Arch::Aarch64 if ctx.soft_float => &["-mgeneral-regs-only"],
+            _ => &[],
+        };
+        cflags.extend(arch_flags.iter().map(|f| f.to_string()));
+        if ctx.pie {
+            cflags.push("-fPIE".to_string());
+        }
+
+        Ok(Some(CApp {
+            dir: dir.clone(),
+            name: dir
+                .canonicalize()?
+                .file_name()
+                .context("C app has no name")?
+                .to_string_lossy()
+                .into_owned(),
+            toolchain: toolchain(ctx.arch, self.cross_compile.as_deref())?,
+            cflags,
+            axlibc_id: axlibc.id.repr.clone(),
+            axlibc_dir: axlibc_dir.into(),
+            binary_dir: binary_dir.into(),
+            linker_script: binary_dir.join(format!("linker_{}.lds", ctx.platform)),
+            pie: ctx.pie,
+        }))
+    }
+}
+
+/// Returns the prefix of the musl cross toolchain for `arch`, downloading it
+/// into the cache directory if it is not installed.
+fn toolchain(arch: Arch, prefix: Option<&str>) -> anyhow::Result<String> {
+    if let Some(prefix) = prefix {
+        return Ok(prefix.to_string());
+    }
+
+    let triple = format!("{}-linux-musl", arch);
+    let prefix = format!("{}-", triple);
+    if crate::find_program(&format!("{}gcc", prefix)).is_some() {
+        return Ok(prefix);
+    }
+
+    let cache_dir = crate::cache_dir()?;
+    let bin_dir = cache_dir.join(format!("{}-cross", triple)).join("bin");
+    if !bin_dir.join(format!("{}gcc", prefix)).exists() {
+        if let Arch::Loongarch64 = arch {
+            bail!(
+                "`{}gcc` is not found, install a musl toolchain for loongarch64 and pass its prefix with `--cross-compile`",
+                prefix
+            );
+        }
+
+        crate::require_network(&format!("downloading the `{}` toolchain", triple))?;
+        fs::create_dir_all(&cache_dir).context("failed to create cache directory")?;
+        let archive = cache_dir.join(format!("{}-cross.tgz", triple));
+        crate::run_command(
+            Command::new("curl")
+                .args(["-fL", "--retry", "3", "-o"])
+                .arg(&archive)
+                .arg(format!("https://musl.cc/{}-cross.tgz", triple)),
+        )?;
+        crate::run_command(
+            Command::new("tar")
+                .arg("xzf")
+                .arg(&archive)
+                .arg("-C")
+                .arg(&cache_dir),
+        )?;
+        fs::remove_file(&archive).ok();
+    }
+
+    Ok(bin_dir.join(prefix).to_string_lossy().into_owned())
+}
+
+fn c_sources(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut sources = fs::read_dir(dir)
+        .with_context(|| format!("failed to read `{}`", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .filter(|path| {
+            path.as_ref()
+                .map_or(true, |path| path.extension().is_some_and(|ext| ext == "c"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    sources.sort();
+    Ok(sources)
+}
+
+impl CApp {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the ID of the axlibc package the app is linked against.
+    #[inline]
+    pub fn package_id(&self) -> &str {
+        &self.axlibc_id
+    }
+
+    fn compile(&self, src_dir: &Path, obj_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        fs::create_dir_all(obj_dir).context("failed to create object directory")?;
+
+        let mut objects = vec![];
+        for src in c_sources(src_dir)? {
+            let obj = obj_dir.join(src.file_name().unwrap()).with_extension("o");
+            crate::run_command(
+                Command::new(format!("{}gcc", self.toolchain))
+                    .args(&self.cflags)
+                    .arg("-c")
+                    .arg(&src)
+                    .arg("-o")
+                    .arg(&obj),
+            )?;
+            objects.push(obj);
+        }
+        Ok(objects)
+    }
+
+    /// Compiles the C parts of axlibc and the app, then links them with the
+    /// Rust part of axlibc into the final ELF.
+    pub fn build(&self) -> anyhow::Result<PathBuf> {
+        let out_dir = self.binary_dir.join("c-app").join(&self.name);
+
+        let mut objects = self.compile(&self.dir, &out_dir.join("app"))?;
+        let libc_dir = self.axlibc_dir.join("c");
+        if libc_dir.is_dir() {
+            objects.extend(self.compile(&libc_dir, &out_dir.join("libc"))?);
+        }
+
+        let output = Command::new(format!("{}gcc", self.toolchain))
+            .args(&self.cflags)
+            .arg("-print-libgcc-file-name")
+            .output()
+            .context("failed to locate libgcc")?;
+        let libgcc = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        let elf = self.binary_dir.join(format!("{}.elf", self.name));
+        let pie_flags: &[&str] = if self.pie {
+            &["-pie", "--no-dynamic-linker", "-znotext"]
+        } else {
+            &["-no-pie"]
+        };
+        crate::run_command(
+            Command::new(format!("{}ld", self.toolchain))
+                .args(["-nostdlib", "-static"])
+                .args(pie_flags)
+                .arg("--gc-sections")
+                .arg(format!("-T{}", self.linker_script.display()))
+                .args(&objects)
+                .arg(self.binary_dir.join("libaxlibc.a"))
+                .arg(libgcc)
+                .arg("-o")
+                .arg(&elf),
+        )?;
+
+        crate::info("Linked", format!("`{}`", elf.display()));
+        Ok(elf)
+    }
+}
@@ -0,0 +1,188 @@
+//! Runners that the user has configured for cargo, which the runner of
+//! cargo-arceos takes the place of.
+//!
+//! Cargo reads `.cargo/config.toml` from the current directory and all of its
+//! parents, then from `$CARGO_HOME`, with the closest file taking precedence.
+//! `CARGO_TARGET_<TRIPLE>_RUNNER` takes precedence over all files.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::platforms::Arch;
+
+/// A runner that cargo would use for the target.
+#[derive(Debug)]
+pub struct ConfiguredRunner {
+    pub args: Vec<String>,
+    /// Where the runner is configured, like a file or an environment variable.
+    pub source: String,
+}
+
+impl ConfiguredRunner {
+    /// Returns whether this is the runner of cargo-arceos, as written by
+    /// `cargo arceos ide-setup --runner`.
+    pub fn is_cargo_arceos(&self) -> bool {
+        self.args.windows(2).any(|args| {
+            Path::new(&args[0])
+                .file_stem()
+                .is_some_and(|stem| stem == "cargo-arceos")
+                && args[1] == "runner"
+        })
+    }
+}
+
+/// Returns the config files of cargo, the one taking precedence first.
+fn config_files() -> Vec<PathBuf> {
+    let mut files = vec![];
+    if let Ok(dir) = env::current_dir() {
+        for dir in dir.ancestors() {
+            files.extend(candidates(&dir.join(".cargo")));
+        }
+    }
+    let home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::home_dir().map(|home| home.join(".cargo")));
+    if let Some(home) = home {
+        let home = candidates(&home);
+        if !home.iter().any(|file| files.contains(file)) {
+            files.extend(home);
+        }
+    }
+    files
+}
+
+/// Returns the config file in `dir`, where `config.toml` wins over the legacy
+/// `config`.
+fn candidates(dir: &Path) -> Option<PathBuf> {
+    ["config.toml", "config"]
+        .into_iter()
+        .map(|name| dir.join(name))
+        .find(|file| file.is_file())
+}
+
+/// Returns the runner, as a string or an array, at `target.<key>.runner`.
+fn runner_at(config: &toml_edit::DocumentMut, key: &str) -> Option<Vec<String>> {
+    let runner = config.get("target")?.get(key)?.get("runner")?;
+    if let Some(line) = runner.as_str() {
+        // Cargo splits string runners on whitespace.
+        return Some(line.split_whitespace().map(str::to_string).collect());
+    }
+    let args = runner.as_array()?;
+    Some(
+        args.iter()
+            .filter_map(|arg| arg.as_str().map(str::to_string))
+            .collect(),
+    )
+}
+
+/// Returns the runner that cargo would use for `target`, built for `arch`.
+pub fn runner(target: &str, arch: Arch) -> Option<ConfiguredRunner> {
+    let var = format!(
+        "CARGO_TARGET_{}_RUNNER",
+        target.to_uppercase().replace(['-', '.'], "_")
+    );
+    if let Ok(line) = env::var(&var) {
+        return Some(ConfiguredRunner {
+            args: line.split_whitespace().map(str::to_string).collect(),
+            source: format!("`{}`", var),
+        });
+    }
+
+    for file in config_files() {
+        let Some(config) = fs::read_to_string(&file)
+            .ok()
+            .and_then(|toml| toml.parse::<toml_edit::DocumentMut>().ok())
+        else {
+            continue;
+        };
+        let source = |key: &str| format!("`target.{}.runner` in `{}`", key, file.display());
+        if let Some(args) = runner_at(&config, target) {
+            return Some(ConfiguredRunner {
+                args,
+                source: source(target),
+            });
+        }
+        let cfgs = config
+            .get("target")
+            .and_then(|targets| targets.as_table_like());
+        for (key, _) in cfgs.into_iter().flat_map(|targets| targets.iter()) {
+            if let Some(cfg) = key
+                .strip_prefix("cfg(")
+                .and_then(|cfg| cfg.strip_suffix(')'))
+                && matches_cfg(cfg, arch)
+                && let Some(args) = runner_at(&config, key)
+            {
+                return Some(ConfiguredRunner {
+                    args,
+                    source: source(&format!("'{}'", key)),
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Evaluates the `cfg(...)` predicate `cfg` for the bare-metal target of
+/// `arch`.
+fn matches_cfg(cfg: &str, arch: Arch) -> bool {
+    let cfg = cfg.trim();
+    for (op, all) in [("all(", true), ("any(", false)] {
+        if let Some(inner) = cfg
+            .strip_prefix(op)
+            .and_then(|inner| inner.strip_suffix(')'))
+        {
+            let mut predicates = split_top_level(inner).into_iter();
+            return if all {
+                predicates.all(|predicate| matches_cfg(predicate, arch))
+            } else {
+                predicates.any(|predicate| matches_cfg(predicate, arch))
+            };
+        }
+    }
+    if let Some(inner) = cfg
+        .strip_prefix("not(")
+        .and_then(|inner| inner.strip_suffix(')'))
+    {
+        return !matches_cfg(inner, arch);
+    }
+
+    let (key, value) = match cfg.split_once('=') {
+        Some((key, value)) => (key.trim(), Some(value.trim().trim_matches('"'))),
+        None => (cfg, None),
+    };
+    let expected = match key {
+        "target_arch" => arch.as_ref(),
+        "target_os" => "none",
+        "target_vendor" => "unknown",
+        "target_env" => "",
+        "target_endian" => "little",
+        "target_pointer_width" => "64",
+        // Bare-metal targets are in no family, and other keys are rarely used
+        // to select runners.
+        _ => return false,
+    };
+    value == Some(expected)
+}
+
+/// Splits `list` at the commas that are not nested in parentheses.
+fn split_top_level(list: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&list[start..]);
+    parts.retain(|part| !part.trim().is_empty());
+    parts
+}
@@ -1,32 +1,128 @@
 use std::{
-    path::PathBuf,
-    process::{Command, Stdio},
+    path::{Path, PathBuf},
+    process::Command,
 };
 
-use anyhow::Context;
-use clap::Args;
+use anyhow::{Context, bail};
+use cargo_metadata::Metadata;
+use clap::{Args, builder::TypedValueParser};
+use strum::VariantNames;
 
 trait CargoOptionsExt {
     fn build(&mut self) -> Command;
-    fn target_dir(&self) -> anyhow::Result<PathBuf>;
+    fn select_app(&mut self, app: &Path) -> anyhow::Result<()>;
+    fn metadata(&self) -> anyhow::Result<Metadata>;
+    fn target_dir(&self, metadata: &Metadata) -> PathBuf;
     fn profile(&self) -> &str;
 }
 
+/// Arguments for rustc that would break the kernel, as cargo-arceos sets up
+/// the target and how it is linked.
+const BLOCKED_ARGS: &[&str] = &[
+    "--target",
+    "--crate-type",
+    "-Clinker=",
+    "-Clink-arg=-T",
+    "-Clink-args=-T",
+    "-Crelocation-model=",
+    "-Cpanic=",
+];
+
+/// Keeps the extra args after `--` that cargo passes on to rustc or clippy,
+/// except for the blocked ones.
+fn forward_args(args: &mut Vec<String>) {
+    let mut blocked = vec![];
+    let mut args_iter = std::mem::take(args).into_iter();
+    while let Some(arg) = args_iter.next() {
+        let mut group = vec![arg];
+        if matches!(
+            group[0].as_str(),
+            "-C" | "--codegen" | "--target" | "--crate-type"
+        ) && let Some(value) = args_iter.next()
+        {
+            group.push(value);
+        }
+        let normalized = match group.as_slice() {
+            [flag, value] if flag.starts_with('-') && !flag.starts_with("--") => {
+                format!("-C{}", value)
+            }
+            [flag, value] if flag == "--codegen" => format!("-C{}", value),
+            [flag, value] => format!("{}={}", flag, value),
+            [arg] => match arg.strip_prefix("--codegen=") {
+                Some(value) => format!("-C{}", value),
+                None => arg.clone(),
+            },
+            _ => unreachable!(),
+        };
+        if BLOCKED_ARGS
+            .iter()
+            .any(|blocked| normalized.starts_with(blocked))
+        {
+            blocked.extend(group);
+        } else {
+            args.extend(group);
+        }
+    }
+    if !blocked.is_empty() {
+        crate::warn_code(
+            crate::diagnostics::Code::IgnoredOption,
+            format!(
+                "extra args `{}` are ignored, as cargo-arceos sets them up for the kernel",
+                blocked.join(" ")
+            ),
+        );
+    }
+}
+
 macro_rules! impl_cargo_options_ext {
     (@common) => {
-        fn target_dir(&self) -> anyhow::Result<PathBuf> {
-            if let Some(target_dir) = &self.target_dir {
-                return Ok(PathBuf::from(target_dir));
+        fn select_app(&mut self, app: &Path) -> anyhow::Result<()> {
+            if self.manifest_path.is_some() {
+                bail!("`--app` cannot be used together with `--manifest-path`");
+            }
+
+            // Like `make A=...` in ArceOS, paths may also be relative to the
+            // workspace root.
+            let mut manifest_path = app.join("Cargo.toml");
+            if !manifest_path.is_file() {
+                let metadata = self.metadata()?;
+                manifest_path = metadata.workspace_root.as_std_path().join(&manifest_path);
+            }
+            if !manifest_path.is_file() {
+                bail!("app `{}` is not a package", app.display());
             }
 
+            self.manifest_path = Some(manifest_path);
+            Ok(())
+        }
+
+        fn metadata(&self) -> anyhow::Result<Metadata> {
             let mut metadata = cargo_metadata::MetadataCommand::new();
             if let Some(manifest_path) = &self.manifest_path {
                 metadata.manifest_path(manifest_path);
             }
             metadata.no_deps();
-            let metadata = metadata.exec().context("failed to get metadata")?;
+            let flags = [
+                (self.frozen, "--frozen"),
+                (self.locked, "--locked"),
+                (self.offline, "--offline"),
+            ];
+            metadata.other_options(
+                flags
+                    .iter()
+                    .filter(|(given, _)| *given)
+                    .map(|(_, flag)| flag.to_string())
+                    .collect::<Vec<_>>(),
+            );
+            metadata.exec().context("failed to get metadata")
+        }
 
-            Ok(metadata.target_directory.into())
+        fn target_dir(&self, metadata: &Metadata) -> PathBuf {
+            if let Some(target_dir) = &self.target_dir {
+                return PathBuf::from(target_dir);
+            }
+
+            metadata.target_directory.clone().into()
         }
 
         fn profile(&self) -> &str {
@@ -40,31 +136,50 @@ macro_rules! impl_cargo_options_ext {
         }
     };
     (@args $self:ident) => {
-        if !$self.args.is_empty() {
-            crate::warn(format!("extra args `{}` is ignored", $self.args.join(" ")));
-            $self.args.clear();
-        }
+        forward_args(&mut $self.args);
     };
     (@target $self:ident) => {
         if !$self.target.is_empty() {
             $self.target.clear();
-            crate::warn("`--target` option is ignored");
+            crate::warn_code(
+                crate::diagnostics::Code::IgnoredOption,
+                "`--target` option is ignored",
+            );
+        }
+    };
+    (@color $self:ident $command:ident) => {
+        crate::set_color($self.color.as_deref(), Some(&mut $command));
+    };
+    (@quiet $self:ident $command:ident) => {
+        if $self.quiet {
+            crate::set_quiet(&mut $command);
         }
     };
-    (@stdout $self:ident $command:ident) => {
-        if $self.message_format.is_empty() {
-            $command
-                .arg("--message-format=json-render-diagnostics")
-                .stdout(Stdio::piped());
+    (@offline $self:ident $command:ident) => {
+        if $self.offline || $self.frozen {
+            crate::set_offline(&mut $command);
         }
     };
+    (@verbose $self:ident $command:ident) => {
+        if $self.verbose > 0 {
+            crate::set_verbose($self.verbose, &mut $command);
+        }
+    };
+    (@stdout $self:ident $command:ident $formats:ident) => {
+        crate::pipe_messages(&$formats, &mut $command);
+    };
     ($command:path) => {
         impl CargoOptionsExt for $command {
             fn build(&mut self) -> Command {
                 impl_cargo_options_ext!(@args self);
                 impl_cargo_options_ext!(@target self);
+                let formats = std::mem::take(&mut self.message_format);
                 let mut command = self.command();
-                impl_cargo_options_ext!(@stdout self command);
+                impl_cargo_options_ext!(@color self command);
+                impl_cargo_options_ext!(@quiet self command);
+                impl_cargo_options_ext!(@verbose self command);
+                impl_cargo_options_ext!(@offline self command);
+                impl_cargo_options_ext!(@stdout self command formats);
                 command
             }
             impl_cargo_options_ext!(@common);
@@ -74,8 +189,13 @@ macro_rules! impl_cargo_options_ext {
         impl CargoOptionsExt for $command {
             fn build(&mut self) -> Command {
                 impl_cargo_options_ext!(@target self);
+                let formats = std::mem::take(&mut self.message_format);
                 let mut command = self.command();
-                impl_cargo_options_ext!(@stdout self command);
+                impl_cargo_options_ext!(@color self command);
+                impl_cargo_options_ext!(@quiet self command);
+                impl_cargo_options_ext!(@verbose self command);
+                impl_cargo_options_ext!(@offline self command);
+                impl_cargo_options_ext!(@stdout self command formats);
                 command
             }
             impl_cargo_options_ext!(@common);
@@ -86,7 +206,12 @@ macro_rules! impl_cargo_options_ext {
             fn build(&mut self) -> Command {
                 impl_cargo_options_ext!(@args self);
                 impl_cargo_options_ext!(@target self);
-                self.command()
+                let mut command = self.command();
+                impl_cargo_options_ext!(@color self command);
+                impl_cargo_options_ext!(@quiet self command);
+                impl_cargo_options_ext!(@verbose self command);
+                impl_cargo_options_ext!(@offline self command);
+                command
             }
             impl_cargo_options_ext!(@common);
         }
@@ -97,59 +222,495 @@ impl_cargo_options_ext!(no_arg cargo_options::Build);
 impl_cargo_options_ext!(cargo_options::Rustc);
 impl_cargo_options_ext!(no_arg cargo_options::Check);
 impl_cargo_options_ext!(cargo_options::Clippy);
+impl_cargo_options_ext!(no_arg cargo_options::Doc);
 impl_cargo_options_ext!(no_stdout cargo_options::Run);
 impl_cargo_options_ext!(cargo_options::Test);
 
+/// A cargo invocation along with what is needed to process its results.
+pub struct Invocation {
+    pub command: Command,
+    pub features: crate::features::Context,
+    pub c_app: Option<crate::capp::CApp>,
+    pub cache: Option<crate::cache::Sccache>,
+    /// Where to copy the images of the built executables.
+    pub artifacts: Option<crate::artifacts::Collector>,
+    /// What to do with the built executables, or their copies.
+    pub signing: Option<crate::signing::Signer>,
+    /// The container that cargo runs in.
+    pub container: Option<crate::container::Container>,
+    /// The runner to run the built executable with on the host, when cargo
+    /// cannot run it from the container.
+    pub runner: Option<Command>,
+    /// Whether to list the symbols of the built static libraries.
+    pub list_symbols: bool,
+    /// The workspace members, to summarize the build per package.
+    pub members: Vec<cargo_metadata::PackageId>,
+    /// Where to record the build, see `cargo arceos stats`.
+    pub stats: Option<crate::stats::Recorder>,
+}
+
+/// The parts of an [`Invocation`] that every command sets up the same way.
+struct Setup {
+    command: Command,
+    metadata: Metadata,
+    arceos_metadata: crate::metadata::ArceOSMetadata,
+    target_dir: PathBuf,
+    profile: String,
+    features: crate::features::Context,
+    cache: Option<crate::cache::Sccache>,
+    container: Option<crate::container::Container>,
+}
+
+impl From<Setup> for Invocation {
+    fn from(setup: Setup) -> Self {
+        Invocation {
+            command: setup.command,
+            features: setup.features,
+            c_app: None,
+            cache: setup.cache,
+            artifacts: None,
+            signing: None,
+            container: setup.container,
+            runner: None,
+            list_symbols: false,
+            members: setup.metadata.workspace_members,
+            stats: None,
+        }
+    }
+}
+
+impl crate::options::ArceOSOptions {
+    /// Sets up `cargo` to build the kernel with these options, for a command
+    /// enabling `features`. Once the app is selected and the container is
+    /// known, `build` makes the cargo command, and may push configs before
+    /// they are applied to it.
+    fn setup<C: CargoOptionsExt>(
+        &mut self,
+        cargo: &mut C,
+        features: crate::features::Context,
+        build: impl FnOnce(
+            &mut C,
+            &mut Self,
+            Option<&crate::container::Container>,
+        ) -> anyhow::Result<Command>,
+    ) -> anyhow::Result<Setup> {
+        self.init()?;
+        if let Some(app) = self.app() {
+            cargo.select_app(app)?;
+        }
+        let metadata = cargo.metadata()?;
+        let target_dir = cargo.target_dir(&metadata);
+        let container = self.container(&metadata, &target_dir)?;
+        let mut command = build(cargo, self, container.as_ref())?;
+
+        let profile = cargo.profile().to_string();
+        self.apply(&metadata, &target_dir, &profile, &mut command)?;
+        self.apply_log_file(&mut command)?;
+        crate::unification::check(&metadata, self.target());
+        let cache = self.apply_cache(&mut command)?;
+        let arceos_metadata = crate::metadata::ArceOSMetadata::new(&metadata)?;
+        self.apply_reproducible(&metadata, &target_dir, &mut command);
+        self.apply_target_features(&arceos_metadata, &mut command)?;
+        self.enable_features(&features, &metadata, &mut command)?;
+
+        Ok(Setup {
+            command,
+            metadata,
+            arceos_metadata,
+            target_dir,
+            profile,
+            features,
+            cache,
+            container,
+        })
+    }
+}
+
 macro_rules! command {
-    ($command:ident) => {
-        #[derive(Debug, Args)]
+    ($command:ident $(, $platforms:ident)?) => {
+        #[derive(Debug, Clone, Args)]
         pub struct $command {
             #[command(flatten)]
             cargo: cargo_options::$command,
             #[command(flatten)]
-            pub arceos: crate::options::ArceOSOptions,
+            arceos: crate::options::ArceOSOptions,
+            $(
+                #[command(flatten)]
+                $platforms: crate::matrix::PlatformsOptions,
+            )?
         }
 
         impl $command {
-            pub fn build(&mut self) -> anyhow::Result<Command> {
-                let mut command = self.cargo.build();
-
-                let target_dir = self.cargo.target_dir()?;
-                let profile = self.cargo.profile();
-                self.arceos.apply(&target_dir, profile, &mut command)?;
+            pub fn build(&mut self) -> anyhow::Result<Invocation> {
+                let features = self.feature_context();
+                let setup = self
+                    .arceos
+                    .setup(&mut self.cargo, features, |cargo, _, _| Ok(cargo.build()))?;
+                Ok(setup.into())
+            }
 
-                Ok(command)
+            pub fn feature_context(&self) -> crate::features::Context {
+                self.arceos.feature_context()
             }
+
+            $(
+                /// Returns the cargo command for each platform, if
+                /// `--all-platforms` is given.
+                pub fn build_all_platforms(
+                    &self,
+                ) -> anyhow::Result<Option<Vec<(crate::platforms::Platform, Command)>>> {
+                    if !self.$platforms.is_enabled() {
+                        return Ok(None);
+                    }
+                    let mut cargo = self.cargo.clone();
+                    if let Some(app) = self.arceos.app() {
+                        cargo.select_app(app)?;
+                    }
+                    let target_dir = cargo.target_dir(&cargo.metadata()?);
+
+                    let mut builds = vec![];
+                    for platform in crate::matrix::platforms() {
+                        let mut command = self.clone();
+                        command.arceos.set_platform(platform);
+                        command.cargo.target_dir =
+                            Some(crate::matrix::target_dir(&target_dir, platform));
+                        let invocation = command.build()?;
+                        if invocation.container.is_some() {
+                            bail!("`--all-platforms` cannot run cargo in a container");
+                        }
+                        builds.push((platform, invocation.command));
+                    }
+                    Ok(Some(builds))
+                }
+            )?
         }
     };
 }
 
-command!(Build);
 command!(Rustc);
-command!(Check);
-command!(Clippy);
+command!(Check, platforms);
+command!(Clippy, platforms);
+command!(Doc);
 
 #[derive(Debug, Args)]
+pub struct Build {
+    #[command(flatten)]
+    cargo: cargo_options::Build,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    #[command(flatten)]
+    c_app: crate::capp::CAppOptions,
+    #[command(flatten)]
+    artifacts: crate::artifacts::ArtifactOptions,
+    #[command(flatten)]
+    image: crate::image::ImageOptions,
+    #[command(flatten)]
+    signing: crate::signing::SigningOptions,
+    /// Build the library of the package as CRATE_TYPE instead of linking a
+    /// kernel, like `staticlib` for embedding the app into other images,
+    /// with a header listing its symbols
+    #[arg(
+        long,
+        value_name = "CRATE_TYPE",
+        value_parser = enum_variants!(crate::library::CrateType),
+        conflicts_with = "artifact_dir",
+        help_heading = "Compilation Options"
+    )]
+    crate_type: Option<crate::library::CrateType>,
+}
+
+impl Build {
+    pub fn build(&mut self) -> anyhow::Result<Invocation> {
+        // `--out-dir` copies images rather than the ELF files that cargo would
+        // copy, and works without `-Z unstable-options`.
+        let out_dir = self.cargo.artifact_dir.take();
+        let crate_type = self.crate_type;
+        let features = self.feature_context();
+        let mut setup = self
+            .arceos
+            .setup(&mut self.cargo, features, |cargo, arceos, _| {
+                let mut command = cargo.build();
+                if let Some(crate_type) = crate_type {
+                    command = crate::library::rustc_command(&command, crate_type);
+                    arceos.build_library();
+                }
+                Ok(command)
+            })?;
+
+        let profile = setup.profile.as_str();
+        let binary_dir = self.arceos.binary_dir(&setup.target_dir, profile);
+        let c_app = self.c_app.apply(
+            &setup.features,
+            &setup.metadata,
+            &binary_dir,
+            profile,
+            &mut setup.command,
+        )?;
+        let artifacts = out_dir.map(|dir| {
+            self.artifacts.collector(
+                dir,
+                self.arceos.platform(),
+                self.arceos.target(),
+                profile,
+                &self.image,
+            )
+        });
+        let stats = self.arceos.stats(&setup.target_dir, profile);
+
+        Ok(Invocation {
+            c_app,
+            artifacts,
+            signing: (!self.signing.is_empty()).then(|| crate::signing::Signer {
+                options: self.signing.clone(),
+                platform: self.arceos.platform(),
+                image: self.image.clone(),
+            }),
+            list_symbols: self.crate_type == Some(crate::library::CrateType::Staticlib),
+            stats,
+            ..setup.into()
+        })
+    }
+
+    pub fn feature_context(&self) -> crate::features::Context {
+        self.arceos.feature_context()
+    }
+}
+
+#[derive(Debug, Clone, Args)]
 pub struct Run {
     #[command(flatten)]
     cargo: cargo_options::Run,
     #[command(flatten)]
-    pub arceos: crate::options::ArceOSOptions,
+    arceos: crate::options::ArceOSOptions,
     #[command(flatten)]
     qemu: crate::options::QEMUOptions,
+    #[command(flatten)]
+    serial: crate::serial::SerialOptions,
+    #[command(flatten)]
+    image: crate::image::ImageOptions,
+    #[command(flatten)]
+    metrics: crate::metrics::MetricsOptions,
+    #[command(flatten)]
+    runner: crate::options::RunnerOptions,
+    #[command(flatten)]
+    harness: crate::harness::HarnessOptions,
+    /// Run the binary whose name matches PATTERN, where `*` matches any
+    /// characters, if none is given with `--bin` or `--example`
+    #[arg(long, value_name = "PATTERN", help_heading = "Target Selection")]
+    bin_pattern: Option<String>,
+    /// Build and run the kernel with each of PROFILES under the same settings,
+    /// like `debug,release`, and compare the runs
+    #[arg(
+        long,
+        value_name = "PROFILES",
+        value_delimiter = ',',
+        conflicts_with_all = ["release", "profile"],
+        help_heading = "Compare Options"
+    )]
+    compare: Vec<String>,
+    /// Compare when the guest first prints a line containing TEXT
+    #[arg(
+        long,
+        value_name = "TEXT",
+        requires = "compare",
+        help_heading = "Compare Options"
+    )]
+    compare_marker: Vec<String>,
 }
 
 impl Run {
-    pub fn build(&mut self) -> anyhow::Result<Command> {
-        let mut command = self.cargo.build();
+    /// Runs the kernel with each profile of `--compare` if given, returning
+    /// the exit code.
+    pub fn compare(
+        &self,
+        handler: &mut dyn crate::events::EventHandler,
+    ) -> Option<anyhow::Result<i32>> {
+        if self.compare.is_empty() {
+            return None;
+        }
+        Some(crate::compare::run(
+            self,
+            &self.compare,
+            &self.compare_marker,
+            handler,
+        ))
+    }
+
+    /// Returns the command that runs the kernel built with `profile`.
+    pub fn with_profile(&self, profile: &str) -> Run {
+        let mut run = self.clone();
+        run.compare.clear();
+        run.compare_marker.clear();
+        match profile {
+            "debug" | "dev" => {}
+            "release" => run.cargo.release = true,
+            profile => run.cargo.profile = Some(profile.to_string()),
+        }
+        run
+    }
+
+    /// Unless `interactive`, the output of cargo and the guest is reported as
+    /// events instead of going to the terminal.
+    pub fn build(&mut self, interactive: bool) -> anyhow::Result<Invocation> {
+        if self.qemu.is_ci() {
+            self.arceos.deny_warnings();
+            if self.cargo.message_format.is_empty() {
+                self.cargo.message_format.push("json".to_string());
+            }
+        }
+        let features = self.feature_context();
+        let (qemu, serial) = (&self.qemu, &self.serial);
+        let bin_pattern = self.bin_pattern.as_deref();
+        let mut setup =
+            self.arceos
+                .setup(&mut self.cargo, features, |cargo, arceos, container| {
+                    select_bin(cargo, bin_pattern, qemu.is_ci())?;
+                    if !matches!(arceos.platform(), crate::platforms::Platform::Dummy) {
+                        crate::qemu::check_programs(
+                            arceos.arch(),
+                            !serial.attached() && !qemu.is_remote(),
+                        )?;
+                        serial.check_programs()?;
+                    }
+                    // The guest console stays on the terminal when interactive, so
+                    // cargo cannot report to a pipe then, unless it only builds in
+                    // a container.
+                    let formats = (!interactive || container.is_some())
+                        .then(|| std::mem::take(&mut cargo.message_format));
+                    // The args after `--` are for the app, so they go into its
+                    // command line rather than to the runner.
+                    let app_args = std::mem::take(&mut cargo.args);
+                    let mut command = cargo.build();
+                    if !app_args.is_empty() {
+                        command.env(crate::qemu::APP_ARGS_ENV, crate::qemu::cmdline(&app_args));
+                    }
+                    if let Some(formats) = formats {
+                        crate::pipe_messages(&formats, &mut command);
+                    }
+                    if !interactive {
+                        crate::events::enable(&mut command);
+                    }
+
+                    for config in [
+                        qemu.guest_config()?,
+                        qemu.display_config()?,
+                        qemu.cpu_consoles_config(arceos.platform())?,
+                        qemu.net_config()?,
+                    ]
+                    .into_iter()
+                    .flatten()
+                    {
+                        arceos.push_config(config);
+                    }
+                    Ok(command)
+                })?;
+
+        let command = &mut setup.command;
+        crate::timings::mark_started(command);
+        if let Some(stats) = self.arceos.stats(&setup.target_dir, &setup.profile) {
+            stats.apply(command);
+        }
+
+        let metadata = &setup.arceos_metadata;
+        self.qemu.apply_firmware(self.arceos.platform(), metadata)?;
+        let mut args = vec![];
+        self.qemu.runner_args(&mut args);
+        self.serial.runner_args(&mut args);
+        self.image.runner_args(&mut args);
+        self.metrics.extend(&metadata.metrics)?;
+        self.metrics.runner_args(&mut args);
+        self.harness.runner_args(&mut args);
+        let (target, arch) = (self.arceos.target(), self.arceos.arch());
+        let runner = match &mut setup.container {
+            Some(container) => {
+                container.build_only();
+                Some(self.runner.host_command(target, arch, metadata, args)?)
+            }
+            None => {
+                self.runner.apply(target, arch, metadata, args, command)?;
+                None
+            }
+        };
+
+        Ok(Invocation {
+            runner,
+            // Only one package is run, so there is nothing to summarize.
+            members: vec![],
+            // The runner records the build.
+            stats: None,
+            ..setup.into()
+        })
+    }
+
+    pub fn feature_context(&self) -> crate::features::Context {
+        let mut ctx = self.arceos.feature_context();
+        self.qemu.update_feature_context(&mut ctx);
+        ctx
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Watch {
+    #[command(flatten)]
+    run: Run,
+}
 
-        let target_dir = self.cargo.target_dir()?;
-        let profile = self.cargo.profile();
-        self.arceos.apply(&target_dir, profile, &mut command)?;
+impl Watch {
+    pub fn execute(mut self, handler: &mut dyn crate::events::EventHandler) -> anyhow::Result<i32> {
+        // Before the watcher starts, as it spawns a thread.
+        self.run.arceos.init()?;
+        // Pick the binary once, instead of asking again on every change.
+        let mut run = self.run.clone();
+        if let Some(app) = run.arceos.app() {
+            run.cargo.select_app(app)?;
+        }
+        select_bin(&mut run.cargo, run.bin_pattern.as_deref(), run.qemu.is_ci())?;
+        self.run.cargo.bin = run.cargo.bin.clone();
+        self.run.cargo.packages = run.cargo.packages.clone();
+
+        let metadata = run.cargo.metadata()?;
+        let target_dir = run.cargo.target_dir(&metadata);
+        let mut ignore = vec![target_dir];
+        ignore.extend(run.arceos.output_paths());
+        ignore.extend(run.qemu.output_paths());
+        ignore.extend(run.serial.output_paths());
+        crate::watch::start(metadata.workspace_root.into(), ignore);
+        // Ctrl-C reaches the runner as well, which stops the guest.
+        crate::signal::install();
+
+        loop {
+            let code = crate::Cli::Run(Box::new(self.run.clone())).execute(handler)?;
+            if crate::signal::received().is_some() {
+                return Ok(code);
+            }
+            crate::info(
+                "Watching",
+                "for changes to the sources, press Ctrl-C to stop",
+            );
+            if !crate::watch::wait() {
+                return Ok(code);
+            }
+        }
+    }
+}
 
-        self.qemu.apply(self.arceos.target(), &mut command);
+#[derive(Debug, Args)]
+pub struct Test {
+    #[command(flatten)]
+    run: Run,
+    /// Print the helper for the guest to add to the app, and exit
+    #[arg(long, exclusive = true, help_heading = "Test Options")]
+    guest_code: bool,
+}
 
-        Ok(command)
+impl Test {
+    pub fn execute(mut self, handler: &mut dyn crate::events::EventHandler) -> anyhow::Result<i32> {
+        if self.guest_code {
+            print!("{}", crate::harness::GUEST_CODE);
+            return Ok(0);
+        }
+        self.run.harness.enable();
+        crate::Cli::Run(Box::new(self.run)).execute(handler)
     }
 }
 
@@ -157,11 +718,968 @@ impl Run {
 pub struct Runner {
     #[command(flatten)]
     qemu: crate::options::QEMUOptions,
+    #[command(flatten)]
+    serial: crate::serial::SerialOptions,
+    #[command(flatten)]
+    image: crate::image::ImageOptions,
+    #[command(flatten)]
+    metrics: crate::metrics::MetricsOptions,
+    #[command(flatten)]
+    harness: crate::harness::HarnessOptions,
     binary: PathBuf,
 }
 
 impl Runner {
+    pub fn execute(self) -> anyhow::Result<i32> {
+        crate::stats::record_run(&self.binary);
+        self.harness.start();
+        if self.serial.attached() {
+            self.serial
+                .execute(&self.image, self.binary, self.qemu.timeout())
+        } else {
+            self.qemu.execute(&self.image, &self.metrics, self.binary)
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct IdeSetup {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// Config files to write
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "cargo",
+        value_parser = enum_variants!(crate::ide::Editor),
+        help_heading = "IDE Options"
+    )]
+    write: Vec<crate::ide::Editor>,
+    /// Also write the runner into `.cargo/config.toml`, for plain `cargo run`
+    /// to boot the kernel like `cargo arceos run`
+    #[arg(long, help_heading = "IDE Options")]
+    runner: bool,
+    /// Check that the config files are up to date instead of writing them
+    #[arg(long, help_heading = "IDE Options")]
+    check: bool,
+    #[command(flatten)]
+    runner_options: crate::options::RunnerOptions,
+}
+
+impl IdeSetup {
+    pub fn execute(mut self) -> anyhow::Result<()> {
+        let features = self.arceos.feature_context();
+        let setup =
+            self.arceos
+                .setup(&mut self.cargo, features, |cargo, _, _| Ok(cargo.build()))?;
+
+        let mut settings = crate::ide::BuildSettings::new(&setup.command);
+        if self.runner {
+            settings.runner = Some(self.runner_options.runner(
+                self.arceos.target(),
+                self.arceos.arch(),
+                &setup.arceos_metadata,
+            )?);
+        }
+        let root = setup.metadata.workspace_root.as_std_path();
+        for editor in &self.write {
+            match editor {
+                crate::ide::Editor::Cargo => {
+                    crate::ide::write_cargo_config(root, &settings, self.check)?
+                }
+                crate::ide::Editor::Vscode => {
+                    crate::ide::write_vscode_settings(root, &settings, self.check)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Env {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// How to print the environment, where the formats for other tools only
+    /// have the variables exported to the build
+    #[arg(
+        long,
+        default_value = "annotated",
+        value_parser = enum_variants!(crate::envvars::Format),
+        help_heading = "Env Options"
+    )]
+    format: crate::envvars::Format,
+}
+
+impl Env {
+    pub fn execute(mut self) -> anyhow::Result<()> {
+        let features = self.arceos.feature_context();
+        let setup =
+            self.arceos
+                .setup(&mut self.cargo, features, |cargo, _, _| Ok(cargo.build()))?;
+
+        crate::envvars::print_exported(&setup.command, self.arceos.target(), self.format);
+        Ok(())
+    }
+}
+
+/// Picks the binary of `cargo` to run when the package has several and none
+/// is given, by `bin_pattern` or by asking on the terminal, unless on a CI
+/// runner. Otherwise it is left to cargo.
+fn select_bin(
+    cargo: &mut cargo_options::Run,
+    bin_pattern: Option<&str>,
+    ci: bool,
+) -> anyhow::Result<()> {
+    if !cargo.bin.is_empty() || !cargo.example.is_empty() {
+        if bin_pattern.is_some() {
+            crate::warn_code(
+                crate::diagnostics::Code::IgnoredOption,
+                "`--bin-pattern` option is ignored",
+            );
+        }
+        return Ok(());
+    }
+
+    let metadata = cargo.metadata()?;
+    let packages: Vec<&cargo_metadata::Package> = match cargo.packages.as_slice() {
+        [] => match metadata.root_package() {
+            Some(package) => vec![package],
+            None => metadata.workspace_default_packages(),
+        },
+        names => metadata
+            .workspace_packages()
+            .into_iter()
+            .filter(|package| names.iter().any(|name| name == package.name.as_str()))
+            .collect(),
+    };
+    if bin_pattern.is_none()
+        && let [package] = packages.as_slice()
+        && package.default_run.is_some()
+    {
+        return Ok(());
+    }
+
+    // With `--ci`, there is nobody to ask.
+    let interactive = is_interactive() && !ci;
+    let bins: Vec<(&str, &str)> = packages
+        .iter()
+        .flat_map(|package| {
+            package
+                .targets
+                .iter()
+                .filter(|target| target.is_bin())
+                .map(|target| (package.name.as_str(), target.name.as_str()))
+        })
+        .filter(|(_, bin)| bin_pattern.is_none_or(|pattern| matches_pattern(pattern, bin)))
+        .collect();
+    let (package, bin) = match bins.as_slice() {
+        [] if bin_pattern.is_some() => bail!("no binary matches `{}`", bin_pattern.unwrap()),
+        [] => return Ok(()),
+        [bin] => *bin,
+        // Only ask if cargo would not know which binary to run either.
+        _ if bin_pattern.is_none() && !interactive => return Ok(()),
+        _ if !interactive => bail!(
+            "several binaries match `{}`, use `--bin` to pick one of: {}",
+            bin_pattern.unwrap(),
+            bins.iter()
+                .map(|(_, bin)| *bin)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        _ => choose_bin(&bins)?,
+    };
+
+    crate::info("Selected", format!("binary `{}` of `{}`", bin, package));
+    if packages.len() > 1 {
+        cargo.packages = vec![package.to_string()];
+    }
+    cargo.bin = vec![bin.to_string()];
+    Ok(())
+}
+
+fn is_interactive() -> bool {
+    use std::io::IsTerminal;
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Asks on the terminal which of `bins`, given with their packages, to run.
+fn choose_bin<'a>(bins: &[(&'a str, &'a str)]) -> anyhow::Result<(&'a str, &'a str)> {
+    eprintln!("Several binaries can be run:");
+    for (i, (package, bin)) in bins.iter().enumerate() {
+        eprintln!("  {}) {} ({})", i + 1, bin, package);
+    }
+    loop {
+        eprint!("Run which one? [1-{}] ", bins.len());
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            bail!("no binary selected");
+        }
+        let answer = answer.trim();
+        if let Ok(i @ 1..) = answer.parse::<usize>()
+            && let Some(bin) = bins.get(i - 1)
+        {
+            return Ok(*bin);
+        }
+        if let Some(bin) = bins.iter().find(|(_, bin)| *bin == answer) {
+            return Ok(*bin);
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            // Try every position for the rest of the pattern to start at.
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain([name.len()])
+                .any(|i| matches_pattern(rest, &name[i..]))
+        }
+    }
+}
+
+/// Returns the config that `elf` was built with for `platform`, which has the
+/// config files merged into it, or the config of the platform.
+fn built_config(
+    elf: &Path,
+    platform: crate::platforms::Platform,
+) -> anyhow::Result<axconfig_gen::Config> {
+    let path = crate::scratch::path(elf)
+        .with_file_name(platform.to_string())
+        .join("axconfig.toml");
+    let Ok(toml) = std::fs::read_to_string(&path) else {
+        return Ok(platform.into());
+    };
+    axconfig_gen::Config::from_toml(&toml)
+        .map_err(|e| anyhow::anyhow!("failed to parse config file `{}`: {}", path.display(), e))
+}
+
+/// Returns the ELF of the binary selected by `--package` and `--bin` that a
+/// previous build has left in the target directory.
+fn built_elf(
+    cargo: &mut cargo_options::Check,
+    arceos: &crate::options::ArceOSOptions,
+) -> anyhow::Result<PathBuf> {
+    arceos.init()?;
+    if let Some(app) = arceos.app() {
+        cargo.select_app(app)?;
+    }
+    let metadata = cargo.metadata()?;
+    let target_dir = cargo.target_dir(&metadata);
+    let binary_dir = arceos.binary_dir(&target_dir, cargo.profile());
+    arceos.set_scratch_dir(&target_dir, cargo.profile(), None)?;
+
+    let package = match cargo.check.packages.as_slice() {
+        [] => metadata.root_package(),
+        [name] => metadata.packages.iter().find(|p| p.name.as_str() == name),
+        _ => bail!("only one package can be selected"),
+    }
+    .context("no package selected, use `--package` to pick one")?;
+    // Examples are built into a directory of their own.
+    let example = !cargo.check.example.is_empty();
+    let (kind, flag, dir, names) = if example {
+        (
+            "example",
+            "--example",
+            binary_dir.join("examples"),
+            &cargo.check.example,
+        )
+    } else {
+        ("binary", "--bin", binary_dir, &cargo.check.bin)
+    };
+    let targets: Vec<&str> = package
+        .targets
+        .iter()
+        .filter(|target| {
+            if example {
+                target.is_example()
+            } else {
+                target.is_bin()
+            }
+        })
+        .map(|target| target.name.as_str())
+        .filter(|name| names.is_empty() || names.iter().any(|n| n == name))
+        .collect();
+    let target = match targets.as_slice() {
+        [target] => *target,
+        [] => bail!("package `{}` has no such {}", package.name, kind),
+        _ => bail!(
+            "package `{}` has several {} targets, use `{}` to pick one of: {}",
+            package.name,
+            kind,
+            flag,
+            targets.join(", ")
+        ),
+    };
+
+    let elf = dir.join(target);
+    if !elf.is_file() {
+        bail!(
+            "`{}` does not exist, build it with `cargo arceos build` first",
+            elf.display()
+        );
+    }
+    Ok(elf)
+}
+
+#[derive(Debug, Args)]
+pub struct Symbolize {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// ELF to resolve addresses with [default: the built binary]
+    #[arg(long, value_name = "PATH", help_heading = "Symbolize Options")]
+    elf: Option<PathBuf>,
+    /// File with the guest output [default: stdin]
+    input: Option<PathBuf>,
+}
+
+impl Symbolize {
+    pub fn execute(mut self) -> anyhow::Result<()> {
+        crate::set_color(self.cargo.color.as_deref(), None);
+        let elf = match self.elf.take() {
+            Some(elf) => elf,
+            None => built_elf(&mut self.cargo, &self.arceos)?,
+        };
+        let input = match &self.input {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read `{}`", path.display()))?,
+            None => std::io::read_to_string(std::io::stdin()).context("failed to read stdin")?,
+        };
+        crate::symbolize::print(&elf, &input)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Explain {
+    /// Code of the warning or error, like `AXW0001`
+    #[arg(value_parser = enum_variants!(crate::diagnostics::Code))]
+    code: crate::diagnostics::Code,
+}
+
+impl Explain {
+    pub fn execute(self) {
+        println!("{}", self.code.explanation());
+        if self.code.is_warning() {
+            println!();
+            println!("This warning can be allowed with `--allow {}`.", self.code);
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Init {
+    /// Ask for each default on the terminal, offering the given ones
+    #[arg(short, long)]
+    interactive: bool,
+    /// Path to Cargo.toml
+    #[arg(long, value_name = "PATH", default_value = "Cargo.toml")]
+    manifest_path: PathBuf,
+    /// Platform to run on by default
+    #[arg(short = 'P', long, value_parser = enum_variants!(crate::platforms::Platform))]
+    platform: Option<crate::platforms::Platform>,
+    /// Number of CPUs by default
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    cpus: Option<u32>,
+    /// Enable the network device by default
+    #[arg(long)]
+    net: bool,
+    /// Disk image to run with by default
+    #[arg(short, long)]
+    disk: Option<PathBuf>,
+    /// Enable graphics by default
+    #[arg(short, long)]
+    graphics: bool,
+    /// Log level by default
+    #[arg(short = 'L', long, value_name = "LEVEL")]
+    log: Option<log::LevelFilter>,
+}
+
+impl Init {
+    pub fn execute(self) -> anyhow::Result<()> {
+        let mut defaults = crate::init::Defaults {
+            platform: self.platform,
+            cpus: self.cpus,
+            net: self.net.then_some(true),
+            disk: self.disk.map(Some),
+            graphics: self.graphics.then_some(true),
+            log: self.log,
+        };
+        if self.interactive {
+            if !is_interactive() {
+                bail!("`--interactive` needs a terminal");
+            }
+            defaults.ask()?;
+        } else if defaults.entries().is_empty() {
+            bail!("no defaults given, pass them as options or choose them with `--interactive`");
+        }
+        defaults.write(&self.manifest_path)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Config {
+    #[command(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum ConfigCommand {
+    /// Show the configs that set a key, from the defconfig to the options,
+    /// and which of them wins
+    Resolve(Box<ConfigResolve>),
+    /// Write the built-in configs of the platforms into DIR, to change them
+    /// and load them with `--platform-dir`
+    ExportPlatforms(ConfigExportPlatforms),
+}
+
+#[derive(Debug, Args)]
+struct ConfigExportPlatforms {
+    /// Directory to write the configs into
+    dir: PathBuf,
+    /// Replace the configs that are already in DIR
+    #[arg(long)]
+    force: bool,
+}
+
+impl ConfigExportPlatforms {
+    fn execute(self) -> anyhow::Result<()> {
+        let count = crate::platforms::export(&self.dir, self.force)?;
+        crate::info(
+            "Exported",
+            format!("{} configs to `{}`", count, self.dir.display()),
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+struct ConfigResolve {
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// Key to resolve, like `plat.phys-memory-size`, or the constant that
+    /// `axconfig` generates for it, like `plat::PHYS_MEMORY_SIZE`
+    key: String,
+    /// Coloring
+    #[arg(long, value_name = "WHEN", value_parser = ["auto", "always", "never"])]
+    color: Option<String>,
+}
+
+impl Config {
+    pub fn execute(self) -> anyhow::Result<()> {
+        match self.command {
+            ConfigCommand::Resolve(command) => command.execute(),
+            ConfigCommand::ExportPlatforms(command) => command.execute(),
+        }
+    }
+}
+
+impl ConfigResolve {
+    fn execute(self) -> anyhow::Result<()> {
+        use axconfig_gen::Config;
+
+        crate::set_color(self.color.as_deref(), None);
+        self.arceos.init()?;
+        let platform = self.arceos.platform();
+        let schema = crate::schema::Schema::new(
+            (!matches!(platform, crate::platforms::Platform::Dummy)).then_some(self.arceos.arch()),
+        );
+
+        // The constants of `axconfig` are the keys in upper snake case.
+        let key = self
+            .key
+            .trim_start_matches("axconfig::")
+            .replace("::", ".")
+            .replace('_', "-")
+            .to_lowercase();
+        let (table, name) = key
+            .split_once('.')
+            .unwrap_or((Config::GLOBAL_TABLE_NAME, &key));
+
+        let mut layers = vec![
+            (
+                "defconfig".to_string(),
+                Config::from_toml(crate::platforms::defconfig()).unwrap(),
+            ),
+            (
+                format!("platform `{}`", platform),
+                Config::from_toml(platform.config_toml()).unwrap(),
+            ),
+        ];
+        for layer in self.arceos.config_layers(&schema)? {
+            layers.push((layer.source, layer.config));
+        }
+
+        let mut winner = None;
+        for (source, config) in &layers {
+            match config.config_at(table, name) {
+                Some(item) => {
+                    let value = item.value().to_toml_value();
+                    println!("{:>12} `{}` in {}", "set", value, source);
+                    winner = Some((value, source.clone()));
+                }
+                None => println!("{:>12} in {}", "not set", source),
+            }
+        }
+        if table == Config::GLOBAL_TABLE_NAME {
+            for (key, value, option) in self.arceos.config_overrides() {
+                if key == name {
+                    println!("{:>12} `{}` by `{}`", "overridden", value, option);
+                    winner = Some((value, format!("`{}`", option)));
+                }
+            }
+        }
+
+        let Some((value, source)) = winner else {
+            bail!(
+                "`{}` is not set by any config of platform `{}`",
+                key,
+                platform
+            );
+        };
+        println!();
+        println!(
+            "{} = {}, from {}",
+            console::style(&key).bold(),
+            value,
+            source
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Firmware {
+    #[command(subcommand)]
+    command: FirmwareCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum FirmwareCommand {
+    /// Download the firmware of a platform into the cache directory
+    Fetch(FirmwareFetch),
+}
+
+#[derive(Debug, Args)]
+struct FirmwareFetch {
+    /// Platform to fetch the firmware of
+    #[arg(value_parser = enum_variants!(crate::platforms::Platform))]
+    platform: crate::platforms::Platform,
+    /// Download the files again even if they are in the cache
+    #[arg(long)]
+    force: bool,
+    /// Path to Cargo.toml, for its `firmware` metadata
+    #[arg(long, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+}
+
+impl Firmware {
+    pub fn execute(self) -> anyhow::Result<()> {
+        match self.command {
+            FirmwareCommand::Fetch(command) => command.execute(),
+        }
+    }
+}
+
+impl FirmwareFetch {
+    fn execute(self) -> anyhow::Result<()> {
+        let mut command = cargo_metadata::MetadataCommand::new();
+        if let Some(path) = &self.manifest_path {
+            command.manifest_path(path);
+        }
+        // Firmware can be fetched outside of a package as well.
+        let metadata = match command.no_deps().exec() {
+            Ok(metadata) => Some(crate::metadata::ArceOSMetadata::new(&metadata)?),
+            Err(_) if self.manifest_path.is_none() => None,
+            Err(e) => return Err(e).context("failed to get metadata"),
+        };
+        let files = crate::firmware::files(self.platform, metadata.as_ref());
+        crate::firmware::fetch(self.platform, &files, self.force)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Push {
+    /// File to copy into the guest
+    #[arg(required_unless_present = "guest_code")]
+    local: Option<PathBuf>,
+    /// Path in the guest [default: the name of the file in `/`]
+    remote: Option<String>,
+    /// Address that QEMU forwards to the file server of the guest
+    #[arg(long, default_value = crate::transfer::DEFAULT_ADDR)]
+    addr: String,
+    /// Print the file server for the guest to add to the app, and exit
+    #[arg(long, exclusive = true)]
+    guest_code: bool,
+}
+
+impl Push {
+    pub fn execute(self) -> anyhow::Result<()> {
+        if self.guest_code {
+            print!("{}", crate::transfer::GUEST_CODE);
+            return Ok(());
+        }
+        let local = self.local.unwrap();
+        let remote = match self.remote {
+            Some(remote) => remote,
+            None => format!(
+                "/{}",
+                local
+                    .file_name()
+                    .with_context(|| format!("`{}` is not a file", local.display()))?
+                    .to_string_lossy()
+            ),
+        };
+        crate::transfer::push(&self.addr, &local, &remote)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Pull {
+    /// Path in the guest of the file to copy
+    remote: String,
+    /// Where to copy the file to [default: its name in the current directory]
+    local: Option<PathBuf>,
+    /// Address that QEMU forwards to the file server of the guest
+    #[arg(long, default_value = crate::transfer::DEFAULT_ADDR)]
+    addr: String,
+}
+
+impl Pull {
+    pub fn execute(self) -> anyhow::Result<()> {
+        let local = match self.local {
+            Some(local) => local,
+            None => Path::new(&self.remote)
+                .file_name()
+                .with_context(|| format!("`{}` has no file name", self.remote))?
+                .into(),
+        };
+        crate::transfer::pull(&self.addr, &self.remote, &local)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Memmap {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// ELF to show the sections of [default: the built binary]
+    #[arg(long, value_name = "PATH", help_heading = "Memmap Options")]
+    elf: Option<PathBuf>,
+}
+
+impl Memmap {
+    pub fn execute(mut self) -> anyhow::Result<()> {
+        crate::set_color(self.cargo.color.as_deref(), None);
+        let elf = match self.elf.take() {
+            Some(elf) => elf,
+            None => built_elf(&mut self.cargo, &self.arceos)?,
+        };
+        self.arceos.init()?;
+        if matches!(self.arceos.platform(), crate::platforms::Platform::Dummy) {
+            bail!("the dummy platform has no memory map");
+        }
+
+        let config = built_config(&elf, self.arceos.platform())?;
+        crate::memmap::print(&elf, &config)
+    }
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Size Options")]
+pub struct Size {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// ELF to measure [default: the built binary]
+    #[arg(long, value_name = "PATH")]
+    elf: Option<PathBuf>,
+    /// Report how the sizes changed since the baseline of the platform and
+    /// profile
+    #[arg(long)]
+    diff: bool,
+    /// Save the sizes as the baseline of the platform and profile
+    #[arg(long)]
+    save_baseline: bool,
+    /// Fail if the kernel grew by more than SIZE, like `4K`, or a percentage,
+    /// like `1%`, since the baseline
+    #[arg(
+        long,
+        env = "ARCEOS_SIZE_MAX_GROWTH",
+        requires = "diff",
+        value_name = "SIZE|PERCENT"
+    )]
+    max_growth: Option<crate::size::Threshold>,
+    /// Number of crates to list
+    #[arg(long, default_value_t = 20, value_name = "N")]
+    top: usize,
+}
+
+impl Size {
+    pub fn execute(mut self) -> anyhow::Result<()> {
+        crate::set_color(self.cargo.color.as_deref(), None);
+        let elf = match self.elf.take() {
+            Some(elf) => elf,
+            None => built_elf(&mut self.cargo, &self.arceos)?,
+        };
+        self.arceos.init()?;
+        let sizes = crate::size::measure(&elf)?;
+
+        let metadata = self.cargo.metadata()?;
+        let baseline = crate::size::baseline_path(
+            &self.cargo.target_dir(&metadata),
+            self.arceos.platform().as_ref(),
+            self.cargo.profile(),
+        );
+        if self.diff {
+            let base = crate::size::load_baseline(&baseline)?;
+            crate::size::print_diff(&sizes, &base, self.top, self.max_growth)?;
+        } else {
+            crate::size::print(&sizes, self.top);
+        }
+        if self.save_baseline {
+            crate::size::save_baseline(&baseline, &sizes)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Stats Options")]
+pub struct Stats {
+    /// Only show the builds for PLATFORM
+    #[arg(long, value_parser = enum_variants!(crate::platforms::Platform))]
+    platform: Option<crate::platforms::Platform>,
+    /// Only show the builds with PROFILE
+    #[arg(long, value_name = "PROFILE")]
+    profile: Option<String>,
+    /// Number of recent builds to list
+    #[arg(short = 'n', default_value_t = 20, value_name = "N")]
+    last: usize,
+    /// Forget the recorded builds
+    #[arg(long, conflicts_with_all = ["platform", "profile"])]
+    clear: bool,
+    /// Path to Cargo.toml
+    #[arg(long, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+    /// Target directory that the builds were recorded in
+    #[arg(long, value_name = "DIR")]
+    target_dir: Option<PathBuf>,
+    /// Scratch directory that the builds were recorded in, see `--scratch-dir`
+    /// of `build`
+    #[arg(long, env = "ARCEOS_SCRATCH_DIR", value_name = "DIR")]
+    scratch_dir: Option<PathBuf>,
+}
+
+impl Stats {
     pub fn execute(self) -> anyhow::Result<()> {
-        self.qemu.execute(self.binary)
+        let dir = match (self.scratch_dir, self.target_dir) {
+            (Some(dir), _) | (None, Some(dir)) => dir,
+            (None, None) => {
+                let mut command = cargo_metadata::MetadataCommand::new();
+                if let Some(path) = &self.manifest_path {
+                    command.manifest_path(path);
+                }
+                let metadata = command.no_deps().exec().context("failed to get metadata")?;
+                metadata.target_directory.into()
+            }
+        };
+        let path = crate::stats::path(&dir);
+        if self.clear {
+            return crate::stats::clear(&path);
+        }
+        crate::stats::print(
+            &path,
+            self.platform.as_ref().map(AsRef::as_ref),
+            self.profile.as_deref(),
+            self.last,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Bloat Options")]
+pub struct Bloat {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// ELF to analyze [default: the built binary]
+    #[arg(long, value_name = "PATH")]
+    elf: Option<PathBuf>,
+    /// List the crates with the most code instead of the functions
+    #[arg(long)]
+    crates: bool,
+    /// Number of functions or crates to list
+    #[arg(short = 'n', default_value_t = 20, value_name = "N")]
+    top: usize,
+}
+
+impl Bloat {
+    pub fn execute(mut self) -> anyhow::Result<()> {
+        crate::set_color(self.cargo.color.as_deref(), None);
+        let elf = match self.elf.take() {
+            Some(elf) => elf,
+            None => built_elf(&mut self.cargo, &self.arceos)?,
+        };
+        self.arceos.init()?;
+        crate::bloat::print(&elf, self.top, self.crates)
+    }
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "SD Card Options")]
+pub struct Image {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    #[command(flatten)]
+    image: crate::image::ImageOptions,
+    #[command(flatten)]
+    signing: crate::signing::SigningOptions,
+    /// Directory with the firmware of the board, like the `boot` directory
+    /// of the Raspberry Pi firmware [default: the one fetched with
+    /// `cargo arceos firmware fetch`]
+    #[arg(long, value_name = "DIR")]
+    firmware: Option<PathBuf>,
+    /// Size of the boot partition
+    #[arg(long, default_value = "64M", value_parser = crate::image::parse_size, value_name = "SIZE")]
+    boot_size: u64,
+    /// Where to write the image [default: `sdcard.img` next to the binary]
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Write the image to the block device DEVICE as well, like `/dev/sdX`
+    #[arg(long, value_name = "DEVICE")]
+    write: Option<PathBuf>,
+    /// Write to the device without asking
+    #[arg(long, requires = "write")]
+    yes: bool,
+}
+
+impl Image {
+    pub fn execute(mut self) -> anyhow::Result<()> {
+        let elf = built_elf(&mut self.cargo, &self.arceos)?;
+        self.arceos.init()?;
+        let platform = self.arceos.platform();
+        if !matches!(platform, crate::platforms::Platform::AARCH64_RASPI4) {
+            bail!(
+                "SD card images are only made for `aarch64-raspi4`, not `{}`",
+                platform
+            );
+        }
+
+        let config = built_config(&elf, platform)?;
+        // The firmware decompresses kernels, but only ones compressed with gzip.
+        if let Some(compression) = self.image.compression()
+            && compression != crate::image::Compression::Gzip
+        {
+            bail!(
+                "the Raspberry Pi firmware cannot decompress `{}` kernels, only `gzip` ones",
+                compression.as_ref()
+            );
+        }
+        let kernel = crate::image::prepare(platform, &self.image, elf.clone())?;
+        let kernel = crate::image::compress(&self.image, kernel)?;
+        let firmware = match self.firmware.take() {
+            Some(firmware) => firmware,
+            None => {
+                let metadata = crate::metadata::ArceOSMetadata::new(&self.cargo.metadata()?)?;
+                let files = crate::firmware::files(platform, Some(&metadata));
+                crate::firmware::fetched_dir(platform, &files)?.with_context(|| {
+                    format!(
+                        "no firmware is given, pass `--firmware` or run `cargo arceos firmware fetch {}`",
+                        platform
+                    )
+                })?
+            }
+        };
+        let files = crate::sdcard::raspi4_files(&firmware, &config, &kernel)?;
+        let output = self
+            .output
+            .unwrap_or_else(|| crate::scratch::path(&elf).with_file_name("sdcard.img"));
+        crate::sdcard::build(&files, self.boot_size, &output)
+            .with_context(|| format!("failed to write `{}`", output.display()))?;
+        crate::info("Finished", format!("`{}`", output.display()));
+        self.signing.apply(&output)?;
+
+        if let Some(device) = &self.write {
+            crate::sdcard::write_to_device(&output, device, self.yes)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Attach {
+    #[command(flatten)]
+    cargo: cargo_options::Check,
+    #[command(flatten)]
+    arceos: crate::options::ArceOSOptions,
+    /// Address of the gdbserver, as started by `cargo arceos run --debug`
+    #[arg(
+        long,
+        default_value = "localhost:1234",
+        help_heading = "Debugger Options"
+    )]
+    gdb_address: String,
+    /// Debugger to use [default: gdb if installed, otherwise lldb]
+    #[arg(long, value_parser = enum_variants!(crate::debugger::Debugger), help_heading = "Debugger Options")]
+    debugger: Option<crate::debugger::Debugger>,
+    /// Set a breakpoint at SYMBOL
+    #[arg(
+        long = "break",
+        value_name = "SYMBOL",
+        help_heading = "Debugger Options"
+    )]
+    breakpoints: Vec<String>,
+    /// Set breakpoints at `rust_main` and the panic handler
+    #[arg(long, help_heading = "Debugger Options")]
+    common_breakpoints: bool,
+}
+
+impl Attach {
+    pub fn execute(mut self) -> anyhow::Result<i32> {
+        let elf = built_elf(&mut self.cargo, &self.arceos)?;
+
+        let mut breakpoints = self.breakpoints.clone();
+        if self.common_breakpoints {
+            breakpoints.extend(
+                crate::debugger::COMMON_BREAKPOINTS
+                    .iter()
+                    .map(|b| b.to_string()),
+            );
+        }
+        let debugger = match self.debugger {
+            Some(debugger) => debugger,
+            None => crate::debugger::Debugger::detect()?,
+        };
+        let mut command =
+            debugger.command(self.arceos.arch(), &elf, &self.gdb_address, &breakpoints)?;
+
+        // Ctrl-C interrupts the guest in the debugger, so it must not end this
+        // process as well.
+        crate::signal::install();
+        crate::info("Attaching", format!("`{}`", crate::command_line(&command)));
+        let status = command.status().with_context(|| {
+            format!(
+                "failed to run `{}`",
+                command.get_program().to_string_lossy()
+            )
+        })?;
+        Ok(status.code().unwrap_or(101))
     }
 }
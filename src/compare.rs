@@ -0,0 +1,160 @@
+//! Running the kernel built with several profiles under the same QEMU
+//! settings, for `cargo arceos run --compare debug,release`, and printing how
+//! the runs differ side by side.
+//!
+//! For each profile, the time of the build and of the run are measured, along
+//! with when the guest first printed a line containing each of the markers
+//! given with `--compare-marker`, counted from the start of QEMU.
+
+use std::time::{Duration, Instant};
+
+use console::style;
+
+use crate::events::{Event, EventHandler};
+
+/// What was measured for a profile.
+struct Measurement {
+    build: Option<Duration>,
+    run: Option<Duration>,
+    markers: Vec<Option<Duration>>,
+    code: Option<i32>,
+}
+
+/// Measures a run, passing its events on to `inner`.
+struct Recorder<'a> {
+    inner: &'a mut dyn EventHandler,
+    markers: &'a [String],
+    started: Instant,
+    launched: Option<Instant>,
+    measurement: Measurement,
+}
+
+impl EventHandler for Recorder<'_> {
+    fn handle(&mut self, event: Event) {
+        let now = Instant::now();
+        match &event {
+            Event::QemuLaunched { .. } => {
+                self.launched = Some(now);
+                self.measurement.build = Some(now - self.started);
+            }
+            Event::ConsoleLine(line) => {
+                if let Some(launched) = self.launched {
+                    for (marker, time) in self.markers.iter().zip(&mut self.measurement.markers) {
+                        if time.is_none() && line.contains(marker.as_str()) {
+                            *time = Some(now - launched);
+                        }
+                    }
+                }
+            }
+            Event::Exited { code } => {
+                self.measurement.run = self.launched.map(|launched| now - launched);
+                self.measurement.code = Some(*code);
+            }
+            _ => {}
+        }
+        self.inner.handle(event);
+    }
+}
+
+/// Runs `run` with each of `profiles`, and prints the comparison. Returns
+/// the first exit code that is not 0, or 0.
+pub fn run(
+    run: &crate::commands::Run,
+    profiles: &[String],
+    markers: &[String],
+    handler: &mut dyn EventHandler,
+) -> anyhow::Result<i32> {
+    let mut measurements = vec![];
+    for profile in profiles {
+        crate::info("Comparing", format!("profile `{}`", profile));
+        let mut recorder = Recorder {
+            inner: handler,
+            markers,
+            started: Instant::now(),
+            launched: None,
+            measurement: Measurement {
+                build: None,
+                run: None,
+                markers: vec![None; markers.len()],
+                code: None,
+            },
+        };
+        let code = crate::Cli::Run(Box::new(run.with_profile(profile))).execute(&mut recorder)?;
+        let mut measurement = recorder.measurement;
+        measurement.code.get_or_insert(code);
+        measurements.push(measurement);
+        if crate::signal::received().is_some() {
+            break;
+        }
+    }
+
+    let time = |time: Option<Duration>| time.map_or("-".to_string(), crate::timings::format);
+    let mut rows: Vec<(String, Vec<String>)> = vec![
+        (
+            "build".to_string(),
+            measurements.iter().map(|m| time(m.build)).collect(),
+        ),
+        (
+            "run".to_string(),
+            measurements.iter().map(|m| time(m.run)).collect(),
+        ),
+    ];
+    for (i, marker) in markers.iter().enumerate() {
+        rows.push((
+            format!("`{}`", marker),
+            measurements.iter().map(|m| time(m.markers[i])).collect(),
+        ));
+    }
+    rows.push((
+        "exit code".to_string(),
+        measurements
+            .iter()
+            .map(|m| m.code.map_or("-".to_string(), |code| code.to_string()))
+            .collect(),
+    ));
+
+    let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = profiles
+        .iter()
+        .enumerate()
+        .map(|(i, profile)| {
+            rows.iter()
+                .filter_map(|(_, values)| values.get(i))
+                .map(String::len)
+                .chain([profile.len()])
+                .max()
+                .unwrap()
+        })
+        .collect();
+    let header: Vec<String> = profiles
+        .iter()
+        .zip(&widths)
+        .map(|(profile, width)| format!("{:>width$}", profile, width = width))
+        .collect();
+    eprintln!();
+    eprintln!(
+        "{:label_width$}  {}",
+        "",
+        style(header.join("  ")).bold(),
+        label_width = label_width
+    );
+    for (label, values) in &rows {
+        let values: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{:>width$}", value, width = width))
+            .collect();
+        eprintln!(
+            "{:label_width$}  {}",
+            label,
+            values.join("  "),
+            label_width = label_width
+        );
+    }
+
+    Ok(measurements
+        .iter()
+        .filter_map(|m| m.code)
+        .find(|code| *code != 0)
+        .unwrap_or(0))
+}
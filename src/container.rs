@@ -0,0 +1,196 @@
+//! Building inside a container for `--docker` and `--podman`, for hosts
+//! without the toolchain that ArceOS needs. Only cargo runs in the container,
+//! QEMU still runs on the host.
+//!
+//! The workspace and the target directory are mounted at the same paths in
+//! the container, so that paths in the arguments, in the environment and in
+//! the messages of cargo mean the same on both sides. Other files that the
+//! environment points to are mounted read-only, and paths in the cargo home of
+//! the host are translated to the one in the container.
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::bail;
+use clap::Args;
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Container Options")]
+pub struct ContainerOptions {
+    /// Run cargo in a Docker container of IMAGE, which has the toolchain,
+    /// while QEMU runs on the host
+    #[arg(
+        long,
+        env = "ARCEOS_DOCKER",
+        value_name = "IMAGE",
+        conflicts_with = "podman"
+    )]
+    docker: Option<String>,
+
+    /// Run cargo in a Podman container of IMAGE, like `--docker`
+    #[arg(long, env = "ARCEOS_PODMAN", value_name = "IMAGE")]
+    podman: Option<String>,
+}
+
+/// A container that cargo runs in.
+#[derive(Debug)]
+pub struct Container {
+    engine: &'static str,
+    image: String,
+    /// Directories mounted read-write at the same paths.
+    mounts: Vec<PathBuf>,
+    /// The cargo home in the container, kept in the target directory so that
+    /// downloaded crates are reused.
+    cargo_home: PathBuf,
+    /// Whether `cargo run` is turned into `cargo build`, for the runner to be
+    /// run on the host.
+    build_only: bool,
+}
+
+impl ContainerOptions {
+    /// Returns the container to run cargo in, if one is asked for.
+    pub fn container(
+        &self,
+        workspace_root: &Path,
+        target_dir: &Path,
+    ) -> anyhow::Result<Option<Container>> {
+        let (engine, image) = match (&self.docker, &self.podman) {
+            (Some(image), _) => ("docker", image),
+            (None, Some(image)) => ("podman", image),
+            (None, None) => return Ok(None),
+        };
+        let workspace_root = workspace_root.canonicalize()?;
+        std::fs::create_dir_all(target_dir)?;
+        let target_dir = target_dir.canonicalize()?;
+        let cwd = env::current_dir()?;
+        if !cwd.starts_with(&workspace_root) && !cwd.starts_with(&target_dir) {
+            bail!(
+                "the current directory must be inside the workspace `{}` to build in a container",
+                workspace_root.display()
+            );
+        }
+        Ok(Some(Container {
+            engine,
+            image: image.clone(),
+            cargo_home: target_dir.join("container").join("cargo"),
+            mounts: vec![workspace_root, target_dir],
+            build_only: false,
+        }))
+    }
+}
+
+impl Container {
+    /// Builds the executable instead of running it, as the runner has to be
+    /// run on the host.
+    pub fn build_only(&mut self) {
+        self.build_only = true;
+    }
+
+    fn is_mounted(&self, path: &Path) -> bool {
+        self.mounts.iter().any(|mount| path.starts_with(mount))
+    }
+
+    /// Replaces the cargo home of the host in `value` by the one in the
+    /// container.
+    fn translate(&self, value: &OsStr) -> OsString {
+        let host_cargo_home = env::var_os("CARGO_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::home_dir().map(|home| home.join(".cargo")));
+        match (value.to_str(), host_cargo_home) {
+            (Some(value), Some(home)) => value
+                .replace(
+                    &home.display().to_string(),
+                    &self.cargo_home.display().to_string(),
+                )
+                .into(),
+            _ => value.to_os_string(),
+        }
+    }
+
+    /// Returns a command that runs the cargo `command` in the container, with
+    /// the environment that it sets.
+    pub fn wrap(&self, command: &Command) -> anyhow::Result<Command> {
+        let mut container = Command::new(self.engine);
+        container.args(["run", "--rm", "--init", "-i"]);
+        // Images are pulled when they are missing, unless offline.
+        if crate::is_offline() {
+            container.arg("--pull=never");
+        }
+        // Files in the mounted directories stay owned by the user.
+        #[cfg(unix)]
+        if self.engine == "podman" {
+            container.arg("--userns=keep-id");
+        } else {
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            container.arg(format!("--user={}:{}", uid, gid));
+        }
+        for mount in &self.mounts {
+            container.arg("-v").arg(volume(mount, false));
+        }
+        let mut extra_mounts: Vec<PathBuf> = vec![];
+        for (key, value) in command.get_envs() {
+            let Some(value) = value else {
+                continue;
+            };
+            let path = Path::new(value);
+            if path.is_absolute()
+                && path.exists()
+                && !self.is_mounted(path)
+                && !extra_mounts.iter().any(|mount| path.starts_with(mount))
+            {
+                extra_mounts.push(path.to_path_buf());
+            }
+            let mut env = key.to_os_string();
+            env.push("=");
+            env.push(self.translate(value));
+            container.arg("-e").arg(env);
+        }
+        for mount in &extra_mounts {
+            container.arg("-v").arg(volume(mount, true));
+        }
+        std::fs::create_dir_all(&self.cargo_home)?;
+        let mut cargo_home = OsString::from("CARGO_HOME=");
+        cargo_home.push(&self.cargo_home);
+        container.arg("-e").arg(cargo_home);
+        // Cargo cannot tell that its output ends up on a terminal.
+        if console::colors_enabled_stderr()
+            && command.get_envs().all(|(key, _)| key != "CARGO_TERM_COLOR")
+            && std::io::stderr().is_terminal()
+        {
+            container.args(["-e", "CARGO_TERM_COLOR=always"]);
+        }
+        container.arg("-w").arg(env::current_dir()?);
+        container.arg(&self.image).arg("cargo");
+
+        let mut args = command.get_args();
+        if self.build_only
+            && let Some(subcommand) = args.next()
+        {
+            container.arg(if subcommand == "run" {
+                OsStr::new("build")
+            } else {
+                subcommand
+            });
+        }
+        for arg in args {
+            container.arg(self.translate(arg));
+        }
+        Ok(container)
+    }
+}
+
+/// Returns the `-v` argument that mounts `path` at the same path.
+fn volume(path: &Path, read_only: bool) -> OsString {
+    let mut volume = path.as_os_str().to_os_string();
+    volume.push(":");
+    volume.push(path);
+    if read_only {
+        volume.push(":ro");
+    }
+    volume
+}
@@ -0,0 +1,33 @@
+//! Crash directories, which keep what is needed to look into a failed run.
+//!
+//! The memory and registers of the guest can only be dumped while QEMU still
+//! runs, so they are written by [`crate::qemu::run`] when it stops QEMU. The
+//! console and the command lines are added here for any failed run.
+
+use std::{env, fs, path::Path, process::Command};
+
+use anyhow::Context;
+
+use crate::{exit::GuestExit, qemu::QemuRun};
+
+/// Files of a previous run, removed so they are not mistaken for this one's.
+const DUMPS: [&str; 2] = ["registers.txt", "memory.elf"];
+
+pub fn clear(dir: &Path) {
+    for name in DUMPS {
+        fs::remove_file(dir.join(name)).ok();
+    }
+}
+
+/// Writes the console, the command lines and the exit of `run` to `dir`.
+pub fn write(dir: &Path, command: &Command, run: &QemuRun, exit: GuestExit) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create crash directory `{}`", dir.display()))?;
+    let runner: Vec<String> = env::args().collect();
+    let commands = format!("{}\n{}\n", runner.join(" "), crate::command_line(command));
+    fs::write(dir.join("command-line.txt"), commands)?;
+    fs::write(dir.join("console.log"), &run.console)?;
+    fs::write(dir.join("exit.txt"), format!("{}\n", exit))?;
+    crate::info("Crash", format!("artifacts in `{}`", dir.display()));
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Attaching a debugger to the gdbserver of QEMU, for `cargo arceos attach`.
+
+use std::{path::Path, process::Command};
+
+use anyhow::Context;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+use crate::platforms::Arch;
+
+/// Breakpoints set with `--common-breakpoints`: the entry of the runtime and
+/// the panic handler.
+pub const COMMON_BREAKPOINTS: &[&str] = &["rust_main", "rust_begin_unwind"];
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Debugger {
+    /// `gdb-multiarch`, or `gdb` if it is not installed
+    Gdb,
+    Lldb,
+}
+
+impl Debugger {
+    /// Returns the debugger program that is installed, preferring gdb.
+    pub fn detect() -> anyhow::Result<Self> {
+        if crate::find_program("gdb-multiarch").is_some() || crate::find_program("gdb").is_some() {
+            Ok(Debugger::Gdb)
+        } else if crate::find_program("lldb").is_some() {
+            Ok(Debugger::Lldb)
+        } else {
+            anyhow::bail!("neither gdb nor lldb is found in PATH")
+        }
+    }
+
+    /// Returns the command that debugs `elf` on the gdbserver at `address`.
+    pub fn command(
+        self,
+        arch: Arch,
+        elf: &Path,
+        address: &str,
+        breakpoints: &[String],
+    ) -> anyhow::Result<Command> {
+        match self {
+            Debugger::Gdb => {
+                let program = ["gdb-multiarch", "gdb"]
+                    .into_iter()
+                    .find(|program| crate::find_program(program).is_some())
+                    .context("gdb is not found in PATH")?;
+                let architecture = match arch {
+                    Arch::Aarch64 => "aarch64",
+                    Arch::Loongarch64 => "Loongarch64",
+                    Arch::Riscv64 => "riscv:rv64",
+                    Arch::X86_64 => "i386:x86-64",
+                };
+
+                let mut command = Command::new(program);
+                command
+                    .arg("-q")
+                    .arg(elf)
+                    .args(["-ex", &format!("set architecture {}", architecture)])
+                    .args(["-ex", &format!("target remote {}", address)]);
+                for breakpoint in breakpoints {
+                    command.args(["-ex", &format!("break {}", breakpoint)]);
+                }
+                Ok(command)
+            }
+            Debugger::Lldb => {
+                let mut command = Command::new("lldb");
+                command
+                    .args(["--arch", arch.as_ref()])
+                    .arg(elf)
+                    .args(["-o", &format!("gdb-remote {}", address)]);
+                for breakpoint in breakpoints {
+                    command.args(["-o", &format!("breakpoint set --name {}", breakpoint)]);
+                }
+                Ok(command)
+            }
+        }
+    }
+}
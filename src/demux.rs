@@ -0,0 +1,133 @@
+//! Giving each CPU of an SMP kernel a serial port of its own with
+//! `--cpu-consoles`, so that what the CPUs print does not interleave.
+//!
+//! CPU 0 keeps the console. The other CPUs print to files next to the kernel,
+//! which are kept with `--cpu-consoles=files`, or followed with `prefix` and
+//! printed along with the console, each line tagged with its CPU. The log
+//! file, the metrics and the test harness only see the console.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use console::style;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum CpuConsoles {
+    /// Print the lines of every CPU on the terminal, tagged with the CPU
+    Prefix,
+    /// Keep what the other CPUs print in files next to the kernel
+    Files,
+}
+
+/// Whether the console is printed with the lines tagged.
+static PREFIXED: AtomicBool = AtomicBool::new(false);
+/// What each CPU printed after its last newline.
+static LINES: Mutex<Vec<Vec<u8>>> = Mutex::new(vec![]);
+
+/// Returns the file that CPU `cpu` prints to.
+pub fn log_path(kernel: &Path, cpu: u32) -> PathBuf {
+    crate::scratch::path(kernel).with_extension(format!("cpu{}.log", cpu))
+}
+
+/// Returns whether [`print`] has to print the console.
+pub fn is_prefixed() -> bool {
+    PREFIXED.load(Ordering::Relaxed)
+}
+
+/// Prints the lines in `data` that CPU `cpu` printed, tagged with the CPU.
+pub fn print(cpu: usize, data: &[u8]) -> io::Result<()> {
+    let mut lines = LINES.lock().unwrap();
+    if lines.len() <= cpu {
+        lines.resize(cpu + 1, vec![]);
+    }
+    let line = &mut lines[cpu];
+    let mut out = io::stdout().lock();
+    for &byte in data {
+        line.push(byte);
+        if byte == b'\n' {
+            write!(out, "{} ", style(format!("[cpu{}]", cpu)).dim())?;
+            out.write_all(line)?;
+            line.clear();
+        }
+    }
+    out.flush()
+}
+
+/// Prints what CPU `cpu` printed last without a newline.
+fn finish(cpu: usize) {
+    let partial = LINES
+        .lock()
+        .unwrap()
+        .get(cpu)
+        .is_some_and(|line| !line.is_empty());
+    if partial {
+        print(cpu, b"\n").ok();
+    }
+}
+
+/// Follows the files that the CPUs from 1 on print to.
+pub struct Follower {
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Starts following `paths`, the files of the CPUs from 1 on, which QEMU
+/// creates once it starts.
+pub fn follow(paths: Vec<PathBuf>) -> Follower {
+    PREFIXED.store(true, Ordering::Relaxed);
+    let stop = Arc::new(AtomicBool::new(false));
+    let threads = paths
+        .into_iter()
+        .enumerate()
+        .map(|(i, path)| {
+            // Whatever is left from an earlier run is not printed again.
+            fs::remove_file(&path).ok();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let cpu = i + 1;
+                let mut file = None;
+                let mut buf = [0; 4096];
+                loop {
+                    // Read once more after QEMU has stopped.
+                    let stopping = stop.load(Ordering::Relaxed);
+                    if file.is_none() {
+                        file = File::open(&path).ok();
+                    }
+                    if let Some(file) = &mut file {
+                        while let Ok(n @ 1..) = file.read(&mut buf) {
+                            print(cpu, &buf[..n]).ok();
+                        }
+                    }
+                    if stopping {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+                finish(cpu);
+            })
+        })
+        .collect();
+    Follower { stop, threads }
+}
+
+impl Follower {
+    /// Prints the rest of what the CPUs printed, after QEMU has stopped.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads {
+            thread.join().ok();
+        }
+        finish(0);
+    }
+}
@@ -0,0 +1,329 @@
+//! Codes of the warnings and errors that cargo-arceos reports, for
+//! `cargo arceos explain` and for allowing warnings with `--allow` or turning
+//! them into errors with `--deny`.
+
+use std::{env, fmt, process::Command, sync::Mutex};
+
+use strum::{AsRefStr, EnumString, VariantNames};
+
+/// Tells the runner which warnings are allowed.
+const ALLOW_ENV: &str = "CARGO_ARCEOS_ALLOW";
+/// Tells the runner which warnings are denied.
+const DENY_ENV: &str = "CARGO_ARCEOS_DENY";
+
+static ALLOWED: Mutex<Vec<Code>> = Mutex::new(vec![]);
+static DENIED: Mutex<Vec<Code>> = Mutex::new(vec![]);
+/// The codes of the warnings reported so far, once per report.
+static REPORTED: Mutex<Vec<Code>> = Mutex::new(vec![]);
+
+/// A warning or error code. Codes are never reused once released.
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, VariantNames, AsRefStr)]
+pub enum Code {
+    #[strum(serialize = "AXW0001")]
+    MissingFeature,
+    #[strum(serialize = "AXW0002")]
+    NoRootPackage,
+    #[strum(serialize = "AXW0003")]
+    UnifiedStd,
+    #[strum(serialize = "AXW0004")]
+    MachineFallback,
+    #[strum(serialize = "AXW0005")]
+    KernelOutsideMemory,
+    #[strum(serialize = "AXW0006")]
+    KernelOverlap,
+    #[strum(serialize = "AXW0007")]
+    VirtioMmioMismatch,
+    #[strum(serialize = "AXW0008")]
+    CargoRunnerOverridden,
+    #[strum(serialize = "AXW0009")]
+    CpusOverridden,
+    #[strum(serialize = "AXW0010")]
+    IgnoredOption,
+    #[strum(serialize = "AXW0011")]
+    SmpMismatch,
+    #[strum(serialize = "AXE0001")]
+    UnknownConfigKey,
+    #[strum(serialize = "AXE0002")]
+    ConfigTypeMismatch,
+    #[strum(serialize = "AXE0003")]
+    MissingConfigKey,
+    #[strum(serialize = "AXE0004")]
+    GuestImageOverlap,
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+impl Code {
+    pub fn is_warning(self) -> bool {
+        self.as_ref().starts_with("AXW")
+    }
+
+    /// Returns the description of the diagnostic and how to deal with it.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            Code::MissingFeature => {
+                "\
+A feature that the build needs is not enabled.
+
+Some options only work if a crate of ArceOS is built with a matching feature,
+like `--cpus` with more than one CPU and the `smp` feature of `axstd`. Without
+it, the option has no effect on the kernel, or the kernel does not boot.
+
+Enable the feature in the dependency on the package named in the warning, or
+pass `--auto-features` to have it enabled for the build."
+            }
+            Code::NoRootPackage => {
+                "\
+Features cannot be enabled automatically, because the workspace has no root
+package.
+
+`--auto-features` and `--scheduler` enable features on the dependencies of the
+package being built, which is only known for a package, not for a virtual
+workspace.
+
+Select the app with `--app` or `--manifest-path`, or enable the features in
+its manifest."
+            }
+            Code::UnifiedStd => {
+                "\
+The `std` feature of a crate is enabled for the bare-metal target.
+
+Cargo builds a crate once with the features that all of its dependents ask
+for. If one of them enables `std`, the crate is built with `std` for the
+kernel as well, which fails or links the wrong code. With resolver 1, this
+includes build scripts and proc macros that run on the host.
+
+Disable the default features of the dependency named in the warning, or set
+`resolver = \"2\"` in the workspace if the dependent is built for the host."
+            }
+            Code::MachineFallback => {
+                "\
+The installed QEMU does not provide the preferred machine of the platform,
+so an older one is used.
+
+The older machine may lack devices or boot differently, like the `virt`
+machine of LoongArch before QEMU 8.1, which boots through UEFI firmware.
+
+Install the QEMU version named in the warning to use the preferred machine."
+            }
+            Code::KernelOutsideMemory => {
+                "\
+The kernel image is not within the physical memory of the platform.
+
+The physical address of the kernel is its virtual address minus
+`plat.phys-virt-offset`. It has to lie between `plat.phys-memory-base` and
+`plat.phys-memory-base` + `plat.phys-memory-size`, or the kernel cannot be
+loaded.
+
+Check `plat.kernel-base-vaddr` and the memory settings of the config."
+            }
+            Code::KernelOverlap => {
+                "\
+The kernel image overlaps a region reserved for devices or guest images.
+
+Loading the kernel there would write into device registers, or a guest image
+would overwrite the kernel.
+
+Move `plat.kernel-base-paddr` and `plat.kernel-base-vaddr`, or the region named
+in the warning."
+            }
+            Code::VirtioMmioMismatch => {
+                "\
+The config of the kernel does not list a virtio-mmio transport that QEMU puts
+a device into.
+
+On the MMIO bus, the kernel only probes the transports in
+`devices.virtio-mmio-regions`. QEMU plugs each `-device` into the free
+transport with the highest address, so a device at a transport missing from
+the list is not found by the guest.
+
+Add the transports of the machine to `devices.virtio-mmio-regions`, or use
+`--bus pci`."
+            }
+            Code::CargoRunnerOverridden => {
+                "\
+A runner is configured for the target in cargo, and cargo-arceos replaces it.
+
+Cargo runs the kernel with the runner in `target.<triple>.runner` or
+`target.'cfg(...)'.runner` of `.cargo/config.toml`, or in
+`CARGO_TARGET_<TRIPLE>_RUNNER`. cargo-arceos passes its own runner with
+`--config`, which takes precedence, so the configured one is not run.
+
+Pass `--respect-cargo-runner` to have the configured runner run the runner of
+cargo-arceos, like a `--runner-wrapper`, or remove it from the config."
+            }
+            Code::CpusOverridden => {
+                "\
+A config file sets `smp` to a different number of CPUs than `--cpus`.
+
+Without `--cpus`, the number of CPUs is taken from `smp` in the config, and
+QEMU is started with as many. Given explicitly, `--cpus` takes precedence
+over the config files. Remove one of them, or make them agree."
+            }
+            Code::IgnoredOption => {
+                "\
+An option or argument is given that the command does not use.
+
+Some options of cargo are set by cargo-arceos itself, like `--target`, which
+is derived from `--arch` and `--platform`, and others only apply in some
+cases, like `--bin-pattern` when no binary is picked with `--bin`. The command
+goes on without them.
+
+Remove the option from the command line."
+            }
+            Code::SmpMismatch => {
+                "\
+QEMU is started with another number of CPUs than the kernel was built for.
+
+`--smp` is passed to QEMU as it is, while the kernel is built for the number
+of CPUs given with `--cpus` or `smp` in the config. ArceOS waits for all of
+the CPUs it was built for to boot, and others are left alone or confuse it,
+so the kernel may hang. The run is refused unless `--allow-smp-mismatch` is
+given, which reports this warning instead.
+
+Build with `--cpus` set to the number of CPUs that QEMU starts, or drop
+`--smp`."
+            }
+            Code::UnknownConfigKey => {
+                "\
+A config file has a key that none of the bundled configs define.
+
+Keys of config files given with `--configs` are merged into the config of the
+platform as they are, so a misspelled key would leave the intended one unset.
+Keys in tables that no bundled config has are not checked, as the app may use
+them.
+
+Fix the name of the key, usually the one suggested in the error."
+            }
+            Code::ConfigTypeMismatch => {
+                "\
+A key in a config file has a value of a different type than in the bundled
+configs.
+
+ArceOS reads the config as Rust constants of the bundled types, so a value of
+another type does not compile or means something else.
+
+Change the value, or the type in its `# type` comment, to the one named in the
+error."
+            }
+            Code::MissingConfigKey => {
+                "\
+The config lacks a key that all bundled platforms of the architecture define.
+
+ArceOS needs these keys to build the kernel for the architecture.
+
+Add the keys named in the error to a config file given with `--configs`."
+            }
+            Code::GuestImageOverlap => {
+                "\
+Two guest images given with `--guest-image` overlap in guest memory.
+
+QEMU would load the later one over the earlier one.
+
+Load the images at addresses that leave room for each of them."
+            }
+        }
+    }
+}
+
+/// An error with a code.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub code: Code,
+    message: String,
+}
+
+impl Diagnostic {
+    pub fn new(code: Code, message: impl fmt::Display) -> Self {
+        Diagnostic {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// How a warning is reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// Adds `codes` to `list`, and passes the list to the runner spawned by
+/// `command` in `var`.
+fn extend(list: &Mutex<Vec<Code>>, codes: &[Code], var: &str, command: Option<&mut Command>) {
+    let mut list = list.lock().unwrap();
+    for code in codes {
+        if !list.contains(code) {
+            list.push(*code);
+        }
+    }
+    if let Some(command) = command
+        && !list.is_empty()
+    {
+        let codes: Vec<&str> = list.iter().map(|code| code.as_ref()).collect();
+        command.env(var, codes.join(","));
+    }
+}
+
+/// Allows the warnings with `allow` and denies the ones with `deny`, here and
+/// in the runner spawned by `command`.
+pub fn set_levels(allow: &[Code], deny: &[Code], mut command: Option<&mut Command>) {
+    extend(&ALLOWED, allow, ALLOW_ENV, command.as_deref_mut());
+    extend(&DENIED, deny, DENY_ENV, command);
+}
+
+/// Allows and denies the warnings that the parent process does.
+pub fn levels_from_env() {
+    let codes = |var| -> Vec<Code> {
+        env::var(var)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|code| code.parse().ok())
+            .collect()
+    };
+    set_levels(&codes(ALLOW_ENV), &codes(DENY_ENV), None);
+}
+
+pub fn level(code: Code) -> Level {
+    if DENIED.lock().unwrap().contains(&code) {
+        Level::Deny
+    } else if ALLOWED.lock().unwrap().contains(&code) {
+        Level::Allow
+    } else {
+        Level::Warn
+    }
+}
+
+/// Records that a warning with `code` has been reported.
+pub fn reported(code: Code) {
+    REPORTED.lock().unwrap().push(code);
+}
+
+/// Returns the codes of the reported warnings with how many times each was
+/// reported, in the order of their first report, and whether any of them
+/// were denied.
+pub fn summary() -> (Vec<(Code, usize)>, bool) {
+    let mut counts: Vec<(Code, usize)> = vec![];
+    for code in REPORTED.lock().unwrap().iter() {
+        match counts.iter_mut().find(|(c, _)| c == code) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((*code, 1)),
+        }
+    }
+    let denied = counts.iter().any(|(code, _)| level(*code) == Level::Deny);
+    (counts, denied)
+}
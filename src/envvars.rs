@@ -0,0 +1,327 @@
+//! Listing of the environment variables understood by cargo-arceos, for
+//! `cargo arceos env`, and loading them from files.
+//!
+//! Options are read from `ARCEOS_<OPTION>` variables. Besides the
+//! environment, they can be set in `arceos.env` and `.env` in the current
+//! directory, with `KEY=VALUE` lines. The command line takes precedence over
+//! the environment, which takes precedence over `arceos.env`, which takes
+//! precedence over `.env`. Only the `ARCEOS_` variables of `.env` are loaded,
+//! as it is often meant for other tools.
+//!
+//! Between `arceos.env` and `.env`, the defaults that `cargo arceos init`
+//! writes into the `arceos.defaults` metadata of the manifest in the current
+//! directory are loaded, with keys named like the long options.
+
+use std::{collections::HashMap, env, fs, process::Command, sync::OnceLock};
+
+use clap::{ArgMatches, CommandFactory, parser::ValueSource};
+use console::style;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+/// Prefix of the environment variables of options.
+const PREFIX: &str = "ARCEOS_";
+
+/// The files that variables are loaded from, the one taking precedence first,
+/// and whether only the variables with [`PREFIX`] are loaded from them.
+const ENV_FILES: [(&str, bool); 2] = [("arceos.env", false), (".env", true)];
+
+/// How `cargo arceos env` prints the environment.
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Format {
+    /// The options with where their values come from, and the variables
+    /// exported to the build
+    Annotated,
+    /// An attribute set of the exported variables, for `mkShell` of Nix
+    Nix,
+    /// `export` lines for the `.envrc` of direnv
+    Direnv,
+    /// Lines for the `GITHUB_ENV` file of GitHub Actions
+    GithubActions,
+}
+
+/// The variables loaded from files, and the files that they came from.
+static LOADED: OnceLock<HashMap<String, &'static str>> = OnceLock::new();
+
+/// The manifest whose `arceos.defaults` metadata is loaded.
+const MANIFEST: &str = "Cargo.toml";
+
+/// Returns the variables of the defaults in the metadata of [`MANIFEST`].
+fn manifest_defaults() -> Vec<(String, String)> {
+    let Some(doc) = fs::read_to_string(MANIFEST)
+        .ok()
+        .and_then(|toml| toml.parse::<toml_edit::DocumentMut>().ok())
+    else {
+        return vec![];
+    };
+    let Some(defaults) = ["package", "workspace"].into_iter().find_map(|root| {
+        doc.get(root)?
+            .get("metadata")?
+            .get("arceos")?
+            .get("defaults")?
+            .as_table_like()
+    }) else {
+        return vec![];
+    };
+
+    let options = env_options();
+    let mut vars = vec![];
+    for (key, item) in defaults.iter() {
+        let long = format!("--{}", key);
+        let Some(option) = options
+            .iter()
+            .find(|option| option.long.as_deref() == Some(&long))
+        else {
+            crate::warn(format!(
+                "`{}` in the defaults of `{}` is not an option that can be set there, it is ignored",
+                key, MANIFEST
+            ));
+            continue;
+        };
+        let value = match item.as_value() {
+            Some(toml_edit::Value::String(s)) => s.value().clone(),
+            Some(toml_edit::Value::Array(array)) => array
+                .iter()
+                .map(|value| match value.as_str() {
+                    Some(s) => s.to_string(),
+                    None => value.to_string().trim().to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            Some(value) => value.to_string().trim().to_string(),
+            None => {
+                crate::warn(format!(
+                    "`{}` in the defaults of `{}` is not a value, it is ignored",
+                    key, MANIFEST
+                ));
+                continue;
+            }
+        };
+        vars.push((option.env.clone(), value));
+    }
+    vars
+}
+
+/// Returns the variables in the env file `file`, only those with [`PREFIX`]
+/// if `prefixed_only`.
+fn env_file(file: &str, prefixed_only: bool) -> Vec<(String, String)> {
+    let Ok(content) = fs::read_to_string(file) else {
+        return vec![];
+    };
+    let mut vars = vec![];
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = parse_line(line) else {
+            crate::warn(format!("ignoring line {} of `{}`: {}", n + 1, file, line));
+            continue;
+        };
+        if !prefixed_only || key.starts_with(PREFIX) {
+            vars.push((key.to_string(), value.to_string()));
+        }
+    }
+    vars
+}
+
+/// Sets the variables in the env files and the defaults of the manifest in
+/// the current directory that are not set yet. It must be called before any
+/// thread is spawned.
+pub fn load_files() {
+    // The defaults of the manifest go between the env files.
+    let [(first, first_prefixed), (second, second_prefixed)] = ENV_FILES;
+    let sources = [
+        (first, env_file(first, first_prefixed)),
+        (MANIFEST, manifest_defaults()),
+        (second, env_file(second, second_prefixed)),
+    ];
+    let mut loaded = HashMap::new();
+    for (source, vars) in sources {
+        for (key, value) in vars {
+            if loaded.contains_key(&key) || env::var_os(&key).is_some() {
+                continue;
+            }
+            // SAFETY: no other thread is running yet.
+            unsafe { env::set_var(&key, value) };
+            loaded.insert(key, source);
+        }
+    }
+    LOADED.set(loaded).ok();
+}
+
+/// Parses `KEY=VALUE`, optionally after `export` and with the value in
+/// quotes.
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let value = value.trim();
+    let unquoted = ['"', '\'']
+        .into_iter()
+        .find_map(|q| value.strip_prefix(q)?.strip_suffix(q));
+    Some((key, unquoted.unwrap_or(value)))
+}
+
+/// Returns where the value of the variable `key` came from.
+fn source(key: &str) -> String {
+    match LOADED.get().and_then(|loaded| loaded.get(key)) {
+        Some(file) => format!("`{}`", file),
+        None => "environment".to_string(),
+    }
+}
+
+/// An option that can be set through an environment variable.
+struct EnvOption {
+    id: String,
+    long: Option<String>,
+    env: String,
+    defaults: Vec<String>,
+}
+
+/// Collects the options of every subcommand that have an environment variable.
+fn env_options() -> Vec<EnvOption> {
+    let cli = crate::Cli::command();
+    let mut options: Vec<EnvOption> = vec![];
+    for arg in cli.get_subcommands().flat_map(|sub| sub.get_arguments()) {
+        let Some(env) = arg.get_env() else {
+            continue;
+        };
+        let env = env.to_string_lossy().into_owned();
+        if options.iter().any(|option| option.env == env) {
+            continue;
+        }
+        options.push(EnvOption {
+            id: arg.get_id().to_string(),
+            long: arg.get_long().map(|long| format!("--{}", long)),
+            env,
+            defaults: arg
+                .get_default_values()
+                .iter()
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect(),
+        });
+    }
+    options
+}
+
+/// Returns the matches of the `env` subcommand from the command line of the
+/// process, which is where the sources of the values can be found.
+fn env_matches() -> Option<ArgMatches> {
+    let matches = crate::Cli::command()
+        .try_get_matches_from(env::args_os())
+        .ok()?;
+    matches.subcommand_matches("env").cloned()
+}
+
+/// Prints the variables exported to the `build` for `target` in `format`,
+/// for other build systems to build the same way.
+pub fn print_exported(build: &Command, target: &str, format: Format) {
+    let vars = build
+        .get_envs()
+        .filter_map(|(key, value)| Some((key.to_string_lossy(), value?.to_string_lossy())))
+        .chain([("CARGO_BUILD_TARGET".into(), target.into())]);
+    match format {
+        Format::Annotated => print(build),
+        Format::Nix => {
+            println!("{{");
+            for (key, value) in vars {
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace("${", "\\${")
+                    .replace('\n', "\\n");
+                println!("  {} = \"{}\";", key, value);
+            }
+            println!("}}");
+        }
+        Format::Direnv => {
+            for (key, value) in vars {
+                println!("export {}={}", key, quote(&value));
+            }
+        }
+        Format::GithubActions => {
+            for (key, value) in vars {
+                if value.contains('\n') {
+                    println!("{}<<ARCEOS_EOF\n{}\nARCEOS_EOF", key, value);
+                } else {
+                    println!("{}={}", key, value);
+                }
+            }
+        }
+    }
+}
+
+/// Prints every environment variable, its value and where the value came from,
+/// followed by the variables that `build` exports to the build.
+fn print(build: &Command) {
+    let matches = env_matches();
+
+    println!("{}", style("# Options").bold());
+    for option in env_options() {
+        // Options of other subcommands are not in the matches.
+        let from_matches = matches.as_ref().and_then(|matches| {
+            let values = matches
+                .try_get_raw(&option.id)
+                .ok()??
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            Some((values.join(","), matches.value_source(&option.id)?))
+        });
+
+        let (value, source) = match from_matches {
+            Some((value, ValueSource::CommandLine)) => (Some(value), "command line".into()),
+            Some((value, ValueSource::EnvVariable)) => (Some(value), source(&option.env)),
+            Some((value, _)) => (Some(value), "default".into()),
+            None => match env::var(&option.env) {
+                Ok(value) => (Some(value), source(&option.env)),
+                Err(_) if !option.defaults.is_empty() => {
+                    (Some(option.defaults.join(",")), "default".into())
+                }
+                Err(_) => (None, "unset".into()),
+            },
+        };
+
+        let flag = option.long.as_deref().unwrap_or_default();
+        match value {
+            Some(value) => println!(
+                "{}={} {}",
+                option.env,
+                quote(&value),
+                style(format!("# {}, {}", flag, source)).dim()
+            ),
+            None => println!(
+                "{}",
+                style(format!("# {} ({}) is {}", option.env, flag, source)).dim()
+            ),
+        }
+    }
+
+    println!();
+    println!("{}", style("# Exported to the build").bold());
+    for (key, value) in build.get_envs() {
+        if let Some(value) = value {
+            println!(
+                "{}={}",
+                key.to_string_lossy(),
+                quote(&value.to_string_lossy())
+            );
+        }
+    }
+}
+
+/// Quotes `value` for a POSIX shell if needed, so that the output can be
+/// sourced.
+fn quote(value: &str) -> String {
+    if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_./:,=+@%".contains(c))
+    {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
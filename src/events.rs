@@ -0,0 +1,147 @@
+//! Progress reporting for frontends that embed cargo-arceos.
+//!
+//! The runner is spawned by cargo, so when events are requested it reports
+//! back by printing JSON messages to its stdout, next to the ones cargo prints.
+
+use std::{env, path::PathBuf, process::Command};
+
+use serde::{Deserialize, Serialize};
+
+/// Tells the runner to report what it does on stdout.
+const EVENTS_ENV: &str = "CARGO_ARCEOS_EVENTS";
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Cargo has been started with the given command line.
+    BuildStarted { command: String },
+    /// A target of a package has been built. `executable` is set for binaries,
+    /// examples and tests.
+    ArtifactReady {
+        package_id: String,
+        target: String,
+        kind: ArtifactKind,
+        executable: Option<PathBuf>,
+    },
+    /// The compiler has reported a diagnostic for a package. `rendered` is the
+    /// diagnostic as the compiler would print it.
+    CompilerMessage {
+        package_id: String,
+        level: cargo_metadata::diagnostic::DiagnosticLevel,
+        rendered: Option<String>,
+    },
+    /// The build script of a package has run, with its output in `out_dir`.
+    BuildScriptExecuted {
+        package_id: String,
+        out_dir: PathBuf,
+    },
+    /// The runner has started QEMU with the given command line.
+    QemuLaunched { command: String },
+    /// A line printed by cargo, or by the guest once it is running.
+    ConsoleLine(String),
+    /// Everything has finished with the given exit code.
+    Exited { code: i32 },
+}
+
+/// The kind of target that an artifact is built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Lib,
+    Bin,
+    Example,
+    /// A test harness, built from any target with `cargo test`.
+    Test,
+    Bench,
+    BuildScript,
+}
+
+impl ArtifactKind {
+    pub(crate) fn of(artifact: &cargo_metadata::Artifact) -> ArtifactKind {
+        let target = &artifact.target;
+        if target.is_custom_build() {
+            ArtifactKind::BuildScript
+        } else if target.is_bench() {
+            ArtifactKind::Bench
+        } else if artifact.profile.test || target.is_test() {
+            ArtifactKind::Test
+        } else if target.is_example() {
+            ArtifactKind::Example
+        } else if target.is_bin() {
+            ArtifactKind::Bin
+        } else {
+            ArtifactKind::Lib
+        }
+    }
+}
+
+/// Receives the events of a command.
+pub trait EventHandler {
+    fn handle(&mut self, event: Event);
+
+    /// Whether the guest console stays attached to the terminal instead of
+    /// being reported as [`Event::ConsoleLine`]s.
+    fn interactive(&self) -> bool {
+        false
+    }
+}
+
+impl<F: FnMut(Event)> EventHandler for F {
+    fn handle(&mut self, event: Event) {
+        self(event)
+    }
+}
+
+/// A message printed by the runner, tagged like cargo's JSON messages.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum RunnerMessage {
+    QemuLaunched { command: String },
+}
+
+/// Makes the runner spawned by the cargo `command` report its events.
+pub(crate) fn enable(command: &mut Command) {
+    command.env(EVENTS_ENV, "1");
+}
+
+/// Reports that QEMU is about to be started with `command`, if requested.
+pub(crate) fn qemu_launched(command: &Command) {
+    if env::var_os(EVENTS_ENV).is_some() {
+        let message = RunnerMessage::QemuLaunched {
+            command: crate::command_line(command),
+        };
+        println!("{}", serde_json::to_string(&message).unwrap());
+    }
+}
+
+/// Turns a line of text printed to cargo's stdout into an event.
+pub(crate) fn parse_line(line: String) -> Event {
+    match serde_json::from_str(&line) {
+        Ok(RunnerMessage::QemuLaunched { command }) => Event::QemuLaunched { command },
+        Err(_) => Event::ConsoleLine(line),
+    }
+}
+
+/// The CLI itself, which leaves the console alone and prints what cargo says.
+pub(crate) struct Terminal;
+
+impl EventHandler for Terminal {
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::ConsoleLine(line) => {
+                eprintln!("{}", line);
+                crate::logfile::message(&(line + "\n"));
+            }
+            Event::CompilerMessage {
+                rendered: Some(rendered),
+                ..
+            } if crate::renders_diagnostics() => {
+                eprint!("{}", rendered);
+                crate::logfile::message(&rendered);
+            }
+            _ => {}
+        }
+    }
+
+    fn interactive(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,118 @@
+//! Decoding of the status a guest reports when it shuts QEMU down.
+//!
+//! Guests report success by simply powering off. Otherwise they send one of
+//! the status bytes below through a device whose value QEMU turns into its
+//! exit code as `(status << 1) | 1`:
+//!
+//! - x86_64: `isa-debug-exit` at I/O port `0xf4`, which does the encoding
+//!   itself,
+//! - riscv64: the SiFive test device of the `virt` machine, by writing
+//!   `(((status << 1) | 1) << 16) | 0x3333`,
+//! - aarch64: semihosting `SYS_EXIT_EXTENDED` with `(status << 1) | 1`, only
+//!   with `--semihosting-exit`, as semihosting also lets the guest open the
+//!   files of the host.
+//!
+//! LoongArch has no such device, and neither has aarch64 by default, so only
+//! a clean power off can be told apart there.
+//!
+//! Status 0 is not sent, since it would make QEMU exit with 1, which is also
+//! how QEMU fails by itself, and is reported as a QEMU error. Status 1 is
+//! fine, and makes QEMU exit with 3.
+
+use std::process::Command;
+
+use serde::Serialize;
+
+use crate::platforms::Arch;
+
+pub const TEST_FAILED: u8 = 1;
+pub const PANIC: u8 = 2;
+pub const WATCHDOG: u8 = 3;
+
+/// How a guest run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "kind", content = "status")]
+pub enum GuestExit {
+    Success,
+    TestFailed,
+    Panic,
+    Watchdog,
+    /// A status byte not defined above.
+    Other(u8),
+    /// QEMU itself failed with the given exit code.
+    QemuError(i32),
+    /// QEMU was killed by a signal.
+    Killed,
+    /// The runner was stopped by the given signal.
+    Interrupted(i32),
+    /// QEMU was stopped after `--timeout`.
+    TimedOut,
+}
+
+impl GuestExit {
+    pub fn new(status: std::process::ExitStatus) -> Self {
+        let Some(code) = status.code() else {
+            return GuestExit::Killed;
+        };
+        // QEMU exits with 1 on its own errors, which is also what a status of
+        // 0 would be encoded as, so guests must not send that.
+        if code == 0 {
+            return GuestExit::Success;
+        }
+        if code & 1 == 0 || code == 1 || code > 0x1ff {
+            return GuestExit::QemuError(code);
+        }
+        match (code >> 1) as u8 {
+            TEST_FAILED => GuestExit::TestFailed,
+            PANIC => GuestExit::Panic,
+            WATCHDOG => GuestExit::Watchdog,
+            status => GuestExit::Other(status),
+        }
+    }
+
+    /// Returns the exit code of the runner, which is the status byte sent by
+    /// the guest, or 101 if the guest did not get to send one.
+    pub fn code(self) -> i32 {
+        match self {
+            GuestExit::Success => 0,
+            GuestExit::TestFailed => TEST_FAILED.into(),
+            GuestExit::Panic => PANIC.into(),
+            GuestExit::Watchdog => WATCHDOG.into(),
+            GuestExit::Other(status) => status.into(),
+            GuestExit::QemuError(_) | GuestExit::Killed => 101,
+            GuestExit::Interrupted(signal) => 128 + signal,
+            // Like `timeout(1)`.
+            GuestExit::TimedOut => 124,
+        }
+    }
+}
+
+impl std::fmt::Display for GuestExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuestExit::Success => write!(f, "guest exited successfully"),
+            GuestExit::TestFailed => write!(f, "guest reported failed tests"),
+            GuestExit::Panic => write!(f, "guest panicked"),
+            GuestExit::Watchdog => write!(f, "guest watchdog expired"),
+            GuestExit::Other(status) => write!(f, "guest exited with status {}", status),
+            GuestExit::QemuError(code) => write!(f, "QEMU failed with exit code {}", code),
+            GuestExit::Killed => write!(f, "QEMU was killed"),
+            GuestExit::Interrupted(signal) => write!(f, "interrupted by signal {}", signal),
+            GuestExit::TimedOut => write!(f, "guest did not exit in time"),
+        }
+    }
+}
+
+/// Adds the QEMU arguments that give the guest a way to report its status,
+/// through semihosting on aarch64 if `semihosting` is given.
+pub fn qemu_args(arch: Arch, semihosting: bool, command: &mut Command) {
+    match arch {
+        Arch::X86_64 => {
+            command.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
+        }
+        Arch::Aarch64 if semihosting => {
+            command.args(["-semihosting-config", "enable=on,target=native"]);
+        }
+        Arch::Aarch64 | Arch::Riscv64 | Arch::Loongarch64 => {}
+    }
+}
@@ -0,0 +1,321 @@
+use std::{env, path::PathBuf, process::Command, sync::LazyLock};
+
+use anyhow::{Context as _, bail};
+use cargo_metadata::{DependencyKind, Metadata};
+use toml_edit::{DocumentMut, Item};
+
+use crate::{
+    options::{BusType, Scheduler},
+    platforms::{Arch, Platform},
+};
+
+static FEATURES: LazyLock<Vec<Feature>> = LazyLock::new(|| {
+    parse(include_str!("features.toml")).expect("built-in feature table is invalid")
+});
+
+struct Feature {
+    name: String,
+    reason: String,
+    packages: Vec<String>,
+    when: Condition,
+}
+
+#[derive(Debug, Default)]
+struct Condition {
+    smp: Option<bool>,
+    arch: Option<Vec<String>>,
+    platform: Option<Vec<String>>,
+    soft_float: Option<bool>,
+    net: Option<bool>,
+    disk: Option<bool>,
+    graphics: Option<bool>,
+    bus: Option<String>,
+    app_fp: Option<bool>,
+    scheduler: Option<String>,
+}
+
+impl Condition {
+    fn matches(&self, ctx: &Context) -> bool {
+        fn flag(cond: Option<bool>, value: bool) -> bool {
+            cond.is_none_or(|cond| cond == value)
+        }
+
+        fn one_of(cond: &Option<Vec<String>>, value: &str) -> bool {
+            cond.as_ref()
+                .is_none_or(|cond| cond.iter().any(|c| c == value))
+        }
+
+        flag(self.smp, ctx.cpus > 1)
+            && one_of(&self.arch, ctx.arch.as_ref())
+            && one_of(&self.platform, ctx.platform.as_ref())
+            && flag(self.soft_float, ctx.soft_float)
+            && flag(self.net, ctx.net)
+            && flag(self.disk, ctx.disk)
+            && flag(self.graphics, ctx.graphics)
+            && self.bus.as_ref().is_none_or(|bus| bus == ctx.bus.as_ref())
+            && flag(self.app_fp, ctx.app_fp)
+            && self.scheduler.as_ref().is_none_or(|scheduler| {
+                ctx.scheduler
+                    .is_some_and(|s| s.as_ref() == scheduler.as_str())
+            })
+    }
+}
+
+fn parse(toml: &str) -> anyhow::Result<Vec<Feature>> {
+    fn bool_of(item: &Item, key: &str) -> anyhow::Result<bool> {
+        item.as_bool()
+            .with_context(|| format!("`{}` should be a boolean", key))
+    }
+
+    fn strings_of(item: &Item, key: &str) -> anyhow::Result<Vec<String>> {
+        item.as_array()
+            .and_then(|array| {
+                array
+                    .iter()
+                    .map(|v| v.as_str().map(str::to_string))
+                    .collect()
+            })
+            .with_context(|| format!("`{}` should be an array of strings", key))
+    }
+
+    let doc = toml.parse::<DocumentMut>()?;
+    let mut features = vec![];
+
+    for (name, item) in doc.iter() {
+        let table = item
+            .as_table()
+            .with_context(|| format!("feature `{}` should be a table", name))?;
+
+        let mut feature = None;
+        let mut reason = None;
+        let mut packages = None;
+        let mut when = Condition::default();
+        for (key, item) in table.iter() {
+            match key {
+                "feature" => {
+                    feature = Some(
+                        item.as_str()
+                            .context("`feature` should be a string")?
+                            .to_string(),
+                    )
+                }
+                "reason" => {
+                    reason = Some(
+                        item.as_str()
+                            .context("`reason` should be a string")?
+                            .to_string(),
+                    )
+                }
+                "packages" => packages = Some(strings_of(item, key)?),
+                "smp" => when.smp = Some(bool_of(item, key)?),
+                "arch" => when.arch = Some(strings_of(item, key)?),
+                "platform" => when.platform = Some(strings_of(item, key)?),
+                "soft-float" => when.soft_float = Some(bool_of(item, key)?),
+                "net" => when.net = Some(bool_of(item, key)?),
+                "disk" => when.disk = Some(bool_of(item, key)?),
+                "graphics" => when.graphics = Some(bool_of(item, key)?),
+                "bus" => {
+                    when.bus = Some(
+                        item.as_str()
+                            .context("`bus` should be a string")?
+                            .to_string(),
+                    )
+                }
+                "app-fp" => when.app_fp = Some(bool_of(item, key)?),
+                "scheduler" => {
+                    when.scheduler = Some(
+                        item.as_str()
+                            .context("`scheduler` should be a string")?
+                            .to_string(),
+                    )
+                }
+                _ => bail!("unknown key `{}` in feature `{}`", key, name),
+            }
+        }
+
+        features.push(Feature {
+            name: feature.unwrap_or_else(|| name.to_string()),
+            reason: reason.with_context(|| format!("feature `{}` has no reason", name))?,
+            packages: packages.with_context(|| format!("feature `{}` has no packages", name))?,
+            when,
+        });
+    }
+
+    Ok(features)
+}
+
+/// Returns the package that `command` builds, given by `--manifest-path` or
+/// found in the current directory like cargo does.
+fn selected_package<'a>(
+    metadata: &'a Metadata,
+    command: &Command,
+) -> Option<&'a cargo_metadata::Package> {
+    let manifest_path = command
+        .get_args()
+        .skip_while(|arg| *arg != "--manifest-path")
+        .nth(1)
+        .map(PathBuf::from)
+        .or_else(|| Some(env::current_dir().ok()?.join("Cargo.toml")))?;
+    let manifest_path = manifest_path.canonicalize().unwrap_or(manifest_path);
+    metadata
+        .packages
+        .iter()
+        .find(|package| package.manifest_path == manifest_path)
+        .or_else(|| metadata.root_package())
+}
+
+/// The crates of ArceOS that apps pick its features on, in order of
+/// preference.
+const FRONTENDS: [&str; 3] = ["axstd", "axlibc", "axfeat"];
+
+/// Enables `features` on the dependency of the root package on one of
+/// [`FRONTENDS`], and disables the default features of the root package if
+/// `no_default`.
+pub fn enable_ax_features(
+    metadata: &Metadata,
+    features: &[String],
+    no_default: bool,
+    command: &mut Command,
+) -> anyhow::Result<()> {
+    if features.is_empty() && !no_default {
+        return Ok(());
+    }
+    let package = selected_package(metadata, command).context(
+        "`--ax-features` needs a package, select the app with `--app` or `--manifest-path`",
+    )?;
+    let dep = FRONTENDS
+        .iter()
+        .find_map(|name| {
+            package
+                .dependencies
+                .iter()
+                .find(|dep| dep.kind == DependencyKind::Normal && dep.name == *name)
+        })
+        .with_context(|| {
+            format!(
+                "package `{}` depends on none of {}",
+                package.name,
+                FRONTENDS.map(|name| format!("`{}`", name)).join(", ")
+            )
+        })?;
+    // The features can only be checked if the crate is in the workspace.
+    let provider = metadata.packages.iter().find(|p| p.name == dep.name);
+
+    if no_default {
+        command.arg("--no-default-features");
+        if dep.uses_default_features
+            && let Some(provider) = provider
+            && provider
+                .features
+                .get("default")
+                .is_some_and(|default| !default.is_empty())
+        {
+            crate::warn(format!(
+                "the default features of `{}` stay enabled, set `default-features = false` on the dependency to disable them",
+                dep.name
+            ));
+        }
+    }
+
+    let name = dep.rename.as_ref().unwrap_or(&dep.name);
+    for feature in features {
+        if let Some(provider) = provider
+            && !provider.features.contains_key(feature)
+        {
+            let mut known: Vec<&str> = provider.features.keys().map(String::as_str).collect();
+            known.retain(|f| *f != "default");
+            bail!(
+                "package `{}` has no feature `{}`, expected one of: {}",
+                dep.name,
+                feature,
+                known.join(", ")
+            );
+        }
+        let feature = if dep.optional {
+            format!("{}?/{}", name, feature)
+        } else {
+            format!("{}/{}", name, feature)
+        };
+        crate::info("Enabling", format!("feature `{}`", feature));
+        command.args(["--features", &feature]);
+    }
+    Ok(())
+}
+
+/// The build and run configuration that feature requirements depend on.
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub cpus: u32,
+    pub arch: Arch,
+    pub platform: Platform,
+    pub soft_float: bool,
+    pub net: bool,
+    pub disk: bool,
+    pub graphics: bool,
+    pub bus: BusType,
+    pub app_fp: bool,
+    pub scheduler: Option<Scheduler>,
+    pub pie: bool,
+}
+
+impl Context {
+    fn required(&self) -> impl Iterator<Item = &'static Feature> {
+        FEATURES.iter().filter(|f| f.when.matches(self))
+    }
+
+    /// Warns about the required features that `package` is built without,
+    /// returning how many there are.
+    pub fn check(&self, package: &str, features: &[String]) -> usize {
+        let mut missing = 0;
+        for f in self.required() {
+            if f.packages.iter().any(|p| p == package) && !features.contains(&f.name) {
+                missing += 1;
+                crate::warn_code(
+                    crate::diagnostics::Code::MissingFeature,
+                    format!(
+                        "feature `{}` should be enabled for package `{}` when {}",
+                        f.name, package, f.reason
+                    ),
+                );
+            }
+        }
+        missing
+    }
+
+    /// Enables the required features accepted by `filter` on the direct
+    /// dependencies of the root package that provide them.
+    pub fn enable(
+        &self,
+        metadata: &Metadata,
+        command: &mut Command,
+        filter: impl Fn(&str) -> bool,
+    ) {
+        let Some(package) = selected_package(metadata, command) else {
+            crate::warn_code(
+                crate::diagnostics::Code::NoRootPackage,
+                "features can only be enabled automatically for a root package",
+            );
+            return;
+        };
+
+        for dep in &package.dependencies {
+            if dep.kind != DependencyKind::Normal {
+                continue;
+            }
+
+            let crate_name = dep.name.replace('-', "_");
+            let name = dep.rename.as_ref().unwrap_or(&dep.name);
+            for f in self.required().filter(|f| filter(&f.name)) {
+                if f.packages.contains(&crate_name) && !dep.features.contains(&f.name) {
+                    let feature = if dep.optional {
+                        format!("{}?/{}", name, f.name)
+                    } else {
+                        format!("{}/{}", name, f.name)
+                    };
+                    crate::info("Enabling", format!("feature `{}`", feature));
+                    command.args(["--features", &feature]);
+                }
+            }
+        }
+    }
+}
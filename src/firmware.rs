@@ -0,0 +1,215 @@
+//! Firmware that platforms need besides the kernel, fetched into the cache
+//! directory by `cargo arceos firmware fetch`.
+//!
+//! Each file is verified against its SHA-256 when it is fetched and whenever
+//! it is used. Files without a pinned digest are trusted on first use: the
+//! digest of the first download is recorded in `<platform>.sha256` next to
+//! the directory of the platform, so that later corruption or tampering is
+//! still noticed.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+
+use crate::platforms::Platform;
+
+/// A firmware file of a platform.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FirmwareFile {
+    /// Name of the file in the cache directory.
+    pub name: String,
+    pub url: String,
+    /// SHA-256 of the file, in hex.
+    pub sha256: Option<String>,
+    /// Whether QEMU loads the file with `-bios`.
+    #[serde(default)]
+    pub bios: bool,
+}
+
+/// Where the Raspberry Pi firmware is published.
+const RASPI4_FIRMWARE_URL: &str = "https://github.com/raspberrypi/firmware/raw/master/boot";
+
+/// Returns the firmware of `platform` that cargo-arceos knows about.
+fn builtin(platform: Platform) -> Vec<FirmwareFile> {
+    let file = |name: &str, url: String, bios: bool| FirmwareFile {
+        name: name.to_string(),
+        url,
+        sha256: None,
+        bios,
+    };
+    match platform {
+        Platform::AARCH64_RASPI4 => ["start4.elf", "fixup4.dat", "bcm2711-rpi-4-b.dtb"]
+            .into_iter()
+            .map(|name| file(name, format!("{}/{}", RASPI4_FIRMWARE_URL, name), false))
+            .collect(),
+        Platform::LOONGARCH64_QEMU_VIRT => vec![file(
+            "QEMU_EFI.fd",
+            "https://github.com/loongson/Firmware/raw/main/LoongArchVirtMachine/QEMU_EFI.fd"
+                .to_string(),
+            true,
+        )],
+        _ => vec![],
+    }
+}
+
+/// Returns the firmware of `platform`, from `[package.metadata.arceos.firmware]`
+/// if it lists the platform, or the built-in one.
+pub fn files(
+    platform: Platform,
+    metadata: Option<&crate::metadata::ArceOSMetadata>,
+) -> Vec<FirmwareFile> {
+    metadata
+        .and_then(|metadata| metadata.firmware.get(platform.as_ref()))
+        .cloned()
+        .unwrap_or_else(|| builtin(platform))
+}
+
+/// Returns the directory that the firmware of `platform` is fetched into.
+pub fn dir(platform: Platform) -> anyhow::Result<PathBuf> {
+    Ok(crate::cache_dir()?.join("firmware").join(platform.as_ref()))
+}
+
+/// Returns the file that records the digests of the firmware of `platform`,
+/// in the format of `sha256sum`. It is kept out of the directory of the
+/// firmware, which may be copied as a whole, like onto an SD card.
+fn digests_file(platform: Platform) -> anyhow::Result<PathBuf> {
+    Ok(crate::cache_dir()?
+        .join("firmware")
+        .join(format!("{}.sha256", platform)))
+}
+
+/// Returns the recorded digests of the firmware of `platform`, by file name.
+fn recorded(platform: Platform) -> anyhow::Result<BTreeMap<String, String>> {
+    let digests = fs::read_to_string(digests_file(platform)?).unwrap_or_default();
+    Ok(digests
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(digest, name)| (name.to_string(), digest.to_string()))
+        .collect())
+}
+
+fn record(platform: Platform, name: &str, digest: String) -> anyhow::Result<()> {
+    let mut digests = recorded(platform)?;
+    digests.insert(name.to_string(), digest);
+    let lines: String = digests
+        .iter()
+        .map(|(name, digest)| format!("{}  {}\n", digest, name))
+        .collect();
+    crate::write_atomic(&digests_file(platform)?, lines).context("failed to record the digest")
+}
+
+fn sha256(path: &Path) -> anyhow::Result<String> {
+    let data = fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    Ok(crate::checksum::sha256(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Checks `path` against the pinned digest of `file`, or against the one
+/// recorded when it was fetched.
+fn verify(platform: Platform, file: &FirmwareFile, path: &Path) -> anyhow::Result<()> {
+    let expected = match &file.sha256 {
+        Some(sha256) => sha256.to_lowercase(),
+        None => recorded(platform)?
+            .remove(&file.name)
+            .with_context(|| format!("`{}` has no recorded digest", path.display()))?,
+    };
+    let actual = sha256(path)?;
+    if actual != expected {
+        bail!(
+            "`{}` has SHA-256 {}, expected {}; fetch it again with `cargo arceos firmware fetch --force {}`",
+            path.display(),
+            actual,
+            expected,
+            platform
+        );
+    }
+    Ok(())
+}
+
+/// Downloads the firmware `files` of `platform` that are not in the cache
+/// yet, or all of them if `force`.
+pub fn fetch(platform: Platform, files: &[FirmwareFile], force: bool) -> anyhow::Result<()> {
+    if files.is_empty() {
+        bail!(
+            "there is no firmware for platform `{}`, list it in `[package.metadata.arceos.firmware.{}]`",
+            platform,
+            platform
+        );
+    }
+    let dir = dir(platform)?;
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create `{}`", dir.display()))?;
+
+    for file in files {
+        let path = dir.join(&file.name);
+        if !force && path.exists() && verify(platform, file, &path).is_ok() {
+            crate::info("Fresh", format!("`{}`", path.display()));
+            continue;
+        }
+        crate::require_network(&format!("fetching `{}`", file.name))?;
+
+        let temp = crate::temp_path(&path);
+        let result = crate::run_command(
+            Command::new("curl")
+                .args(["-fL", "--retry", "3", "-o"])
+                .arg(&temp)
+                .arg(&file.url),
+        )
+        .and_then(|_| {
+            let actual = sha256(&temp)?;
+            if let Some(expected) = &file.sha256
+                && actual != expected.to_lowercase()
+            {
+                bail!(
+                    "`{}` has SHA-256 {}, expected {}",
+                    file.url,
+                    actual,
+                    expected
+                );
+            }
+            fs::rename(&temp, &path)
+                .with_context(|| format!("failed to write `{}`", path.display()))?;
+            record(platform, &file.name, actual)
+        });
+        if let Err(e) = result {
+            fs::remove_file(&temp).ok();
+            bail!("failed to fetch `{}`: {}", file.name, e);
+        }
+        crate::info("Fetched", format!("`{}`", path.display()));
+    }
+    Ok(())
+}
+
+/// Returns the directory with the fetched firmware of `platform` after
+/// verifying it, if it has been fetched.
+pub fn fetched_dir(platform: Platform, files: &[FirmwareFile]) -> anyhow::Result<Option<PathBuf>> {
+    let dir = dir(platform)?;
+    if files.is_empty() || !files.iter().all(|file| dir.join(&file.name).exists()) {
+        return Ok(None);
+    }
+    for file in files {
+        verify(platform, file, &dir.join(&file.name))?;
+    }
+    Ok(Some(dir))
+}
+
+/// Returns the fetched file that QEMU loads with `-bios` on `platform`.
+pub fn bios(platform: Platform, files: &[FirmwareFile]) -> anyhow::Result<Option<PathBuf>> {
+    let Some(file) = files.iter().find(|file| file.bios) else {
+        return Ok(None);
+    };
+    let path = dir(platform)?.join(&file.name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    verify(platform, file, &path)?;
+    Ok(Some(path))
+}
@@ -0,0 +1,25 @@
+//! The guest side of `cargo arceos test`, printed by `cargo arceos test
+//! --guest-code`.
+//!
+//! Copy it into an app that depends on `axstd`, and list the tests in `main`,
+//! like `ax_tests!(tests::alloc, tests::threads)`. A test is a function that
+//! panics when it fails. The panic ends the run, and the harness counts the
+//! test that was running as failed.
+
+/// Runs the test functions, reporting them to the harness of cargo-arceos,
+/// except those whose names do not contain `--test-filter`, which the app has
+/// in its environment.
+#[macro_export]
+macro_rules! ax_tests {
+    ($($test:path),* $(,)?) => {{
+        let filter = std::env::var("AX_TEST_FILTER").unwrap_or_default();
+        $(
+            let name = stringify!($test).replace(' ', "");
+            if name.contains(filter.as_str()) {
+                println!("[AX-TEST] {} ...", name);
+                $test();
+                println!("[AX-TEST] {} ... ok", name);
+            }
+        )*
+    }};
+}
@@ -0,0 +1,232 @@
+//! The results of tests run in the guest, for `cargo arceos test` and
+//! `--test-harness`.
+//!
+//! Tests report themselves on the console with lines that may follow other
+//! output of the guest:
+//!
+//! - `[AX-TEST] <NAME> ...` when a test starts,
+//! - `[AX-TEST] <NAME> ... ok`, `... failed` or `... ignored` when it ends.
+//!
+//! A test that has started but not ended when the guest exits, like because
+//! it panicked, has failed. `--test-filter` goes to the runner, which sets
+//! `AX_TEST_FILTER` in the environment of the app on the kernel command line,
+//! for the guest to skip the tests whose names do not contain it without
+//! being rebuilt. The results of those tests are left out either way, as a
+//! board on the serial port gets no command line. The results are summarized
+//! like libtest does. The guest side is in `harness-guest.rs`.
+
+use std::{ffi::OsString, io::Write, sync::Mutex, time::Duration};
+
+use clap::Args;
+use console::style;
+
+use crate::exit::GuestExit;
+
+/// Starts the lines of the protocol.
+const PREFIX: &str = "[AX-TEST] ";
+
+/// The variable of the environment of the app that tells it which tests to
+/// run.
+const FILTER_VAR: &str = "AX_TEST_FILTER";
+
+/// The helper for the guest, to copy into the app.
+pub const GUEST_CODE: &str = include_str!("harness-guest.rs");
+
+static HARNESS: Mutex<Option<Harness>> = Mutex::new(None);
+
+/// The filter of the tests that the runner was given.
+static FILTER: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Test Options")]
+pub struct HarnessOptions {
+    /// Collect the results of the tests that the guest reports, see `cargo
+    /// arceos test`
+    #[arg(long)]
+    test_harness: bool,
+
+    /// Only run the tests whose names contain FILTER, which the guest gets as
+    /// `AX_TEST_FILTER` on the kernel command line
+    #[arg(long, value_name = "FILTER")]
+    test_filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Ok,
+    Failed,
+    Ignored,
+}
+
+/// The results reported so far.
+#[derive(Debug, Default)]
+struct Harness {
+    filter: Option<String>,
+    results: Vec<(String, Outcome)>,
+    /// The test that has started without ending yet.
+    running: Option<String>,
+    filtered: usize,
+    line: Vec<u8>,
+}
+
+impl Harness {
+    fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            self.line.clear();
+            self.parse(line.trim_end_matches('\r'));
+        }
+    }
+
+    fn parse(&mut self, line: &str) {
+        let Some(start) = line.find(PREFIX) else {
+            return;
+        };
+        let line = line[start + PREFIX.len()..].trim_end();
+        if let Some(name) = line.strip_suffix(" ...") {
+            self.running = Some(name.to_string());
+            return;
+        }
+        let Some((name, outcome)) = line.rsplit_once(" ... ") else {
+            return;
+        };
+        let outcome = match outcome {
+            "ok" => Outcome::Ok,
+            "failed" => Outcome::Failed,
+            "ignored" => Outcome::Ignored,
+            _ => return,
+        };
+        if self.running.as_deref() == Some(name) {
+            self.running = None;
+        }
+        if self
+            .filter
+            .as_deref()
+            .is_some_and(|filter| !name.contains(filter))
+        {
+            self.filtered += 1;
+        } else {
+            self.results.push((name.to_string(), outcome));
+        }
+    }
+}
+
+impl HarnessOptions {
+    /// Collects the results of the tests, for `cargo arceos test`.
+    pub fn enable(&mut self) {
+        self.test_harness = true;
+    }
+
+    pub fn runner_args(&self, runner: &mut Vec<OsString>) {
+        if self.test_harness {
+            runner.push("--test-harness".into());
+        }
+        if let Some(filter) = &self.test_filter {
+            runner.push("--test-filter".into());
+            runner.push(filter.into());
+        }
+    }
+
+    /// Starts collecting the results from the console, and has the guest run
+    /// only the tests that match the filter.
+    pub fn start(&self) {
+        FILTER.lock().unwrap().clone_from(&self.test_filter);
+        if self.test_harness {
+            *HARNESS.lock().unwrap() = Some(Harness {
+                filter: self.test_filter.clone(),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+/// Returns the `KEY=VAL` entry of the environment of the app that tells it
+/// which tests to run, if the runner was given a filter.
+pub fn guest_env() -> Option<String> {
+    FILTER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|filter| format!("{}={}", FILTER_VAR, filter))
+}
+
+/// Collects results from the output of the guest console.
+pub fn console(data: &[u8]) {
+    if let Some(harness) = HARNESS.lock().unwrap().as_mut() {
+        harness.feed(data);
+    }
+}
+
+/// Prints the results collected since the last call for a run that ended
+/// with `exit` after `elapsed`, and returns how the run ended considering
+/// them.
+pub fn finish(exit: GuestExit, elapsed: Duration) -> GuestExit {
+    let mut harness = HARNESS.lock().unwrap();
+    let Some(harness) = harness.as_mut() else {
+        return exit;
+    };
+    // Whatever the guest printed last without a newline counts as a line.
+    if !harness.line.is_empty() {
+        harness.feed(b"\n");
+    }
+    let mut results = std::mem::take(&mut harness.results);
+    if let Some(name) = harness.running.take() {
+        results.push((name, Outcome::Failed));
+    }
+    let filtered = std::mem::take(&mut harness.filtered);
+
+    let count = |outcome| results.iter().filter(|(_, o)| *o == outcome).count();
+    let (passed, failed, ignored) = (
+        count(Outcome::Ok),
+        count(Outcome::Failed),
+        count(Outcome::Ignored),
+    );
+    let mut out = std::io::stdout().lock();
+    let mut report = || -> std::io::Result<()> {
+        writeln!(
+            out,
+            "\nrunning {} test{}",
+            results.len(),
+            if results.len() == 1 { "" } else { "s" }
+        )?;
+        for (name, outcome) in &results {
+            let outcome = match outcome {
+                Outcome::Ok => style("ok").green(),
+                Outcome::Failed => style("FAILED").red(),
+                Outcome::Ignored => style("ignored").yellow(),
+            };
+            writeln!(out, "test {} ... {}", name, outcome)?;
+        }
+        if failed > 0 {
+            writeln!(out, "\nfailures:")?;
+            for (name, _) in results.iter().filter(|(_, o)| *o == Outcome::Failed) {
+                writeln!(out, "    {}", name)?;
+            }
+        }
+        let result = if failed > 0 {
+            style("FAILED").red()
+        } else {
+            style("ok").green()
+        };
+        writeln!(
+            out,
+            "\ntest result: {}. {} passed; {} failed; {} ignored; 0 measured; {} filtered out; finished in {}\n",
+            result,
+            passed,
+            failed,
+            ignored,
+            filtered,
+            crate::timings::format(elapsed)
+        )
+    };
+    report().ok();
+
+    match exit {
+        GuestExit::Success if failed > 0 => GuestExit::TestFailed,
+        exit => exit,
+    }
+}
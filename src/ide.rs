@@ -0,0 +1,191 @@
+use std::{fs, path::Path, process::Command};
+
+use anyhow::{Context, bail};
+use strum::{AsRefStr, EnumString, VariantNames};
+use toml_edit::{DocumentMut, Item, Table, value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Editor {
+    /// `.cargo/config.toml`, picked up by cargo and every rust-analyzer client
+    Cargo,
+    /// `.vscode/settings.json`
+    Vscode,
+}
+
+/// What a build with the chosen options passes to cargo.
+#[derive(Debug)]
+pub struct BuildSettings {
+    pub target: String,
+    pub env: Vec<(String, String)>,
+    pub features: Vec<String>,
+    /// The runner for plain `cargo run` to boot the kernel with.
+    pub runner: Option<Vec<String>>,
+}
+
+impl BuildSettings {
+    /// Collects the settings from a cargo `command` set up for a build.
+    pub fn new(command: &Command) -> Self {
+        let mut target = String::new();
+        let mut features = vec![];
+        let mut args = command.get_args().map(|arg| arg.to_string_lossy());
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--target" => target = args.next().unwrap_or_default().into_owned(),
+                "--features" => features.extend(args.next().map(|f| f.into_owned())),
+                _ => {}
+            }
+        }
+
+        // The build info names the time of the build, which a config file
+        // would keep stale.
+        let env = command
+            .get_envs()
+            .filter(|(key, _)| *key != "AX_BUILD_INFO")
+            .filter_map(|(key, value)| {
+                Some((
+                    key.to_string_lossy().into_owned(),
+                    value?.to_string_lossy().into_owned(),
+                ))
+            })
+            .collect();
+
+        Self {
+            target,
+            env,
+            features,
+            runner: None,
+        }
+    }
+
+    fn rustflags(&self) -> Option<Vec<String>> {
+        let (_, rustflags) = self.env.iter().find(|(key, _)| key == "RUSTFLAGS")?;
+        Some(rustflags.split_whitespace().map(str::to_string).collect())
+    }
+}
+
+/// Updates `.cargo/config.toml` under `root`, keeping unrelated settings, or
+/// only checks that it is up to date if `check`.
+pub fn write_cargo_config(
+    root: &Path,
+    settings: &BuildSettings,
+    check: bool,
+) -> anyhow::Result<()> {
+    let path = root.join(".cargo").join("config.toml");
+    let mut doc = match fs::read_to_string(&path) {
+        Ok(toml) => toml
+            .parse::<DocumentMut>()
+            .with_context(|| format!("failed to parse `{}`", path.display()))?,
+        Err(_) => DocumentMut::new(),
+    };
+
+    table(&mut doc, "build")?.insert("target", value(&settings.target));
+
+    // Cargo reads `RUSTFLAGS` itself instead of passing it on, so it goes
+    // into the target table.
+    let rustflags = settings.rustflags();
+    if rustflags.is_some() || settings.runner.is_some() {
+        let target = table(&mut doc, "target")?;
+        if !target.contains_key(&settings.target) {
+            target.insert(&settings.target, Item::Table(Table::new()));
+        }
+        let Some(target) = target[&settings.target].as_table_mut() else {
+            bail!(
+                "`target.{}` in `{}` is not a table",
+                settings.target,
+                path.display()
+            );
+        };
+        if let Some(rustflags) = rustflags {
+            target.insert(
+                "rustflags",
+                value(rustflags.into_iter().collect::<toml_edit::Array>()),
+            );
+        }
+        if let Some(runner) = &settings.runner {
+            target.insert("runner", value(runner.iter().collect::<toml_edit::Array>()));
+        }
+    }
+
+    let env = table(&mut doc, "env")?;
+    for (key, val) in &settings.env {
+        if key != "RUSTFLAGS" {
+            env.insert(key, value(val));
+        }
+    }
+
+    write(&path, doc.to_string(), check)
+}
+
+/// Updates `.vscode/settings.json` under `root`, keeping unrelated settings,
+/// or only checks that it is up to date if `check`.
+pub fn write_vscode_settings(
+    root: &Path,
+    settings: &BuildSettings,
+    check: bool,
+) -> anyhow::Result<()> {
+    let path = root.join(".vscode").join("settings.json");
+    let mut json = match fs::read_to_string(&path) {
+        // VS Code also accepts comments here, which cannot be kept.
+        Ok(json) => serde_json::from_str(&json).with_context(|| {
+            format!(
+                "failed to parse `{}`, remove any comments and try again",
+                path.display()
+            )
+        })?,
+        Err(_) => serde_json::Value::Object(Default::default()),
+    };
+    let Some(object) = json.as_object_mut() else {
+        bail!("`{}` is not a JSON object", path.display());
+    };
+
+    object.insert(
+        "rust-analyzer.cargo.target".into(),
+        settings.target.clone().into(),
+    );
+    object.insert(
+        "rust-analyzer.cargo.extraEnv".into(),
+        settings
+            .env
+            .iter()
+            .map(|(key, val)| (key.clone(), val.clone().into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+    );
+    if !settings.features.is_empty() {
+        object.insert(
+            "rust-analyzer.cargo.features".into(),
+            settings.features.clone().into(),
+        );
+    }
+
+    write(&path, serde_json::to_string_pretty(&json)? + "\n", check)
+}
+
+fn table<'a>(doc: &'a mut DocumentMut, key: &str) -> anyhow::Result<&'a mut Table> {
+    let mut table = Table::new();
+    table.set_implicit(true);
+    doc.entry(key)
+        .or_insert(Item::Table(table))
+        .as_table_mut()
+        .with_context(|| format!("`{}` is not a table", key))
+}
+
+fn write(path: &Path, contents: String, check: bool) -> anyhow::Result<()> {
+    if fs::read_to_string(path).is_ok_and(|old| old == contents) {
+        crate::info("Fresh", format!("`{}`", path.display()));
+        return Ok(());
+    }
+    if check {
+        bail!(
+            "`{}` is out of date with the options, run `cargo arceos ide-setup` to update it",
+            path.display()
+        );
+    }
+    fs::create_dir_all(path.parent().unwrap())
+        .with_context(|| format!("failed to create `{}`", path.parent().unwrap().display()))?;
+    crate::write_atomic(path, contents)
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+    crate::info("Wrote", format!("`{}`", path.display()));
+    Ok(())
+}
@@ -0,0 +1,336 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{Context, bail};
+use clap::{Args, builder::TypedValueParser};
+use strum::{AsRefStr, EnumString, VariantNames};
+
+use crate::platforms::{Arch, Platform};
+
+#[derive(Debug, Default, Clone, Args)]
+#[command(next_help_heading = "Image Options")]
+pub struct ImageOptions {
+    /// Pad the image to SIZE, or to a multiple of SIZE with `align:SIZE`
+    #[arg(long, value_name = "SIZE")]
+    pad_to: Option<Padding>,
+
+    /// Append a checksum of the (padded) image
+    #[arg(long, value_parser = enum_variants!(Checksum))]
+    append_checksum: Option<Checksum>,
+
+    /// Compress the raw image into `<image>.gz` etc., for bootloaders that
+    /// decompress kernels, like U-Boot
+    #[arg(long, value_parser = enum_variants!(Compression))]
+    compress: Option<Compression>,
+}
+
+/// How the image is padded.
+#[derive(Debug, Clone, Copy)]
+pub enum Padding {
+    Size(u64),
+    Align(u64),
+}
+
+impl FromStr for Padding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("align:") {
+            Some(align) => match parse_size(align)? {
+                0 => bail!("alignment must not be zero"),
+                align => Ok(Padding::Align(align)),
+            },
+            None => Ok(Padding::Size(parse_size(s)?)),
+        }
+    }
+}
+
+/// Parses a size like `512`, `4K`, `16M` or `1G`.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (number, shift) = match s.as_bytes().last() {
+        Some(b'K' | b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M' | b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G' | b'g') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .with_context(|| format!("invalid size `{}`", s))
+}
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Checksum {
+    /// CRC-32, appended as 4 little-endian bytes
+    Crc32,
+    /// SHA-256, appended as the 32-byte digest
+    Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Compression {
+    /// gzip, which most bootloaders can decompress
+    Gzip,
+    /// Zstandard
+    Zstd,
+    /// LZ4, the fastest to decompress
+    Lz4,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    /// Returns the command that writes the compressed file to stdout.
+    fn command(self) -> Command {
+        let (program, args): (_, &[&str]) = match self {
+            // Without `-n`, the archive would depend on the name and time of
+            // the image.
+            Compression::Gzip => ("gzip", &["-9", "-n", "-c"]),
+            Compression::Zstd => ("zstd", &["-19", "-q", "-c"]),
+            Compression::Lz4 => ("lz4", &["-9", "-q", "-c"]),
+        };
+        let mut command = Command::new(program);
+        command.args(args);
+        command
+    }
+}
+
+/// The stages applied to an image after it has been extracted from the ELF.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImageLayout {
+    pub pad_to: Option<Padding>,
+    pub checksum: Option<Checksum>,
+}
+
+impl ImageLayout {
+    fn is_empty(&self) -> bool {
+        self.pad_to.is_none() && self.checksum.is_none()
+    }
+
+    fn apply(&self, image: &Path) -> anyhow::Result<()> {
+        let mut data =
+            fs::read(image).with_context(|| format!("failed to read `{}`", image.display()))?;
+
+        let len = data.len() as u64;
+        match self.pad_to {
+            Some(Padding::Size(size)) if len > size => {
+                bail!("image is {} bytes, larger than the pad size {}", len, size)
+            }
+            Some(Padding::Size(size)) => data.resize(size as usize, 0),
+            Some(Padding::Align(align)) => data.resize(len.next_multiple_of(align) as usize, 0),
+            None => {}
+        }
+
+        match self.checksum {
+            Some(Checksum::Crc32) => {
+                let crc = crate::checksum::crc32(&data);
+                data.extend(crc.to_le_bytes());
+            }
+            Some(Checksum::Sha256) => {
+                let digest = crate::checksum::sha256(&data);
+                data.extend(digest);
+            }
+            None => {}
+        }
+
+        fs::write(image, data).with_context(|| format!("failed to write `{}`", image.display()))
+    }
+}
+
+impl ImageOptions {
+    /// Returns the layout for `platform`, with the options overriding what the
+    /// platform asks for.
+    fn layout(&self, platform: Platform) -> anyhow::Result<ImageLayout> {
+        let default = platform.image_layout()?;
+        Ok(ImageLayout {
+            pad_to: self.pad_to.or(default.pad_to),
+            checksum: self.append_checksum.or(default.checksum),
+        })
+    }
+
+    pub fn runner_args(&self, runner: &mut Vec<OsString>) {
+        if let Some(pad_to) = self.pad_to {
+            runner.push("--pad-to".into());
+            runner.push(
+                match pad_to {
+                    Padding::Size(size) => size.to_string(),
+                    Padding::Align(align) => format!("align:{}", align),
+                }
+                .into(),
+            );
+        }
+
+        if let Some(checksum) = self.append_checksum {
+            runner.push("--append-checksum".into());
+            runner.push(checksum.as_ref().into());
+        }
+
+        if let Some(compression) = self.compress {
+            runner.push("--compress".into());
+            runner.push(compression.as_ref().into());
+        }
+    }
+
+    #[inline]
+    pub fn compression(&self) -> Option<Compression> {
+        self.compress
+    }
+}
+
+/// Returns whether kernels of `arch` are booted as raw binaries, which are
+/// made with `rust-objcopy`.
+pub fn is_raw(arch: Arch) -> bool {
+    matches!(arch, Arch::Aarch64 | Arch::Riscv64)
+}
+
+/// Turns the built ELF into the image that gets loaded onto the machine.
+///
+/// AArch64 and RISC-V kernels are booted as raw binaries, while the others
+/// can be loaded as ELF files directly, unless the image has to be padded or
+/// checksummed, in which case the ELF is copied to `<binary>.img` first.
+pub fn prepare(
+    platform: Platform,
+    options: &ImageOptions,
+    binary: PathBuf,
+) -> anyhow::Result<PathBuf> {
+    let layout = options.layout(platform)?;
+    let raw = is_raw(platform.into());
+    if !raw && layout.is_empty() {
+        return Ok(binary);
+    }
+    let kernel = crate::scratch::path(&binary).with_extension(if raw { "bin" } else { "img" });
+
+    // The hash of the ELF and the layout that the image was made from is kept
+    // next to it along with the size of the image, so that unchanged kernels
+    // are not converted over and over.
+    let stamp = {
+        let mut stamp = OsString::from(kernel.as_os_str());
+        stamp.push(".hash");
+        PathBuf::from(stamp)
+    };
+    let hash = hash_file(&binary, layout)?;
+    if let Ok(len) = fs::metadata(&kernel).map(|metadata| metadata.len())
+        && fs::read_to_string(&stamp).is_ok_and(|old| old == format!("{} {}", hash, len))
+    {
+        crate::info("Fresh", format!("`{}`", kernel.display()));
+        return Ok(kernel);
+    }
+    fs::remove_file(&stamp).ok();
+
+    // The image is made next to its final place and renamed into it, so that a
+    // partial image is never left behind, e.g. when interrupted.
+    let temp = crate::temp_path(&kernel);
+    let result = if raw {
+        let mut command = Command::new("rust-objcopy");
+        command
+            .args(["--strip-all", "-O", "binary"])
+            .arg(binary)
+            .arg(&temp);
+        crate::run_command(&mut command)
+    } else {
+        fs::copy(&binary, &temp)
+            .map(|_| ())
+            .with_context(|| format!("failed to copy `{}`", binary.display()))
+    };
+    if let Err(e) = result.and_then(|_| layout.apply(&temp)).and_then(|_| {
+        fs::rename(&temp, &kernel)
+            .with_context(|| format!("failed to write `{}`", kernel.display()))
+    }) {
+        fs::remove_file(&temp).ok();
+        return Err(e);
+    }
+    let len = fs::metadata(&kernel)?.len();
+    crate::write_atomic(&stamp, format!("{} {}", hash, len))
+        .context("failed to write image hash")?;
+
+    Ok(kernel)
+}
+
+/// Compresses the raw `image` made by [`prepare`] as given with `--compress`,
+/// returning the path of the compressed file, or `image` if it is not
+/// compressed.
+pub fn compress(options: &ImageOptions, image: PathBuf) -> anyhow::Result<PathBuf> {
+    let Some(compression) = options.compress else {
+        return Ok(image);
+    };
+    if image.extension().is_none_or(|ext| ext != "bin") {
+        bail!(
+            "only raw images can be compressed, but `{}` is booted as an ELF file",
+            image.display()
+        );
+    }
+    let mut output = OsString::from(image.as_os_str());
+    output.push(".");
+    output.push(compression.extension());
+    let output = PathBuf::from(output);
+
+    // The image is only rewritten when it changes.
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    if let (Ok(image_time), Ok(output_time)) = (modified(&image), modified(&output))
+        && output_time >= image_time
+    {
+        crate::info("Fresh", format!("`{}`", output.display()));
+        return Ok(output);
+    }
+
+    let mut command = compression.command();
+    let program = command.get_program().to_string_lossy().into_owned();
+    if crate::find_program(&program).is_none() {
+        bail!(
+            "`{}` is needed for `--compress {}`",
+            program,
+            compression.as_ref()
+        );
+    }
+    let result = command
+        .arg(&image)
+        .output()
+        .with_context(|| format!("failed to run `{}`", program))?;
+    if !result.status.success() {
+        bail!(
+            "`{}` failed with {}: {}",
+            program,
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    crate::write_atomic(&output, &result.stdout)
+        .with_context(|| format!("failed to write `{}`", output.display()))?;
+
+    let len = fs::metadata(&image)?.len();
+    crate::info(
+        "Compressed",
+        format!(
+            "`{}` from {} to {} KiB ({}%)",
+            output.display(),
+            len.div_ceil(1024),
+            result.stdout.len().div_ceil(1024),
+            result.stdout.len() as u64 * 100 / len.max(1)
+        ),
+    );
+    Ok(output)
+}
+
+fn hash_file(path: &Path, layout: ImageLayout) -> anyhow::Result<String> {
+    let mut data =
+        fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    data.extend(format!("{:?}", layout).into_bytes());
+    Ok(crate::checksum::sha256(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
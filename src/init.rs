@@ -0,0 +1,191 @@
+//! Choosing the defaults of the options for a project with `cargo arceos
+//! init`, which writes them into `[package.metadata.arceos.defaults]` of its
+//! manifest, or `[workspace.metadata.arceos.defaults]` in a virtual one.
+//!
+//! The defaults are keys named like the long options, loaded along with the
+//! env files, see [`crate::envvars`].
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use strum::VariantNames;
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+use crate::platforms::Platform;
+
+/// The log levels offered, from the least verbose.
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// The defaults of a project, where `None` leaves a default out.
+#[derive(Debug, Default)]
+pub struct Defaults {
+    pub platform: Option<Platform>,
+    pub cpus: Option<u32>,
+    pub net: Option<bool>,
+    pub disk: Option<Option<PathBuf>>,
+    pub graphics: Option<bool>,
+    pub log: Option<log::LevelFilter>,
+}
+
+/// Reads a line from the terminal after printing `question`, returning it
+/// trimmed, or `default` if it is empty.
+fn ask(question: &str, default: &str) -> anyhow::Result<String> {
+    eprint!("{} [{}] ", question, default);
+    std::io::stderr().flush().ok();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        bail!("no answer given");
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+/// Asks which of `choices` to take, by number or by name.
+fn choose<'a>(question: &str, choices: &[&'a str], default: &str) -> anyhow::Result<&'a str> {
+    for (i, choice) in choices.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, choice);
+    }
+    loop {
+        let answer = ask(question, default)?;
+        if let Ok(i @ 1..) = answer.parse::<usize>()
+            && let Some(choice) = choices.get(i - 1)
+        {
+            return Ok(choice);
+        }
+        if let Some(choice) = choices.iter().find(|choice| **choice == answer) {
+            return Ok(choice);
+        }
+    }
+}
+
+fn confirm(question: &str, default: bool) -> anyhow::Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        match ask(question, hint)?.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            answer if answer == hint.to_lowercase() => return Ok(default),
+            _ => {}
+        }
+    }
+}
+
+impl Defaults {
+    /// Asks for every default on the terminal, offering the ones that are
+    /// already given.
+    pub fn ask(&mut self) -> anyhow::Result<()> {
+        let platform = self
+            .platform
+            .unwrap_or(Platform::X86_64_QEMU_Q35)
+            .as_ref()
+            .to_string();
+        eprintln!("Platforms:");
+        // The dummy platform only builds on the host, so it is left out.
+        let platform = choose("Platform?", &Platform::VARIANTS[1..], &platform)?;
+        self.platform = Some(platform.parse().unwrap());
+
+        let cpus = self.cpus.unwrap_or(1).to_string();
+        self.cpus = loop {
+            if let Ok(cpus @ 1..) = ask("Number of CPUs?", &cpus)?.parse() {
+                break Some(cpus);
+            }
+        };
+
+        self.net = Some(confirm("Network device?", self.net.unwrap_or(false))?);
+        let disk = self.disk.clone().flatten();
+        let disk = ask(
+            "Disk image, or `none`?",
+            &disk.map_or("none".to_string(), |disk| disk.display().to_string()),
+        )?;
+        self.disk = Some((disk != "none").then(|| disk.into()));
+        self.graphics = Some(confirm("Graphics?", self.graphics.unwrap_or(false))?);
+
+        eprintln!("Log levels:");
+        let log = self.log.unwrap_or(log::LevelFilter::Warn).to_string();
+        let log = choose("Log level?", &LOG_LEVELS, &log.to_lowercase())?;
+        self.log = Some(log.parse().unwrap());
+        Ok(())
+    }
+
+    /// Returns the keys to set, with `None` for those to remove.
+    pub fn entries(&self) -> Vec<(&'static str, Option<Value>)> {
+        let mut entries = vec![];
+        if let Some(platform) = self.platform {
+            entries.push(("platform", Some(platform.as_ref().into())));
+        }
+        if let Some(cpus) = self.cpus {
+            entries.push(("cpus", Some(i64::from(cpus).into())));
+        }
+        if let Some(net) = self.net {
+            entries.push(("net", net.then(|| "user".into())));
+        }
+        if let Some(disk) = &self.disk {
+            entries.push((
+                "disk",
+                disk.as_ref()
+                    .map(|disk| disk.to_string_lossy().into_owned().into()),
+            ));
+        }
+        if let Some(graphics) = self.graphics {
+            entries.push(("graphics", graphics.then(|| true.into())));
+        }
+        if let Some(log) = self.log {
+            entries.push(("log", Some(log.to_string().to_lowercase().into())));
+        }
+        entries
+    }
+
+    /// Writes the defaults into the manifest at `path`, keeping the other
+    /// contents and defaults.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let toml = fs::read_to_string(path)
+            .with_context(|| format!("failed to read `{}`", path.display()))?;
+        let mut doc = toml
+            .parse::<DocumentMut>()
+            .with_context(|| format!("failed to parse `{}`", path.display()))?;
+        let root = if doc.contains_key("package") {
+            "package"
+        } else {
+            "workspace"
+        };
+
+        let mut table = doc.as_table_mut();
+        for key in [root, "metadata", "arceos", "defaults"] {
+            if !table.contains_key(key) {
+                let mut new = Table::new();
+                new.set_implicit(key != "defaults");
+                table.insert(key, Item::Table(new));
+            }
+            let Some(inner) = table[key].as_table_mut() else {
+                bail!("`{}` in `{}` is not a table", key, path.display());
+            };
+            table = inner;
+        }
+        for (key, value) in self.entries() {
+            match value {
+                Some(value) => {
+                    table.insert(key, Item::Value(value));
+                }
+                None => {
+                    table.remove(key);
+                }
+            }
+        }
+
+        crate::write_atomic(path, doc.to_string())
+            .with_context(|| format!("failed to write `{}`", path.display()))?;
+        crate::info(
+            "Updated",
+            format!(
+                "`{}.metadata.arceos.defaults` in `{}`",
+                root,
+                path.display()
+            ),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Packing a directory into a CPIO archive for the kernel to embed as its
+//! initial ramfs.
+//!
+//! The archive is written in the `newc` format that Linux uses for initramfs,
+//! and is rewritten only if its contents change, so that crates embedding it
+//! are not rebuilt needlessly. Next to it, `initramfs.rs` puts the archive
+//! into the `.initramfs` section of the kernel as `INITRAMFS`, for the app to
+//! include with
+//!
+//! ```text
+//! include!(env!("AX_INITRAMFS_RS"));
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+
+const MODE_DIR: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+struct Archive {
+    data: Vec<u8>,
+    ino: u32,
+    /// Whether modification times are left out, for `--reproducible`.
+    reproducible: bool,
+}
+
+impl Archive {
+    fn push(&mut self, name: &str, mode: u32, mtime: u64, contents: &[u8]) {
+        self.ino += 1;
+        let fields = [
+            self.ino,
+            mode,
+            0, // uid
+            0, // gid
+            if mode & MODE_DIR != 0 { 2 } else { 1 },
+            if self.reproducible { 0 } else { mtime as u32 },
+            contents.len() as u32,
+            0, // devmajor
+            0, // devminor
+            0, // rdevmajor
+            0, // rdevminor
+            name.len() as u32 + 1,
+            0, // check
+        ];
+        self.data.extend(b"070701");
+        for field in fields {
+            self.data.extend(format!("{:08x}", field).as_bytes());
+        }
+        self.data.extend(name.as_bytes());
+        self.data.push(0);
+        self.pad();
+        self.data.extend(contents);
+        self.pad();
+    }
+
+    fn pad(&mut self) {
+        while !self.data.len().is_multiple_of(4) {
+            self.data.push(0);
+        }
+    }
+
+    /// Adds the entries below `dir`, named relative to the root as `prefix`.
+    fn push_dir(&mut self, dir: &Path, prefix: &str) -> anyhow::Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .with_context(|| format!("failed to read directory `{}`", dir.display()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        // The order of `read_dir` depends on the file system.
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            let metadata = fs::symlink_metadata(&path)
+                .with_context(|| format!("failed to read `{}`", path.display()))?;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |time| time.as_secs());
+            let permissions = permissions(&metadata);
+
+            if metadata.is_symlink() {
+                let target = fs::read_link(&path)?;
+                let target = target.to_string_lossy();
+                self.push(&name, MODE_SYMLINK | 0o777, mtime, target.as_bytes());
+            } else if metadata.is_dir() {
+                self.push(&name, MODE_DIR | permissions, mtime, &[]);
+                self.push_dir(&path, &format!("{}/", name))?;
+            } else {
+                let contents = fs::read(&path)
+                    .with_context(|| format!("failed to read `{}`", path.display()))?;
+                self.push(&name, MODE_FILE | permissions, mtime, &contents);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    if metadata.is_dir() { 0o755 } else { 0o644 }
+}
+
+/// Packs `dir` into `initramfs.cpio` in `binary_dir`, returning its path.
+pub fn pack(dir: &Path, binary_dir: &Path, reproducible: bool) -> anyhow::Result<PathBuf> {
+    if !dir.is_dir() {
+        anyhow::bail!("initramfs `{}` is not a directory", dir.display());
+    }
+    let mut archive = Archive {
+        data: vec![],
+        ino: 0,
+        reproducible,
+    };
+    archive.push_dir(dir, "")?;
+    archive.push("TRAILER!!!", 0, 0, &[]);
+
+    let path = binary_dir.join("initramfs.cpio");
+    write_if_changed(&path, &archive.data)?;
+    Ok(path)
+}
+
+/// Writes `initramfs.rs` next to `archive`, which embeds it into the
+/// `.initramfs` section, returning its path.
+pub fn write_section(archive: &Path) -> anyhow::Result<PathBuf> {
+    let len = fs::metadata(archive)
+        .with_context(|| format!("failed to read `{}`", archive.display()))?
+        .len();
+    let stub = format!(
+        "// Generated by cargo-arceos for `--initramfs`.\n\
+         #[unsafe(link_section = \".initramfs\")]\n\
+         #[used]\n\
+         pub static INITRAMFS: [u8; {}] = *include_bytes!({:?});\n",
+        len, archive
+    );
+    let path = archive.with_file_name("initramfs.rs");
+    write_if_changed(&path, stub.as_bytes())?;
+    Ok(path)
+}
+
+fn write_if_changed(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    if fs::read(path).ok().is_none_or(|old| old != data) {
+        crate::write_atomic(path, data)
+            .with_context(|| format!("failed to write initramfs `{}`", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Archive {
+        Archive {
+            data: vec![],
+            ino: 0,
+            reproducible: true,
+        }
+    }
+
+    /// Returns the `index`th field of the header at `data`.
+    fn field(data: &[u8], index: usize) -> u32 {
+        let start = 6 + index * 8;
+        u32::from_str_radix(std::str::from_utf8(&data[start..start + 8]).unwrap(), 16).unwrap()
+    }
+
+    #[test]
+    fn newc_header() {
+        let mut archive = archive();
+        archive.push("hello", MODE_FILE | 0o644, 1234, b"hi!");
+        let data = &archive.data;
+        assert_eq!(&data[..6], b"070701");
+        assert!(data[6..110].iter().all(u8::is_ascii_hexdigit));
+        assert_eq!(field(data, 0), 1); // ino
+        assert_eq!(field(data, 1), 0o100644); // mode
+        assert_eq!(field(data, 4), 1); // nlink
+        assert_eq!(field(data, 5), 0); // mtime, left out
+        assert_eq!(field(data, 6), 3); // filesize
+        assert_eq!(field(data, 11), 6); // namesize, with the NUL
+        // The name ends at 116, which is aligned, and the contents are
+        // padded to 120.
+        assert_eq!(&data[110..116], b"hello\0");
+        assert_eq!(&data[116..120], b"hi!\0");
+        assert_eq!(data.len(), 120);
+    }
+
+    #[test]
+    fn newc_padding() {
+        let mut archive = archive();
+        archive.push("ab", MODE_DIR | 0o755, 0, &[]);
+        // The header and the name with its NUL take 113 bytes.
+        assert_eq!(archive.data.len(), 116);
+        assert_eq!(field(&archive.data, 4), 2);
+        archive.push("ab/cd", MODE_FILE, 0, b"12345");
+        assert_eq!(archive.data.len(), 116 + 116 + 8);
+        assert!(archive.data.len().is_multiple_of(4));
+    }
+
+    #[test]
+    fn newc_trailer() {
+        let mut archive = archive();
+        archive.push("TRAILER!!!", 0, 0, &[]);
+        let data = &archive.data;
+        assert_eq!(field(data, 1), 0);
+        assert_eq!(field(data, 11), 11);
+        assert_eq!(&data[110..121], b"TRAILER!!!\0");
+        assert_eq!(data.len(), 124);
+    }
+}
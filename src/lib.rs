@@ -1,13 +1,78 @@
+// https://github.com/clap-rs/clap/discussions/4264
+macro_rules! enum_variants {
+    ($e:ty) => {
+        clap::builder::PossibleValuesParser::new(<$e>::VARIANTS).map(|s| s.parse::<$e>().unwrap())
+    };
+}
+
+mod artifacts;
+mod bloat;
+mod cache;
+mod capp;
+mod cargo_config;
+mod checksum;
 mod commands;
+mod compare;
+mod container;
+mod crash;
+mod debugger;
+mod demux;
+mod diagnostics;
+pub mod envvars;
+pub mod events;
+mod exit;
+mod features;
+mod firmware;
+mod harness;
+mod ide;
+mod image;
+mod init;
+mod initramfs;
+mod library;
+mod logfile;
+mod manifest;
+mod matrix;
+mod memmap;
+mod metadata;
+mod metrics;
 mod options;
+mod packages;
 mod platforms;
+mod qemu;
+mod remote;
+pub mod rustc_wrapper;
+mod schema;
+mod scratch;
+mod sdcard;
+mod serial;
+mod signal;
+mod signing;
+mod size;
+mod stats;
+mod symbolize;
+mod timings;
+mod transfer;
+mod unification;
+mod vfio;
+mod watch;
 
-use std::io::BufReader;
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::Instant,
+};
 
-use anyhow::bail;
+use anyhow::{Context, bail};
 use clap::Parser;
 use console::style;
 
+pub use events::{Event, EventHandler};
+
 #[derive(Debug, Parser)]
 #[command(
     version,
@@ -26,41 +91,308 @@ pub enum Cli {
     Rustc(commands::Rustc),
     Check(commands::Check),
     Clippy(commands::Clippy),
+    #[command(alias = "d")]
+    Doc(commands::Doc),
     #[command(alias = "r")]
-    Run(commands::Run),
+    Run(Box<commands::Run>),
+    /// Run the kernel, and rebuild and rerun it when its sources change
+    Watch(Box<commands::Watch>),
+    /// Run the kernel and collect the results of the tests that it reports
+    /// on the console, see `--guest-code`
+    Test(Box<commands::Test>),
+    /// Write the options that the project runs with by default into its
+    /// manifest, see `--interactive`
+    Init(commands::Init),
+    /// Point cargo and rust-analyzer at the chosen platform
+    IdeSetup(commands::IdeSetup),
+    /// List the environment variables that are recognized or exported
+    Env(commands::Env),
+    /// Attach a debugger to a kernel started with `run --debug`
+    Attach(commands::Attach),
+    /// Resolve the addresses in a panic or backtrace of the guest
+    Symbolize(commands::Symbolize),
+    /// Print the memory map of the platform with the built kernel in it
+    Memmap(commands::Memmap),
+    /// Print the sizes of the built kernel and of the crates in it, or how
+    /// they changed since a baseline
+    Size(commands::Size),
+    /// List the functions or crates that take the most space in the built
+    /// kernel
+    Bloat(commands::Bloat),
+    /// Show how long the recorded builds took and how large their kernels
+    /// are, and how that changed over time
+    Stats(commands::Stats),
+    /// Make a bootable SD card image of the built kernel
+    Image(commands::Image),
+    /// Inspect the config that the kernel is built with
+    Config(commands::Config),
+    /// Manage the firmware that platforms boot with
+    Firmware(commands::Firmware),
+    /// Copy a file into a running guest that serves files, see `--guest-code`
+    Push(commands::Push),
+    /// Copy a file out of a running guest that serves files
+    Pull(commands::Pull),
+    /// Describe the warning or error with the given code
+    Explain(commands::Explain),
     #[command(hide = true)]
     Runner(commands::Runner),
 }
 
 impl Cli {
     pub fn run(self) {
-        if let Err(e) = self.execute() {
-            eprintln!("{}: {}", style("error").for_stderr().red().bold(), e);
+        // The runner leaves the summary to the process that started the build.
+        let summarize = !matches!(self, Cli::Runner(_));
+        let result = self.execute(&mut events::Terminal);
+        options::release_config_locks();
+        match result {
+            Ok(code) => {
+                let denied = summarize_warnings(summarize);
+                std::process::exit(if denied && code == 0 { 101 } else { code })
+            }
+            Err(e) => {
+                let header = match e
+                    .chain()
+                    .find_map(|e| e.downcast_ref::<diagnostics::Diagnostic>())
+                {
+                    Some(diagnostic) => format!("error[{}]", diagnostic.code),
+                    None => "error".to_string(),
+                };
+                print_error(header, e);
+                summarize_warnings(summarize);
+                std::process::exit(101);
+            }
         }
     }
 
-    fn execute(self) -> anyhow::Result<()> {
-        let (mut command, arceos) = match self {
-            Cli::Build(mut command) => (command.build()?, command.arceos),
-            Cli::Rustc(mut command) => (command.build()?, command.arceos),
-            Cli::Check(mut command) => (command.build()?, command.arceos),
-            Cli::Clippy(mut command) => (command.build()?, command.arceos),
-            Cli::Run(mut command) => (command.build()?, command.arceos),
+    /// Executes the command, reporting what happens to `handler` instead of
+    /// the terminal. Returns the exit code of the command.
+    pub fn execute(self, handler: &mut dyn EventHandler) -> anyhow::Result<i32> {
+        if env::var_os(QUIET_ENV).is_some() {
+            QUIET.store(true, Ordering::Relaxed);
+        }
+        if let Some(level) = env::var(VERBOSE_ENV)
+            .ok()
+            .and_then(|level| level.parse().ok())
+        {
+            VERBOSE.store(level, Ordering::Relaxed);
+        }
+        if let Ok(when) = env::var(COLOR_ENV) {
+            set_color(Some(&when), None);
+        }
+        if env::var_os(OFFLINE_ENV).is_some() {
+            OFFLINE.store(true, Ordering::Relaxed);
+        }
+        diagnostics::levels_from_env();
+        logfile::open_from_env();
+
+        let commands::Invocation {
+            mut command,
+            features,
+            c_app,
+            cache,
+            artifacts,
+            signing,
+            container,
+            runner,
+            list_symbols,
+            members,
+            stats,
+        } = match self {
+            Cli::Build(mut command) => command.build()?,
+            Cli::Rustc(mut command) => command.build()?,
+            Cli::Check(mut command) => match command.build_all_platforms()? {
+                Some(builds) => return matrix::run(builds),
+                None => command.build()?,
+            },
+            Cli::Clippy(mut command) => match command.build_all_platforms()? {
+                Some(builds) => return matrix::run(builds),
+                None => command.build()?,
+            },
+            Cli::Doc(mut command) => command.build()?,
+            Cli::Run(mut command) => match command.compare(handler) {
+                Some(code) => return code,
+                None => command.build(handler.interactive())?,
+            },
+            Cli::Init(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::IdeSetup(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Env(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Symbolize(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Push(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Pull(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Explain(command) => {
+                command.execute();
+                return Ok(0);
+            }
+            Cli::Memmap(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Size(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Bloat(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Stats(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Image(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Config(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Firmware(command) => {
+                command.execute()?;
+                return Ok(0);
+            }
+            Cli::Attach(command) => {
+                return command.execute();
+            }
+            Cli::Watch(command) => {
+                return command.execute(handler);
+            }
+            Cli::Test(command) => {
+                return command.execute(handler);
+            }
             Cli::Runner(command) => {
                 return command.execute();
             }
         };
 
-        let mut child = command.spawn().expect("failed to execute cargo");
+        let envs: Vec<(OsString, OsString)> = command
+            .get_envs()
+            .filter_map(|(key, value)| Some((key.to_os_string(), value?.to_os_string())))
+            .collect();
+        if let Some(container) = &container {
+            command = container.wrap(&command)?;
+            command.stdout(Stdio::piped());
+        }
+        handler.handle(Event::BuildStarted {
+            command: command_line(&command),
+        });
+        for (key, value) in &envs {
+            verbose(
+                "Env",
+                format!("{}={}", key.to_string_lossy(), value.to_string_lossy()),
+            );
+        }
+        if logfile::is_open() {
+            // Cargo only uses colors on a terminal, so keep them when its
+            // output goes through the log file.
+            if console::colors_enabled_stderr() && env::var_os("CARGO_TERM_COLOR").is_none() {
+                command.env("CARGO_TERM_COLOR", "always");
+            }
+            command.stderr(Stdio::piped());
+        }
+        let started = Instant::now();
+        let mut child = command.spawn().with_context(|| {
+            format!(
+                "failed to run `{}`",
+                command.get_program().to_string_lossy()
+            )
+        })?;
+        watch::set_running(Some(child.id()));
+        let stderr = child.stderr.take().map(|stderr| {
+            std::thread::spawn(move || {
+                let mut stderr = BufReader::new(stderr);
+                let mut line = String::new();
+                while let Ok(1..) = stderr.read_line(&mut line) {
+                    eprint!("{}", line);
+                    logfile::message(&line);
+                    line.clear();
+                }
+            })
+        });
 
+        let mut executables = vec![];
+        let mut libraries = vec![];
+        let mut summary = packages::Summary::new(&members);
         if let Some(stdout) = child.stdout.take().map(BufReader::new) {
-            for message in cargo_metadata::Message::parse_stream(stdout).flatten() {
+            let forward = FORWARD_MESSAGES.load(Ordering::Relaxed);
+            for line in stdout.lines().map_while(Result::ok) {
+                let message = match serde_json::from_str(&line) {
+                    Ok(message) => {
+                        // The JSON messages were asked for with `--message-format`.
+                        if forward {
+                            println!("{}", line);
+                        }
+                        message
+                    }
+                    Err(_) => cargo_metadata::Message::TextLine(line),
+                };
                 match message {
                     cargo_metadata::Message::TextLine(line) => {
-                        eprintln!("{}", line);
+                        handler.handle(events::parse_line(line));
+                    }
+                    cargo_metadata::Message::CompilerMessage(message) => {
+                        summary.message(&message.package_id.repr, &message.message.level);
+                        handler.handle(Event::CompilerMessage {
+                            package_id: message.package_id.repr,
+                            level: message.message.level,
+                            rendered: message.message.rendered,
+                        });
                     }
                     cargo_metadata::Message::CompilerArtifact(artifact) => {
-                        arceos.check_features(&artifact.target.name, &artifact.features);
+                        let kind = events::ArtifactKind::of(&artifact);
+                        // Features are required of the crates of ArceOS, while
+                        // the other targets of a package share its features.
+                        let missing = if kind == events::ArtifactKind::Lib {
+                            features.check(&artifact.target.name, &artifact.features)
+                        } else {
+                            0
+                        };
+                        if kind != events::ArtifactKind::BuildScript {
+                            summary.artifact(&artifact.package_id.repr, missing);
+                        }
+                        if let Some(executable) = &artifact.executable {
+                            executables.push(executable.clone().into_std_path_buf());
+                        }
+                        if list_symbols {
+                            libraries.extend(
+                                artifact
+                                    .filenames
+                                    .iter()
+                                    .map(|file| file.as_std_path())
+                                    .filter(|file| library::is_static(file))
+                                    .map(Path::to_path_buf),
+                            );
+                        }
+                        handler.handle(Event::ArtifactReady {
+                            package_id: artifact.package_id.repr,
+                            target: artifact.target.name,
+                            kind,
+                            executable: artifact.executable.map(Into::into),
+                        });
+                    }
+                    cargo_metadata::Message::BuildScriptExecuted(script) => {
+                        handler.handle(Event::BuildScriptExecuted {
+                            package_id: script.package_id.repr,
+                            out_dir: script.out_dir.into(),
+                        });
                     }
                     _ => {}
                 }
@@ -68,40 +400,321 @@ impl Cli {
         }
 
         let status = child.wait().expect("could not get cargo's exit status");
-        std::process::exit(status.code().unwrap_or(101));
+        watch::set_running(None);
+        if let Some(stderr) = stderr {
+            stderr.join().ok();
+        }
+        summary.print();
+        if status.success()
+            && let Some(c_app) = c_app
+        {
+            let elf = c_app.build()?;
+            executables.push(elf.clone());
+            handler.handle(Event::ArtifactReady {
+                package_id: c_app.package_id().to_string(),
+                target: c_app.name().to_string(),
+                kind: events::ArtifactKind::Bin,
+                executable: Some(elf),
+            });
+        }
+
+        if let Some(cache) = cache {
+            cache.report();
+        }
+
+        if status.success()
+            && let Some(stats) = stats
+        {
+            stats.record("build", started.elapsed(), &executables);
+        }
+
+        if status.success() {
+            for library in &libraries {
+                library::write_symbols(library)?;
+            }
+        }
+
+        let collected = artifacts.is_some();
+        if status.success()
+            && let Some(artifacts) = artifacts
+        {
+            executables = artifacts.collect(&executables)?;
+        }
+        if status.success()
+            && let Some(signing) = signing
+        {
+            signing.apply(&executables, collected)?;
+        }
+
+        let mut code = status.code().unwrap_or(101);
+        if status.success()
+            && let Some(runner) = runner
+        {
+            code = run_on_host(runner, &envs, &executables, handler)?;
+        }
+        handler.handle(Event::Exited { code });
+        Ok(code)
+    }
+}
+
+/// Runs the only executable of `executables` with `runner`, in the
+/// environment `envs` of the cargo command that built it, and returns the
+/// exit code.
+fn run_on_host(
+    mut runner: Command,
+    envs: &[(OsString, OsString)],
+    executables: &[PathBuf],
+    handler: &mut dyn EventHandler,
+) -> anyhow::Result<i32> {
+    let executable = match executables {
+        [executable] => executable,
+        [] => bail!("no executable was built to run"),
+        _ => bail!("several executables were built, pick one with `--bin` or `--example`"),
+    };
+    runner.envs(envs.iter().map(|(key, value)| (key, value)));
+    runner.arg(executable);
+    // Like when cargo runs it, the runner reports its events on stdout.
+    if !handler.interactive() {
+        runner.stdout(Stdio::piped());
+    }
+    let mut child = runner
+        .spawn()
+        .with_context(|| format!("failed to run `{}`", runner.get_program().to_string_lossy()))?;
+    watch::set_running(Some(child.id()));
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            handler.handle(events::parse_line(line));
+        }
+    }
+    let status = child
+        .wait()
+        .context("could not get the runner's exit status")?;
+    watch::set_running(None);
+    Ok(status.code().unwrap_or(101))
+}
+
+/// Tells the runner to stay quiet as well.
+const QUIET_ENV: &str = "CARGO_ARCEOS_QUIET";
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Suppresses status messages, here and in the runner spawned by `command`.
+fn set_quiet(command: &mut Command) {
+    QUIET.store(true, Ordering::Relaxed);
+    command.env(QUIET_ENV, "1");
+}
+
+/// Tells the runner how verbose to be.
+const VERBOSE_ENV: &str = "CARGO_ARCEOS_VERBOSE";
+
+static VERBOSE: AtomicU8 = AtomicU8::new(0);
+
+/// Prints the decisions that are made along the way with `-v`, here and in
+/// the runner spawned by `command`.
+fn set_verbose(level: u8, command: &mut Command) {
+    VERBOSE.store(level, Ordering::Relaxed);
+    command.env(VERBOSE_ENV, level.to_string());
+}
+
+/// Tells the runner not to access the network.
+const OFFLINE_ENV: &str = "CARGO_ARCEOS_OFFLINE";
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Keeps cargo-arceos off the network like cargo with `--offline` or
+/// `--frozen`, here and in the runner spawned by `command`.
+fn set_offline(command: &mut Command) {
+    OFFLINE.store(true, Ordering::Relaxed);
+    command.env(OFFLINE_ENV, "1");
+}
+
+/// Returns whether the network may not be used, with `--offline` or, like
+/// cargo, with `CARGO_NET_OFFLINE=true`.
+fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+        || env::var("CARGO_NET_OFFLINE").is_ok_and(|offline| offline == "true")
+}
+
+/// Fails if `what`, which cargo-arceos downloads itself, cannot use the
+/// network.
+fn require_network(what: &str) -> anyhow::Result<()> {
+    if OFFLINE.load(Ordering::Relaxed) {
+        bail!("{} requires network, but `--offline` given", what);
+    }
+    if is_offline() {
+        bail!("{} requires network, but `CARGO_NET_OFFLINE` is set", what);
+    }
+    Ok(())
+}
+
+/// Prefix of the environment variables that hold the arguments of the runner
+/// that are not UTF-8, which are replaced by `$<VAR>` in its command line.
+const RUNNER_ARG_ENV_PREFIX: &str = "CARGO_ARCEOS_RUNNER_ARG_";
+
+/// Returns the arguments of the process, with the ones that the runner got
+/// through the environment put back in place.
+pub fn args_os() -> impl Iterator<Item = std::ffi::OsString> {
+    env::args_os().map(|arg| {
+        arg.to_str()
+            .and_then(|arg| arg.strip_prefix('$'))
+            .filter(|var| var.starts_with(RUNNER_ARG_ENV_PREFIX))
+            .and_then(env::var_os)
+            .unwrap_or(arg)
+    })
+}
+
+/// Tells the runner when to use colors.
+const COLOR_ENV: &str = "CARGO_ARCEOS_COLOR";
+
+/// Uses colors `when` is `always`, or not when it is `never`, here and in
+/// the runner spawned by `command`. Without `when`, `CARGO_TERM_COLOR` is
+/// honored like cargo does, and colors are otherwise only used on terminals.
+fn set_color(when: Option<&str>, command: Option<&mut Command>) {
+    let when = when
+        .map(str::to_string)
+        .or_else(|| env::var("CARGO_TERM_COLOR").ok());
+    let enabled = match when.as_deref() {
+        Some("always") => true,
+        Some("never") => false,
+        // Cargo rejects other values itself.
+        _ => return,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+    if let Some(command) = command {
+        command.env(COLOR_ENV, when.unwrap());
     }
 }
 
+/// Whether cargo's JSON messages are printed to stdout as well, because they
+/// were asked for.
+static FORWARD_MESSAGES: AtomicBool = AtomicBool::new(false);
+
+/// Makes cargo print JSON messages to a pipe, so that they can be processed,
+/// while keeping the output that `formats` from `--message-format` ask for.
+///
+/// Diagnostics are rendered from the messages unless JSON is asked for, in
+/// which case the messages are forwarded as they are.
+fn pipe_messages(formats: &[String], command: &mut Command) {
+    let formats: Vec<&str> = formats
+        .iter()
+        .flat_map(|format| format.split(','))
+        .map(str::trim)
+        .collect();
+
+    let format = if formats.iter().any(|format| format.starts_with("json")) {
+        FORWARD_MESSAGES.store(true, Ordering::Relaxed);
+        formats.join(",")
+    } else {
+        let mut format = vec![if formats.contains(&"short") {
+            "json-diagnostic-short"
+        } else {
+            "json"
+        }];
+        if console::colors_enabled_stderr() {
+            format.push("json-diagnostic-rendered-ansi");
+        }
+        format.join(",")
+    };
+    command
+        .arg(format!("--message-format={}", format))
+        .stdout(Stdio::piped());
+}
+
+/// Whether diagnostics have to be rendered from cargo's messages.
+fn renders_diagnostics() -> bool {
+    !FORWARD_MESSAGES.load(Ordering::Relaxed)
+}
+
 fn info(name: &str, msg: impl std::fmt::Display) {
-    eprintln!("{:>12} {}", style(name).for_stderr().green().bold(), msg);
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    let line = format!("{:>12} {}", style(name).for_stderr().green().bold(), msg);
+    eprintln!("{}", line);
+    logfile::message(&(line + "\n"));
+}
+
+/// Prints a status message only with `-v`.
+fn verbose(name: &str, msg: impl std::fmt::Display) {
+    if VERBOSE.load(Ordering::Relaxed) > 0 {
+        info(name, msg);
+    }
 }
 
 fn warn(msg: impl std::fmt::Display) {
-    eprintln!(
-        "{}",
-        style(format!(
-            "{}: {}",
-            style("warning").for_stderr().yellow(),
-            msg
-        ))
+    print_warning("warning".to_string(), msg);
+}
+
+/// Prints a warning with `code`, unless it is allowed, or an error if it is
+/// denied.
+fn warn_code(code: diagnostics::Code, msg: impl std::fmt::Display) {
+    match diagnostics::level(code) {
+        diagnostics::Level::Allow => return,
+        diagnostics::Level::Warn => print_warning(format!("warning[{}]", code), msg),
+        diagnostics::Level::Deny => print_error(format!("error[{}]", code), msg),
+    }
+    diagnostics::reported(code);
+}
+
+/// Prints how many warnings with codes were reported, if `print`, and
+/// returns whether any of them were denied.
+fn summarize_warnings(print: bool) -> bool {
+    let (counts, denied) = diagnostics::summary();
+    if print && !counts.is_empty() {
+        let total: usize = counts.iter().map(|(_, count)| count).sum();
+        let codes: Vec<String> = counts
+            .iter()
+            .map(|(code, count)| format!("{} ({})", code, count))
+            .collect();
+        let msg = format!(
+            "{} {}: {}, see `cargo arceos explain <CODE>`",
+            total,
+            if total == 1 {
+                "diagnostic"
+            } else {
+                "diagnostics"
+            },
+            codes.join(", ")
+        );
+        if denied {
+            print_error("error".to_string(), format!("denied {}", msg));
+        } else {
+            print_warning("warning".to_string(), msg);
+        }
+    }
+    denied
+}
+
+fn print_warning(header: String, msg: impl std::fmt::Display) {
+    let line = style(format!("{}: {}", style(header).for_stderr().yellow(), msg))
         .for_stderr()
         .bold()
-    );
-}
-
-fn run_command(command: &mut std::process::Command) -> anyhow::Result<()> {
-    info(
-        "Running",
-        format!(
-            "`{} {}`",
-            command.get_program().to_string_lossy(),
-            command
-                .get_args()
-                .map(|arg| arg.to_string_lossy())
-                .collect::<Vec<_>>()
-                .join(" ")
-        ),
-    );
+        .to_string();
+    eprintln!("{}", line);
+    logfile::message(&(line + "\n"));
+}
+
+fn print_error(header: String, msg: impl std::fmt::Display) {
+    let line = format!("{}: {}", style(header).for_stderr().red().bold(), msg);
+    eprintln!("{}", line);
+    logfile::message(&(line + "\n"));
+}
+
+fn command_line(command: &Command) -> String {
+    format!(
+        "{} {}",
+        command.get_program().to_string_lossy(),
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    )
+}
+
+fn run_command(command: &mut Command) -> anyhow::Result<()> {
+    info("Running", format!("`{}`", command_line(command)));
 
     let status = command.status()?;
     if !status.success() {
@@ -109,3 +722,123 @@ fn run_command(command: &mut std::process::Command) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+/// Looks for `program` in `PATH`.
+fn find_program(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Returns a path next to `path` for writing it before it is renamed into
+/// place.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".tmp{}", std::process::id()));
+    temp.into()
+}
+
+/// Writes `contents` to `path` through a temporary file that is renamed over
+/// it, so that `path` never holds partial contents, even after a crash.
+fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let temp = temp_path(path);
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+/// Returns the directory for files shared across projects, following the XDG
+/// base directory specification.
+fn cache_dir() -> anyhow::Result<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("cargo-arceos"))
+        .context("failed to determine the cache directory")
+}
+
+/// Splits a command line into arguments the way a POSIX shell would, honoring
+/// single quotes, double quotes and backslash escapes.
+fn split_args(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_default();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => arg.get_or_insert_default().push(c),
+            (_, '\\') => {
+                let c = chars
+                    .next()
+                    .with_context(|| format!("trailing backslash in `{}`", line))?;
+                match (quote, c) {
+                    // A backslash before a newline continues the line.
+                    (_, '\n') => {}
+                    // In double quotes, it only escapes what is special there.
+                    (Some(_), '$' | '`' | '"' | '\\') | (None, _) => {
+                        arg.get_or_insert_default().push(c)
+                    }
+                    (Some(_), c) => arg.get_or_insert_default().extend(['\\', c]),
+                }
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (_, c) => arg.get_or_insert_default().push(c),
+        }
+    }
+
+    if quote.is_some() {
+        bail!("unterminated quote in `{}`", line);
+    }
+    args.extend(arg);
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_args;
+
+    #[test]
+    fn split_args_quotes() {
+        assert_eq!(
+            split_args(r#"sudo -E 'a b' "c d" e\ f"#).unwrap(),
+            ["sudo", "-E", "a b", "c d", "e f"]
+        );
+        assert_eq!(split_args(r#"'it'"'"'s'"#).unwrap(), ["it's"]);
+    }
+
+    #[test]
+    fn split_args_empty() {
+        assert_eq!(split_args(r#"a '' "" b"#).unwrap(), ["a", "", "", "b"]);
+        assert!(split_args("  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_args_escapes() {
+        assert_eq!(split_args(r#""a\b""#).unwrap(), [r"a\b"]);
+        assert_eq!(split_args(r#""\$ \` \" \\""#).unwrap(), [r#"$ ` " \"#]);
+        assert_eq!(split_args(r"'a\b' a\b").unwrap(), [r"a\b", "ab"]);
+        assert_eq!(split_args("a \\\n b").unwrap(), ["a", "b"]);
+    }
+
+    #[test]
+    fn split_args_errors() {
+        assert!(split_args("'a").is_err());
+        assert!(split_args(r#""a"#).is_err());
+        assert!(split_args("a\\").is_err());
+    }
+}
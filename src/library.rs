@@ -0,0 +1,135 @@
+//! Building the app as a library with `cargo arceos build --crate-type`, for
+//! embedding it into a larger firmware image or hypervisor guest bundle
+//! instead of booting it as a kernel.
+//!
+//! The library is built with `cargo rustc`, which is the only way to choose
+//! the crate type from the command line, and without the linker script of the
+//! platform, as whatever links the library places it. Next to a static
+//! library, a header lists the symbols that it exports.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, bail};
+use strum::{AsRefStr, EnumString, VariantNames};
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum CrateType {
+    Staticlib,
+    Rlib,
+}
+
+/// Returns the `cargo rustc` that builds the library of the package as
+/// `crate_type`, in place of the `cargo build` that is `command`.
+pub fn rustc_command(command: &Command, crate_type: CrateType) -> Command {
+    let mut rustc = Command::new(command.get_program());
+    rustc.arg("rustc").args(command.get_args().skip(1)).args([
+        "--lib",
+        "--crate-type",
+        crate_type.as_ref(),
+    ]);
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => rustc.env(key, value),
+            None => rustc.env_remove(key),
+        };
+    }
+    if let Some(dir) = command.get_current_dir() {
+        rustc.current_dir(dir);
+    }
+    // Like every build, the messages of cargo go to a pipe.
+    rustc.stdout(Stdio::piped());
+    rustc
+}
+
+/// Returns the global symbols defined in the static library `archive`,
+/// leaving out the mangled ones of Rust, as `(name, is_function)`.
+fn exported_symbols(archive: &Path) -> anyhow::Result<BTreeSet<(String, bool)>> {
+    let program = ["llvm-nm", "nm"]
+        .into_iter()
+        .find(|program| crate::find_program(program).is_some())
+        .context("nm is not found in PATH")?;
+
+    let output = Command::new(program)
+        .args(["--defined-only", "--extern-only"])
+        .arg(archive)
+        .output()
+        .with_context(|| format!("failed to run `{}`", program))?;
+    if !output.status.success() {
+        bail!(
+            "`{}` failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // Each member starts with `member.o:`, followed by its symbols as
+    // `address type name`.
+    let mut symbols = BTreeSet::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(_), Some(kind), Some(name), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let function = match kind {
+            "T" | "W" => true,
+            "D" | "B" | "R" | "G" | "S" | "V" => false,
+            _ => continue,
+        };
+        if name.starts_with("_ZN")
+            || name.starts_with("_R")
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            continue;
+        }
+        symbols.insert((name.to_string(), function));
+    }
+    Ok(symbols)
+}
+
+/// Writes the header that lists the symbols of the static library
+/// `archive`, next to it, and returns its path.
+pub fn write_symbols(archive: &Path) -> anyhow::Result<PathBuf> {
+    let symbols = exported_symbols(archive)?;
+    let name = archive.file_name().unwrap().to_string_lossy();
+    let stem = archive.file_stem().unwrap().to_string_lossy();
+    let path = archive.with_file_name(format!("{}.symbols.h", stem));
+    let guard = format!("{}_SYMBOLS_H", stem.to_uppercase().replace('-', "_"));
+
+    let mut header = format!(
+        "/* The symbols that `{}` exports, listed by cargo-arceos. Only\n * their names are known, so the declarations use placeholder types. */\n\n#ifndef {}\n#define {}\n\n",
+        name, guard, guard
+    );
+    for (symbol, function) in &symbols {
+        if *function {
+            header.push_str(&format!("extern void {}(void);\n", symbol));
+        } else {
+            header.push_str(&format!("extern char {}[];\n", symbol));
+        }
+    }
+    header.push_str(&format!("\n#endif /* {} */\n", guard));
+
+    crate::write_atomic(&path, header)
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+    crate::info(
+        "Listed",
+        format!(
+            "{} symbols of `{}` in `{}`",
+            symbols.len(),
+            name,
+            path.display()
+        ),
+    );
+    Ok(path)
+}
+
+/// Returns whether `path` is a static library.
+pub fn is_static(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "a")
+}
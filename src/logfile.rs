@@ -0,0 +1,280 @@
+//! A copy of everything printed during a command, for archiving CI logs.
+//!
+//! The runner is spawned by cargo with its stderr going through cargo-arceos,
+//! which copies it already, so the runner only copies the guest console.
+
+use std::{
+    env, fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{self, Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+/// Tells the runner to copy the guest console to the log file.
+const LOG_FILE_ENV: &str = "CARGO_ARCEOS_LOG_FILE";
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+/// Where to copy the output and how, like `build.log,strip-ansi,timestamps`.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    path: PathBuf,
+    strip_ansi: bool,
+    timestamps: bool,
+}
+
+impl LogFile {
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl FromStr for LogFile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut path = s;
+        let mut strip_ansi = false;
+        let mut timestamps = false;
+        // Paths may contain commas themselves, so only known flags are taken
+        // off the end.
+        while let Some((rest, flag)) = path.rsplit_once(',') {
+            match flag {
+                "strip-ansi" => strip_ansi = true,
+                "timestamps" => timestamps = true,
+                _ => break,
+            }
+            path = rest;
+        }
+        if path.is_empty() {
+            anyhow::bail!("no path in `{}`", s);
+        }
+        Ok(LogFile {
+            path: path.into(),
+            strip_ansi,
+            timestamps,
+        })
+    }
+}
+
+impl fmt::Display for LogFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if self.strip_ansi {
+            write!(f, ",strip-ansi")?;
+        }
+        if self.timestamps {
+            write!(f, ",timestamps")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where an escape sequence being stripped is at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+    Osc,
+    OscEsc,
+}
+
+struct Log {
+    file: File,
+    options: LogFile,
+    /// Whether this is the runner, which leaves messages to its parent.
+    runner: bool,
+    line_start: bool,
+    escape: Escape,
+}
+
+impl Log {
+    fn write(&mut self, data: &[u8]) {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            if self.options.strip_ansi && !self.strip(byte) {
+                continue;
+            }
+            if self.line_start && self.options.timestamps {
+                out.extend(timestamp().as_bytes());
+            }
+            out.push(byte);
+            self.line_start = byte == b'\n';
+        }
+        // The log is a best effort, and failing to write it must not fail the
+        // command.
+        self.file.write_all(&out).ok();
+    }
+
+    /// Advances the escape sequence state, returning whether `byte` is kept.
+    fn strip(&mut self, byte: u8) -> bool {
+        self.escape = match (self.escape, byte) {
+            (Escape::None, 0x1b) => Escape::Esc,
+            (Escape::None, _) => return true,
+            (Escape::Esc, b'[') => Escape::Csi,
+            (Escape::Esc, b']') => Escape::Osc,
+            (Escape::Esc, _) => Escape::None,
+            // A CSI sequence ends with a byte in `@`..=`~`.
+            (Escape::Csi, 0x40..=0x7e) => Escape::None,
+            (Escape::Csi, _) => Escape::Csi,
+            // An OSC sequence ends with BEL or `ESC \`.
+            (Escape::Osc, 0x07) => Escape::None,
+            (Escape::Osc, 0x1b) => Escape::OscEsc,
+            (Escape::Osc, _) => Escape::Osc,
+            (Escape::OscEsc, _) => Escape::None,
+        };
+        false
+    }
+}
+
+/// Returns the time of day in UTC, like `[12:34:56.789] `.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs() % 86400;
+    format!(
+        "[{:02}:{:02}:{:02}.{:03}] ",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Starts copying the output to the log file, truncating it, and makes the
+/// runner spawned by `command` copy the guest console to it.
+pub fn open(options: &LogFile, command: &mut Command) -> anyhow::Result<()> {
+    let options = LogFile {
+        path: path::absolute(&options.path)?,
+        ..options.clone()
+    };
+    // The runner writes to the file as well, so both have to append to it.
+    let file = File::create(&options.path)
+        .and_then(|_| OpenOptions::new().append(true).open(&options.path))
+        .with_context(|| format!("failed to create log file `{}`", options.path.display()))?;
+    command.env(LOG_FILE_ENV, options.to_string());
+    *LOG.lock().unwrap() = Some(Log {
+        file,
+        options,
+        runner: false,
+        line_start: true,
+        escape: Escape::None,
+    });
+    Ok(())
+}
+
+/// Continues the log file in the runner, if one was opened.
+pub fn open_from_env() {
+    let Some(options) = env::var(LOG_FILE_ENV)
+        .ok()
+        .and_then(|spec| spec.parse::<LogFile>().ok())
+    else {
+        return;
+    };
+    let Ok(file) = OpenOptions::new().append(true).open(&options.path) else {
+        return;
+    };
+    *LOG.lock().unwrap() = Some(Log {
+        file,
+        options,
+        runner: true,
+        line_start: true,
+        escape: Escape::None,
+    });
+}
+
+/// Whether the output is being copied to a log file.
+pub fn is_open() -> bool {
+    LOG.lock().unwrap().is_some()
+}
+
+/// Copies a message printed by cargo-arceos or cargo.
+pub fn message(text: &str) {
+    if let Some(log) = LOG.lock().unwrap().as_mut()
+        && !log.runner
+    {
+        log.write(text.as_bytes());
+    }
+}
+
+/// Copies output of the guest console.
+pub fn console(data: &[u8]) {
+    if let Some(log) = LOG.lock().unwrap().as_mut() {
+        log.write(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn parse_flags() {
+        let log: LogFile = "build.log".parse().unwrap();
+        assert_eq!(log.path, Path::new("build.log"));
+        assert!(!log.strip_ansi && !log.timestamps);
+
+        let log: LogFile = "build.log,timestamps,strip-ansi".parse().unwrap();
+        assert_eq!(log.path, Path::new("build.log"));
+        assert!(log.strip_ansi && log.timestamps);
+        assert_eq!(log.to_string(), "build.log,strip-ansi,timestamps");
+    }
+
+    #[test]
+    fn parse_unknown_suffix() {
+        // Unknown suffixes are a part of the path, and so is anything before
+        // them.
+        let log: LogFile = "ci,run.log,color".parse().unwrap();
+        assert_eq!(log.path, Path::new("ci,run.log,color"));
+        assert!(!log.strip_ansi);
+        let log: LogFile = "ci,strip-ansi,x.log,timestamps".parse().unwrap();
+        assert_eq!(log.path, Path::new("ci,strip-ansi,x.log"));
+        assert!(!log.strip_ansi && log.timestamps);
+
+        assert!(",strip-ansi".parse::<LogFile>().is_err());
+        assert!("".parse::<LogFile>().is_err());
+    }
+
+    #[test]
+    fn strip_split_sequences() {
+        let path = env::temp_dir().join(format!("cargo-arceos-{}-strip.log", std::process::id()));
+        let mut log = Log {
+            file: File::create(&path).unwrap(),
+            options: LogFile {
+                path: path.clone(),
+                strip_ansi: true,
+                timestamps: false,
+            },
+            runner: false,
+            line_start: true,
+            escape: Escape::None,
+        };
+        // A color, a title ended by BEL, a hyperlink ended by `ESC \` and an
+        // escape that is not CSI or OSC, all split across writes
+        for data in [
+            &b"a\x1b["[..],
+            b"1;31",
+            b"mb\x1b]0;ti",
+            b"tle\x07c\x1b]8;;",
+            b"url\x1b",
+            b"\\d\x1b",
+            b"Ke\r\n",
+        ] {
+            log.write(data);
+        }
+        assert_eq!(log.escape, Escape::None);
+        assert_eq!(fs::read(&path).unwrap(), b"abcde\r\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
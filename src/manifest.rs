@@ -0,0 +1,46 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::exit::GuestExit;
+
+/// A record of a guest run, written next to the binary as `<binary>.run.json`
+/// for tools that need the outcome without parsing the console.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RunManifest {
+    pub binary: PathBuf,
+    pub platform: String,
+    /// One run per machine, in the order they were booted.
+    pub runs: Vec<MachineRun>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MachineRun {
+    pub machine: String,
+    pub exit: GuestExit,
+}
+
+impl RunManifest {
+    /// Returns the first unsuccessful exit among the runs, if any.
+    pub fn exit(&self) -> GuestExit {
+        self.runs
+            .iter()
+            .map(|run| run.exit)
+            .find(|exit| *exit != GuestExit::Success)
+            .unwrap_or(GuestExit::Success)
+    }
+
+    pub fn path(binary: &Path) -> PathBuf {
+        let mut path = binary.as_os_str().to_owned();
+        path.push(".run.json");
+        path.into()
+    }
+
+    pub fn write(&self) -> anyhow::Result<()> {
+        let path = Self::path(&self.binary);
+        crate::write_atomic(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("failed to write run manifest `{}`", path.display()))
+    }
+}
@@ -0,0 +1,184 @@
+//! Checking every platform at once with `cargo arceos check --all-platforms`
+//! and `cargo arceos clippy --all-platforms`, to catch code that only breaks
+//! under the `cfg` of some architecture or platform.
+//!
+//! Each platform is checked by a cargo of its own, all running at the same
+//! time, in a target directory of its own so that they neither wait for the
+//! lock of one directory nor rebuild each other's crates. The diagnostics
+//! that several platforms report alike are shown once, with the platforms
+//! that they occur on.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::Context;
+use clap::Args;
+use console::style;
+use strum::VariantNames;
+
+use crate::platforms::Platform;
+
+#[derive(Debug, Clone, Args)]
+pub struct PlatformsOptions {
+    /// Check every platform in parallel, reporting each diagnostic once with
+    /// the platforms it occurs on
+    #[arg(
+        long,
+        conflicts_with_all = ["arch", "platform"],
+        help_heading = "ArceOS Options"
+    )]
+    all_platforms: bool,
+}
+
+impl PlatformsOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.all_platforms
+    }
+}
+
+/// Returns the platforms that `--all-platforms` checks.
+pub fn platforms() -> impl Iterator<Item = Platform> {
+    Platform::VARIANTS.iter().map(|name| name.parse().unwrap())
+}
+
+/// Returns the target directory for `platform` under `target_dir`.
+pub fn target_dir(target_dir: &Path, platform: Platform) -> PathBuf {
+    target_dir.join("platforms").join(platform.as_ref())
+}
+
+/// What the cargo of a platform reported.
+struct Report {
+    /// The rendered diagnostics, in order.
+    diagnostics: Vec<String>,
+    /// Whether any of the diagnostics is an error.
+    errors: bool,
+    stderr: String,
+    success: bool,
+}
+
+fn check(mut command: Command) -> anyhow::Result<Report> {
+    command.stderr(Stdio::piped());
+    let mut child = command.spawn().context("failed to run `cargo`")?;
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = thread::spawn(move || {
+        let mut output = String::new();
+        stderr.read_to_string(&mut output).ok();
+        output
+    });
+
+    let mut diagnostics = vec![];
+    let mut errors = false;
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if let Ok(cargo_metadata::Message::CompilerMessage(message)) =
+                serde_json::from_str(&line)
+                && let Some(rendered) = message.message.rendered
+            {
+                errors |= matches!(
+                    message.message.level,
+                    cargo_metadata::diagnostic::DiagnosticLevel::Error
+                        | cargo_metadata::diagnostic::DiagnosticLevel::Ice
+                );
+                diagnostics.push(rendered);
+            }
+        }
+    }
+    let status = child.wait().context("could not get cargo's exit status")?;
+    Ok(Report {
+        diagnostics,
+        errors,
+        stderr: stderr.join().unwrap_or_default(),
+        success: status.success(),
+    })
+}
+
+/// Runs the cargo `builds` of each platform at once, prints the diagnostics
+/// that they report, and returns the exit code.
+pub fn run(builds: Vec<(Platform, Command)>) -> anyhow::Result<i32> {
+    let count = builds.len();
+    crate::info("Checking", format!("{} platforms in parallel", count));
+    let handles: Vec<_> = builds
+        .into_iter()
+        .map(|(platform, command)| (platform, thread::spawn(move || check(command))))
+        .collect();
+
+    // Each diagnostic with the platforms that report it, in the order that
+    // they are first reported.
+    let mut diagnostics: Vec<(String, Vec<Platform>)> = vec![];
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut failed = vec![];
+    for (platform, handle) in handles {
+        let report = handle.join().unwrap()?;
+        for rendered in report.diagnostics {
+            match seen.get(&rendered) {
+                Some(&i) => diagnostics[i].1.push(platform),
+                None => {
+                    seen.insert(rendered.clone(), diagnostics.len());
+                    diagnostics.push((rendered, vec![platform]));
+                }
+            }
+        }
+        if !report.success {
+            failed.push((platform, (!report.errors).then_some(report.stderr)));
+        }
+    }
+
+    for (rendered, platforms) in &diagnostics {
+        let on = if platforms.len() == count {
+            "all platforms".to_string()
+        } else {
+            platforms
+                .iter()
+                .map(|platform| format!("`{}`", platform))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        eprint!("{}", rendered);
+        eprintln!("{} on {}\n", style("note:").bold(), on);
+    }
+    // Failures that are not explained by the diagnostics, like a missing
+    // target, are shown with what cargo printed.
+    for (platform, stderr) in &failed {
+        let Some(stderr) = stderr else {
+            continue;
+        };
+        eprintln!(
+            "{} checking `{}` failed:\n{}",
+            style("error:").red().bold(),
+            platform,
+            stderr.trim_end()
+        );
+    }
+
+    if failed.is_empty() {
+        crate::info(
+            "Checked",
+            format!(
+                "{} platforms, {} different diagnostics",
+                count,
+                diagnostics.len()
+            ),
+        );
+        Ok(0)
+    } else {
+        crate::info(
+            "Checked",
+            format!(
+                "{} platforms, {} failed: {}",
+                count,
+                failed.len(),
+                failed
+                    .iter()
+                    .map(|(platform, _)| platform.as_ref())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        );
+        Ok(101)
+    }
+}
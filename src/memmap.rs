@@ -0,0 +1,263 @@
+//! The memory map of a kernel on its platform, for `cargo arceos memmap`.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, bail};
+use axconfig_gen::Config;
+use console::style;
+
+use crate::diagnostics::Code;
+
+/// The flag of ELF sections that occupy memory when the kernel runs.
+const SHF_ALLOC: u64 = 0x2;
+
+/// A physical memory region of the platform or the kernel.
+struct Region {
+    name: String,
+    start: u64,
+    size: u64,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.start.saturating_add(self.size)
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+/// An allocated section of the kernel ELF.
+pub struct Section {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Returns the allocated sections of the little-endian ELF64 `elf`.
+pub fn sections(elf: &[u8]) -> anyhow::Result<Vec<Section>> {
+    if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+        bail!("not a little-endian 64-bit ELF");
+    }
+    let u16_at = |at: usize| -> Option<u64> {
+        Some(u16::from_le_bytes(elf.get(at..at + 2)?.try_into().ok()?).into())
+    };
+    let u32_at = |at: usize| -> Option<u64> {
+        Some(u32::from_le_bytes(elf.get(at..at + 4)?.try_into().ok()?).into())
+    };
+    let u64_at = |at: usize| -> Option<u64> {
+        Some(u64::from_le_bytes(elf.get(at..at + 8)?.try_into().ok()?))
+    };
+
+    let parse = || -> Option<Vec<Section>> {
+        let shoff = u64_at(0x28)? as usize;
+        let shentsize = u16_at(0x3a)? as usize;
+        let shnum = u16_at(0x3c)? as usize;
+        let shstrndx = u16_at(0x3e)? as usize;
+        let header = |i: usize| shoff + i * shentsize;
+        let strtab = u64_at(header(shstrndx) + 0x18)? as usize;
+
+        let mut sections = vec![];
+        for i in 0..shnum {
+            let at = header(i);
+            if u64_at(at + 0x08)? & SHF_ALLOC == 0 {
+                continue;
+            }
+            let name = strtab + u32_at(at)? as usize;
+            let len = elf.get(name..)?.iter().position(|&b| b == 0)?;
+            sections.push(Section {
+                name: String::from_utf8_lossy(&elf[name..name + len]).into_owned(),
+                addr: u64_at(at + 0x10)?,
+                size: u64_at(at + 0x20)?,
+            });
+        }
+        Some(sections)
+    };
+    parse().context("truncated ELF")
+}
+
+/// Returns the value at `key` of `table` as TOML. Large numbers are written
+/// as strings in the config, see [`uint`].
+fn value(config: &Config, table: &str, key: &str) -> Option<toml_edit::Value> {
+    let toml = config.config_at(table, key)?.value().to_toml_value();
+    let doc: toml_edit::DocumentMut = format!("value = {}", toml).parse().ok()?;
+    doc.get("value")?.as_value().cloned()
+}
+
+fn uint(value: &toml_edit::Value) -> Option<u64> {
+    match value {
+        toml_edit::Value::Integer(i) => u64::try_from(*i.value()).ok(),
+        toml_edit::Value::String(s) => {
+            let s = s.value().replace('_', "");
+            match s.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => s.parse().ok(),
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn config_uint(config: &Config, table: &str, key: &str) -> anyhow::Result<u64> {
+    value(config, table, key)
+        .as_ref()
+        .and_then(uint)
+        .with_context(|| format!("`{}.{}` is missing from the config", table, key))
+}
+
+/// Returns the `(base, size)` pairs at `key` of `table`.
+pub fn config_regions(config: &Config, table: &str, key: &str) -> Vec<(u64, u64)> {
+    let Some(toml_edit::Value::Array(regions)) = value(config, table, key) else {
+        return vec![];
+    };
+    regions
+        .iter()
+        .filter_map(|region| {
+            let region = region.as_array()?;
+            Some((uint(region.get(0)?)?, uint(region.get(1)?)?))
+        })
+        .collect()
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut unit = 0;
+    while unit < UNITS.len() - 1 && size != 0 && size.is_multiple_of(1024 << (unit * 10)) {
+        unit += 1;
+    }
+    format!("{} {}", size >> (unit * 10), UNITS[unit])
+}
+
+/// Prints the physical memory map of the platform in `config` with the
+/// kernel `elf` in it, and its virtual memory map.
+pub fn print(elf: &Path, config: &Config) -> anyhow::Result<()> {
+    let data = fs::read(elf).with_context(|| format!("failed to read `{}`", elf.display()))?;
+    let sections = sections(&data)
+        .with_context(|| format!("failed to read sections of `{}`", elf.display()))?;
+    let sections: Vec<Section> = sections.into_iter().filter(|s| s.size > 0).collect();
+    if sections.is_empty() {
+        bail!("`{}` has no allocated sections", elf.display());
+    }
+
+    let offset = config_uint(config, "plat", "phys-virt-offset")?;
+    let memory = Region {
+        name: "RAM".to_string(),
+        start: config_uint(config, "plat", "phys-memory-base")?,
+        size: config_uint(config, "plat", "phys-memory-size")?,
+    };
+
+    let mut regions = vec![];
+    for (start, size) in config_regions(config, "devices", "mmio-regions") {
+        regions.push(Region {
+            name: "MMIO".to_string(),
+            start,
+            size,
+        });
+    }
+    for (start, size) in config_regions(config, "devices", "virtio-mmio-regions") {
+        regions.push(Region {
+            name: "VirtIO MMIO".to_string(),
+            start,
+            size,
+        });
+    }
+    if let Ok(ecam) = config_uint(config, "devices", "pci-ecam-base")
+        && ecam != 0
+    {
+        // Each bus takes 1 MiB of the ECAM space.
+        let buses = config_uint(config, "devices", "pci-bus-end").unwrap_or(0xff) + 1;
+        regions.push(Region {
+            name: "PCIe ECAM".to_string(),
+            start: ecam,
+            size: buses << 20,
+        });
+    }
+    for (start, size) in config_regions(config, "guests", "images") {
+        regions.push(Region {
+            name: "guest image".to_string(),
+            start,
+            size,
+        });
+    }
+
+    let kernel_start = sections.iter().map(|s| s.addr).min().unwrap();
+    let kernel_end = sections.iter().map(|s| s.addr + s.size).max().unwrap();
+    let kernel = Region {
+        name: "kernel".to_string(),
+        start: kernel_start.wrapping_sub(offset),
+        size: kernel_end - kernel_start,
+    };
+
+    let mut problems = vec![];
+    if kernel.start < memory.start || kernel.end() > memory.end() {
+        problems.push((
+            Code::KernelOutsideMemory,
+            format!(
+                "the kernel at {:#x}..{:#x} is not within RAM at {:#x}..{:#x}",
+                kernel.start,
+                kernel.end(),
+                memory.start,
+                memory.end()
+            ),
+        ));
+    }
+    for region in regions.iter().filter(|region| region.overlaps(&kernel)) {
+        problems.push((
+            Code::KernelOverlap,
+            format!(
+                "the kernel at {:#x}..{:#x} overlaps {} at {:#x}..{:#x}",
+                kernel.start,
+                kernel.end(),
+                region.name,
+                region.start,
+                region.end()
+            ),
+        ));
+    }
+
+    // Regions other than RAM and the kernel are reserved.
+    let mut rows: Vec<(&Region, bool)> = regions.iter().map(|region| (region, true)).collect();
+    rows.push((&memory, false));
+    rows.push((&kernel, false));
+    rows.sort_by_key(|(region, _)| (region.start, std::cmp::Reverse(region.size)));
+
+    println!("Physical memory:");
+    for (region, reserved) in rows {
+        let line = format!(
+            "  {:#018x}..{:#018x} {:>9}  {}",
+            region.start,
+            region.end(),
+            format_size(region.size),
+            region.name
+        );
+        if reserved && region.overlaps(&kernel) {
+            println!(
+                "{}",
+                style(format!("{} <- overlaps the kernel", line)).red()
+            );
+        } else if std::ptr::eq(region, &kernel) {
+            println!("{}", style(line).bold());
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    println!();
+    println!("Kernel image:");
+    for section in &sections {
+        println!(
+            "  {:#018x}..{:#018x} {:>9}  {} (physical {:#x})",
+            section.addr,
+            section.addr + section.size,
+            format_size(section.size),
+            section.name,
+            section.addr.wrapping_sub(offset)
+        );
+    }
+
+    for (code, problem) in problems {
+        crate::warn_code(code, problem);
+    }
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Project settings read from `[package.metadata.arceos]`, or from
+/// `[workspace.metadata.arceos]` if the former is absent.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ArceOSMetadata {
+    /// Command that the generated runner invocation is prefixed with.
+    pub runner_wrapper: Option<CommandLine>,
+    /// Extra `-C target-feature`s for kernel and app crates.
+    pub target_features: TargetFeatures,
+    /// Patterns of metrics to scrape from the console.
+    pub metrics: Vec<String>,
+    /// Firmware of platforms, by platform, replacing the built-in one.
+    pub firmware: BTreeMap<String, Vec<crate::firmware::FirmwareFile>>,
+}
+
+/// Target features, like `-neon,+strict-align`, for the crates of ArceOS and
+/// for the packages being built.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TargetFeatures {
+    pub kernel: Option<String>,
+    pub app: Option<String>,
+}
+
+/// A command line given either as a single string or as a list of arguments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum CommandLine {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl CommandLine {
+    pub fn to_args(&self) -> anyhow::Result<Vec<String>> {
+        match self {
+            CommandLine::Line(line) => crate::split_args(line),
+            CommandLine::Args(args) => Ok(args.clone()),
+        }
+    }
+}
+
+impl ArceOSMetadata {
+    pub fn new(metadata: &cargo_metadata::Metadata) -> anyhow::Result<Self> {
+        let value = metadata
+            .root_package()
+            .and_then(|package| package.metadata.get("arceos"))
+            .or_else(|| metadata.workspace_metadata.get("arceos"));
+
+        match value {
+            Some(value) => Self::deserialize(value).context("failed to parse `arceos` metadata"),
+            None => Ok(Self::default()),
+        }
+    }
+}
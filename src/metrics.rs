@@ -0,0 +1,465 @@
+//! Scraping of metrics that guests print to the console.
+//!
+//! A pattern is the text of a console line with captures in it, like
+//! `latency: {latency:float} us`. A capture is `{NAME:TYPE}`, where `TYPE` is
+//! `int`, `float` or `str` (a run of non-space characters), and `{{`/`}}` stand
+//! for literal braces. Patterns may match anywhere in a line.
+//!
+//! This mini-language is deliberate rather than regular expressions: console
+//! lines are mostly literal text full of `.`, `(` and `[` that would all need
+//! escaping, and the type of a capture decides whether its value is recorded
+//! as a number or a string.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{Context, bail};
+use clap::{Args, builder::TypedValueParser};
+use serde::Serialize;
+use serde_json::Value;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+static SCRAPER: Mutex<Option<Scraper>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Metrics Options")]
+pub struct MetricsOptions {
+    /// Scrape metrics from console lines matching PATTERN, like
+    /// `latency: {latency:float} us`
+    #[arg(long = "metric", value_name = "PATTERN")]
+    metrics: Vec<Pattern>,
+
+    /// TOML file with the patterns to scrape in a `patterns` array
+    #[arg(long, value_name = "FILE")]
+    metrics_spec: Option<PathBuf>,
+
+    /// Format of the metrics file written next to the binary
+    #[arg(long, default_value = "json", value_parser = enum_variants!(MetricsFormat))]
+    metrics_format: MetricsFormat,
+}
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum MetricsFormat {
+    /// `<binary>.metrics.json`, with the values of each metric per machine
+    Json,
+    /// `<binary>.metrics.csv`, with a row per value
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Int,
+    Float,
+    Str,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Capture(String, Type),
+}
+
+/// A console line pattern with named, typed captures.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            if let Some(r) = rest.strip_prefix("{{") {
+                literal.push('{');
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("}}") {
+                literal.push('}');
+                rest = r;
+            } else if c == '{' {
+                let end = rest
+                    .find('}')
+                    .with_context(|| format!("unclosed capture in `{}`", s))?;
+                let (name, ty) = rest[1..end]
+                    .split_once(':')
+                    .unwrap_or((&rest[1..end], "str"));
+                if name.is_empty() {
+                    bail!("capture without a name in `{}`", s);
+                }
+                let ty = match ty {
+                    "int" => Type::Int,
+                    "float" => Type::Float,
+                    "str" => Type::Str,
+                    _ => bail!("unknown capture type `{}` in `{}`", ty, s),
+                };
+                if matches!(segments.last(), Some(Segment::Capture(..))) && literal.is_empty() {
+                    bail!("captures must be separated by text in `{}`", s);
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Capture(name.to_string(), ty));
+                rest = &rest[end + 1..];
+            } else if c == '}' {
+                bail!("unmatched `}}` in `{}`", s);
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        if !segments.iter().any(|s| matches!(s, Segment::Capture(..))) {
+            bail!("no captures in `{}`", s);
+        }
+
+        Ok(Pattern {
+            source: s.to_string(),
+            segments,
+        })
+    }
+}
+
+impl Pattern {
+    /// Returns the captured values if `line` matches, trying every position.
+    fn captures(&self, line: &str) -> Option<Vec<(&str, Value)>> {
+        line.char_indices()
+            .map(|(i, _)| i)
+            .chain([line.len()])
+            .find_map(|start| self.captures_at(&line[start..]))
+    }
+
+    fn captures_at(&self, mut text: &str) -> Option<Vec<(&str, Value)>> {
+        let mut values = vec![];
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => text = text.strip_prefix(literal.as_str())?,
+                Segment::Capture(name, ty) => {
+                    let len = match ty {
+                        Type::Int => number_len(text, false),
+                        Type::Float => number_len(text, true),
+                        // A string ends at a space or where the next literal
+                        // starts, even if that literal starts with a space.
+                        Type::Str => {
+                            let word = text.find(char::is_whitespace).unwrap_or(text.len());
+                            match self.segments.get(i + 1) {
+                                Some(Segment::Literal(next)) => text
+                                    .find(next.as_str())
+                                    .filter(|&at| at <= word)
+                                    .unwrap_or(word),
+                                _ => word,
+                            }
+                        }
+                    };
+                    if len == 0 {
+                        return None;
+                    }
+                    let raw = &text[..len];
+                    let value = match ty {
+                        Type::Int => Value::from(raw.parse::<i64>().ok()?),
+                        Type::Float => Value::from(raw.parse::<f64>().ok()?),
+                        Type::Str => Value::from(raw),
+                    };
+                    values.push((name.as_str(), value));
+                    text = &text[len..];
+                }
+            }
+        }
+        Some(values)
+    }
+}
+
+/// Returns the length of the number at the start of `text`.
+fn number_len(text: &str, float: bool) -> usize {
+    let bytes = text.as_bytes();
+    let digits = |mut i: usize| {
+        while bytes.get(i).is_some_and(u8::is_ascii_digit) {
+            i += 1;
+        }
+        i
+    };
+
+    let sign = usize::from(matches!(bytes.first(), Some(b'-' | b'+')));
+    let mut end = digits(sign);
+    if end == sign {
+        return 0;
+    }
+    if float {
+        if bytes.get(end) == Some(&b'.') && digits(end + 1) > end + 1 {
+            end = digits(end + 1);
+        }
+        if matches!(bytes.get(end), Some(b'e' | b'E')) {
+            let sign = end + 1 + usize::from(matches!(bytes.get(end + 1), Some(b'-' | b'+')));
+            if digits(sign) > sign {
+                end = digits(sign);
+            }
+        }
+    }
+    end
+}
+
+/// The values scraped so far, by metric name.
+#[derive(Debug, Default)]
+struct Scraper {
+    patterns: Vec<Pattern>,
+    values: BTreeMap<String, Vec<Value>>,
+    line: Vec<u8>,
+}
+
+impl Scraper {
+    fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            self.line.clear();
+            let line = line.trim_end_matches('\r');
+            for pattern in &self.patterns {
+                for (name, value) in pattern.captures(line).into_iter().flatten() {
+                    self.values.entry(name.to_string()).or_default().push(value);
+                }
+            }
+        }
+    }
+}
+
+/// The metrics scraped from the run on one machine.
+#[derive(Debug, Serialize)]
+pub struct MachineMetrics {
+    pub machine: String,
+    pub metrics: BTreeMap<String, Vec<Value>>,
+}
+
+impl MetricsOptions {
+    /// Adds patterns from the package metadata.
+    pub fn extend(&mut self, patterns: &[String]) -> anyhow::Result<()> {
+        for pattern in patterns {
+            self.metrics.push(pattern.parse()?);
+        }
+        Ok(())
+    }
+
+    pub fn runner_args(&self, runner: &mut Vec<OsString>) {
+        for pattern in &self.metrics {
+            runner.push("--metric".into());
+            runner.push(pattern.source.as_str().into());
+        }
+
+        if let Some(spec) = &self.metrics_spec {
+            runner.push("--metrics-spec".into());
+            runner.push(spec.into());
+        }
+
+        if !self.metrics.is_empty() || self.metrics_spec.is_some() {
+            runner.push("--metrics-format".into());
+            runner.push(self.metrics_format.as_ref().into());
+        }
+    }
+
+    /// Starts scraping the console, if there is anything to scrape.
+    pub fn start(&self) -> anyhow::Result<()> {
+        let mut patterns = self.metrics.clone();
+        if let Some(path) = &self.metrics_spec {
+            let toml = fs::read_to_string(path)
+                .with_context(|| format!("failed to read metrics spec `{}`", path.display()))?;
+            let spec = toml
+                .parse::<toml_edit::DocumentMut>()
+                .with_context(|| format!("failed to parse metrics spec `{}`", path.display()))?;
+            let spec = spec
+                .get("patterns")
+                .and_then(|patterns| patterns.as_array())
+                .with_context(|| format!("no `patterns` array in `{}`", path.display()))?;
+            for pattern in spec {
+                let pattern = pattern.as_str().with_context(|| {
+                    format!("patterns in `{}` should be strings", path.display())
+                })?;
+                patterns.push(pattern.parse()?);
+            }
+        }
+
+        if !patterns.is_empty() {
+            *SCRAPER.lock().unwrap() = Some(Scraper {
+                patterns,
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes the metrics of all machines next to `binary`, if any were
+    /// scraped.
+    pub fn write(&self, binary: &Path, runs: &[MachineMetrics]) -> anyhow::Result<()> {
+        if SCRAPER.lock().unwrap().is_none() {
+            return Ok(());
+        }
+
+        let (extension, contents) = match self.metrics_format {
+            MetricsFormat::Json => (
+                "metrics.json",
+                serde_json::to_string_pretty(&serde_json::json!({ "runs": runs }))? + "\n",
+            ),
+            MetricsFormat::Csv => {
+                let mut csv = "machine,metric,index,value\n".to_string();
+                for run in runs {
+                    for (name, values) in &run.metrics {
+                        for (i, value) in values.iter().enumerate() {
+                            let value = match value {
+                                Value::String(s) => csv_field(s),
+                                value => value.to_string(),
+                            };
+                            csv.push_str(&format!(
+                                "{},{},{},{}\n",
+                                csv_field(&run.machine),
+                                csv_field(name),
+                                i,
+                                value
+                            ));
+                        }
+                    }
+                }
+                ("metrics.csv", csv)
+            }
+        };
+
+        let mut path = binary.as_os_str().to_owned();
+        path.push(".");
+        path.push(extension);
+        let path = PathBuf::from(path);
+        crate::write_atomic(&path, contents)
+            .with_context(|| format!("failed to write metrics `{}`", path.display()))?;
+        crate::info("Metrics", format!("`{}`", path.display()));
+        Ok(())
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Scrapes output of the guest console.
+pub fn console(data: &[u8]) {
+    if let Some(scraper) = SCRAPER.lock().unwrap().as_mut() {
+        scraper.feed(data);
+    }
+}
+
+/// Returns the metrics scraped since the last call, for the run on `machine`.
+pub fn take(machine: &str) -> Option<MachineMetrics> {
+    let mut scraper = SCRAPER.lock().unwrap();
+    let scraper = scraper.as_mut()?;
+    // Whatever the guest printed last without a newline counts as a line.
+    if !scraper.line.is_empty() {
+        scraper.feed(b"\n");
+    }
+    Some(MachineMetrics {
+        machine: machine.to_string(),
+        metrics: std::mem::take(&mut scraper.values),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_segments() {
+        let segments = pattern("{{x}} {name} took {t:float} ms").segments;
+        assert!(matches!(&segments[0], Segment::Literal(s) if s == "{x} "));
+        assert!(matches!(&segments[1], Segment::Capture(n, Type::Str) if n == "name"));
+        assert!(matches!(&segments[2], Segment::Literal(s) if s == " took "));
+        assert!(matches!(&segments[3], Segment::Capture(n, Type::Float) if n == "t"));
+        assert!(matches!(&segments[4], Segment::Literal(s) if s == " ms"));
+        assert_eq!(segments.len(), 5);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in [
+            "no captures",
+            "{{escaped}}",
+            "{unclosed",
+            "unmatched }",
+            "{:int}",
+            "{x:bool}",
+            "{a:int}{b:int}",
+        ] {
+            assert!(s.parse::<Pattern>().is_err(), "`{}` parsed", s);
+        }
+    }
+
+    #[test]
+    fn captures() {
+        let p = pattern("latency: {latency:float} us, {n:int} ops");
+        assert_eq!(
+            p.captures("[ 1.0] latency: 1.5e3 us, -42 ops done"),
+            Some(vec![
+                ("latency", Value::from(1500.0)),
+                ("n", Value::from(-42))
+            ])
+        );
+        assert_eq!(p.captures("latency: fast us, 1 ops"), None);
+        assert_eq!(p.captures("latency: 1.5 us"), None);
+
+        // A string stops at a space or at the literal after it.
+        let p = pattern("test {name}: {result}");
+        assert_eq!(
+            p.captures("test alloc::vec: ok (3 ms)"),
+            Some(vec![
+                ("name", Value::from("alloc::vec")),
+                ("result", Value::from("ok"))
+            ])
+        );
+        // An int does not take the fraction of a float.
+        assert_eq!(
+            pattern("x={x:int}.").captures("x=1.5"),
+            Some(vec![("x", Value::from(1))])
+        );
+    }
+
+    #[test]
+    fn number_lengths() {
+        assert_eq!(number_len("123 ", false), 3);
+        assert_eq!(number_len("-7", false), 2);
+        assert_eq!(number_len("+", false), 0);
+        assert_eq!(number_len("abc", false), 0);
+        assert_eq!(number_len("1.5", false), 1);
+        assert_eq!(number_len("1.5", true), 3);
+        assert_eq!(number_len("1.", true), 1);
+        assert_eq!(number_len("2.5e-3s", true), 6);
+        assert_eq!(number_len("2e", true), 1);
+        assert_eq!(number_len("-1E+2", true), 5);
+    }
+
+    #[test]
+    fn scrape_split_lines() {
+        let mut scraper = Scraper {
+            patterns: vec![pattern("ops: {ops:int}")],
+            ..Default::default()
+        };
+        scraper.feed(b"ops: 1\r\nop");
+        scraper.feed(b"s: 2\nops: 3");
+        assert_eq!(scraper.values["ops"], vec![Value::from(1), Value::from(2)]);
+    }
+}
@@ -1,92 +1,400 @@
 use std::{
-    env, fs,
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, File, TryLockError},
+    io,
     net::Ipv4Addr,
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
 use axconfig_gen::{Config, ConfigValue};
+use cargo_metadata::Metadata;
 use clap::{Args, builder::TypedValueParser};
-use heck::ToShoutySnakeCase;
+use console::style;
 use strum::{AsRefStr, EnumString, VariantNames};
 
-use crate::platforms::{Arch, Platform};
-
-// https://github.com/clap-rs/clap/discussions/4264
-macro_rules! enum_variants {
-    ($e:ty) => {
-        clap::builder::PossibleValuesParser::new(<$e>::VARIANTS).map(|s| s.parse::<$e>().unwrap())
-    };
-}
-
-struct Feature {
-    name: &'static str,
-    cond: &'static str,
-    packages: &'static [&'static str],
-}
-
-const SMP: Feature = Feature {
-    name: "smp",
-    cond: "number of CPUs > 1",
-    packages: &[
-        "axlibc",
-        "arceos_posix_api",
-        "axstd",
-        "axfeat",
-        "axhal",
-        "axruntime",
-        "axtask",
-    ],
-};
-
-const FP_SIMD: Feature = Feature {
-    name: "fp_simd",
-    cond: "compiling to AArch64 without soft float",
-    packages: &["axlibc", "axstd", "axfeat", "axhal"],
+use crate::{
+    cache::{BuildCache, Sccache},
+    demux::CpuConsoles,
+    diagnostics::{Code, Diagnostic},
+    exit::GuestExit,
+    features,
+    image::ImageOptions,
+    logfile::LogFile,
+    manifest::{MachineRun, RunManifest},
+    metadata::ArceOSMetadata,
+    metrics::MetricsOptions,
+    platforms::{Arch, Platform, QemuMachineSpec},
+    schema::Schema,
+    timings,
+    vfio::PciAddress,
 };
 
 #[derive(Debug, Clone, Args)]
 #[command(next_help_heading = "ArceOS Options")]
 pub struct ArceOSOptions {
+    /// Path to the app package, like `A` in the ArceOS Makefile
+    #[arg(long, env = "ARCEOS_APP", value_name = "PATH")]
+    app: Option<PathBuf>,
+
     #[command(flatten)]
     arch_or_platform: ArchOrPlatform,
 
     /// Enable soft float
-    #[arg(long, env)]
+    #[arg(long, env = "ARCEOS_SOFT_FLOAT")]
     soft_float: bool,
 
-    /// Number of CPUs
-    #[arg(long, default_value_t = 1, env, value_name = "N")]
-    cpus: u32,
+    /// Number of CPUs [default: `smp` of the config, or 1]
+    #[arg(long, env = "ARCEOS_CPUS", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    cpus: Option<u32>,
 
     /// Additional config files
-    #[arg(short, long, env, value_name = "PATH")]
+    #[arg(short, long, env = "ARCEOS_CONFIGS", value_name = "PATH")]
     configs: Vec<PathBuf>,
 
+    /// Timer ticks per second
+    #[arg(long, env = "ARCEOS_TICKS", value_name = "HZ", value_parser = clap::value_parser!(u64).range(1..))]
+    ticks: Option<u64>,
+
+    /// Kernel command line, passed to QEMU with `-append` and written to the
+    /// config as `bootargs`
+    #[arg(long, env = "ARCEOS_BOOTARGS", value_name = "STRING")]
+    bootargs: Option<String>,
+
+    /// Set an environment variable for the app, which gets it on the kernel
+    /// command line and in `AX_APP_ENV` when it is built
+    #[arg(long, value_name = "KEY=VAL", value_parser = crate::qemu::parse_env_entry)]
+    env_guest: Vec<String>,
+
+    /// Pack a directory into a CPIO archive for the kernel to embed, with its
+    /// path exported as `AX_INITRAMFS`, and `AX_INITRAMFS_RS` for the app to
+    /// put it into the `.initramfs` section with `include!`
+    #[arg(long, env = "ARCEOS_INITRAMFS", value_name = "DIR")]
+    initramfs: Option<PathBuf>,
+
+    /// Task scheduler, enabling the matching `sched_*` feature
+    #[arg(long, env = "ARCEOS_SCHEDULER", value_parser = enum_variants!(Scheduler))]
+    scheduler: Option<Scheduler>,
+
     /// Log level
-    #[arg(short = 'L', long, default_value_t = log::LevelFilter::Warn, env, value_name = "LEVEL")]
+    #[arg(short = 'L', long, default_value_t = log::LevelFilter::Warn, env = "ARCEOS_LOG", value_name = "LEVEL")]
     log: log::LevelFilter,
 
     /// IP address
-    #[arg(long, env, default_value = "10.0.2.15", value_name = "ADDR")]
+    #[arg(
+        long,
+        env = "ARCEOS_IP",
+        default_value = "10.0.2.15",
+        value_name = "ADDR"
+    )]
     ip: Ipv4Addr,
 
     /// Gateway
-    #[arg(long, env = "GW", default_value = "10.0.2.2", value_name = "ADDR")]
+    #[arg(
+        long,
+        env = "ARCEOS_GW",
+        default_value = "10.0.2.2",
+        value_name = "ADDR"
+    )]
     gateway: Ipv4Addr,
+
+    /// Device bus type [default: derived from the platform]
+    #[arg(long, env = "ARCEOS_BUS", value_parser = enum_variants!(BusType))]
+    bus: Option<BusType>,
+
+    /// Features of ArceOS to enable on the `axstd`, `axlibc` or `axfeat`
+    /// dependency of the app, like `net,fs`
+    #[arg(
+        long,
+        env = "ARCEOS_AX_FEATURES",
+        value_delimiter = ',',
+        value_name = "FEATURES"
+    )]
+    ax_features: Vec<String>,
+
+    /// Do not enable the default features of the app, which usually pick the
+    /// features of ArceOS
+    #[arg(long, env = "ARCEOS_NO_DEFAULT_AX_FEATURES")]
+    no_default_ax_features: bool,
+
+    /// Enable missing required features on direct dependencies
+    #[arg(long, env = "ARCEOS_AUTO_FEATURES")]
+    auto_features: bool,
+
+    /// Make builds reproducible across machines by remapping paths, fixing
+    /// `SOURCE_DATE_EPOCH` and trimming line endings and trailing spaces from
+    /// the generated config
+    #[arg(long, env = "ARCEOS_REPRODUCIBLE")]
+    reproducible: bool,
+
+    /// Copy all output to a file, like `PATH[,strip-ansi][,timestamps]`
+    #[arg(long, env = "ARCEOS_LOG_FILE", value_name = "PATH")]
+    log_file: Option<LogFile>,
+
+    /// Compile through a build cache
+    #[arg(long, env = "ARCEOS_CACHE", value_parser = enum_variants!(BuildCache))]
+    cache: Option<BuildCache>,
+
+    /// Compile ArceOS crates and app crates with different target features
+    #[arg(long, env = "ARCEOS_TARGET_FEATURE_PRESET", value_parser = enum_variants!(TargetFeaturePreset))]
+    target_feature_preset: Option<TargetFeaturePreset>,
+
+    /// Optimize the kernel for size or speed, overriding the profile
+    #[arg(long, env = "ARCEOS_OPT", value_parser = enum_variants!(OptPreset))]
+    opt: Option<OptPreset>,
+
+    /// Link a position-independent kernel that relocates itself at boot,
+    /// exported as `AX_PIE`
+    #[arg(long, env = "ARCEOS_PIE")]
+    pie: bool,
+
+    /// Do not export `AX_BUILD_INFO`, which has the commit and the time of the
+    /// build and so differs between builds
+    #[arg(long, env = "ARCEOS_NO_BUILD_INFO")]
+    no_build_info: bool,
+
+    /// Note above each key of the generated `axconfig.toml` where its value
+    /// comes from, for reviewing changes to a committed config
+    #[arg(long, env = "ARCEOS_ANNOTATE_CONFIG")]
+    annotate_config: bool,
+
+    /// Cargo to build with, like a wrapper such as `cross` [default: `CARGO`,
+    /// or `cargo` in PATH]
+    #[arg(long, env = "ARCEOS_CARGO_PATH", value_name = "PATH")]
+    cargo_path: Option<PathBuf>,
+
+    /// Load the configs of platforms from DIR before the built-in ones, as
+    /// `<platform>.toml` and `defconfig.toml`, see `cargo arceos config
+    /// export-platforms`
+    #[arg(long, env = "ARCEOS_PLATFORM_DIR", value_name = "DIR")]
+    platform_dir: Option<PathBuf>,
+
+    /// Write the generated files, like `axconfig.toml` and the images of the
+    /// kernel, into DIR instead of the target directory, for when that is
+    /// read-only or on a network filesystem
+    #[arg(long, env = "ARCEOS_SCRATCH_DIR", value_name = "DIR")]
+    scratch_dir: Option<PathBuf>,
+
+    /// Do not record the build for `cargo arceos stats`
+    #[arg(long, env = "ARCEOS_NO_STATS")]
+    no_stats: bool,
+
+    /// Allow the warnings with the given codes, see `cargo arceos explain`
+    #[arg(long, env = "ARCEOS_ALLOW", value_delimiter = ',', value_name = "CODES", value_parser = enum_variants!(Code))]
+    allow: Vec<Code>,
+
+    /// Turn the warnings with the given codes into errors that fail the
+    /// command
+    #[arg(long, env = "ARCEOS_DENY", value_delimiter = ',', value_name = "CODES", value_parser = enum_variants!(Code))]
+    deny: Vec<Code>,
+
+    #[command(flatten)]
+    container: crate::container::ContainerOptions,
+
+    /// Configs generated from other options, merged after `configs`
+    #[arg(skip)]
+    generated_configs: Vec<String>,
+
+    /// The number of CPUs in the config that [`apply`](Self::apply) wrote.
+    #[arg(skip)]
+    resolved_cpus: Option<u32>,
+
+    /// Whether a library is built instead of a kernel, see
+    /// [`build_library`](Self::build_library).
+    #[arg(skip)]
+    library: bool,
+}
+
+/// Returns the generated config `toml` with a comment above each key naming
+/// where its value comes from, as found in `sources` by table and key.
+fn annotate_config(toml: &str, sources: &BTreeMap<(String, String), String>) -> String {
+    let mut table = Config::GLOBAL_TABLE_NAME;
+    let mut annotated = String::new();
+    for line in toml.lines() {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            table = name;
+        } else if !line.starts_with([' ', '#', ']'])
+            && let Some((key, _)) = line.split_once(" = ")
+            && let Some(source) = sources.get(&(table.to_string(), key.to_string()))
+        {
+            annotated.push_str(&format!("# Set by {}.\n", source));
+        }
+        annotated.push_str(line);
+        annotated.push('\n');
+    }
+    annotated
+}
+
+/// The locks on the generated configs that the command has built with, held
+/// until it exits, so that concurrent builds of the same platform do not
+/// change the config underneath each other.
+static CONFIG_LOCKS: Mutex<BTreeMap<PathBuf, File>> = Mutex::new(BTreeMap::new());
+
+/// Writes `config` to `path` unless it is there already, and holds a lock on
+/// it until [`release_config_locks`]. Builds with the same config share the
+/// lock, and a build that changes it waits for the others to finish.
+fn write_config(path: &Path, config: &str) -> io::Result<()> {
+    let lock_path = path.with_extension("lock");
+    let mut locks = CONFIG_LOCKS.lock().unwrap();
+    // A config written earlier by this command is no longer built with.
+    locks.remove(path);
+    loop {
+        let lock = lock_file(&lock_path, path, true)?;
+        // Anything but the exact config, like one cut short, is rewritten.
+        if fs::read_to_string(path).is_ok_and(|old_config| old_config == config) {
+            locks.insert(path.to_path_buf(), lock);
+            return Ok(());
+        }
+        drop(lock);
+        let lock = lock_file(&lock_path, path, false)?;
+        if fs::read_to_string(path)
+            .ok()
+            .is_none_or(|old_config| old_config != config)
+        {
+            crate::write_atomic(path, config)?;
+        }
+        // The lock cannot be shared without being released first, so the
+        // config is checked again once it is.
+        drop(lock);
+    }
+}
+
+/// Opens the lock file at `path` of the config at `config` and locks it,
+/// shared or exclusively, waiting for the builds that hold it otherwise.
+fn lock_file(path: &Path, config: &Path, shared: bool) -> io::Result<File> {
+    loop {
+        let file = File::options().create(true).append(true).open(path)?;
+        let acquired = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        match acquired {
+            Ok(()) => {}
+            Err(TryLockError::Error(e)) => return Err(e),
+            Err(TryLockError::WouldBlock) => {
+                crate::info(
+                    "Blocking",
+                    format!(
+                        "waiting for another build to finish with `{}`",
+                        config.display()
+                    ),
+                );
+                if shared {
+                    file.lock_shared()?;
+                } else {
+                    file.lock()?;
+                }
+            }
+        }
+        // The build that held it may have removed it in the meantime.
+        if is_same_file(&file, path) {
+            return Ok(file);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(file), Ok(path)) => file.dev() == path.dev() && file.ino() == path.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+/// Releases the locks taken by [`write_config`], removing the lock files
+/// that no other build holds.
+pub fn release_config_locks() {
+    for (path, lock) in std::mem::take(&mut *CONFIG_LOCKS.lock().unwrap()) {
+        let lock_path = path.with_extension("lock");
+        // Elsewhere, a removed lock file cannot be told from the new one.
+        if cfg!(unix)
+            && lock.unlock().is_ok()
+            && lock.try_lock().is_ok()
+            && is_same_file(&lock, &lock_path)
+        {
+            fs::remove_file(&lock_path).ok();
+        }
+    }
+}
+
+/// A config merged into the config of the platform.
+pub struct ConfigLayer {
+    /// Where the config comes from, like "config file `a.toml`".
+    pub source: String,
+    pub config: Config,
+}
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Scheduler {
+    /// First in, first out
+    Fifo,
+    /// Round robin
+    Rr,
+    /// Completely fair
+    Cfs,
+}
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum TargetFeaturePreset {
+    /// Keep FP/SIMD registers out of the kernel while apps may use them
+    SplitFp,
+}
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum OptPreset {
+    /// Smallest kernel, with `opt-level = "z"` and fat LTO
+    KernelSize,
+    /// Fastest kernel, with `opt-level = 3` and fat LTO
+    KernelSpeed,
+}
+
+impl OptPreset {
+    /// Returns the profile settings of the preset, as TOML values.
+    fn settings(self) -> [(&'static str, &'static str); 4] {
+        let opt_level = match self {
+            OptPreset::KernelSize => "\"z\"",
+            OptPreset::KernelSpeed => "3",
+        };
+        [
+            ("opt-level", opt_level),
+            ("lto", "\"fat\""),
+            ("codegen-units", "1"),
+            // Unwinding is not supported by ArceOS, and the unwind tables
+            // only make the kernel larger.
+            ("panic", "\"abort\""),
+        ]
+    }
 }
 
 #[derive(Debug, Clone, Args)]
 #[group(multiple = false)]
 struct ArchOrPlatform {
     /// Target architecture
-    #[arg(short = 'A', long, env, value_parser = enum_variants!(Arch))]
+    #[arg(short = 'A', long, env = "ARCEOS_ARCH", value_parser = enum_variants!(Arch))]
     arch: Option<Arch>,
 
     /// Target platform
-    #[arg(short = 'P', long, env, value_parser = enum_variants!(Platform))]
+    #[arg(short = 'P', long, env = "ARCEOS_PLATFORM", value_parser = enum_variants!(Platform))]
     platform: Option<Platform>,
 }
 
@@ -103,16 +411,35 @@ impl From<ArchOrPlatform> for Platform {
 }
 
 impl ArceOSOptions {
+    #[inline]
+    pub fn app(&self) -> Option<&Path> {
+        self.app.as_deref()
+    }
+
     #[inline]
     pub fn platform(&self) -> Platform {
         self.arch_or_platform.clone().into()
     }
 
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.arch_or_platform = ArchOrPlatform {
+            arch: None,
+            platform: Some(platform),
+        };
+    }
+
     #[inline]
     pub fn arch(&self) -> Arch {
         self.platform().into()
     }
 
+    #[inline]
+    pub fn bus(&self) -> BusType {
+        self.bus
+            .clone()
+            .unwrap_or_else(|| self.platform().default_bus())
+    }
+
     #[inline]
     pub fn target(&self) -> &'static str {
         match (self.arch(), self.soft_float) {
@@ -124,71 +451,393 @@ impl ArceOSOptions {
         }
     }
 
-    pub fn apply(
+    /// Returns the configs merged into the config of the platform, in order:
+    /// the config files, generated configs and `--bootargs`.
+    pub fn config_layers(&self, schema: &Schema) -> anyhow::Result<Vec<ConfigLayer>> {
+        let mut layers = vec![];
+        for path in &self.configs {
+            let toml = fs::read_to_string(path)
+                .with_context(|| format!("failed to read config file `{}`", path.display()))?;
+            let config = Config::from_toml(&toml).map_err(|e| {
+                anyhow::anyhow!("failed to parse config file `{}`: {}", path.display(), e)
+            })?;
+            schema.check_file(path, &toml, &config)?;
+            layers.push(ConfigLayer {
+                source: format!("config file `{}`", path.display()),
+                config,
+            });
+        }
+        for toml in &self.generated_configs {
+            let config = Config::from_toml(toml)
+                .map_err(|e| anyhow::anyhow!("failed to parse generated config: {}", e))?;
+            layers.push(ConfigLayer {
+                source: "generated config".to_string(),
+                config,
+            });
+        }
+        if let Some(bootargs) = &self.bootargs {
+            let config = Config::from_toml(&format!(
+                "# Kernel command line.\nbootargs = {} # str\n",
+                toml_edit::Value::from(bootargs.as_str())
+            ))
+            .unwrap();
+            layers.push(ConfigLayer {
+                source: "`--bootargs`".to_string(),
+                config,
+            });
+        }
+        Ok(layers)
+    }
+
+    /// Returns the global keys that options set after merging, with their
+    /// values and the options.
+    pub fn config_overrides(&self) -> Vec<(&'static str, String, &'static str)> {
+        let mut overrides = vec![];
+        if let Some(cpus) = self.cpus {
+            overrides.push(("smp", cpus.to_string(), "--cpus"));
+        }
+        if let Some(ticks) = self.ticks {
+            overrides.push(("ticks-per-sec", ticks.to_string(), "--ticks"));
+        }
+        overrides
+    }
+
+    /// Returns the number of CPUs, which is `smp` of the config unless
+    /// `--cpus` is given.
+    pub fn cpus(&self) -> u32 {
+        self.resolved_cpus.or(self.cpus).unwrap_or(1)
+    }
+
+    /// Builds a library, which is not linked with the linker script of the
+    /// platform.
+    pub fn build_library(&mut self) {
+        self.library = true;
+    }
+
+    /// Adds a config in the TOML format to be merged into the generated one.
+    pub fn push_config(&mut self, toml: String) {
+        self.generated_configs.push(toml);
+    }
+
+    /// Returns the directory where cargo puts the built artifacts.
+    #[inline]
+    pub fn binary_dir(&self, target_dir: &Path, profile: &str) -> PathBuf {
+        target_dir.join(self.target()).join(profile)
+    }
+
+    /// Returns the directory for the files generated for the build, which is
+    /// the binary directory unless `--scratch-dir` is given.
+    pub fn generated_dir(&self, target_dir: &Path, profile: &str) -> PathBuf {
+        match &self.scratch_dir {
+            Some(dir) => dir.join(self.target()).join(profile),
+            None => self.binary_dir(target_dir, profile),
+        }
+    }
+
+    /// Returns where to record the build for `cargo arceos stats`, unless
+    /// `--no-stats` is given.
+    pub fn stats(&self, target_dir: &Path, profile: &str) -> Option<crate::stats::Recorder> {
+        (!self.no_stats).then(|| crate::stats::Recorder {
+            path: crate::stats::path(self.scratch_dir.as_deref().unwrap_or(target_dir)),
+            platform: self.platform().to_string(),
+            profile: profile.to_string(),
+        })
+    }
+
+    /// Returns the files and directories that builds write to, besides the
+    /// target directory.
+    pub fn output_paths(&self) -> Vec<PathBuf> {
+        let log_file = self.log_file.as_ref().map(|log_file| log_file.path());
+        [log_file, self.scratch_dir.as_deref()]
+            .into_iter()
+            .flatten()
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Sends the generated files of the build to `--scratch-dir`, if given,
+    /// in the runner spawned by `command` as well.
+    pub fn set_scratch_dir(
+        &self,
+        target_dir: &Path,
+        profile: &str,
+        command: Option<&mut Command>,
+    ) -> anyhow::Result<()> {
+        if self.scratch_dir.is_none() {
+            return Ok(());
+        }
+        let dir = self.generated_dir(target_dir, profile);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create `{}`", dir.display()))?;
+        crate::scratch::set(&dir.canonicalize()?, command);
+        Ok(())
+    }
+
+    /// Returns the container that cargo runs in with `--docker` or `--podman`.
+    pub fn container(
         &self,
+        metadata: &Metadata,
+        target_dir: &Path,
+    ) -> anyhow::Result<Option<crate::container::Container>> {
+        self.container
+            .container(metadata.workspace_root.as_std_path(), target_dir)
+    }
+
+    /// Sets up what the options change for the whole command, before
+    /// anything else is done with them.
+    pub fn init(&self) -> anyhow::Result<()> {
+        self.set_warning_levels(None);
+        // Cargo is spawned as `CARGO`, like cargo does for its subcommands.
+        if let Some(cargo) = &self.cargo_path
+            && env::var_os("CARGO").is_none_or(|current| current != cargo.as_os_str())
+        {
+            // SAFETY: commands call this before spawning any thread.
+            unsafe { env::set_var("CARGO", cargo) };
+        }
+        if let Some(dir) = &self.platform_dir {
+            crate::platforms::load_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Allows the warnings given with `--allow` and denies the ones given with
+    /// `--deny`, in the runner spawned by `command` as well.
+    pub fn set_warning_levels(&self, command: Option<&mut Command>) {
+        crate::diagnostics::set_levels(&self.allow, &self.deny, command);
+    }
+
+    /// Denies every warning, even the ones given with `--allow`.
+    pub fn deny_warnings(&mut self) {
+        self.allow.clear();
+        self.deny = Code::VARIANTS
+            .iter()
+            .map(|code| code.parse().unwrap())
+            .filter(|code: &Code| code.is_warning())
+            .collect();
+    }
+
+    pub fn apply(
+        &mut self,
+        metadata: &Metadata,
         target_dir: &Path,
         profile: &str,
         command: &mut Command,
     ) -> anyhow::Result<()> {
+        self.set_warning_levels(Some(command));
         let platform: Platform = self.platform();
         let arch: Arch = self.arch();
         let target = self.target();
+        let bus = self.bus();
+        if self.bus.is_none() {
+            crate::verbose(
+                "Bus",
+                format!("{}, the default of platform `{}`", bus.as_ref(), platform),
+            );
+        }
+        platform.check_bus(&bus)?;
+        if self.pie {
+            platform.check_pie()?;
+        }
 
         command.args(["--target", target]);
+        if let Some(opt) = self.opt {
+            // Cargo calls the profile of debug builds `dev`.
+            let name = if profile == "debug" { "dev" } else { profile };
+            for (key, value) in opt.settings() {
+                command
+                    .arg("--config")
+                    .arg(format!("profile.{}.{}={}", name, key, value));
+            }
+        }
 
-        let binary_dir = target_dir.join(target).join(profile);
+        let binary_dir = self.binary_dir(target_dir, profile);
+        self.set_scratch_dir(target_dir, profile, Some(command))?;
+        let generated_dir = self.generated_dir(target_dir, profile);
 
-        // Update config file
-        let config_path = binary_dir.join("axconfig.toml");
+        // Update config file, in a directory of the platform since the binary
+        // directory is shared by the platforms of an architecture.
+        let config_dir = generated_dir.join(platform.to_string());
+        let config_path = config_dir.join("axconfig.toml");
         if !config_path.exists() {
-            fs::create_dir_all(&binary_dir).context("failed to create target directory")?;
+            fs::create_dir_all(&config_dir).map_err(|e| {
+                crate::scratch::unwritable(&generated_dir, "the generated files", e)
+            })?;
         }
 
         let mut config: Config = platform.into();
-        for path in &self.configs {
-            let toml = fs::read_to_string(path)
-                .with_context(|| format!("failed to read config file `{}`", path.display()))?;
-            let c = Config::from_toml(&toml).map_err(|e| {
-                anyhow::anyhow!("failed to parse config file `{}`: {}", path.display(), e)
-            })?;
-            config.merge(&c).map_err(|e| {
-                anyhow::anyhow!("failed to merge config file `{}`: {}", path.display(), e)
-            })?;
+        let schema = Schema::new((!matches!(platform, Platform::Dummy)).then_some(arch));
+        // Where each key comes from, by table and key. Configs cannot set the
+        // keys of others, except for `smp` and the overrides of options.
+        let mut sources: BTreeMap<(String, String), String> = BTreeMap::new();
+        let mut add_sources = |config: &Config, source: &str| {
+            for item in config.iter() {
+                sources.insert(
+                    (item.table_name().to_string(), item.key().to_string()),
+                    source.to_string(),
+                );
+            }
+        };
+        add_sources(
+            &Config::from_toml(crate::platforms::defconfig()).unwrap(),
+            "defconfig",
+        );
+        add_sources(
+            &Config::from_toml(platform.config_toml()).unwrap(),
+            &format!("platform `{}`", platform),
+        );
+        // The number of CPUs is a platform key that config files may set too,
+        // like `--cpus`, which takes precedence if given.
+        let mut file_cpus = None;
+        for mut layer in self.config_layers(&schema)? {
+            if let Some(smp) = layer
+                .config
+                .table_at_mut(Config::GLOBAL_TABLE_NAME)
+                .and_then(|table| table.remove("smp"))
+            {
+                let value = smp.value().to_toml_value();
+                if let Some(cpus) = self.cpus
+                    && value != cpus.to_string()
+                {
+                    crate::warn_code(
+                        Code::CpusOverridden,
+                        format!(
+                            "{} sets `smp = {}`, which `--cpus {}` overrides",
+                            layer.source, value, cpus
+                        ),
+                    );
+                }
+                file_cpus = Some((smp, layer.source.clone()));
+            }
+            config
+                .merge(&layer.config)
+                .map_err(|e| anyhow::anyhow!("failed to merge {}: {}", layer.source, e))?;
+            add_sources(&layer.config, &layer.source);
         }
-        config
-            .config_at_mut(Config::GLOBAL_TABLE_NAME, "smp")
-            .unwrap()
-            .value_mut()
-            .update(ConfigValue::new(&self.cpus.to_string()).unwrap())
-            .unwrap();
-        let config = config.dump_toml().unwrap();
-
-        if fs::read_to_string(&config_path)
-            .ok()
-            .is_none_or(|old_config| old_config != config)
+        if self.cpus.is_none()
+            && let Some((smp, source)) = &file_cpus
+        {
+            config
+                .config_at_mut(Config::GLOBAL_TABLE_NAME, "smp")
+                .context("`smp` is missing from the config")?
+                .value_mut()
+                .update(smp.value().clone())
+                .map_err(|e| anyhow::anyhow!("failed to set `smp` from {}: {}", source, e))?;
+            sources.insert(
+                (Config::GLOBAL_TABLE_NAME.to_string(), "smp".to_string()),
+                source.clone(),
+            );
+        }
+        schema.check_required(&config)?;
+        for (key, value, option) in self.config_overrides() {
+            sources.insert(
+                (Config::GLOBAL_TABLE_NAME.to_string(), key.to_string()),
+                format!("`{}`", option),
+            );
+            config
+                .config_at_mut(Config::GLOBAL_TABLE_NAME, key)
+                .with_context(|| format!("`{}` is missing from the config", key))?
+                .value_mut()
+                .update(ConfigValue::new(&value).unwrap())
+                .map_err(|e| anyhow::anyhow!("failed to set `{}`: {}", key, e))?;
+        }
+        let uint = |table, key| {
+            config
+                .config_at(table, key)
+                .and_then(|item| item.value().to_toml_value().parse::<u32>().ok())
+        };
+        let cpus = uint(Config::GLOBAL_TABLE_NAME, "smp").unwrap_or(1);
+        if let Some(max) = uint("plat", "max-cpu-num")
+            && cpus > max
         {
-            fs::write(&config_path, config).context("failed to write config file")?;
+            let source = match (self.cpus, &file_cpus) {
+                (Some(_), _) => "`--cpus`",
+                (None, Some((_, source))) => source,
+                (None, None) => "the config",
+            };
+            anyhow::bail!(
+                "platform `{}` has at most {} CPUs, but {} asks for {}; \
+                 raise `plat.max-cpu-num` in a config file if the board has more",
+                platform,
+                max,
+                source,
+                cpus
+            );
+        }
+        self.resolved_cpus = Some(cpus);
+        let mut config = config.dump_toml().unwrap();
+        if self.annotate_config {
+            config = annotate_config(&config, &sources);
+        }
+        if self.reproducible {
+            // axconfig-gen sorts the tables and keys itself, so only the line
+            // endings and trailing spaces, which may depend on where the
+            // config files came from, are left to normalize.
+            config = config
+                .lines()
+                .map(|line| line.trim_end().to_string() + "\n")
+                .collect();
         }
 
+        write_config(&config_path, &config)
+            .map_err(|e| crate::scratch::unwritable(&config_dir, "the config file", e))?;
+
         // Set environment variables
         command.env("AX_CONFIG_PATH", config_path.canonicalize().unwrap());
         command.env("AX_PLATFORM", platform.as_ref());
         command.env("AX_ARCH", arch.as_ref());
-        command.env("AX_SMP", self.cpus.to_string());
+        command.env("AX_SMP", self.cpus().to_string());
         command.env("AX_TARGET", target);
         command.env("AX_MODE", profile);
         command.env("AX_LOG", self.log.to_string());
         command.env("AX_IP", self.ip.to_string());
         command.env("AX_GW", self.gateway.to_string());
+        command.env("AX_BUS", bus.as_ref());
+        if let Some(bootargs) = &self.bootargs {
+            command.env("AX_BOOTARGS", bootargs);
+        }
+        if !self.env_guest.is_empty() {
+            command.env(
+                crate::qemu::APP_ENV_ENV,
+                crate::qemu::cmdline(&self.env_guest),
+            );
+        }
+        if let Some(dir) = &self.initramfs {
+            let archive = crate::initramfs::pack(dir, &config_dir, self.reproducible)?;
+            let archive = archive.canonicalize().unwrap();
+            command.env(
+                "AX_INITRAMFS_RS",
+                crate::initramfs::write_section(&archive)?,
+            );
+            command.env("AX_INITRAMFS", archive);
+        }
+        if !self.no_build_info {
+            command.env(
+                "AX_BUILD_INFO",
+                build_info(
+                    platform,
+                    metadata.workspace_root.as_std_path(),
+                    self.reproducible,
+                ),
+            );
+        }
 
-        if !matches!(platform, Platform::Dummy) {
+        if !matches!(platform, Platform::Dummy) && !self.library {
             // Set link flags
+            let pie_flags = if self.pie {
+                // The dynamic relocations are applied by the kernel itself,
+                // so there is no interpreter, and `.text` may have them too.
+                command.env("AX_PIE", "y");
+                "-C relocation-model=pie -C link-arg=-pie -C link-arg=--no-dynamic-linker -C link-arg=-znotext"
+            } else {
+                "-C link-arg=-no-pie"
+            };
             command.env(
                 "RUSTFLAGS",
                 format!(
-                    "-C link-arg=-T{}/linker_{}.lds -C link-arg=-no-pie -C link-arg=-znostart-stop-gc",
+                    "-C link-arg=-T{}/linker_{}.lds {} -C link-arg=-znostart-stop-gc",
                     binary_dir.display(),
-                    platform
+                    platform,
+                    pie_flags
                 ),
             );
         }
@@ -196,278 +845,1813 @@ impl ArceOSOptions {
         Ok(())
     }
 
-    fn features(&self) -> Vec<&Feature> {
-        let mut features = vec![];
+    /// Remaps the paths that end up in the kernel to machine-independent ones
+    /// and sets `SOURCE_DATE_EPOCH`, if `--reproducible` is given.
+    pub fn apply_reproducible(
+        &self,
+        metadata: &Metadata,
+        target_dir: &Path,
+        command: &mut Command,
+    ) {
+        if !self.reproducible {
+            return;
+        }
+
+        let cargo_home = env::var_os("CARGO_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::home_dir().map(|home| home.join(".cargo")));
+        // When several prefixes match, rustc uses the last one, so the more
+        // specific paths come later.
+        let remaps = cargo_home.map(|home| (home, "/cargo")).into_iter().chain([
+            (metadata.workspace_root.clone().into(), "."),
+            (target_dir.to_path_buf(), "target"),
+        ]);
+
+        let mut rustflags = command
+            .get_envs()
+            .find(|(key, _)| *key == "RUSTFLAGS")
+            .and_then(|(_, value)| value)
+            .map(|value| value.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for (from, to) in remaps {
+            if !rustflags.is_empty() {
+                rustflags.push(' ');
+            }
+            rustflags.push_str(&format!("--remap-path-prefix={}={}", from.display(), to));
+        }
+        command.env("RUSTFLAGS", rustflags);
 
-        if self.cpus > 1 {
-            features.push(&SMP);
+        if env::var_os("SOURCE_DATE_EPOCH").is_none() {
+            command.env(
+                "SOURCE_DATE_EPOCH",
+                commit_time(metadata.workspace_root.as_std_path()).to_string(),
+            );
         }
+    }
 
-        if matches!(self.arch(), Arch::Aarch64) && !self.soft_float {
-            features.push(&FP_SIMD);
+    pub fn feature_context(&self) -> features::Context {
+        features::Context {
+            cpus: self.cpus(),
+            arch: self.arch(),
+            platform: self.platform(),
+            soft_float: self.soft_float,
+            net: false,
+            disk: false,
+            graphics: false,
+            bus: self.bus(),
+            app_fp: self.target_feature_preset.is_some(),
+            scheduler: self.scheduler,
+            pie: self.pie,
         }
+    }
 
-        features
+    /// Starts copying the output of the command to the log file, if one is
+    /// asked for.
+    pub fn apply_log_file(&self, command: &mut Command) -> anyhow::Result<()> {
+        match &self.log_file {
+            Some(log_file) => crate::logfile::open(log_file, command),
+            None => Ok(()),
+        }
     }
 
-    pub fn check_features(&self, package: &str, features: &[String]) {
-        for f in self.features() {
-            if f.packages.contains(&package) && !features.contains(&f.name.to_string()) {
-                crate::warn(format!(
-                    "feature `{}` should be enabled for package `{}` when {}",
-                    f.name, package, f.cond
-                ));
-            }
+    /// Makes cargo compile through the build cache, if one is asked for.
+    pub fn apply_cache(&self, command: &mut Command) -> anyhow::Result<Option<Sccache>> {
+        match self.cache {
+            Some(cache) => cache.apply(command),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets up the target features of kernel and app crates from the preset
+    /// and the `target-features` metadata.
+    pub fn apply_target_features(
+        &self,
+        metadata: &ArceOSMetadata,
+        command: &mut Command,
+    ) -> anyhow::Result<()> {
+        let mut kernel = vec![];
+        let mut app = vec![];
+
+        if let Some(TargetFeaturePreset::SplitFp) = self.target_feature_preset {
+            let Some((k, a)) = self.arch().split_fp_target_features() else {
+                anyhow::bail!(
+                    "FP registers are part of the ABI of `{}`, so they cannot be split between kernel and app crates; use `--soft-float` to keep them out of all crates",
+                    self.target()
+                );
+            };
+            kernel.push(k);
+            app.push(a);
+        }
+        kernel.extend(metadata.target_features.kernel.as_deref());
+        app.extend(metadata.target_features.app.as_deref());
+
+        let join = |features: Vec<&str>| {
+            features
+                .into_iter()
+                .filter(|f| !f.is_empty())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        crate::rustc_wrapper::apply(&join(kernel), &join(app), command)
+    }
+
+    pub fn enable_features(
+        &self,
+        ctx: &features::Context,
+        metadata: &Metadata,
+        command: &mut Command,
+    ) -> anyhow::Result<()> {
+        features::enable_ax_features(
+            metadata,
+            &self.ax_features,
+            self.no_default_ax_features,
+            command,
+        )?;
+        if self.auto_features {
+            ctx.enable(metadata, command, |_| true);
+        } else if self.scheduler.is_some() {
+            // The scheduler was asked for explicitly, so its feature is enabled
+            // even without `--auto-features`.
+            ctx.enable(metadata, command, |feature| feature.starts_with("sched_"));
         }
+        Ok(())
     }
 }
 
+/// Returns the time of the last commit in `dir` in seconds since the Unix
+/// epoch, or 0 outside of git.
+fn commit_time(dir: &Path) -> u64 {
+    Command::new("git")
+        .args(["log", "-1", "--format=%ct"])
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Returns what apps can embed with `env!("AX_BUILD_INFO")`, like
+/// `cargo-arceos 0.1.0, aarch64-qemu-virt, commit 0123456789ab-dirty, built
+/// 2025-01-01T00:00:00Z`.
+///
+/// The commit is that of the workspace at `root`. The time is
+/// `SOURCE_DATE_EPOCH` if it is set, or that of the last commit with
+/// `reproducible`.
+fn build_info(platform: Platform, root: &Path, reproducible: bool) -> String {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .current_dir(root)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let commit = match git(&["rev-parse", "--short=12", "HEAD"]) {
+        Some(commit) if git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty()) => {
+            format!("commit {}-dirty", commit)
+        }
+        Some(commit) => format!("commit {}", commit),
+        None => "no commit".to_string(),
+    };
+    let time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or_else(|| {
+            if reproducible {
+                return commit_time(root);
+            }
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    format!(
+        "cargo-arceos {}, {}, {}, built {}",
+        env!("CARGO_PKG_VERSION"),
+        platform,
+        commit,
+        utc_time(time)
+    )
+}
+
+/// Formats seconds since the Unix epoch like `2025-01-01T00:00:00Z`.
+pub fn utc_time(secs: u64) -> String {
+    // The civil date of the day, counted in eras of 400 years from March 1st
+    // of year 0, after Howard Hinnant's `civil_from_days`.
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    let secs = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
 #[derive(Debug, Clone, Args)]
 #[command(next_help_heading = "QEMU Options")]
 pub struct QEMUOptions {
-    /// Simulate a SMP system
-    #[arg(long, env)]
+    /// Simulate a SMP system, with the `-smp` syntax of QEMU [default: the
+    /// number of CPUs of the build]
+    #[arg(long, env = "ARCEOS_SMP")]
     smp: Option<String>,
 
+    /// Run with `--smp` even if the kernel was built for another number of
+    /// CPUs
+    #[arg(long, requires = "smp", env = "ARCEOS_ALLOW_SMP_MISMATCH")]
+    allow_smp_mismatch: bool,
+
+    /// Give each CPU a serial port of its own, printing the lines of every
+    /// CPU tagged with it, or keeping what the other CPUs print in files next
+    /// to the kernel with `files`
+    #[arg(
+        long,
+        require_equals = true,
+        num_args = 0..=1,
+        default_missing_value = "prefix",
+        conflicts_with = "remote",
+        value_parser = enum_variants!(CpuConsoles),
+        value_name = "MODE"
+    )]
+    cpu_consoles: Option<CpuConsoles>,
+
     /// RAM size
     #[arg(short, long)]
     mem: Option<String>,
 
-    /// Device bus type
-    #[arg(long, value_parser = enum_variants!(BusType))]
-    bus: Option<BusType>,
-
     /// Enable network device and possibly specify the type
-    #[arg(long, require_equals = true, value_parser = enum_variants!(NetDevType))]
+    #[arg(long, require_equals = true, env = "ARCEOS_NET", value_parser = enum_variants!(NetDevType))]
     net: Option<Option<NetDevType>>,
 
     /// Dump network packets to a file
     #[arg(long, requires = "net", value_name = "FILE")]
     net_dump: Option<PathBuf>,
 
-    /// Disk image
-    #[arg(short, long)]
+    /// DNS server that the user network of QEMU provides, which the kernel
+    /// gets as `net.dns-server` of its config [default: 10.0.2.3]
+    #[arg(long, requires = "net", value_name = "ADDR")]
+    dns: Option<Ipv4Addr>,
+
+    /// First address that the DHCP server of the user network hands out
+    /// [default: 10.0.2.15]
+    #[arg(long, requires = "net", value_name = "ADDR")]
+    dhcp_start: Option<Ipv4Addr>,
+
+    /// Isolate the guest from the host and the outside, leaving only the
+    /// forwarded ports
+    #[arg(long, requires = "net")]
+    net_restrict: bool,
+
+    /// Disk image, created as an empty FAT32 disk if it does not exist
+    #[arg(short, long, env = "ARCEOS_DISK")]
     disk: Option<PathBuf>,
 
+    /// Size of the disk image created for `--disk`
+    #[arg(long, requires = "disk", default_value = "64M", value_parser = crate::image::parse_size, value_name = "SIZE")]
+    disk_size: u64,
+
+    /// Initial ramdisk, passed to QEMU with `-initrd`
+    #[arg(long, value_name = "FILE")]
+    initrd: Option<PathBuf>,
+
+    /// Firmware for QEMU to boot the kernel with: `default` for the one of
+    /// QEMU, like OpenSBI on RISC-V, `none` to load the kernel without any, or
+    /// a file [default: the one fetched with `cargo arceos firmware fetch`, or
+    /// the one of QEMU]
+    #[arg(long, value_name = "default|none|FILE")]
+    bios: Option<Bios>,
+
     /// Enable graphics
-    #[arg(short, long)]
+    #[arg(short, long, env = "ARCEOS_GRAPHICS")]
     graphics: bool,
 
-    /// Enable hardware acceleration (KVM on Linux or HVF on macOS)
-    #[arg(long)]
-    accel: bool,
+    /// Display device, enabling graphics [default: virtio-gpu]
+    #[arg(long, value_parser = enum_variants!(DisplayDevice))]
+    display_device: Option<DisplayDevice>,
+
+    /// Resolution of the display, like `1280x800`, which the kernel gets as
+    /// `display.width` and `display.height` of its config
+    #[arg(long, value_name = "WxH")]
+    resolution: Option<Resolution>,
+
+    /// Accelerator, like `kvm` or `tcg,thread=multi` with its properties,
+    /// where `auto` uses the one of the host if the guest can run on it.
+    /// Without a value, the one of the host is used
+    #[arg(
+        long,
+        require_equals = true,
+        num_args = 0..=1,
+        default_value = "auto",
+        default_missing_value = HOST_ACCEL,
+        value_name = "ACCEL[,PROPS]"
+    )]
+    accel: Accel,
+
+    /// CPU model to emulate, like `cortex-a76` [default: `host` with a
+    /// hardware accelerator, the one for the platform otherwise]
+    #[arg(long, value_name = "MODEL")]
+    cpu: Option<String>,
 
     /// Enable debugging
     #[arg(short = 'D', long, conflicts_with = "accel")]
     debug: bool,
+
+    /// Boot the kernel on each of the given machines in turn, like
+    /// `virt-7.2,virt-8.2`, and summarize which of them work
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with = "debug",
+        value_name = "MACHINES"
+    )]
+    machine_matrix: Vec<String>,
+
+    /// Load a guest image at a physical address, like `guest.bin@0x80000000`
+    #[arg(long, value_name = "FILE@ADDR")]
+    guest_image: Vec<GuestImage>,
+
+    /// Pass the host PCI device at BDF, bound to `vfio-pci`, through to the
+    /// guest
+    #[arg(long, value_name = "BDF", conflicts_with = "remote")]
+    passthrough: Vec<PciAddress>,
+
+    /// Add an xHCI USB controller
+    #[arg(long)]
+    usb: bool,
+
+    /// Attach a USB device, like `usb-kbd`, `usb-storage,file=disk.img` or
+    /// `host:VENDOR:PRODUCT` for a host device, implying `--usb`
+    #[arg(long, value_name = "DEVICE")]
+    usb_device: Vec<UsbDevice>,
+
+    /// Pass devices to QEMU in a config file next to the image, loaded with
+    /// `-readconfig`, rather than on the command line
+    #[arg(long, conflicts_with = "remote")]
+    readconfig: bool,
+
+    /// Log the QEMU items, like `int,mmu,guest_errors`, to a file next to the
+    /// image, with defaults for early-boot faults of the architecture
+    #[arg(long, require_equals = true, value_name = "ITEMS")]
+    qemu_log: Option<Option<String>>,
+
+    /// Enable the QEMU trace events matching PATTERN, logged to the same file
+    #[arg(long, value_name = "PATTERN")]
+    trace: Vec<String>,
+
+    /// Stop QEMU, or the board with `--hardware`, if the guest has not exited
+    /// after SECS seconds
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Keep the console, the command lines and, if QEMU is stopped, the
+    /// memory and registers of the guest in PATH when a run fails
+    #[arg(long, value_name = "PATH")]
+    crash_dir: Option<PathBuf>,
+
+    /// Run QEMU on HOST over SSH, after copying the kernel and the images
+    /// there. Hardware acceleration is not detected there, use `--accel=kvm`
+    #[arg(long, value_name = "[USER@]HOST")]
+    remote: Option<String>,
+
+    /// Let aarch64 guests report their exit status through semihosting,
+    /// which also gives them access to the files of the host as the user
+    /// running QEMU
+    #[arg(long, env = "ARCEOS_SEMIHOSTING_EXIT")]
+    semihosting_exit: bool,
+
+    /// Make the run reproducible in CI: TCG with deterministic `-icount`
+    /// timing, a virtio RNG with a fixed seed, no display, a timeout of 10
+    /// minutes unless `--timeout` is given, JSON messages, no questions and
+    /// every warning denied
+    #[arg(
+        long,
+        env = "ARCEOS_CI",
+        conflicts_with_all = ["accel", "display_device", "resolution"]
+    )]
+    ci: bool,
 }
 
-#[derive(Debug, Default, Clone, EnumString, VariantNames, AsRefStr)]
+/// The user network of QEMU, 10.0.2.0/24.
+const USER_NET: [u8; 4] = [10, 0, 2, 0];
+/// The DNS server of the user network of QEMU.
+const USER_NET_DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+
+/// The timeout of a run with `--ci`, in seconds.
+const CI_TIMEOUT: u64 = 600;
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, VariantNames, AsRefStr)]
 #[strum(serialize_all = "snake_case")]
-pub enum BusType {
-    #[default]
-    Pci,
-    Mmio,
+pub enum AccelKind {
+    Auto,
+    Kvm,
+    Hvf,
+    Whpx,
+    Tcg,
 }
 
-impl BusType {
-    fn vdev_suffix(&self) -> &'static str {
+/// The hardware accelerator of the host.
+const HOST_ACCEL: &str = if cfg!(target_vendor = "apple") {
+    "hvf"
+} else if cfg!(windows) {
+    "whpx"
+} else {
+    "kvm"
+};
+
+/// The firmware that QEMU boots the kernel with.
+#[derive(Debug, Clone)]
+pub enum Bios {
+    Default,
+    None,
+    File(PathBuf),
+}
+
+impl FromStr for Bios {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default" => Bios::Default,
+            "none" => Bios::None,
+            path => Bios::File(path.into()),
+        })
+    }
+}
+
+impl Bios {
+    fn as_os_str(&self) -> &OsStr {
         match self {
-            BusType::Pci => "pci",
-            BusType::Mmio => "device",
+            Bios::Default => OsStr::new("default"),
+            Bios::None => OsStr::new("none"),
+            Bios::File(path) => path.as_os_str(),
         }
     }
 }
 
-#[derive(Debug, Default, Clone, EnumString, VariantNames, AsRefStr)]
-#[strum(serialize_all = "snake_case")]
-pub enum NetDevType {
-    #[default]
-    User,
-    // TODO: tap / bridge
+/// An accelerator of QEMU, with the properties passed to `-accel`.
+#[derive(Debug, Clone)]
+pub struct Accel {
+    pub kind: AccelKind,
+    pub properties: Option<String>,
+}
+
+impl FromStr for Accel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, properties) = match s.split_once(',') {
+            Some((kind, properties)) => (kind, Some(properties.to_string())),
+            None => (s, None),
+        };
+        let kind = AccelKind::from_str(kind).map_err(|_| {
+            anyhow::anyhow!(
+                "unknown accelerator `{}`, expected one of {}",
+                kind,
+                AccelKind::VARIANTS.join(", ")
+            )
+        })?;
+        if kind == AccelKind::Auto && properties.is_some() {
+            anyhow::bail!("`auto` takes no properties, name the accelerator instead");
+        }
+        Ok(Accel { kind, properties })
+    }
+}
+
+impl fmt::Display for Accel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.kind.as_ref())?;
+        if let Some(properties) = &self.properties {
+            write!(f, ",{}", properties)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "kebab-case")]
+pub enum DisplayDevice {
+    #[default]
+    VirtioGpu,
+    /// A framebuffer in RAM that the guest sets up through `fw_cfg`
+    Ramfb,
+    /// The display of the Bochs VGA adapter, without the VGA, on PCI only
+    BochsDisplay,
+}
+
+/// The resolution of the display, as `WxH`.
+#[derive(Debug, Clone, Copy)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Resolution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| n.trim().parse().ok().filter(|n| *n > 0);
+        match s.split_once(['x', 'X']) {
+            Some((width, height))
+                if let (Some(width), Some(height)) = (parse(width), parse(height)) =>
+            {
+                Ok(Resolution { width, height })
+            }
+            _ => anyhow::bail!("expected `WxH`, like `1280x800`"),
+        }
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// A device on the USB controller.
+#[derive(Debug, Clone)]
+pub enum UsbDevice {
+    /// A mass storage device backed by a raw disk image.
+    Storage(PathBuf),
+    /// A host device passed through by its vendor and product IDs.
+    Host { vendor: u16, product: u16 },
+    /// Any other QEMU USB device, with its properties.
+    Other(String),
+}
+
+impl FromStr for UsbDevice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(ids) = s.strip_prefix("host:") {
+            let (vendor, product) = ids
+                .split_once(':')
+                .with_context(|| format!("expected `host:VENDOR:PRODUCT`, got `{}`", s))?;
+            let id = |id: &str| {
+                u16::from_str_radix(id.trim_start_matches("0x"), 16)
+                    .with_context(|| format!("invalid USB ID `{}` in `{}`", id, s))
+            };
+            return Ok(UsbDevice::Host {
+                vendor: id(vendor)?,
+                product: id(product)?,
+            });
+        }
+        if let Some(properties) = s.strip_prefix("usb-storage") {
+            let file = properties
+                .strip_prefix(",file=")
+                .with_context(|| format!("expected `usb-storage,file=PATH`, got `{}`", s))?;
+            return Ok(UsbDevice::Storage(file.into()));
+        }
+        Ok(UsbDevice::Other(s.to_string()))
+    }
+}
+
+impl fmt::Display for UsbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsbDevice::Storage(file) => write!(f, "usb-storage,file={}", file.display()),
+            UsbDevice::Host { vendor, product } => write!(f, "host:{:04x}:{:04x}", vendor, product),
+            UsbDevice::Other(device) => f.write_str(device),
+        }
+    }
+}
+
+/// A guest kernel image loaded next to a hypervisor.
+#[derive(Debug, Clone)]
+pub struct GuestImage {
+    file: PathBuf,
+    addr: u64,
+}
+
+impl FromStr for GuestImage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (file, addr) = s
+            .rsplit_once('@')
+            .with_context(|| format!("expected `FILE@ADDR`, got `{}`", s))?;
+        let addr = addr.replace('_', "");
+        let addr = match addr.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => addr.parse(),
+        }
+        .with_context(|| format!("invalid address in `{}`", s))?;
+        Ok(GuestImage {
+            file: file.into(),
+            addr,
+        })
+    }
+}
+
+/// Returns the number of CPUs that the QEMU `-smp` option `smp` starts, like
+/// `4` or `cpus=4`, or the product of the topology like `sockets=2,cores=2`.
+fn smp_cpus(smp: &str) -> Option<u32> {
+    let mut cpus = None;
+    let mut topology = None;
+    for (i, part) in smp.split(',').enumerate() {
+        match part.split_once('=') {
+            None if i == 0 => cpus = Some(part.parse().ok()?),
+            Some(("cpus", value)) => cpus = Some(value.parse().ok()?),
+            Some(("sockets" | "dies" | "clusters" | "cores" | "threads", value)) => {
+                topology = Some(topology.unwrap_or(1) * value.parse::<u32>().ok()?)
+            }
+            _ => {}
+        }
+    }
+    cpus.or(topology)
+}
+
+/// Picks the first machine of `platform` that the QEMU `program` supports.
+fn select_machine(platform: Platform, program: &str) -> anyhow::Result<&'static QemuMachineSpec> {
+    let machines = platform.qemu_machines();
+    if machines.is_empty() {
+        anyhow::bail!("unsupported platform: {}", platform);
+    }
+
+    let supported = crate::qemu::machines(program)?;
+    let version = crate::qemu::version(program);
+    let program_name = || version.clone().unwrap_or_else(|| format!("`{}`", program));
+    let Some(machine) = machines.iter().find(|m| {
+        supported.iter().any(|s| s == m.name)
+            && !version
+                .as_deref()
+                .is_some_and(|version| crate::qemu::older_than(version, m.since))
+    }) else {
+        anyhow::bail!(
+            "{} supports none of the machines for platform `{}`: {}",
+            program_name(),
+            platform,
+            machines
+                .iter()
+                .map(|m| format!("`{}` (requires QEMU {} or later)", m.name, m.since))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    };
+
+    if !std::ptr::eq(machine, &machines[0]) {
+        let message = if machine.name != machines[0].name {
+            format!(
+                "machine `{}` requires QEMU {} or later, falling back to `{}`",
+                machines[0].name, machines[0].since, machine.name
+            )
+        } else {
+            format!(
+                "machine `{}` is best run with QEMU {} or later, {} runs it as QEMU {} did",
+                machine.name,
+                machines[0].since,
+                program_name(),
+                machine.since
+            )
+        };
+        crate::warn_code(Code::MachineFallback, message);
+    }
+
+    Ok(machine)
+}
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Runner Options")]
+pub struct RunnerOptions {
+    /// Command to prefix the runner with (e.g. `sudo -E`)
+    #[arg(long, env = "ARCEOS_RUNNER_WRAPPER", value_name = "COMMAND")]
+    runner_wrapper: Option<String>,
+    /// Run through the runner configured for the target in cargo, instead of
+    /// replacing it
+    #[arg(long)]
+    respect_cargo_runner: bool,
+}
+
+/// Returns the program of the runner, which is this executable by its absolute
+/// path. It resolves even if `cargo-arceos` is not in the PATH that cargo runs
+/// it with, like under wrappers of cargo, or if this executable was run by a
+/// path or renamed. The runner is given to cargo as an array, so spaces in
+/// the path need no quoting.
+fn runner_program() -> String {
+    runner_program_at(
+        env::current_exe()
+            .ok()
+            .map(|exe| exe.canonicalize().unwrap_or(exe)),
+    )
+}
+
+/// Returns the program of the runner for this executable at `exe`, if known.
+fn runner_program_at(exe: Option<PathBuf>) -> String {
+    match exe.map(|path| path.into_os_string().into_string()) {
+        Some(Ok(path)) => path,
+        // A path that is not UTF-8 cannot be put in the config of cargo.
+        _ => {
+            crate::verbose(
+                "Runner",
+                "the path of this executable is unknown or not UTF-8, running `cargo-arceos` from PATH",
+            );
+            "cargo-arceos".to_string()
+        }
+    }
+}
+
+/// Returns the `--config` value that makes cargo run `runner` for `target`.
+/// Cargo splits `CARGO_TARGET_<TRIPLE>_RUNNER` on whitespace, so the runner
+/// is passed as an array instead to keep every argument intact.
+fn runner_config(target: &str, runner: Vec<String>) -> String {
+    format!(
+        "target.{}.runner={}",
+        target,
+        runner.into_iter().collect::<toml_edit::Array>()
+    )
+}
+
+impl RunnerOptions {
+    fn wrapper(&self, metadata: &ArceOSMetadata) -> anyhow::Result<Vec<String>> {
+        if let Some(wrapper) = &self.runner_wrapper {
+            crate::split_args(wrapper)
+        } else if let Some(wrapper) = &metadata.runner_wrapper {
+            wrapper.to_args()
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// Returns the command line that runs the runner, before its arguments.
+    pub fn runner(
+        &self,
+        target: &str,
+        arch: Arch,
+        metadata: &ArceOSMetadata,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut runner = self.wrapper(metadata)?;
+        if let Some(configured) = crate::cargo_config::runner(target, arch)
+            && !configured.is_cargo_arceos()
+        {
+            if self.respect_cargo_runner {
+                runner.extend(configured.args);
+            } else {
+                crate::warn_code(
+                    Code::CargoRunnerOverridden,
+                    format!(
+                        "the runner in {} is replaced by the runner of cargo-arceos, pass `--respect-cargo-runner` to run through it",
+                        configured.source
+                    ),
+                );
+            }
+        }
+        runner.extend([runner_program(), "runner".to_string()]);
+        Ok(runner)
+    }
+
+    /// Makes cargo run the runner with `args`.
+    pub fn apply(
+        &self,
+        target: &str,
+        arch: Arch,
+        metadata: &ArceOSMetadata,
+        args: Vec<OsString>,
+        command: &mut Command,
+    ) -> anyhow::Result<()> {
+        let mut runner = self.runner(target, arch, metadata)?;
+        // The config is TOML, which cannot hold arguments that are not UTF-8,
+        // like some paths. They are passed in the environment instead.
+        for (i, arg) in args.into_iter().enumerate() {
+            runner.push(arg.into_string().unwrap_or_else(|arg| {
+                let var = format!("{}{}", crate::RUNNER_ARG_ENV_PREFIX, i);
+                command.env(&var, arg);
+                format!("${}", var)
+            }));
+        }
+
+        command.arg("--config").arg(runner_config(target, runner));
+        Ok(())
+    }
+
+    /// Returns the command that runs the runner with `args` on the host,
+    /// for cargo to build the executable in a container. The executable is
+    /// appended to it.
+    pub fn host_command(
+        &self,
+        target: &str,
+        arch: Arch,
+        metadata: &ArceOSMetadata,
+        args: Vec<OsString>,
+    ) -> anyhow::Result<Command> {
+        let runner = self.runner(target, arch, metadata)?;
+        let mut command = Command::new(&runner[0]);
+        command.args(&runner[1..]).args(args);
+        Ok(command)
+    }
+}
+
+#[derive(Debug, Clone, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum BusType {
+    Pci,
+    Mmio,
+}
+
+impl BusType {
+    fn vdev_suffix(&self) -> &'static str {
+        match self {
+            BusType::Pci => "pci",
+            BusType::Mmio => "device",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum NetDevType {
+    #[default]
+    User,
+    // TODO: tap / bridge
 }
 
 impl QEMUOptions {
-    pub fn apply(&self, target: &str, command: &mut Command) {
-        let mut runner: String = "cargo-arceos runner".to_string();
+    /// Returns whether QEMU runs on another machine with `--remote`.
+    #[inline]
+    pub fn is_remote(&self) -> bool {
+        self.remote.is_some()
+    }
+
+    /// Returns the files and directories that runs write to.
+    pub fn output_paths(&self) -> Vec<PathBuf> {
+        [&self.disk, &self.net_dump, &self.crash_dir]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    pub fn update_feature_context(&self, ctx: &mut features::Context) {
+        ctx.net = self.net.is_some();
+        ctx.disk = self.disk.is_some();
+        ctx.graphics = self.graphics();
+    }
+
+    /// Boots with the firmware fetched for `platform`, unless `--bios` is
+    /// given.
+    pub fn apply_firmware(
+        &mut self,
+        platform: Platform,
+        metadata: &ArceOSMetadata,
+    ) -> anyhow::Result<()> {
+        if self.bios.is_none() {
+            let files = crate::firmware::files(platform, Some(metadata));
+            self.bios = crate::firmware::bios(platform, &files)?.map(Bios::File);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the guest has a display, with `--graphics` or
+    /// `--display-device`, unless `--ci` is given.
+    #[inline]
+    fn graphics(&self) -> bool {
+        !self.ci && (self.graphics || self.display_device.is_some())
+    }
+
+    /// Returns whether the run is set up for CI, with `--ci`.
+    #[inline]
+    pub fn is_ci(&self) -> bool {
+        self.ci
+    }
+
+    /// Returns a config with the resolution of the display, for the display
+    /// driver of the kernel to set it up.
+    pub fn display_config(&self) -> anyhow::Result<Option<String>> {
+        let Some(resolution) = self.resolution else {
+            return Ok(None);
+        };
+        if !self.graphics() {
+            anyhow::bail!("`--resolution` needs a display, add `--graphics` or `--display-device`");
+        }
+        Ok(Some(format!(
+            "[display]\n# Display device of QEMU.\ndevice = \"{}\" # str\n# Resolution of the display.\nwidth = {} # uint\nheight = {} # uint\n",
+            self.display_device.unwrap_or_default().as_ref(),
+            resolution.width,
+            resolution.height
+        )))
+    }
+
+    /// Returns a config with the serial ports of the CPUs for
+    /// `--cpu-consoles`, for each CPU of the kernel to print to its own.
+    pub fn cpu_consoles_config(&self, platform: Platform) -> anyhow::Result<Option<String>> {
+        if self.cpu_consoles.is_none() {
+            return Ok(None);
+        }
+        let ports = platform
+            .qemu_machines()
+            .first()
+            .map_or(&[][..], |machine| machine.serial_ports);
+        if ports.len() < 2 {
+            anyhow::bail!(
+                "platform `{}` has no serial ports for each CPU, which `--cpu-consoles` needs",
+                platform
+            );
+        }
+        let ports = ports
+            .iter()
+            .map(|port| format!("{:#x}", port))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Ok(Some(format!(
+            "[console]\n# Serial ports of the CPUs, CPU n printing to the nth.\ncpu-serial-ports = [{}] # [uint]\n",
+            ports
+        )))
+    }
+
+    /// Returns the number of CPUs that QEMU starts.
+    fn cpus(&self) -> u32 {
+        self.smp
+            .as_deref()
+            .and_then(smp_cpus)
+            .or_else(|| env::var("AX_SMP").ok()?.parse().ok())
+            .unwrap_or(1)
+    }
+
+    /// Returns a config with the DNS server of the user network, for the
+    /// resolver of the kernel to use it.
+    pub fn net_config(&self) -> anyhow::Result<Option<String>> {
+        if self.net.is_none() {
+            return Ok(None);
+        }
+        for (addr, option) in [(self.dns, "--dns"), (self.dhcp_start, "--dhcp-start")] {
+            if let Some(addr) = addr
+                && addr.octets()[..3] != USER_NET[..3]
+            {
+                anyhow::bail!(
+                    "`{}` has to be in the user network of QEMU, {}/24",
+                    option,
+                    Ipv4Addr::from(USER_NET)
+                );
+            }
+        }
+        Ok(Some(format!(
+            "[net]\n# DNS server of the network.\ndns-server = \"{}\" # str\n",
+            self.dns.unwrap_or(USER_NET_DNS)
+        )))
+    }
+
+    /// Returns a config describing where the guest images are placed, for the
+    /// hypervisor to find them.
+    pub fn guest_config(&self) -> anyhow::Result<Option<String>> {
+        if self.guest_image.is_empty() {
+            return Ok(None);
+        }
+
+        let mut regions = vec![];
+        for guest in &self.guest_image {
+            let size = fs::metadata(&guest.file)
+                .with_context(|| format!("failed to read guest image `{}`", guest.file.display()))?
+                .len();
+            if let Some((addr, _)) = regions
+                .iter()
+                .find(|(addr, len)| guest.addr < addr + len && *addr < guest.addr + size)
+            {
+                anyhow::bail!(Diagnostic::new(
+                    Code::GuestImageOverlap,
+                    format!(
+                        "guest image `{}` at {:#x} overlaps the one at {:#x}",
+                        guest.file.display(),
+                        guest.addr,
+                        addr
+                    )
+                ));
+            }
+            regions.push((guest.addr, size));
+        }
 
+        let regions = regions
+            .iter()
+            .map(|(addr, size)| format!("[{:#x}, {:#x}]", addr, size))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Ok(Some(format!(
+            "[guests]\n# Guest images loaded by QEMU, as (base address, size) pairs.\nimages = [{}] # [(uint, uint)]\n",
+            regions
+        )))
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+            .or(self.ci.then_some(CI_TIMEOUT))
+            .map(Duration::from_secs)
+    }
+
+    pub fn runner_args(&self, runner: &mut Vec<OsString>) {
         if let Some(smp) = &self.smp {
-            runner.push_str(" --smp ");
-            runner.push_str(smp);
+            runner.push("--smp".into());
+            runner.push(smp.into());
         }
 
-        if let Some(mem) = &self.mem {
-            runner.push_str(" --mem ");
-            runner.push_str(mem);
+        if self.allow_smp_mismatch {
+            runner.push("--allow-smp-mismatch".into());
         }
 
-        if let Some(bus) = &self.bus {
-            runner.push_str(" --bus ");
-            runner.push_str(bus.as_ref());
+        if let Some(mode) = self.cpu_consoles {
+            runner.push(format!("--cpu-consoles={}", mode.as_ref()).into());
+        }
+
+        if let Some(mem) = &self.mem {
+            runner.push("--mem".into());
+            runner.push(mem.into());
         }
 
         if let Some(net) = &self.net {
-            runner.push_str(" --net");
-            if let Some(net) = net {
-                runner.push('=');
-                runner.push_str(net.as_ref());
-            }
+            runner.push(
+                match net {
+                    Some(net) => format!("--net={}", net.as_ref()),
+                    None => "--net".to_string(),
+                }
+                .into(),
+            );
         }
 
         if let Some(dump) = &self.net_dump {
-            runner.push_str(" --net-dump ");
-            runner.push_str(dump.to_str().unwrap());
+            runner.push("--net-dump".into());
+            runner.push(dump.into());
+        }
+
+        if let Some(dns) = self.dns {
+            runner.push("--dns".into());
+            runner.push(dns.to_string().into());
+        }
+
+        if let Some(dhcp_start) = self.dhcp_start {
+            runner.push("--dhcp-start".into());
+            runner.push(dhcp_start.to_string().into());
+        }
+
+        if self.net_restrict {
+            runner.push("--net-restrict".into());
         }
 
         if let Some(disk) = &self.disk {
-            runner.push_str(" --disk ");
-            runner.push_str(disk.to_str().unwrap());
+            runner.push("--disk".into());
+            runner.push(disk.into());
+            runner.push("--disk-size".into());
+            runner.push(self.disk_size.to_string().into());
+        }
+
+        if let Some(initrd) = &self.initrd {
+            runner.push("--initrd".into());
+            runner.push(initrd.into());
+        }
+
+        if let Some(bios) = &self.bios {
+            runner.push("--bios".into());
+            runner.push(bios.as_os_str().into());
         }
 
         if self.graphics {
-            runner.push_str(" --graphics");
+            runner.push("--graphics".into());
         }
 
-        if self.accel {
-            runner.push_str(" --accel");
+        if let Some(device) = self.display_device {
+            runner.push("--display-device".into());
+            runner.push(device.as_ref().into());
+        }
+
+        if let Some(resolution) = self.resolution {
+            runner.push("--resolution".into());
+            runner.push(resolution.to_string().into());
+        }
+
+        runner.push(format!("--accel={}", self.accel).into());
+
+        if let Some(cpu) = &self.cpu {
+            runner.push("--cpu".into());
+            runner.push(cpu.into());
         }
 
         if self.debug {
-            runner.push_str(" --debug");
+            runner.push("--debug".into());
         }
 
-        command.env(
-            format!("CARGO_TARGET_{}_RUNNER", target.to_shouty_snake_case()),
-            runner,
-        );
+        if !self.machine_matrix.is_empty() {
+            runner.push("--machine-matrix".into());
+            runner.push(self.machine_matrix.join(",").into());
+        }
+
+        for address in &self.passthrough {
+            runner.push("--passthrough".into());
+            runner.push(address.to_string().into());
+        }
+
+        if self.usb {
+            runner.push("--usb".into());
+        }
+
+        for device in &self.usb_device {
+            runner.push("--usb-device".into());
+            runner.push(device.to_string().into());
+        }
+
+        for guest in &self.guest_image {
+            runner.push("--guest-image".into());
+            runner.push(format!("{}@{:#x}", guest.file.display(), guest.addr).into());
+        }
+
+        if self.readconfig {
+            runner.push("--readconfig".into());
+        }
+
+        if let Some(items) = &self.qemu_log {
+            runner.push(
+                match items {
+                    Some(items) => format!("--qemu-log={}", items),
+                    None => "--qemu-log".to_string(),
+                }
+                .into(),
+            );
+        }
+
+        for pattern in &self.trace {
+            runner.push("--trace".into());
+            runner.push(pattern.into());
+        }
+
+        if let Some(timeout) = self.timeout {
+            runner.push("--timeout".into());
+            runner.push(timeout.to_string().into());
+        }
+
+        if self.ci {
+            runner.push("--ci".into());
+        }
+
+        if self.semihosting_exit {
+            runner.push("--semihosting-exit".into());
+        }
+
+        if let Some(dir) = &self.crash_dir {
+            runner.push("--crash-dir".into());
+            runner.push(dir.into());
+        }
+
+        if let Some(host) = &self.remote {
+            runner.push("--remote".into());
+            runner.push(host.into());
+        }
     }
 
-    pub fn execute(self, binary: PathBuf) -> anyhow::Result<()> {
+    /// Runs `binary` in QEMU and returns the exit code for the status reported
+    /// by the guest.
+    pub fn execute(
+        self,
+        image: &ImageOptions,
+        metrics: &MetricsOptions,
+        binary: PathBuf,
+    ) -> anyhow::Result<i32> {
+        let build_time = timings::since_started();
         let platform = Platform::from_str(&env::var("AX_PLATFORM")?)?;
+        crate::signal::install();
+
+        let arch: Arch = platform.into();
+
+        let program = arch.qemu_program();
+        if let Some(host) = &self.remote {
+            crate::remote::set(host);
+        }
+        let machine = select_machine(platform, program)?;
+        if machine.needs_firmware && !matches!(self.bios, Some(Bios::File(_))) {
+            anyhow::bail!(
+                "machine `{}` of QEMU {} only boots the kernel from firmware, fetch it with `cargo arceos firmware fetch {}` or pass `--bios FILE`",
+                machine.name,
+                machine.since,
+                platform
+            );
+        }
+        self.check_smp()?;
+        if self.cpu_consoles.is_some() && self.cpus() as usize > machine.serial_ports.len() {
+            anyhow::bail!(
+                "machine `{}` has {} serial ports, too few for `--cpu-consoles` with {} CPUs",
+                machine.name,
+                machine.serial_ports.len(),
+                self.cpus()
+            );
+        }
+        if let BusType::Mmio = BusType::from_str(&env::var("AX_BUS")?)? {
+            if !self.passthrough.is_empty() {
+                anyhow::bail!("PCI passthrough needs the PCI bus, use `--bus pci`");
+            }
+            if self.usb || !self.usb_device.is_empty() {
+                anyhow::bail!("the USB controller needs the PCI bus, use `--bus pci`");
+            }
+            if self.display_device == Some(DisplayDevice::BochsDisplay) {
+                anyhow::bail!("`bochs-display` needs the PCI bus, use `--bus pci`");
+            }
+            self.check_virtio_mmio(machine)?;
+        }
+        for address in &self.passthrough {
+            crate::vfio::check(address)?;
+        }
+        if let Some(disk) = &self.disk
+            && !disk.exists()
+        {
+            crate::sdcard::empty_disk(self.disk_size, disk)
+                .with_context(|| format!("failed to create `{}`", disk.display()))?;
+            crate::info(
+                "Created",
+                format!(
+                    "empty FAT32 disk `{}` of {} MiB",
+                    disk.display(),
+                    self.disk_size.div_ceil(1 << 20)
+                ),
+            );
+        }
 
-        let (machine, mem) = match platform {
-            Platform::AARCH64_QEMU_VIRT => ("virt", None),
-            Platform::AARCH64_RASPI4 => ("raspi4b", Some("2G")),
-            Platform::LOONGARCH64_QEMU_VIRT => ("virt", Some("1G")),
-            Platform::RISCV64_QEMU_VIRT => ("virt", None),
-            Platform::X86_64_QEMU_Q35 => ("q35", None),
-            _ => anyhow::bail!("unsupported platform: {}", platform),
+        let start = Instant::now();
+        let kernel = crate::image::prepare(platform, image, binary.clone())?;
+        // QEMU boots the uncompressed image, the compressed one is made for
+        // loading it onto boards.
+        crate::image::compress(image, kernel.clone())?;
+        let image_time = start.elapsed();
+
+        // Other machines of the matrix run without the needs of the platform
+        // on its own machine.
+        let machines: Vec<(&str, Option<&QemuMachineSpec>)> = if self.machine_matrix.is_empty() {
+            vec![(machine.name, Some(machine))]
+        } else {
+            let supported = crate::qemu::machines(program)?;
+            for name in &self.machine_matrix {
+                if !supported.contains(name) {
+                    anyhow::bail!(
+                        "{} does not support machine `{}`",
+                        crate::qemu::version(program).unwrap_or_else(|| format!("`{}`", program)),
+                        name
+                    );
+                }
+            }
+            self.machine_matrix
+                .iter()
+                .map(|name| (name.as_str(), (*name == machine.name).then_some(machine)))
+                .collect()
         };
 
-        let arch: Arch = platform.into();
+        metrics.start()?;
+        let mut runs = vec![];
+        let mut scraped = vec![];
+        let mut times = vec![];
+        for (machine, spec) in machines {
+            let mut command = self.command(program, arch, machine, spec, &kernel)?;
+            let socket = crate::qemu::add_qmp(&mut command);
+            if self.remote.is_some() {
+                command = crate::remote::prepare(&command)?;
+            }
+            let crash_dir = self.crash_dir.as_ref().map(|dir| {
+                if self.machine_matrix.is_empty() {
+                    dir.clone()
+                } else {
+                    dir.join(machine)
+                }
+            });
+            if let Some(dir) = &crash_dir {
+                crate::crash::clear(dir);
+            }
+            let follower = (self.cpu_consoles == Some(CpuConsoles::Prefix)).then(|| {
+                crate::demux::follow(
+                    (1..self.cpus())
+                        .map(|cpu| crate::demux::log_path(&kernel, cpu))
+                        .collect(),
+                )
+            });
+            let run = crate::qemu::run(&mut command, &socket, self.timeout(), crash_dir.as_deref());
+            if let Some(follower) = follower {
+                follower.stop();
+            }
+            let run = run?;
+            if self.cpu_consoles == Some(CpuConsoles::Files) {
+                for cpu in 1..self.cpus() {
+                    crate::info(
+                        "Logged",
+                        format!(
+                            "console of CPU {} to `{}`",
+                            cpu,
+                            crate::demux::log_path(&kernel, cpu).display()
+                        ),
+                    );
+                }
+            }
 
-        let program = match arch {
-            Arch::Aarch64 => "qemu-system-aarch64",
-            Arch::Loongarch64 => "qemu-system-loongarch64",
-            Arch::Riscv64 => "qemu-system-riscv64",
-            Arch::X86_64 => "qemu-system-x86_64",
+            let exit = match run.signal {
+                Some(signal) => GuestExit::Interrupted(signal),
+                None if run.timed_out => GuestExit::TimedOut,
+                None => GuestExit::new(run.status),
+            };
+            let exit = crate::harness::finish(exit, run.elapsed);
+            if let Some(dir) = &crash_dir
+                && exit != GuestExit::Success
+            {
+                crate::crash::write(dir, &command, &run, exit)?;
+            }
+            runs.push(MachineRun {
+                machine: machine.to_string(),
+                exit,
+            });
+            scraped.extend(crate::metrics::take(machine));
+            times.push((run.boot, run.elapsed));
+            if run.signal.is_some() {
+                break;
+            }
+        }
+
+        let manifest = RunManifest {
+            binary,
+            platform: platform.to_string(),
+            runs,
         };
-        let kernel = match arch {
-            Arch::Aarch64 | Arch::Riscv64 => {
-                let kernel = binary.with_extension("bin");
+        manifest.write()?;
+        metrics.write(&manifest.binary, &scraped)?;
 
-                let mut command = Command::new("rust-objcopy");
-                command
-                    .args(["--strip-all", "-O", "binary"])
-                    .arg(binary)
-                    .arg(&kernel);
-                crate::run_command(&mut command)?;
+        if !self.machine_matrix.is_empty() {
+            for (run, (_, elapsed)) in manifest.runs.iter().zip(&times) {
+                let result = if run.exit == GuestExit::Success {
+                    style("ok").for_stderr().green()
+                } else {
+                    style("failed").for_stderr().red()
+                };
+                crate::info(
+                    "Matrix",
+                    format!(
+                        "{}: {} ({}) in {}",
+                        run.machine,
+                        result,
+                        run.exit,
+                        timings::format(*elapsed)
+                    ),
+                );
+            }
+        }
+
+        let mut phases = vec![];
+        if let Some(build_time) = build_time {
+            phases.push(format!("build {}", timings::format(build_time)));
+        }
+        phases.push(format!("image {}", timings::format(image_time)));
+        if let Some(boot) = times.first().and_then(|(boot, _)| *boot) {
+            phases.push(format!("boot {}", timings::format(boot)));
+        }
+        let run_time = times.iter().map(|(_, elapsed)| *elapsed).sum();
+        phases.push(format!("run {}", timings::format(run_time)));
+        crate::info("Timings", phases.join(", "));
+        let exit = manifest.exit();
+        if exit != GuestExit::Success {
+            crate::warn(exit);
+        }
+
+        Ok(exit.code())
+    }
+
+    /// Checks that the kernel knows the virtio-mmio transports that QEMU
+    /// plugs the devices into on `machine`.
+    fn check_virtio_mmio(&self, machine: &QemuMachineSpec) -> anyhow::Result<()> {
+        let devices = u64::from(self.net.is_some())
+            + u64::from(self.disk.is_some())
+            + u64::from(self.ci)
+            + u64::from(
+                self.graphics()
+                    && self.display_device.unwrap_or_default() == DisplayDevice::VirtioGpu,
+            );
+        if devices == 0 {
+            return Ok(());
+        }
+        let Some(transports) = &machine.virtio_mmio else {
+            anyhow::bail!(
+                "machine `{}` has no virtio-mmio transports, use `--bus pci`",
+                machine.name
+            );
+        };
+        if devices > transports.count {
+            anyhow::bail!(
+                "machine `{}` has only {} virtio-mmio transports",
+                machine.name,
+                transports.count
+            );
+        }
 
-                kernel
+        let Ok(path) = env::var("AX_CONFIG_PATH") else {
+            return Ok(());
+        };
+        let toml = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file `{}`", path))?;
+        let config = Config::from_toml(&toml)
+            .map_err(|e| anyhow::anyhow!("failed to parse config file `{}`: {}", path, e))?;
+        let regions = crate::memmap::config_regions(&config, "devices", "virtio-mmio-regions");
+
+        // QEMU plugs devices into the free transport with the highest address.
+        for slot in (transports.count - devices..transports.count).rev() {
+            let base = transports.base + slot * transports.size;
+            if !regions.iter().any(|(start, _)| *start == base) {
+                crate::warn_code(
+                    Code::VirtioMmioMismatch,
+                    format!(
+                        "QEMU puts a device at {:#x}, which is not in `devices.virtio-mmio-regions` of `{}`",
+                        base, path
+                    ),
+                );
             }
-            _ => binary,
+        }
+        Ok(())
+    }
+
+    /// Checks that `--smp` starts as many CPUs as the kernel was built for,
+    /// since ArceOS waits for all of its CPUs to come up and hangs otherwise.
+    fn check_smp(&self) -> anyhow::Result<()> {
+        let Some(smp) = &self.smp else {
+            return Ok(());
         };
+        let Some(run) = smp_cpus(smp) else {
+            return Ok(());
+        };
+        let built: u32 = env::var("AX_SMP")?.parse()?;
+        if run == built {
+            return Ok(());
+        }
+        let message = format!(
+            "the kernel is built for {} CPUs, but `--smp {}` starts {}",
+            built, smp, run
+        );
+        if !self.allow_smp_mismatch {
+            anyhow::bail!(
+                "{}; build with `--cpus {}`, or pass `--allow-smp-mismatch`",
+                message,
+                run
+            );
+        }
+        crate::warn_code(Code::SmpMismatch, message);
+        Ok(())
+    }
 
+    /// Returns the QEMU command that boots `kernel` on `machine`, as `spec`
+    /// describes if given.
+    fn command(
+        &self,
+        program: &str,
+        arch: Arch,
+        machine: &str,
+        spec: Option<&QemuMachineSpec>,
+        kernel: &Path,
+    ) -> anyhow::Result<Command> {
         let mut command = Command::new(program);
 
+        let mut machine = machine.to_string();
+        for property in spec.map_or(&[][..], |spec| spec.properties) {
+            machine = format!("{},{}", machine, property);
+        }
         let cpus = env::var("AX_SMP").unwrap();
         command
             .arg("-kernel")
             .arg(kernel)
-            .args(["-machine", machine])
+            .args(["-machine", &machine])
             .args(["-smp", self.smp.as_deref().unwrap_or(&cpus)]);
 
-        if let Arch::Aarch64 = arch {
-            command.args(["-cpu", "cortex-a72"]);
+        if let Some(initrd) = &self.initrd {
+            command.arg("-initrd").arg(initrd);
+        }
+
+        // QEMU picks its own firmware without `-bios`, which is what `default`
+        // means unless the platform names it.
+        let bios = match &self.bios {
+            Some(Bios::Default) | None => spec.and_then(|spec| spec.bios).map(OsStr::new),
+            Some(bios) => Some(bios.as_os_str()),
+        };
+        if let Some(bios) = bios {
+            command.arg("-bios").arg(bios);
         }
 
-        if let Some(mem) = self.mem.as_deref().or(mem) {
+        // The environment and args of the app are read again on each run, so
+        // changing them needs no rebuild.
+        let var = |key| env::var(key).unwrap_or_default();
+        let mut app_env = var(crate::qemu::APP_ENV_ENV);
+        if let Some(entry) = crate::harness::guest_env() {
+            if !app_env.is_empty() {
+                app_env.push(' ');
+            }
+            app_env.push_str(&crate::qemu::cmdline(&[entry]));
+        }
+        let cmdline = crate::qemu::kernel_cmdline(
+            &var("AX_BOOTARGS"),
+            &app_env,
+            &var(crate::qemu::APP_ARGS_ENV),
+        );
+        if !cmdline.is_empty() {
+            command.args(["-append", &cmdline]);
+        }
+
+        if self.semihosting_exit && arch != Arch::Aarch64 {
+            crate::warn_code(
+                Code::IgnoredOption,
+                "`--semihosting-exit` option is ignored, only aarch64 guests exit through semihosting",
+            );
+        }
+        crate::exit::qemu_args(arch, self.semihosting_exit, &mut command);
+
+        if let Some(mem) = self.mem.as_deref().or(spec.and_then(|spec| spec.mem)) {
             command.args(["-m", mem]);
         }
 
-        let vdev_suffix = self.bus.unwrap_or_default().vdev_suffix();
+        let bus = BusType::from_str(&env::var("AX_BUS")?)?;
+        let vdev_suffix = bus.vdev_suffix();
 
-        if let Some(net) = self.net {
-            command
-                .arg("-device")
-                .arg(format!("virtio-net-{},netdev=net0", vdev_suffix))
-                .arg("-netdev");
-            match net.unwrap_or_default() {
-                NetDevType::User => {
-                    command.arg("user,id=net0,hostfwd=tcp::5555-:5555,hostfwd=udp::5555-:5555")
-                }
-            };
+        // Devices and their backends, which may go to a config file.
+        let mut devices: Vec<(&str, OsString)> = vec![];
+        for device in spec.map_or(&[][..], |spec| spec.devices) {
+            devices.push(("device", device.into()));
         }
 
-        if let Some(dump) = self.net_dump {
-            command.arg("-object").arg(format!(
-                "filter-dump,id=dump0,netdev=net0,file={}",
-                dump.display()
+        if let Some(net) = &self.net {
+            devices.push((
+                "device",
+                format!("virtio-net-{},netdev=net0", vdev_suffix).into(),
+            ));
+            devices.push((
+                "netdev",
+                match net.clone().unwrap_or_default() {
+                    NetDevType::User => {
+                        let mut netdev =
+                            "user,id=net0,hostfwd=tcp::5555-:5555,hostfwd=udp::5555-:5555"
+                                .to_string();
+                        if let Some(dns) = self.dns {
+                            netdev.push_str(&format!(",dns={}", dns));
+                        }
+                        if let Some(dhcp_start) = self.dhcp_start {
+                            netdev.push_str(&format!(",dhcpstart={}", dhcp_start));
+                        }
+                        if self.net_restrict {
+                            netdev.push_str(",restrict=on");
+                        }
+                        netdev.into()
+                    }
+                },
             ));
         }
 
-        if let Some(disk) = self.disk {
-            command
-                .arg("-device")
-                .arg(format!("virtio-blk-{},drive=disk0", vdev_suffix))
-                .arg("-drive")
-                .arg(format!(
-                    "id=disk0,if=none,format=raw,file={}",
-                    disk.display()
-                ));
+        if let Some(dump) = &self.net_dump {
+            devices.push((
+                "object",
+                crate::qemu::path_value("filter-dump,id=dump0,netdev=net0,file=", dump),
+            ));
         }
 
-        if self.graphics {
-            command
-                .arg("-device")
-                .arg(format!("virtio-gpu-{}", vdev_suffix))
-                .args(["-vga", "none", "-serial", "mon:stdio"]);
+        if let Some(disk) = &self.disk {
+            devices.push((
+                "device",
+                format!("virtio-blk-{},drive=disk0", vdev_suffix).into(),
+            ));
+            devices.push((
+                "drive",
+                crate::qemu::path_value("id=disk0,if=none,format=raw,file=", disk),
+            ));
+        }
+
+        // The RNG of the guest is seeded with `-seed`.
+        if self.ci {
+            devices.push(("object", "rng-builtin,id=rng0".into()));
+            devices.push((
+                "device",
+                format!("virtio-rng-{},rng=rng0", vdev_suffix).into(),
+            ));
+        }
+
+        for address in &self.passthrough {
+            devices.push(("device", format!("vfio-pci,host={}", address).into()));
+        }
+
+        if self.usb || !self.usb_device.is_empty() {
+            devices.push(("device", "qemu-xhci,id=xhci".into()));
+        }
+        for (i, device) in self.usb_device.iter().enumerate() {
+            match device {
+                UsbDevice::Storage(file) => {
+                    devices.push((
+                        "device",
+                        format!("usb-storage,bus=xhci.0,drive=usbdisk{}", i).into(),
+                    ));
+                    devices.push((
+                        "drive",
+                        crate::qemu::path_value(
+                            &format!("id=usbdisk{},if=none,format=raw,file=", i),
+                            file,
+                        ),
+                    ));
+                }
+                UsbDevice::Host { vendor, product } => devices.push((
+                    "device",
+                    format!(
+                        "usb-host,bus=xhci.0,vendorid={:#06x},productid={:#06x}",
+                        vendor, product
+                    )
+                    .into(),
+                )),
+                UsbDevice::Other(device) => {
+                    let (driver, properties) = device.split_once(',').unwrap_or((device, ""));
+                    let mut device = format!("{},bus=xhci.0", driver);
+                    if !properties.is_empty() {
+                        device.push(',');
+                        device.push_str(properties);
+                    }
+                    devices.push(("device", device.into()));
+                }
+            }
+        }
+
+        for guest in &self.guest_image {
+            let mut device = crate::qemu::path_value("loader,file=", &guest.file);
+            device.push(format!(",addr={:#x},force-raw=on", guest.addr));
+            devices.push(("device", device));
+        }
+
+        if self.graphics() {
+            let mut device = match self.display_device.unwrap_or_default() {
+                DisplayDevice::VirtioGpu => format!("virtio-gpu-{}", vdev_suffix),
+                DisplayDevice::Ramfb => "ramfb".to_string(),
+                DisplayDevice::BochsDisplay => "bochs-display".to_string(),
+            };
+            // The guest sets up the resolution of ramfb itself, from its
+            // config.
+            if let Some(resolution) = self.resolution
+                && self.display_device != Some(DisplayDevice::Ramfb)
+            {
+                device.push_str(&format!(
+                    ",xres={},yres={}",
+                    resolution.width, resolution.height
+                ));
+            }
+            devices.push(("device", device.into()));
+            command.args(["-vga", "none", "-serial", "mon:stdio"]);
         } else {
             command.arg("-nographic");
         }
 
+        // The console is only put on stdio with `-nographic` if no other
+        // serial port is given.
+        if self.cpu_consoles.is_some() {
+            if !self.graphics() {
+                command.args(["-serial", "mon:stdio"]);
+            }
+            for cpu in 1..self.cpus() {
+                command.arg("-serial").arg(crate::qemu::path_value(
+                    "file:",
+                    &crate::demux::log_path(kernel, cpu),
+                ));
+            }
+        }
+
+        if self.readconfig {
+            let config = crate::scratch::path(kernel).with_extension("qemu.cfg");
+            crate::qemu::write_config(&config, &devices)?;
+            command.arg("-readconfig").arg(config);
+        } else {
+            for (group, value) in devices {
+                command.arg(format!("-{}", group)).arg(value);
+            }
+        }
+
+        if self.qemu_log.is_some() || !self.trace.is_empty() {
+            if let Some(items) = &self.qemu_log {
+                let items = items.as_deref().unwrap_or(match arch {
+                    Arch::X86_64 => "int,cpu_reset,guest_errors,unimp",
+                    Arch::Aarch64 | Arch::Riscv64 => "int,mmu,guest_errors,unimp",
+                    Arch::Loongarch64 => "int,guest_errors,unimp",
+                });
+                command.args(["-d", items]);
+            }
+            for pattern in &self.trace {
+                command.args(["-trace", pattern]);
+            }
+            let log = crate::scratch::path(kernel).with_extension("qemu.log");
+            crate::info("Logging", format!("QEMU to `{}`", log.display()));
+            command.arg("-D").arg(log);
+        }
+
+        let mut cpu_model = self.cpu.as_deref();
         if self.debug {
             command.args(["-s", "-S"]);
         } else {
-            let accel = self.accel || {
-                (if cfg!(target_arch = "x86_64") {
-                    matches!(arch, Arch::X86_64)
-                } else if cfg!(target_arch = "aarch64") {
-                    matches!(arch, Arch::Aarch64)
-                } else {
-                    false
-                }) && {
-                    if cfg!(target_vendor = "apple") {
-                        true
-                    } else {
-                        Path::new("/dev/kvm").exists()
-                    }
+            let host_arch = if cfg!(target_arch = "x86_64") {
+                Some(Arch::X86_64)
+            } else if cfg!(target_arch = "aarch64") {
+                Some(Arch::Aarch64)
+            } else {
+                None
+            };
+            let (accel, reason) = match self.accel.kind {
+                AccelKind::Auto if self.ci => (AccelKind::Tcg, "`--ci` is given".to_string()),
+                AccelKind::Auto if self.qemu_log.is_some() => {
+                    // QEMU logs little of an accelerated guest.
+                    (AccelKind::Tcg, "`--qemu-log` is given".to_string())
+                }
+                AccelKind::Auto if crate::remote::is_set() => (
+                    AccelKind::Tcg,
+                    "the host of `--remote` is not probed".to_string(),
+                ),
+                AccelKind::Auto if host_arch.is_none_or(|host| host.as_ref() != arch.as_ref()) => (
+                    AccelKind::Tcg,
+                    format!("the guest is {} but the host is not", arch),
+                ),
+                AccelKind::Auto if cfg!(target_vendor = "apple") => {
+                    (AccelKind::Hvf, "the host has HVF".to_string())
                 }
+                AccelKind::Auto if Path::new("/dev/kvm").exists() => {
+                    (AccelKind::Kvm, "`/dev/kvm` exists".to_string())
+                }
+                AccelKind::Auto => (AccelKind::Tcg, "`/dev/kvm` does not exist".to_string()),
+                kind => (kind, "`--accel` is given".to_string()),
             };
-            if accel {
-                command.args([
-                    "-cpu",
-                    "host",
-                    "-accel",
-                    if cfg!(target_vendor = "apple") {
-                        "hvf"
-                    } else {
-                        "kvm"
-                    },
-                ]);
+            crate::verbose("Accel", format!("{}, since {}", accel.as_ref(), reason));
+            if accel != AccelKind::Tcg || self.accel.kind == AccelKind::Tcg || self.ci {
+                let mut value = accel.as_ref().to_string();
+                if let Some(properties) = &self.accel.properties {
+                    value = format!("{},{}", value, properties);
+                }
+                command.arg("-accel").arg(value);
             }
+            cpu_model = cpu_model.or((accel != AccelKind::Tcg).then_some("host"));
+        }
+        if let Some(cpu) = cpu_model.or(spec.and_then(|spec| spec.cpu)) {
+            command.args(["-cpu", cpu]);
+        }
+        // Every instruction takes as long in virtual time on every run, which
+        // `sleep=off` does not wait for in real time.
+        if self.ci {
+            command.args(["-icount", "shift=0,align=off,sleep=off", "-seed", "0"]);
+        }
+
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the runner back out of the `--config` value for `target`.
+    fn parse_runner_config(target: &str, config: &str) -> Vec<String> {
+        let doc: toml_edit::DocumentMut = config.parse().unwrap();
+        doc["target"][target]["runner"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|arg| arg.as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn runner_wrapper_round_trips() {
+        let options = RunnerOptions {
+            runner_wrapper: Some(r#"env 'A=x y' "B=\"q\"" C=a\ b '' "d\e""#.to_string()),
+            respect_cargo_runner: false,
+        };
+        let mut runner = options.wrapper(&ArceOSMetadata::default()).unwrap();
+        assert_eq!(runner, ["env", "A=x y", r#"B="q""#, "C=a b", "", r"d\e"]);
+
+        runner.extend(["/path/to/cargo-arceos".to_string(), "runner".to_string()]);
+        let target = "x86_64-unknown-none";
+        let config = runner_config(target, runner.clone());
+        assert_eq!(parse_runner_config(target, &config), runner);
+    }
+
+    #[test]
+    fn runner_program_with_spaces() {
+        let program = runner_program_at(Some("/home/a user/my tools/cargo-arceos".into()));
+        assert_eq!(program, "/home/a user/my tools/cargo-arceos");
+
+        let target = "aarch64-unknown-none-softfloat";
+        let runner = vec![program.clone(), "runner".to_string()];
+        let parsed = parse_runner_config(target, &runner_config(target, runner));
+        assert_eq!(parsed, [program.as_str(), "runner"]);
+    }
+
+    #[test]
+    fn runner_program_falls_back() {
+        assert_eq!(runner_program_at(None), "cargo-arceos");
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            let exe = OsString::from_vec(b"/tmp/\xff/cargo-arceos".to_vec());
+            assert_eq!(runner_program_at(Some(exe.into())), "cargo-arceos");
         }
+    }
 
-        crate::run_command(&mut command)
+    #[test]
+    fn runner_config_keeps_special_characters() {
+        let runner: Vec<String> = ["a\"b", "c\\d", "e'f", "g\nh", "$HOME"]
+            .map(str::to_string)
+            .into();
+        let target = "riscv64gc-unknown-none-elf";
+        let config = runner_config(target, runner.clone());
+        assert_eq!(parse_runner_config(target, &config), runner);
     }
 }
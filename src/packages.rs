@@ -0,0 +1,110 @@
+//! The outcome of each package of the workspace in a build of several of
+//! them, like with `--workspace`, summarized at the end. With `--keep-going`,
+//! cargo builds whatever it can after an error, so that the summary shows
+//! every package that fails.
+
+use std::collections::BTreeMap;
+
+use cargo_metadata::diagnostic::DiagnosticLevel;
+use console::style;
+
+/// What the build reported for a package.
+#[derive(Debug, Default)]
+struct Package {
+    name: String,
+    built: bool,
+    errors: usize,
+    warnings: usize,
+    /// The features that it was missing, see [`crate::features`].
+    missing_features: usize,
+}
+
+/// The packages of the workspace reported by a build, by package id.
+#[derive(Debug, Default)]
+pub struct Summary {
+    packages: BTreeMap<String, Package>,
+}
+
+impl Summary {
+    /// Follows the workspace members with the given package ids.
+    pub fn new(members: &[cargo_metadata::PackageId]) -> Summary {
+        let packages = members
+            .iter()
+            .map(|id| {
+                // The name is in the id, as in `path+file:///dir#name@0.1.0`,
+                // or `path+file:///dir/name#0.1.0` where it is the directory.
+                let (source, version) = id.repr.rsplit_once('#').unwrap_or(("", &id.repr));
+                let name = match version.split_once('@') {
+                    Some((name, _)) => name,
+                    None => source.rsplit('/').next().unwrap_or(source),
+                };
+                (
+                    id.repr.clone(),
+                    Package {
+                        name: name.to_string(),
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        Summary { packages }
+    }
+
+    pub fn artifact(&mut self, package_id: &str, missing_features: usize) {
+        if let Some(package) = self.packages.get_mut(package_id) {
+            package.built = true;
+            package.missing_features += missing_features;
+        }
+    }
+
+    pub fn message(&mut self, package_id: &str, level: &DiagnosticLevel) {
+        if let Some(package) = self.packages.get_mut(package_id) {
+            match level {
+                DiagnosticLevel::Error | DiagnosticLevel::Ice => package.errors += 1,
+                DiagnosticLevel::Warning => package.warnings += 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// Prints the outcome of each package, if the build reported more than
+    /// one.
+    pub fn print(&self) {
+        let reported: Vec<&Package> = self
+            .packages
+            .values()
+            .filter(|package| package.built || package.errors > 0)
+            .collect();
+        if reported.len() < 2 {
+            return;
+        }
+
+        let count =
+            |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+        eprintln!();
+        for package in &reported {
+            // Padded before it is styled, which the width would count.
+            let status = if package.errors > 0 {
+                style(format!("{:>12}", "failed")).red().bold()
+            } else {
+                style(format!("{:>12}", "built")).green().bold()
+            };
+            let mut notes = vec![];
+            if package.errors > 0 {
+                notes.push(count(package.errors, "error"));
+            }
+            if package.warnings > 0 {
+                notes.push(count(package.warnings, "warning"));
+            }
+            if package.missing_features > 0 {
+                notes.push(count(package.missing_features, "missing feature"));
+            }
+            let notes = if notes.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", notes.join(", "))
+            };
+            eprintln!("{} {}{}", status, package.name, notes);
+        }
+    }
+}
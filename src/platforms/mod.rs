@@ -1,6 +1,11 @@
+use std::{collections::HashMap, fs, path::Path, sync::OnceLock};
+
+use anyhow::{Context, bail};
 use axconfig_gen::Config;
 use strum::{AsRefStr, Display, EnumString, VariantNames};
 
+use crate::{image::ImageLayout, options::BusType};
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr, Display)]
 #[strum(serialize_all = "kebab-case")]
@@ -10,37 +15,340 @@ pub enum Platform {
     AARCH64_PHYTIUM_PI,
     AARCH64_QEMU_VIRT,
     AARCH64_RASPI4,
+    #[strum(to_string = "aarch64-rk3588j")]
+    AARCH64_RK3588J,
+    #[strum(to_string = "loongarch64-2k1000")]
+    LOONGARCH64_2K1000,
     LOONGARCH64_QEMU_VIRT,
     RISCV64_QEMU_VIRT,
+    RISCV64_VISIONFIVE2,
     #[strum(to_string = "x86_64-pc-oslab")]
     X86_64_PC_OSLAB,
+    #[strum(to_string = "x86_64-qemu-microvm")]
+    X86_64_QEMU_MICROVM,
     #[strum(to_string = "x86_64-qemu-q35")]
     X86_64_QEMU_Q35,
 }
 
-impl From<Platform> for Config {
-    fn from(platform: Platform) -> Config {
-        let mut config =
-            Config::from_toml(include_str!("defconfig.toml")).expect("base config is invalid");
-        let plat = Config::from_toml(match platform {
+/// How QEMU emulates a platform: the machine and what the kernel needs on it.
+#[derive(Debug)]
+pub struct QemuMachineSpec {
+    pub name: &'static str,
+    /// The first QEMU version providing the machine as described.
+    pub since: &'static str,
+    /// Properties appended to `-machine`.
+    pub properties: &'static [&'static str],
+    /// CPU model used without `--cpu` and hardware acceleration.
+    pub cpu: Option<&'static str>,
+    /// Memory size used unless overridden.
+    pub mem: Option<&'static str>,
+    /// Devices that the kernel needs, passed with `-device`.
+    pub devices: &'static [&'static str],
+    /// The `-bios` value for the firmware of QEMU, if it has to be named to
+    /// be loaded.
+    pub bios: Option<&'static str>,
+    /// Whether the machine only boots the kernel with firmware from a file.
+    pub needs_firmware: bool,
+    /// The virtio-mmio transports of the machine.
+    pub virtio_mmio: Option<VirtioMmio>,
+    /// The addresses of the serial ports that QEMU can attach, from the one
+    /// of the console, if the kernel can tell them apart.
+    pub serial_ports: &'static [u64],
+}
+
+impl QemuMachineSpec {
+    /// A spec that needs nothing, for the others to start from.
+    const BASE: Self = Self {
+        name: "",
+        since: "",
+        properties: &[],
+        cpu: None,
+        mem: None,
+        devices: &[],
+        bios: None,
+        needs_firmware: false,
+        virtio_mmio: None,
+        serial_ports: &[],
+    };
+}
+
+/// Virtio-mmio transports at `count` consecutive slots of `size` bytes from
+/// `base`.
+#[derive(Debug)]
+pub struct VirtioMmio {
+    pub base: u64,
+    pub size: u64,
+    pub count: u64,
+}
+
+impl Platform {
+    /// Returns the QEMU machines able to run the platform, in order of
+    /// preference. Later ones are fallbacks for older QEMU versions.
+    pub fn qemu_machines(self) -> &'static [QemuMachineSpec] {
+        match self {
+            Platform::AARCH64_QEMU_VIRT => &[QemuMachineSpec {
+                name: "virt",
+                since: "2.0",
+                cpu: Some("cortex-a72"),
+                virtio_mmio: Some(VirtioMmio {
+                    base: 0x0a00_0000,
+                    size: 0x200,
+                    count: 32,
+                }),
+                ..QemuMachineSpec::BASE
+            }],
+            Platform::AARCH64_RASPI4 => &[QemuMachineSpec {
+                name: "raspi4b",
+                since: "9.0",
+                mem: Some("2G"),
+                ..QemuMachineSpec::BASE
+            }],
+            // QEMU boots ELF kernels directly from 8.1, earlier versions jump
+            // to the kernel from the UEFI firmware of the machine.
+            Platform::LOONGARCH64_QEMU_VIRT => &[
+                QemuMachineSpec {
+                    name: "virt",
+                    since: "8.1",
+                    cpu: Some("la464"),
+                    mem: Some("1G"),
+                    ..QemuMachineSpec::BASE
+                },
+                QemuMachineSpec {
+                    name: "virt",
+                    since: "7.1",
+                    cpu: Some("la464"),
+                    mem: Some("1G"),
+                    needs_firmware: true,
+                    ..QemuMachineSpec::BASE
+                },
+            ],
+            // The kernel runs in S-mode on OpenSBI, which QEMU only loads
+            // without being asked from 5.1.
+            Platform::RISCV64_QEMU_VIRT => &[QemuMachineSpec {
+                name: "virt",
+                since: "2.12",
+                bios: Some("default"),
+                virtio_mmio: Some(VirtioMmio {
+                    base: 0x1000_1000,
+                    size: 0x1000,
+                    count: 8,
+                }),
+                ..QemuMachineSpec::BASE
+            }],
+            Platform::X86_64_QEMU_Q35 => &[QemuMachineSpec {
+                name: "q35",
+                since: "1.6",
+                // The I/O ports of COM1 to COM4.
+                serial_ports: &[0x3f8, 0x2f8, 0x3e8, 0x2e8],
+                ..QemuMachineSpec::BASE
+            }],
+            // Without ACPI, the kernel finds the virtio-mmio transports in its
+            // config rather than in the tables of the firmware.
+            Platform::X86_64_QEMU_MICROVM => &[QemuMachineSpec {
+                name: "microvm",
+                since: "6.0",
+                properties: &["acpi=off"],
+                virtio_mmio: Some(VirtioMmio {
+                    base: 0xfeb0_0000,
+                    size: 0x200,
+                    count: 24,
+                }),
+                ..QemuMachineSpec::BASE
+            }],
+            _ => &[],
+        }
+    }
+
+    /// Returns how images have to be padded or checksummed for the boot ROM
+    /// of the platform to accept them, as `image.pad-to` and
+    /// `image.append-checksum` in its config ask for.
+    pub fn image_layout(self) -> anyhow::Result<ImageLayout> {
+        let config = Config::from(self);
+        let value = |key| {
+            config.config_at("image", key).map(|item| {
+                let value = item.value().to_toml_value();
+                value.trim_matches('"').to_string()
+            })
+        };
+        let pad_to = value("pad-to")
+            .map(|value| value.parse())
+            .transpose()
+            .with_context(|| format!("invalid `image.pad-to` of platform `{}`", self))?;
+        let checksum = value("append-checksum")
+            .map(|value| value.parse())
+            .transpose()
+            .with_context(|| format!("invalid `image.append-checksum` of platform `{}`", self))?;
+        Ok(ImageLayout { pad_to, checksum })
+    }
+
+    fn has_pci(self) -> bool {
+        Config::from(self)
+            .config_at("devices", "pci-ecam-base")
+            .is_some_and(|item| item.value().to_toml_value() != "0")
+    }
+
+    /// Returns the device bus used when none is specified, which is PCI if
+    /// the platform has a PCIe ECAM space and MMIO otherwise.
+    pub fn default_bus(self) -> BusType {
+        if matches!(self, Platform::Dummy) || self.has_pci() {
+            BusType::Pci
+        } else {
+            BusType::Mmio
+        }
+    }
+
+    /// Checks that kernels for the platform can be linked position-independent
+    /// with `--pie` and relocate themselves at boot.
+    pub fn check_pie(self) -> anyhow::Result<()> {
+        match Arch::from(self) {
+            _ if matches!(self, Platform::Dummy) => {
+                bail!("platform `{}` does not build a kernel", self)
+            }
+            // Multiboot loads the kernel at the address that it was linked at,
+            // and the boot code of LoongArch does not relocate itself yet.
+            Arch::X86_64 | Arch::Loongarch64 => {
+                bail!("platform `{}` does not support relocatable kernels", self)
+            }
+            Arch::Aarch64 | Arch::Riscv64 => Ok(()),
+        }
+    }
+
+    pub fn check_bus(self, bus: &BusType) -> anyhow::Result<()> {
+        if matches!(self, Platform::Dummy) {
+            return Ok(());
+        }
+
+        match bus {
+            BusType::Pci if !self.has_pci() => {
+                bail!("platform `{}` does not have a PCI bus", self)
+            }
+            BusType::Mmio
+                if matches!(Arch::from(self), Arch::X86_64)
+                    && !matches!(self, Platform::X86_64_QEMU_MICROVM) =>
+            {
+                bail!("platform `{}` does not support the MMIO bus", self)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The name of the config that all platforms start from.
+const DEFCONFIG: &str = "defconfig";
+
+/// The configs loaded from `--platform-dir`, by the names of their files.
+static LOADED: OnceLock<HashMap<String, &'static str>> = OnceLock::new();
+
+/// Loads the configs in `dir` that replace the built-in ones, named like
+/// `<platform>.toml` and `defconfig.toml`. Only the first call loads them.
+pub fn load_dir(dir: &Path) -> anyhow::Result<()> {
+    if LOADED.get().is_some() {
+        return Ok(());
+    }
+    let mut loaded = HashMap::new();
+    let entries =
+        fs::read_dir(dir).with_context(|| format!("failed to read `{}`", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        if name != DEFCONFIG && name.parse::<Platform>().is_err() {
+            crate::warn(format!(
+                "`{}` is not the config of a known platform, it is ignored",
+                path.display()
+            ));
+            continue;
+        }
+        let toml = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read `{}`", path.display()))?;
+        Config::from_toml(&toml)
+            .map_err(|e| anyhow::anyhow!("failed to parse `{}`: {}", path.display(), e))?;
+        crate::verbose("Platform", format!("config `{}`", path.display()));
+        loaded.insert(name, &*Box::leak(toml.into_boxed_str()));
+    }
+    LOADED.set(loaded).ok();
+    Ok(())
+}
+
+/// Returns the config named `name` from `--platform-dir`, or `built_in`.
+fn loaded_or(name: &str, built_in: &'static str) -> &'static str {
+    LOADED
+        .get()
+        .and_then(|loaded| loaded.get(name).copied())
+        .unwrap_or(built_in)
+}
+
+/// Returns the config that all platforms start from.
+pub fn defconfig() -> &'static str {
+    loaded_or(DEFCONFIG, include_str!("defconfig.toml"))
+}
+
+/// Writes the built-in configs into `dir`, for `--platform-dir` to load
+/// after they are changed, and returns how many were written. Existing files
+/// are only replaced if `force`.
+pub fn export(dir: &Path, force: bool) -> anyhow::Result<usize> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create `{}`", dir.display()))?;
+    let configs = std::iter::once((DEFCONFIG, include_str!("defconfig.toml"))).chain(
+        Platform::VARIANTS.iter().map(|name| {
+            let platform: Platform = name.parse().unwrap();
+            (*name, platform.built_in_config_toml())
+        }),
+    );
+    let mut count = 0;
+    for (name, toml) in configs {
+        let path = dir.join(format!("{}.toml", name));
+        if !force && path.exists() {
+            bail!(
+                "`{}` already exists, pass `--force` to replace it",
+                path.display()
+            );
+        }
+        crate::write_atomic(&path, toml)
+            .with_context(|| format!("failed to write `{}`", path.display()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+impl Platform {
+    /// Returns the config of the platform, which is merged into
+    /// [`defconfig`].
+    pub fn config_toml(self) -> &'static str {
+        loaded_or(self.as_ref(), self.built_in_config_toml())
+    }
+
+    fn built_in_config_toml(self) -> &'static str {
+        match self {
             Platform::Dummy => include_str!("dummy.toml"),
             Platform::AARCH64_BSTA1000B => include_str!("aarch64-bsta1000b.toml"),
             Platform::AARCH64_PHYTIUM_PI => include_str!("aarch64-phytium-pi.toml"),
             Platform::AARCH64_QEMU_VIRT => include_str!("aarch64-qemu-virt.toml"),
             Platform::AARCH64_RASPI4 => include_str!("aarch64-raspi4.toml"),
+            Platform::AARCH64_RK3588J => include_str!("aarch64-rk3588j.toml"),
+            Platform::LOONGARCH64_2K1000 => include_str!("loongarch64-2k1000.toml"),
             Platform::LOONGARCH64_QEMU_VIRT => include_str!("loongarch64-qemu-virt.toml"),
             Platform::RISCV64_QEMU_VIRT => include_str!("riscv64-qemu-virt.toml"),
+            Platform::RISCV64_VISIONFIVE2 => include_str!("riscv64-visionfive2.toml"),
             Platform::X86_64_PC_OSLAB => include_str!("x86_64-pc-oslab.toml"),
             Platform::X86_64_QEMU_Q35 => include_str!("x86_64-qemu-q35.toml"),
-        })
-        .expect("platform config is invalid");
+            Platform::X86_64_QEMU_MICROVM => include_str!("x86_64-qemu-microvm.toml"),
+        }
+    }
+}
+
+impl From<Platform> for Config {
+    fn from(platform: Platform) -> Config {
+        let mut config = Config::from_toml(defconfig()).expect("base config is invalid");
+        let plat = Config::from_toml(platform.config_toml()).expect("platform config is invalid");
         config.merge(&plat).expect("failed to load built-in config");
 
         config
     }
 }
 
-#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, VariantNames, AsRefStr, Display)]
 #[strum(serialize_all = "kebab-case")]
 pub enum Arch {
     Aarch64,
@@ -50,6 +358,31 @@ pub enum Arch {
     X86_64,
 }
 
+impl Arch {
+    /// Returns the QEMU program that emulates the architecture.
+    pub fn qemu_program(self) -> &'static str {
+        match self {
+            Arch::Aarch64 => "qemu-system-aarch64",
+            Arch::Loongarch64 => "qemu-system-loongarch64",
+            Arch::Riscv64 => "qemu-system-riscv64",
+            Arch::X86_64 => "qemu-system-x86_64",
+        }
+    }
+
+    /// Returns the target features for kernel and app crates that keep FP and
+    /// SIMD registers out of the kernel while apps may use them.
+    ///
+    /// On AArch64 and RISC-V, FP registers are part of the ABI of the targets,
+    /// so all crates have to agree on them and there is no such split.
+    pub fn split_fp_target_features(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Arch::Loongarch64 => Some(("-lsx", "")),
+            Arch::X86_64 => Some(("", "+sse,+sse2")),
+            Arch::Aarch64 | Arch::Riscv64 => None,
+        }
+    }
+}
+
 impl From<Arch> for Platform {
     fn from(arch: Arch) -> Self {
         match arch {
@@ -67,11 +400,30 @@ impl From<Platform> for Arch {
             Platform::AARCH64_BSTA1000B
             | Platform::AARCH64_PHYTIUM_PI
             | Platform::AARCH64_QEMU_VIRT
-            | Platform::AARCH64_RASPI4 => Self::Aarch64,
-            Platform::LOONGARCH64_QEMU_VIRT => Self::Loongarch64,
-            Platform::RISCV64_QEMU_VIRT => Self::Riscv64,
-            Platform::X86_64_PC_OSLAB | Platform::X86_64_QEMU_Q35 => Self::X86_64,
+            | Platform::AARCH64_RASPI4
+            | Platform::AARCH64_RK3588J => Self::Aarch64,
+            Platform::LOONGARCH64_2K1000 | Platform::LOONGARCH64_QEMU_VIRT => Self::Loongarch64,
+            Platform::RISCV64_QEMU_VIRT | Platform::RISCV64_VISIONFIVE2 => Self::Riscv64,
+            Platform::X86_64_PC_OSLAB
+            | Platform::X86_64_QEMU_Q35
+            | Platform::X86_64_QEMU_MICROVM => Self::X86_64,
             Platform::Dummy => Self::X86_64,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Padding;
+
+    #[test]
+    fn image_layout_from_config() {
+        let layout = Platform::RISCV64_VISIONFIVE2.image_layout().unwrap();
+        assert!(matches!(layout.pad_to, Some(Padding::Align(4096))));
+        assert!(layout.checksum.is_none());
+
+        let layout = Platform::RISCV64_QEMU_VIRT.image_layout().unwrap();
+        assert!(layout.pad_to.is_none() && layout.checksum.is_none());
+    }
+}
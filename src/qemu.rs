@@ -0,0 +1,528 @@
+use std::{
+    env,
+    ffi::OsString,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+
+use crate::platforms::Arch;
+
+/// How long the guest gets to power off before QEMU is terminated.
+const POWERDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long QEMU gets to exit after being terminated before it is killed.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long QEMU gets to write the memory of the guest to a file.
+#[cfg(unix)]
+const DUMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns how to install `package`, named as `(apt, dnf, pacman, apk,
+/// brew)` call it, with the package manager of the host.
+fn install_hint(package: [&str; 5]) -> String {
+    let [apt, dnf, pacman, apk, brew] = package;
+    if cfg!(target_os = "macos") {
+        return format!("install it with `brew install {}`", brew);
+    }
+    if cfg!(windows) {
+        return "install it from https://www.qemu.org/download/#windows and add it to PATH"
+            .to_string();
+    }
+    // The distribution, or the ones that it is based on.
+    let release = std::fs::read_to_string("/etc/os-release").unwrap_or_default();
+    let ids: Vec<&str> = release
+        .lines()
+        .filter_map(|line| {
+            line.strip_prefix("ID=")
+                .or_else(|| line.strip_prefix("ID_LIKE="))
+        })
+        .flat_map(|ids| ids.trim_matches('"').split_whitespace())
+        .collect();
+    let has = |id| ids.contains(&id);
+    if has("debian") || has("ubuntu") {
+        format!("install it with `sudo apt install {}`", apt)
+    } else if has("fedora") || has("rhel") {
+        format!("install it with `sudo dnf install {}`", dnf)
+    } else if has("arch") {
+        format!("install it with `sudo pacman -S {}`", pacman)
+    } else if has("alpine") {
+        format!("install it with `sudo apk add {}`", apk)
+    } else {
+        format!("install `{}` with the package manager of the system", apt)
+    }
+}
+
+/// Fails before the kernel of `arch` is built if a program that running it
+/// needs is missing, with how to install it. QEMU is only needed if `qemu`.
+pub fn check_programs(arch: Arch, qemu: bool) -> anyhow::Result<()> {
+    let program = arch.qemu_program();
+    if qemu && crate::find_program(program).is_none() {
+        let package = match arch {
+            Arch::Aarch64 => [
+                "qemu-system-arm",
+                "qemu-system-aarch64",
+                "qemu-system-aarch64",
+                "qemu-system-aarch64",
+                "qemu",
+            ],
+            Arch::Loongarch64 => [
+                "qemu-system-misc",
+                "qemu-system-loongarch64",
+                "qemu-system-loongarch64",
+                "qemu-system-loongarch64",
+                "qemu",
+            ],
+            Arch::Riscv64 => [
+                "qemu-system-misc",
+                "qemu-system-riscv",
+                "qemu-system-riscv",
+                "qemu-system-riscv64",
+                "qemu",
+            ],
+            Arch::X86_64 => [
+                "qemu-system-x86",
+                "qemu-system-x86",
+                "qemu-system-x86",
+                "qemu-system-x86_64",
+                "qemu",
+            ],
+        };
+        bail!(
+            "`{}` is not found in PATH, {}",
+            program,
+            install_hint(package)
+        );
+    }
+    if crate::image::is_raw(arch) && crate::find_program("rust-objcopy").is_none() {
+        bail!(
+            "`rust-objcopy` is not found in PATH, which makes the raw image of the kernel, install it with `cargo install cargo-binutils && rustup component add llvm-tools`"
+        );
+    }
+    Ok(())
+}
+
+/// The args for the app, as they go on its command line, which the build and
+/// the runner get.
+pub const APP_ARGS_ENV: &str = "AX_APP_ARGS";
+
+/// The environment variables for the app, as `KEY=VAL` entries of the
+/// command line, which the build and the runner get.
+pub const APP_ENV_ENV: &str = "AX_APP_ENV";
+
+/// Returns the command line of the kernel: `bootargs`, the `KEY=VAL` entries
+/// of the environment of the app and, after `--`, its args, like on Linux.
+pub fn kernel_cmdline(bootargs: &str, app_env: &str, app_args: &str) -> String {
+    let mut cmdline = bootargs.to_string();
+    for (part, prefix) in [(app_env, ""), (app_args, "-- ")] {
+        if part.is_empty() {
+            continue;
+        }
+        if !cmdline.is_empty() {
+            cmdline.push(' ');
+        }
+        cmdline.push_str(prefix);
+        cmdline.push_str(part);
+    }
+    cmdline
+}
+
+/// Checks that `s` is a `KEY=VAL` for the environment of the app.
+pub fn parse_env_entry(s: &str) -> anyhow::Result<String> {
+    match s.split_once('=') {
+        Some((key, _))
+            if key.starts_with(|c: char| !c.is_ascii_digit())
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok(s.to_string())
+        }
+        _ => anyhow::bail!(
+            "expected `KEY=VAL` with a key of letters, digits and `_`, not starting with a digit"
+        ),
+    }
+}
+
+/// Returns `args` as they go on the command line of the kernel, where args
+/// with whitespace or quotes are put in double quotes.
+pub fn cmdline(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            if !arg.is_empty()
+                && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\')
+            {
+                arg.clone()
+            } else {
+                format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the names of the machines supported by the QEMU `program`.
+pub fn machines(program: &str) -> anyhow::Result<Vec<String>> {
+    let output = crate::remote::command(program)
+        .args(["-machine", "help"])
+        .output()
+        .with_context(|| format!("failed to run `{}`", program))?;
+    if !output.status.success() {
+        bail!("`{} -machine help` failed with {}", program, output.status);
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Returns the version of the QEMU `program`, like `QEMU 8.2.2`.
+pub fn version(program: &str) -> Option<String> {
+    let output = crate::remote::command(program)
+        .arg("--version")
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout
+        .lines()
+        .next()?
+        .strip_prefix("QEMU emulator version ")?
+        .split_whitespace()
+        .next()?;
+    Some(format!("QEMU {}", version))
+}
+
+/// Returns whether the QEMU `version`, like `QEMU 8.2.2`, is older than
+/// `since`, like `8.1`.
+pub fn older_than(version: &str, since: &str) -> bool {
+    let numbers = |version: &str| -> Vec<u32> {
+        version
+            .trim_start_matches("QEMU ")
+            .split('.')
+            .map_while(|number| number.parse().ok())
+            .collect()
+    };
+    numbers(version) < numbers(since)
+}
+
+/// Returns the option value `prefix` followed by `path`, with the commas in
+/// the path doubled so that QEMU does not split the value there. The path is
+/// kept as it is otherwise, even if it is not UTF-8.
+pub fn path_value(prefix: &str, path: &Path) -> OsString {
+    let mut value = OsString::from(prefix);
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        let mut bytes = value.into_vec();
+        for &byte in path.as_os_str().as_bytes() {
+            if byte == b',' {
+                bytes.push(b',');
+            }
+            bytes.push(byte);
+        }
+        value = OsString::from_vec(bytes);
+    }
+    #[cfg(not(unix))]
+    value.push(path.to_string_lossy().replace(',', ",,"));
+    value
+}
+
+/// Splits an option value into its properties at single commas, turning
+/// doubled commas back into one.
+pub fn split_properties(value: &str) -> Vec<String> {
+    let mut properties = vec![String::new()];
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != ',' {
+            properties.last_mut().unwrap().push(c);
+        } else if chars.next_if_eq(&',').is_some() {
+            properties.last_mut().unwrap().push(',');
+        } else {
+            properties.push(String::new());
+        }
+    }
+    properties
+}
+
+/// Writes `-device`, `-netdev` and similar options to a file for QEMU to
+/// load with `-readconfig`. Each option is given by its group, like `device`,
+/// and its value in the syntax of the command line.
+pub fn write_config(path: &Path, options: &[(&str, OsString)]) -> anyhow::Result<()> {
+    let mut config = String::from("# QEMU configuration written by cargo-arceos\n");
+    for (group, value) in options {
+        let Some(value) = value.to_str() else {
+            bail!(
+                "cannot write `-{} {}` to a QEMU config file, as it is not UTF-8",
+                group,
+                value.display()
+            );
+        };
+        // The first value of some groups is given without its key.
+        let implied = match *group {
+            "device" => Some("driver"),
+            "netdev" => Some("type"),
+            "object" => Some("qom-type"),
+            _ => None,
+        };
+        let mut id = None;
+        let mut properties = vec![];
+        for (i, property) in split_properties(value).into_iter().enumerate() {
+            match property.split_once('=') {
+                Some(("id", value)) => id = Some(value.to_string()),
+                Some((key, value)) => properties.push((key.to_string(), value.to_string())),
+                None => match implied {
+                    Some(key) if i == 0 => properties.push((key.to_string(), property)),
+                    _ => bail!("cannot write `-{} {}` to a QEMU config file", group, value),
+                },
+            }
+        }
+        if properties.iter().any(|(_, value)| value.contains('"')) {
+            bail!("cannot write `-{} {}` to a QEMU config file", group, value);
+        }
+
+        config.push('\n');
+        match id {
+            Some(id) => config.push_str(&format!("[{} \"{}\"]\n", group, id)),
+            None => config.push_str(&format!("[{}]\n", group)),
+        }
+        for (key, value) in properties {
+            config.push_str(&format!("  {} = \"{}\"\n", key, value));
+        }
+    }
+
+    crate::write_atomic(path, config)
+        .with_context(|| format!("failed to write QEMU config `{}`", path.display()))
+}
+
+/// A connection to the QMP socket of QEMU.
+#[cfg(unix)]
+struct Qmp {
+    stream: std::os::unix::net::UnixStream,
+    reader: std::io::BufReader<std::os::unix::net::UnixStream>,
+}
+
+#[cfg(unix)]
+impl Qmp {
+    fn connect(socket: &Path) -> std::io::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(socket)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let reader = std::io::BufReader::new(stream.try_clone()?);
+        let mut qmp = Qmp { stream, reader };
+
+        // Skip the greeting, then leave capabilities negotiation mode.
+        qmp.read_line()?;
+        qmp.execute(serde_json::json!({ "execute": "qmp_capabilities" }))?;
+        Ok(qmp)
+    }
+
+    fn read_line(&mut self) -> std::io::Result<String> {
+        use std::io::BufRead;
+
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        Ok(line)
+    }
+
+    /// Runs `command` and returns its result, skipping events sent meanwhile.
+    fn execute(&mut self, command: serde_json::Value) -> std::io::Result<serde_json::Value> {
+        writeln!(self.stream, "{}", command)?;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let mut response: serde_json::Value = serde_json::from_str(&line)?;
+            if let Some(error) = response.get("error") {
+                let desc = error["desc"].as_str().unwrap_or("unknown error");
+                return Err(std::io::Error::other(desc.to_string()));
+            }
+            if let Some(result) = response.get_mut("return") {
+                return Ok(result.take());
+            }
+        }
+    }
+}
+
+/// Asks the guest to power off through the QMP socket at `socket`.
+#[cfg(unix)]
+fn powerdown(socket: &Path) -> std::io::Result<()> {
+    let mut qmp = Qmp::connect(socket)?;
+    qmp.execute(serde_json::json!({ "execute": "system_powerdown" }))?;
+    Ok(())
+}
+
+/// Writes the registers of all CPUs and the memory of the guest to `dir`
+/// through the QMP socket at `socket`.
+#[cfg(unix)]
+fn dump(socket: &Path, dir: &Path) -> anyhow::Result<()> {
+    let mut qmp = Qmp::connect(socket)?;
+    let registers = qmp.execute(serde_json::json!({
+        "execute": "human-monitor-command",
+        "arguments": { "command-line": "info registers -a" },
+    }))?;
+    std::fs::write(
+        dir.join("registers.txt"),
+        registers.as_str().unwrap_or_default(),
+    )?;
+
+    // QEMU opens the file itself, so it needs an absolute path, or one on the
+    // remote host to be fetched from there.
+    let memory = std::path::absolute(dir.join("memory.elf"))?;
+    let remote = crate::remote::is_set()
+        .then(|| crate::remote::output_path(&format!("memory-{}.elf", std::process::id())));
+    let file = remote
+        .clone()
+        .unwrap_or_else(|| memory.display().to_string());
+    qmp.stream.set_read_timeout(Some(DUMP_TIMEOUT))?;
+    qmp.execute(serde_json::json!({
+        "execute": "dump-guest-memory",
+        "arguments": { "paging": false, "protocol": format!("file:{}", file) },
+    }))
+    .context("failed to dump guest memory")?;
+    if let Some(remote) = remote {
+        crate::remote::fetch(&remote, &memory)?;
+    }
+    Ok(())
+}
+
+/// The result of [`run`].
+pub struct QemuRun {
+    pub status: ExitStatus,
+    /// The signal received by the runner, if any.
+    pub signal: Option<i32>,
+    /// Whether QEMU was stopped because the run took too long.
+    pub timed_out: bool,
+    /// Time until the guest printed something.
+    pub boot: Option<Duration>,
+    /// Time until QEMU exited.
+    pub elapsed: Duration,
+    /// The console output, if kept for a crash directory.
+    pub console: Vec<u8>,
+}
+
+/// Makes the QEMU `command` listen for QMP on a socket, and returns its path.
+/// It is added before the command is wrapped for `--remote`, which forwards
+/// the socket.
+pub fn add_qmp(command: &mut Command) -> PathBuf {
+    let socket = env::temp_dir().join(format!("cargo-arceos-{}.qmp", std::process::id()));
+    #[cfg(unix)]
+    command
+        .arg("-qmp")
+        .arg(format!("unix:{},server=on,wait=off", socket.display()));
+    socket
+}
+
+/// Runs QEMU until it exits. If the runner receives SIGINT or SIGTERM in the
+/// meantime, or the run takes longer than `timeout`, the guest is asked to
+/// power off through the QMP socket at `socket`, and QEMU is terminated if it
+/// does not. Before that, the state of the guest is dumped to `crash_dir` if
+/// given.
+pub fn run(
+    command: &mut Command,
+    socket: &Path,
+    timeout: Option<Duration>,
+    crash_dir: Option<&Path>,
+) -> anyhow::Result<QemuRun> {
+    crate::events::qemu_launched(command);
+    crate::info("Running", format!("`{}`", crate::command_line(command)));
+    let program = command.get_program().to_string_lossy().into_owned();
+    let start = Instant::now();
+    let mut child = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run `{}`", program))?;
+
+    // Pass the console through to see when the guest starts printing.
+    let first_output = Arc::new(OnceLock::new());
+    let forward = {
+        let mut stdout = child.stdout.take().unwrap();
+        let first_output = first_output.clone();
+        let keep = crash_dir.is_some();
+        thread::spawn(move || {
+            let mut console = vec![];
+            let mut buf = [0; 4096];
+            while let Ok(n @ 1..) = stdout.read(&mut buf) {
+                first_output.get_or_init(Instant::now);
+                if keep {
+                    console.extend_from_slice(&buf[..n]);
+                }
+                crate::logfile::console(&buf[..n]);
+                crate::metrics::console(&buf[..n]);
+                crate::harness::console(&buf[..n]);
+                let written = if crate::demux::is_prefixed() {
+                    crate::demux::print(0, &buf[..n])
+                } else {
+                    let mut out = std::io::stdout().lock();
+                    out.write_all(&buf[..n]).and_then(|_| out.flush())
+                };
+                if written.is_err() {
+                    break;
+                }
+            }
+            console
+        })
+    };
+
+    let mut stopping: Option<Instant> = None;
+    let mut terminated = false;
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if stopping.is_none() && timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+            timed_out = true;
+        }
+        match stopping {
+            None if timed_out || crate::signal::received().is_some() => {
+                #[cfg(unix)]
+                if let Some(dir) = crash_dir {
+                    crate::info("Dumping", format!("guest state to `{}`", dir.display()));
+                    if let Err(e) = std::fs::create_dir_all(dir)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| dump(socket, dir))
+                    {
+                        crate::warn(format!("failed to dump the guest state: {:#}", e));
+                    }
+                }
+                crate::info("Stopping", format!("`{}`", program));
+                #[cfg(unix)]
+                if let Err(e) = powerdown(socket) {
+                    crate::warn(format!("failed to power off the guest: {}", e));
+                }
+                stopping = Some(Instant::now());
+            }
+            Some(since) if since.elapsed() > POWERDOWN_TIMEOUT + TERMINATE_TIMEOUT => {
+                child.kill().ok();
+            }
+            Some(since) if since.elapsed() > POWERDOWN_TIMEOUT && !terminated => {
+                #[cfg(unix)]
+                crate::signal::kill(child.id(), libc::SIGTERM);
+                #[cfg(not(unix))]
+                child.kill().ok();
+                terminated = true;
+            }
+            _ => {}
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let elapsed = start.elapsed();
+    let console = forward.join().unwrap_or_default();
+
+    std::fs::remove_file(socket).ok();
+    Ok(QemuRun {
+        status,
+        signal: crate::signal::received(),
+        timed_out,
+        boot: first_output.get().map(|t| t.duration_since(start)),
+        elapsed,
+        console,
+    })
+}
@@ -0,0 +1,277 @@
+//! Running QEMU on another machine over SSH, for `--remote`.
+//!
+//! The files that the QEMU command refers to are copied to a directory on the
+//! remote host first, with `rsync` if it is installed so that unchanged files
+//! are skipped, and with `scp` otherwise. The console is streamed back by
+//! `ssh`, which also returns the exit code of QEMU, and the QMP socket of
+//! QEMU is forwarded by it to the same path here, for the runner to stop the
+//! guest and dump its state as if QEMU ran locally.
+
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use anyhow::{Context, bail};
+
+/// The directory on the remote host that files are copied to, relative to
+/// the home directory.
+const REMOTE_DIR: &str = ".cache/cargo-arceos";
+
+static HOST: OnceLock<String> = OnceLock::new();
+
+/// Makes QEMU run on `host`, like `user@lab-server`.
+pub fn set(host: &str) {
+    HOST.get_or_init(|| host.to_string());
+}
+
+pub fn is_set() -> bool {
+    HOST.get().is_some()
+}
+
+/// Returns a command that runs `program` locally or on the remote host. Its
+/// arguments must not need quoting for the remote shell.
+pub fn command(program: &str) -> Command {
+    match HOST.get() {
+        Some(host) => {
+            let mut command = Command::new("ssh");
+            command.args([host, "--", program]);
+            command
+        }
+        None => Command::new(program),
+    }
+}
+
+/// Quotes `arg` for the remote shell.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./,=:@+%".contains(c))
+    {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Copies `files` to the remote directory, by their local path and their
+/// name there.
+fn copy(host: &str, files: &BTreeMap<PathBuf, String>) -> anyhow::Result<()> {
+    let status = Command::new("ssh")
+        .args([host, "--", "mkdir", "-p", REMOTE_DIR])
+        .status()
+        .context("failed to run `ssh`")?;
+    if !status.success() {
+        bail!("failed to create `{}` on `{}`", REMOTE_DIR, host);
+    }
+
+    let rsync = Command::new("rsync")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    for (path, name) in files {
+        crate::info("Copying", format!("`{}` to `{}`", path.display(), host));
+        let mut command = if rsync {
+            let mut command = Command::new("rsync");
+            command.arg("--times");
+            command
+        } else {
+            let mut command = Command::new("scp");
+            command.arg("-q");
+            command
+        };
+        let status = command
+            .arg(path)
+            .arg(format!("{}:{}/{}", host, REMOTE_DIR, name))
+            .status()
+            .with_context(|| format!("failed to copy `{}` to `{}`", path.display(), host))?;
+        if !status.success() {
+            bail!("failed to copy `{}` to `{}`", path.display(), host);
+        }
+    }
+    Ok(())
+}
+
+/// Turns the local QEMU `command` into one that runs on the remote host,
+/// after copying the files it refers to there.
+///
+/// Files are found as arguments, like `-kernel FILE`, and as values of
+/// properties, like `-drive file=FILE`.
+pub fn prepare(command: &Command) -> anyhow::Result<Command> {
+    let host = HOST.get().unwrap();
+    let mut files: BTreeMap<PathBuf, String> = BTreeMap::new();
+    let mut remote_path = |path: &Path| -> Option<String> {
+        if !path.is_file() {
+            return None;
+        }
+        let path = path.canonicalize().ok()?;
+        let taken = files.len();
+        let name = files.entry(path.clone()).or_insert_with(|| {
+            // Files are numbered, as files of the same name may come from
+            // different directories. Only plain characters are kept, as the
+            // name goes unquoted into the target of `scp` and `rsync`.
+            let name: String = path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            format!("{}-{}", taken, name)
+        });
+        Some(format!("{}/{}", REMOTE_DIR, name))
+    };
+
+    let mut remote = vec![quote(&command.get_program().to_string_lossy())];
+    for arg in command.get_args() {
+        let Some(arg) = arg.to_str() else {
+            bail!(
+                "QEMU argument `{}` is not valid UTF-8, so it cannot be passed to `{}`",
+                arg.to_string_lossy(),
+                host
+            );
+        };
+        let arg = match remote_path(Path::new(arg)) {
+            Some(path) => path,
+            None => crate::qemu::split_properties(arg)
+                .into_iter()
+                .map(|property| {
+                    let property = match property.split_once('=') {
+                        Some((key, value)) => match remote_path(Path::new(value)) {
+                            Some(path) => format!("{}={}", key, path),
+                            None => property,
+                        },
+                        None => property,
+                    };
+                    property.replace(',', ",,")
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        remote.push(quote(&arg));
+    }
+    copy(host, &files)?;
+
+    Ok(ssh_command(
+        host,
+        command,
+        remote,
+        std::io::stdin().is_terminal(),
+    ))
+}
+
+/// Returns the `ssh` command that runs `remote`, the quoted program and args
+/// of the local QEMU `command`, on `host`.
+fn ssh_command(host: &str, command: &Command, remote: Vec<String>, terminal: bool) -> Command {
+    let mut ssh = Command::new("ssh");
+    // A terminal on the remote host passes keys like Ctrl-A through to QEMU.
+    if terminal {
+        ssh.arg("-t");
+    }
+    if command.get_args().any(|arg| arg == "-s") {
+        // Let the debugger attach to the GDB stub of QEMU as if it ran here.
+        ssh.args(["-L", "1234:localhost:1234"]);
+    }
+    let mut args = command.get_args().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        if arg == "-qmp"
+            && let Some(socket) = args
+                .next()
+                .and_then(|value| value.strip_prefix("unix:"))
+                .and_then(|value| value.split(',').next())
+        {
+            // QEMU makes the socket on the remote host, at the same path.
+            ssh.args(["-o", "StreamLocalBindUnlink=yes", "-L"])
+                .arg(format!("{}:{}", socket, socket));
+        }
+    }
+    ssh.args([host, "--"]).args(remote);
+    ssh
+}
+
+/// Returns the path on the remote host of a file named `name` that QEMU
+/// writes there, relative to where it runs.
+pub fn output_path(name: &str) -> String {
+    format!("{}/{}", REMOTE_DIR, name)
+}
+
+/// Moves `remote`, a file that QEMU wrote on the remote host, to `local`.
+pub fn fetch(remote: &str, local: &Path) -> anyhow::Result<()> {
+    let host = HOST.get().unwrap();
+    let status = Command::new("scp")
+        .arg("-q")
+        .arg(format!("{}:{}", host, remote))
+        .arg(local)
+        .status()
+        .context("failed to run `scp`")?;
+    if !status.success() {
+        bail!("failed to copy `{}` from `{}`", remote, host);
+    }
+    Command::new("ssh")
+        .args([host, "--", "rm", "-f", &quote(remote)])
+        .status()
+        .ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn ssh_command_forwards_qmp() {
+        let mut qemu = Command::new("qemu-system-riscv64");
+        qemu.args(["-kernel", "/tmp/k.bin", "-s", "-qmp"])
+            .arg("unix:/tmp/cargo-arceos-1.qmp,server=on,wait=off");
+        let remote = vec![
+            "qemu-system-riscv64".to_string(),
+            "-kernel".to_string(),
+            ".cache/cargo-arceos/0-k.bin".to_string(),
+            "-s".to_string(),
+            "-qmp".to_string(),
+            "unix:/tmp/cargo-arceos-1.qmp,server=on,wait=off".to_string(),
+        ];
+        let ssh = ssh_command("lab", &qemu, remote.clone(), true);
+        assert_eq!(ssh.get_program(), "ssh");
+        let mut expected: Vec<String> = [
+            "-t",
+            "-L",
+            "1234:localhost:1234",
+            "-o",
+            "StreamLocalBindUnlink=yes",
+            "-L",
+            "/tmp/cargo-arceos-1.qmp:/tmp/cargo-arceos-1.qmp",
+            "lab",
+            "--",
+        ]
+        .map(String::from)
+        .into();
+        expected.extend(remote);
+        assert_eq!(args(&ssh), expected);
+    }
+
+    #[test]
+    fn ssh_command_without_qmp() {
+        let mut qemu = Command::new("qemu-system-x86_64");
+        qemu.args(["-kernel", "/tmp/k.elf"]);
+        let remote = vec!["qemu-system-x86_64".to_string()];
+        let ssh = ssh_command("lab", &qemu, remote, false);
+        assert_eq!(args(&ssh), ["lab", "--", "qemu-system-x86_64"]);
+    }
+}
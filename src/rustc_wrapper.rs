@@ -0,0 +1,101 @@
+//! Per-crate target features.
+//!
+//! `RUSTFLAGS` applies to every crate alike, so cargo-arceos sets itself as
+//! `RUSTC_WRAPPER` and adds the flags to each rustc invocation instead. The
+//! packages selected on the command line, which cargo marks with
+//! `CARGO_PRIMARY_PACKAGE`, are app crates; all others are kernel crates.
+
+use std::{
+    env,
+    ffi::OsString,
+    process::{Command, exit},
+};
+
+use anyhow::Context;
+
+/// Target features for kernel crates.
+const KERNEL_ENV: &str = "CARGO_ARCEOS_KERNEL_TARGET_FEATURES";
+/// Target features for app crates.
+const APP_ENV: &str = "CARGO_ARCEOS_APP_TARGET_FEATURES";
+/// The `RUSTC_WRAPPER` that was set before, to be run in turn.
+const INNER_ENV: &str = "CARGO_ARCEOS_INNER_RUSTC_WRAPPER";
+
+/// Makes cargo compile kernel and app crates with the given target features.
+pub(crate) fn apply(kernel: &str, app: &str, command: &mut Command) -> anyhow::Result<()> {
+    if kernel.is_empty() && app.is_empty() {
+        return Ok(());
+    }
+
+    let exe = env::current_exe().context("failed to locate cargo-arceos")?;
+    // A wrapper set up for the build, like sccache, takes precedence over the
+    // one in the environment.
+    let inner = command
+        .get_envs()
+        .find(|(key, _)| *key == "RUSTC_WRAPPER")
+        .and_then(|(_, value)| value.map(Into::into))
+        .or_else(|| env::var_os("RUSTC_WRAPPER"));
+    if let Some(inner) = inner.filter(|inner| !inner.is_empty()) {
+        command.env(INNER_ENV, inner);
+    }
+    command
+        .env("RUSTC_WRAPPER", exe)
+        .env(KERNEL_ENV, kernel)
+        .env(APP_ENV, app);
+    Ok(())
+}
+
+/// Acts as the rustc wrapper if cargo-arceos was invoked as one, in which
+/// case this does not return.
+pub fn run_if_invoked() {
+    let Some(kernel) = env::var_os(KERNEL_ENV) else {
+        return;
+    };
+    let mut args = env::args_os().skip(1);
+    let Some(rustc) = args.next() else {
+        return;
+    };
+    // The runner is spawned by cargo as well and sees the same environment.
+    if rustc == "arceos" || rustc == "runner" {
+        return;
+    }
+    let mut args: Vec<OsString> = args.collect();
+
+    // Only crates compiled for the target are changed, never build scripts
+    // and proc macros.
+    if args.iter().any(|arg| arg == "--target") {
+        let features = match env::var_os("CARGO_PRIMARY_PACKAGE") {
+            Some(_) => env::var_os(APP_ENV).unwrap_or_default(),
+            None => kernel,
+        };
+        if !features.is_empty() {
+            args.push("-C".into());
+            let mut flag = OsString::from("target-feature=");
+            flag.push(features);
+            args.push(flag);
+        }
+    }
+
+    let mut command = match env::var_os(INNER_ENV) {
+        Some(inner) => {
+            let mut command = Command::new(inner);
+            command.arg(rustc);
+            command
+        }
+        None => Command::new(rustc),
+    };
+    let status = match command.args(args).status() {
+        Ok(status) => status,
+        Err(e) => {
+            crate::print_error(
+                "error".to_string(),
+                format!(
+                    "failed to execute `{}`: {}",
+                    command.get_program().to_string_lossy(),
+                    e
+                ),
+            );
+            exit(101);
+        }
+    };
+    exit(status.code().unwrap_or(101));
+}
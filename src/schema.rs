@@ -0,0 +1,176 @@
+//! Validation of config files against the keys of the bundled configs.
+//!
+//! Keys of a config file are merged into the platform config as they are, so
+//! a misspelled key would otherwise only show as a broken kernel.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::bail;
+use axconfig_gen::{Config, ConfigItem, ConfigType};
+use strum::VariantNames;
+
+use crate::{
+    diagnostics::{Code, Diagnostic},
+    platforms::{Arch, Platform},
+};
+
+/// The keys known from the bundled configs, by table, with their types.
+pub struct Schema {
+    keys: BTreeMap<String, BTreeMap<String, Option<ConfigType>>>,
+    /// Keys that every bundled platform of the architecture defines.
+    required: BTreeSet<(String, String)>,
+}
+
+impl Schema {
+    pub fn new(arch: Option<Arch>) -> Self {
+        let mut keys: BTreeMap<String, BTreeMap<String, Option<ConfigType>>> = BTreeMap::new();
+        let mut required: Option<BTreeSet<(String, String)>> = None;
+        for name in Platform::VARIANTS {
+            let platform = Platform::from_str(name).unwrap();
+            let config = Config::from(platform);
+            for item in config.iter() {
+                let ty = item.value().ty().cloned();
+                let known = keys
+                    .entry(item.table_name().to_string())
+                    .or_default()
+                    .entry(item.key().to_string())
+                    .or_insert(None);
+                if known.is_none() {
+                    *known = ty;
+                }
+            }
+
+            if !matches!(platform, Platform::Dummy) && Some(Arch::from(platform)) == arch {
+                let defined = config
+                    .iter()
+                    .map(|item| (item.table_name().to_string(), item.key().to_string()))
+                    .collect();
+                required = Some(match required {
+                    Some(required) => required.intersection(&defined).cloned().collect(),
+                    None => defined,
+                });
+            }
+        }
+
+        Schema {
+            keys,
+            required: required.unwrap_or_default(),
+        }
+    }
+
+    /// Checks the keys of the config file at `path`, which contains `toml`.
+    ///
+    /// Tables that no bundled config has are left alone, as they may be used
+    /// by the app itself.
+    pub fn check_file(&self, path: &Path, toml: &str, config: &Config) -> anyhow::Result<()> {
+        for item in config.iter() {
+            let Some(table) = self.keys.get(item.table_name()) else {
+                continue;
+            };
+            let location = match line_of(toml, item.table_name(), item.key()) {
+                Some(line) => format!("{}:{}", path.display(), line),
+                None => path.display().to_string(),
+            };
+
+            let Some(ty) = table.get(item.key()) else {
+                let mut message = format!("{}: unknown key `{}`", location, item.item_name());
+                if let Some(similar) = similar_key(item.key(), table.keys()) {
+                    message.push_str(&format!(", did you mean `{}`?", similar));
+                }
+                bail!(Diagnostic::new(Code::UnknownConfigKey, message));
+            };
+            if let Some(ty) = ty
+                && !type_matches(item, ty)
+            {
+                bail!(Diagnostic::new(
+                    Code::ConfigTypeMismatch,
+                    format!(
+                        "{}: `{}` should be of type `{}`",
+                        location,
+                        item.item_name(),
+                        ty
+                    )
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the merged `config` has all keys the architecture needs.
+    pub fn check_required(&self, config: &Config) -> anyhow::Result<()> {
+        let missing: Vec<String> = self
+            .required
+            .iter()
+            .filter(|(table, key)| config.config_at(table, key).is_none())
+            .map(|(table, key)| {
+                if table == Config::GLOBAL_TABLE_NAME {
+                    format!("`{}`", key)
+                } else {
+                    format!("`{}.{}`", table, key)
+                }
+            })
+            .collect();
+        if !missing.is_empty() {
+            bail!(Diagnostic::new(
+                Code::MissingConfigKey,
+                format!("the config is missing {}", missing.join(", "))
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn type_matches(item: &ConfigItem, ty: &ConfigType) -> bool {
+    match item.value().ty() {
+        Some(item_ty) => item_ty == ty,
+        None => item.value().type_matches(ty),
+    }
+}
+
+/// Returns the line number of `key` in `table` of `toml`.
+fn line_of(toml: &str, table: &str, key: &str) -> Option<usize> {
+    let mut current = Config::GLOBAL_TABLE_NAME;
+    for (i, line) in toml.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            current = header.split(']').next().unwrap_or_default().trim();
+        } else if current == table
+            && let Some((name, _)) = line.split_once('=')
+            && name.trim().trim_matches('"') == key
+        {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+/// Returns the known key closest to `key`, if it is close enough to be a typo.
+fn similar_key<'a>(key: &str, known: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+    known
+        .map(|candidate| (distance(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+/// Returns the Levenshtein distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
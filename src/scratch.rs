@@ -0,0 +1,108 @@
+//! Writing the files that cargo-arceos generates, like `axconfig.toml`, the
+//! initramfs and the images of the kernel, somewhere other than the target
+//! directory with `--scratch-dir`, for when it is read-only or on a network
+//! filesystem. What cargo builds stays in the target directory, and so do the
+//! linker scripts, which the build script of `axhal` writes there.
+//!
+//! The files go into `<scratch-dir>/<target>/<profile>`, named after the
+//! binary like they are next to it otherwise.
+
+use std::{
+    env,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+};
+
+/// The directory of the generated files, for the runner.
+const SCRATCH_DIR_ENV: &str = "CARGO_ARCEOS_SCRATCH_DIR";
+
+/// The directory of the generated files of the current build.
+static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Puts the generated files into `dir`, in the runner spawned by `command`
+/// as well.
+pub fn set(dir: &Path, command: Option<&mut Command>) {
+    *DIR.lock().unwrap() = Some(dir.to_path_buf());
+    if let Some(command) = command {
+        command.env(SCRATCH_DIR_ENV, dir);
+    }
+}
+
+fn scratch_dir() -> Option<PathBuf> {
+    DIR.lock()
+        .unwrap()
+        .clone()
+        .or_else(|| env::var_os(SCRATCH_DIR_ENV).map(PathBuf::from))
+}
+
+/// Returns where to write `path`, a file next to the built binary.
+pub fn path(path: &Path) -> PathBuf {
+    match (scratch_dir(), path.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Returns the error for failing to write `what` into `dir`, which points to
+/// `--scratch-dir` if `dir` cannot be written to.
+pub fn unwritable(dir: &Path, what: &str, error: io::Error) -> anyhow::Error {
+    let reason = if scratch_dir().is_some() {
+        None
+    } else if is_network(dir) {
+        Some("is on a network filesystem")
+    } else if matches!(
+        error.kind(),
+        ErrorKind::ReadOnlyFilesystem | ErrorKind::PermissionDenied
+    ) {
+        Some("is read-only")
+    } else {
+        None
+    };
+    let error = anyhow::Error::new(error).context(format!("failed to write {}", what));
+    match reason {
+        Some(reason) => error.context(format!(
+            "failed to write {} into `{}`, which {}, use `--scratch-dir` to write the generated files elsewhere",
+            what,
+            dir.display(),
+            reason
+        )),
+        None => error,
+    }
+}
+
+/// Returns whether `dir`, or its closest existing parent, is on a network
+/// filesystem.
+#[cfg(target_os = "linux")]
+fn is_network(dir: &Path) -> bool {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    // The magic numbers of NFS, SMB, CIFS, SMB2, 9P, AFS and Ceph.
+    const NETWORK: [u32; 7] = [
+        0x6969,
+        0x517b,
+        0xff53_4d42,
+        0xfe53_4d42,
+        0x0102_1997,
+        0x5346_414f,
+        0x00c3_6400,
+    ];
+    let Some(dir) = dir.ancestors().find(|dir| dir.exists()) else {
+        return false;
+    };
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid C string and `stat` is written by the call.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    NETWORK.contains(&(stat.f_type as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_network(_dir: &Path) -> bool {
+    false
+}
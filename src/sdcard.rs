@@ -0,0 +1,695 @@
+//! Bootable SD card images, for `cargo arceos image`.
+//!
+//! The image has an MBR with a single FAT32 boot partition holding the
+//! firmware of the board, a `config.txt` made from the platform config and
+//! the kernel. Empty disks for `--disk` are FAT32 without a partition table,
+//! like the ones that `make disk_img` of ArceOS makes.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, IsTerminal, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use axconfig_gen::Config;
+
+const SECTOR: usize = 512;
+/// The boot partition starts at 1 MiB, like with most partitioning tools.
+const PARTITION_START: usize = 2048;
+const RESERVED_SECTORS: usize = 32;
+/// One sector per cluster keeps partitions of 33 MiB and more FAT32.
+const MIN_CLUSTERS: usize = 65525;
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// 1980-01-01, the earliest date FAT can store, for reproducible images.
+const DATE: u16 = 0x21;
+
+/// Firmware files that the Raspberry Pi 4 cannot boot without.
+const RASPI4_FIRMWARE: [&str; 2] = ["start4.elf", "fixup4.dat"];
+
+/// A file or directory of the boot partition.
+pub enum Node {
+    File(String, Vec<u8>),
+    Dir(String, Vec<Node>),
+}
+
+impl Node {
+    fn name(&self) -> &str {
+        match self {
+            Node::File(name, _) | Node::Dir(name, _) => name,
+        }
+    }
+
+    /// Reads `path` and, if it is a directory, everything below it, sorted
+    /// by name so that images are reproducible.
+    pub fn read(path: &Path) -> anyhow::Result<Vec<Node>> {
+        let mut entries = fs::read_dir(path)
+            .with_context(|| format!("failed to read directory `{}`", path.display()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        entries
+            .into_iter()
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path = entry.path();
+                if path.is_dir() {
+                    Ok(Node::Dir(name, Node::read(&path)?))
+                } else {
+                    let data = fs::read(&path)
+                        .with_context(|| format!("failed to read `{}`", path.display()))?;
+                    Ok(Node::File(name, data))
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns the 8.3 name of the `index`th entry of a directory. Every entry has
+/// a long name as well, so the short one only has to be unique.
+fn short_name(name: &str, index: usize) -> [u8; 11] {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let clean = |s: &str, len: usize| -> Vec<u8> {
+        s.bytes()
+            .filter(u8::is_ascii_alphanumeric)
+            .map(|b| b.to_ascii_uppercase())
+            .take(len)
+            .collect()
+    };
+    let tail = format!("~{}", index + 1);
+    let mut base = clean(base, 8 - tail.len());
+    base.extend(tail.bytes());
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(&base);
+    let ext = clean(ext, 3);
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+    short
+}
+
+fn checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Returns the long name entries of `name`, in the order they are stored.
+fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; 32]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    chars.push(0);
+    let count = chars.len().div_ceil(13);
+    chars.resize(count * 13, 0xffff);
+
+    let mut entries = vec![];
+    for (i, part) in chars.chunks(13).enumerate().rev() {
+        let mut entry = [0; 32];
+        entry[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum(short);
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (offset, c) in offsets.zip(part) {
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+fn short_entry(name: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attr;
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&DATE.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// A FAT32 file system with one sector per cluster, filled from the start.
+/// It is written to a sparse file, so only the sectors in use take up space.
+struct Fat32<'a> {
+    file: &'a mut File,
+    /// The offset of the file system in `file`.
+    start: u64,
+    sectors: usize,
+    fat: Vec<u32>,
+    data_start: usize,
+    /// The CRC-32 of everything written to the data area so far.
+    crc: u32,
+}
+
+impl<'a> Fat32<'a> {
+    fn new(file: &'a mut File, start: u64, sectors: usize) -> anyhow::Result<Self> {
+        if sectors < MIN_CLUSTERS {
+            bail!("{} bytes are too few for FAT32", sectors * SECTOR);
+        }
+        // The FAT takes 4 bytes per cluster, and clusters take what is left.
+        let mut fat_sectors = 1;
+        while (2 + (sectors - RESERVED_SECTORS - 2 * fat_sectors)) * 4 > fat_sectors * SECTOR {
+            fat_sectors += 1;
+        }
+        let clusters = sectors - RESERVED_SECTORS - 2 * fat_sectors;
+        if clusters < MIN_CLUSTERS {
+            bail!("{} bytes are too few for FAT32", sectors * SECTOR);
+        }
+
+        let mut fat = vec![0; clusters + 2];
+        fat[0] = 0x0fff_fff8;
+        fat[1] = END_OF_CHAIN;
+        Ok(Fat32 {
+            file,
+            start,
+            sectors,
+            fat,
+            data_start: RESERVED_SECTORS + 2 * fat_sectors,
+            crc: 0,
+        })
+    }
+
+    /// Allocates enough clusters for `len` bytes, at least one, and returns
+    /// the first.
+    fn alloc(&mut self, len: usize) -> anyhow::Result<u32> {
+        let count = len.div_ceil(SECTOR).max(1);
+        let first = self.fat.iter().rposition(|&next| next != 0).unwrap() + 1;
+        if first + count > self.fat.len() {
+            bail!("the files do not fit into the boot partition");
+        }
+        for cluster in first..first + count {
+            self.fat[cluster] = if cluster + 1 == first + count {
+                END_OF_CHAIN
+            } else {
+                cluster as u32 + 1
+            };
+        }
+        Ok(first as u32)
+    }
+
+    /// Writes `bytes` at `sector` of the file system.
+    fn write_at(&mut self, sector: usize, bytes: &[u8]) -> io::Result<()> {
+        self.file
+            .seek(SeekFrom::Start(self.start + (sector * SECTOR) as u64))?;
+        self.file.write_all(bytes)
+    }
+
+    fn write(&mut self, cluster: u32, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crate::checksum::crc32_update(self.crc, bytes);
+        self.write_at(self.data_start + cluster as usize - 2, bytes)
+    }
+
+    /// Writes the entries of the directory at `cluster` and what they refer
+    /// to. `parent` is `None` for the root directory.
+    fn write_dir(
+        &mut self,
+        cluster: u32,
+        parent: Option<u32>,
+        nodes: &[Node],
+    ) -> anyhow::Result<()> {
+        let mut entries = vec![];
+        if let Some(parent) = parent {
+            entries.push(short_entry(b".          ", ATTR_DIRECTORY, cluster, 0));
+            entries.push(short_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+        }
+
+        let mut dirs = vec![];
+        for (i, node) in nodes.iter().enumerate() {
+            let short = short_name(node.name(), i);
+            entries.extend(long_name_entries(node.name(), &short));
+            match node {
+                Node::File(_, data) => {
+                    let first = if data.is_empty() {
+                        0
+                    } else {
+                        let first = self.alloc(data.len())?;
+                        self.write(first, data)?;
+                        first
+                    };
+                    entries.push(short_entry(&short, ATTR_ARCHIVE, first, data.len() as u32));
+                }
+                Node::Dir(_, children) => {
+                    let first = self.alloc(dir_size(children, true))?;
+                    entries.push(short_entry(&short, ATTR_DIRECTORY, first, 0));
+                    dirs.push((first, children));
+                }
+            }
+        }
+        self.write(cluster, &entries.concat())?;
+
+        for (first, children) in dirs {
+            // `..` of directories in the root refers to cluster 0.
+            self.write_dir(
+                first,
+                Some(if parent.is_some() { cluster } else { 0 }),
+                children,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the boot sector, the FS information sector and the FATs, and
+    /// returns the volume ID. `hidden` is the number of sectors before the
+    /// file system.
+    fn finish(mut self, hidden: usize, label: &[u8; 11]) -> io::Result<u32> {
+        // The volume ID only has to differ between file systems, so it is
+        // made from the contents to keep images reproducible.
+        let volume_id = self.crc;
+        let sectors = self.sectors;
+        let fat_sectors = (self.data_start - RESERVED_SECTORS) / 2;
+        let free = self.fat.iter().filter(|&&next| next == 0).count();
+
+        let mut boot = [0u8; SECTOR];
+        boot[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        boot[3..11].copy_from_slice(b"MSWIN4.1");
+        boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        boot[13] = 1; // sectors per cluster
+        boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot[16] = 2; // FATs
+        boot[21] = 0xf8; // fixed disk
+        boot[24..26].copy_from_slice(&63u16.to_le_bytes()); // sectors per track
+        boot[26..28].copy_from_slice(&255u16.to_le_bytes()); // heads
+        boot[28..32].copy_from_slice(&(hidden as u32).to_le_bytes());
+        boot[32..36].copy_from_slice(&(sectors as u32).to_le_bytes());
+        boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&2u32.to_le_bytes()); // root cluster
+        boot[48..50].copy_from_slice(&1u16.to_le_bytes()); // FS information sector
+        boot[50..52].copy_from_slice(&6u16.to_le_bytes()); // backup boot sector
+        boot[64] = 0x80; // drive number
+        boot[66] = 0x29; // extended boot signature
+        boot[67..71].copy_from_slice(&volume_id.to_le_bytes());
+        boot[71..82].copy_from_slice(label);
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        boot[510..].copy_from_slice(&[0x55, 0xaa]);
+
+        let mut info = [0u8; SECTOR];
+        info[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        info[488..492].copy_from_slice(&(free as u32).to_le_bytes());
+        info[492..496].copy_from_slice(&0xffff_ffffu32.to_le_bytes());
+        info[508..].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+
+        for at in [0, 6] {
+            self.write_at(at, &boot)?;
+            self.write_at(at + 1, &info)?;
+        }
+        // The free clusters at the end are already zero.
+        let used = self.fat.iter().rposition(|&next| next != 0).unwrap() + 1;
+        let fat: Vec<u8> = self.fat[..used]
+            .iter()
+            .flat_map(|next| next.to_le_bytes())
+            .collect();
+        for i in 0..2 {
+            self.write_at(RESERVED_SECTORS + i * fat_sectors, &fat)?;
+        }
+        Ok(volume_id)
+    }
+}
+
+/// Returns the size of a directory with `nodes`, with `.` and `..` unless it
+/// is the root.
+fn dir_size(nodes: &[Node], dots: bool) -> usize {
+    let entries: usize = nodes
+        .iter()
+        .map(|node| 1 + (node.name().encode_utf16().count() + 1).div_ceil(13))
+        .sum();
+    (entries + if dots { 2 } else { 0 }) * 32
+}
+
+/// Writes a disk image with an MBR and a FAT32 boot partition of
+/// `partition_size` bytes that holds `nodes` to `path`.
+pub fn build(nodes: &[Node], partition_size: u64, path: &Path) -> anyhow::Result<()> {
+    let sectors = (partition_size as usize).div_ceil(SECTOR);
+    write_sparse(path, (PARTITION_START + sectors) * SECTOR, |file| {
+        let mut fat = Fat32::new(file, (PARTITION_START * SECTOR) as u64, sectors)?;
+        let root = fat.alloc(dir_size(nodes, false))?;
+        fat.write_dir(root, None, nodes)?;
+        let volume_id = fat.finish(PARTITION_START, b"BOOT       ")?;
+
+        let mut mbr = [0u8; SECTOR];
+        let entry = &mut mbr[446..462];
+        entry[0] = 0x80; // bootable
+        entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]); // CHS start, unused
+        entry[4] = 0x0c; // FAT32 with LBA
+        entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]); // CHS end, unused
+        entry[8..12].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+        mbr[440..444].copy_from_slice(&volume_id.to_le_bytes()); // disk signature
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&mbr)?;
+        Ok(())
+    })
+}
+
+/// Writes an empty FAT32 file system of `size` bytes that takes up the whole
+/// disk to `path`.
+pub fn empty_disk(size: u64, path: &Path) -> anyhow::Result<()> {
+    let sectors = (size as usize).div_ceil(SECTOR);
+    write_sparse(path, sectors * SECTOR, |file| {
+        let mut fat = Fat32::new(file, 0, sectors)?;
+        let root = fat.alloc(dir_size(&[], false))?;
+        fat.write_dir(root, None, &[])?;
+        fat.finish(0, b"NO NAME    ")?;
+        Ok(())
+    })
+}
+
+/// Creates `path` as a sparse file of `len` bytes and lets `write` fill it,
+/// through a temporary file like [`crate::write_atomic`].
+fn write_sparse(
+    path: &Path,
+    len: usize,
+    write: impl FnOnce(&mut File) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let temp = crate::temp_path(path);
+    let result = File::create(&temp)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut file| {
+            file.set_len(len as u64)?;
+            write(&mut file)?;
+            file.sync_all()?;
+            Ok(fs::rename(&temp, path)?)
+        });
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+/// Returns the `config.txt` that makes the firmware of the Raspberry Pi 4 boot
+/// the kernel as the platform `config` expects.
+pub fn raspi4_config_txt(config: &Config) -> String {
+    let uint = |key: &str| {
+        crate::memmap::config_uint(config, "plat", key)
+            .or_else(|_| crate::memmap::config_uint(config, "devices", key))
+    };
+    let mut lines = vec![
+        "# Generated by cargo-arceos".to_string(),
+        "arm_64bit=1".to_string(),
+        "kernel=kernel8.img".to_string(),
+    ];
+    if let Ok(base) = uint("kernel-base-paddr") {
+        lines.push(format!("kernel_address={:#x}", base));
+    }
+    // The PL011 UART is only on the GPIO header if Bluetooth does not use it.
+    if uint("uart-paddr").is_ok_and(|uart| uart == 0xfe20_1000) {
+        lines.push("enable_uart=1".to_string());
+        lines.push("dtoverlay=disable-bt".to_string());
+    }
+    if uint("gicd-paddr").is_ok_and(|gicd| gicd != 0) {
+        lines.push("enable_gic=1".to_string());
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Returns the files of the boot partition of a Raspberry Pi 4, with the
+/// firmware from `firmware`.
+pub fn raspi4_files(firmware: &Path, config: &Config, kernel: &Path) -> anyhow::Result<Vec<Node>> {
+    let mut nodes = Node::read(firmware)?;
+    for name in RASPI4_FIRMWARE {
+        if !nodes.iter().any(|node| node.name() == name) {
+            bail!(
+                "firmware `{}` is missing from `{}`, get it from https://github.com/raspberrypi/firmware/tree/master/boot",
+                name,
+                firmware.display()
+            );
+        }
+    }
+    if !nodes.iter().any(|node| node.name().ends_with(".dtb")) {
+        crate::warn(format!(
+            "`{}` has no device tree, the firmware needs `bcm2711-rpi-4-b.dtb`",
+            firmware.display()
+        ));
+    }
+
+    let kernel =
+        fs::read(kernel).with_context(|| format!("failed to read `{}`", kernel.display()))?;
+    nodes.retain(|node| !matches!(node.name(), "config.txt" | "kernel8.img"));
+    nodes.push(Node::File(
+        "config.txt".to_string(),
+        raspi4_config_txt(config).into_bytes(),
+    ));
+    nodes.push(Node::File("kernel8.img".to_string(), kernel));
+    Ok(nodes)
+}
+
+/// Writes `image` to the block device `device` after asking, unless `yes`.
+pub fn write_to_device(image: &Path, device: &Path, yes: bool) -> anyhow::Result<()> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let device_name = device.to_string_lossy();
+    if mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|source| source.starts_with(device_name.as_ref()))
+    {
+        bail!("`{}` is mounted, unmount it first", device.display());
+    }
+
+    if !yes {
+        if !io::stdin().is_terminal() {
+            bail!(
+                "refusing to overwrite `{}` without `--yes`",
+                device.display()
+            );
+        }
+        eprint!(
+            "All data on `{}` will be lost. Write the image to it? [y/N] ",
+            device.display()
+        );
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("not writing to `{}`", device.display());
+        }
+    }
+
+    let data = fs::read(image)?;
+    crate::info(
+        "Writing",
+        format!("`{}` to `{}`", image.display(), device.display()),
+    );
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(device)
+        .with_context(|| format!("failed to open `{}`", device.display()))?;
+    file.write_all(&data)?;
+    file.sync_all()
+        .with_context(|| format!("failed to write to `{}`", device.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+
+    /// Just enough space for FAT32 with one sector per cluster.
+    const SIZE: u64 = 34 << 20;
+
+    fn temp(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("cargo-arceos-{}-{}", std::process::id(), name))
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    /// Returns the entry of `cluster` in the first FAT of `fs`.
+    fn fat_entry(fs: &[u8], cluster: usize) -> u32 {
+        u32_at(fs, RESERVED_SECTORS * SECTOR + cluster * 4)
+    }
+
+    /// Returns the offset of `cluster` in `fs`.
+    fn cluster_at(fs: &[u8], cluster: usize) -> usize {
+        let fat_sectors = u32_at(fs, 36) as usize;
+        (RESERVED_SECTORS + 2 * fat_sectors + cluster - 2) * SECTOR
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(&short_name("kernel8.img", 0), b"KERNEL~1IMG");
+        assert_eq!(&short_name("config.txt", 9), b"CONFI~10TXT");
+        assert_eq!(&short_name("README", 0), b"README~1   ");
+        assert_eq!(&short_name("a-b.c d.tar.gz", 2), b"ABCDTA~3GZ ");
+    }
+
+    #[test]
+    fn long_name_checksum() {
+        // The checksum as the FAT specification computes it.
+        let spec = |short: &[u8; 11]| {
+            short.iter().fold(0u8, |sum, &b| {
+                ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+            })
+        };
+        for short in [b"KERNEL~1IMG", b"CONFI~10TXT", b"README~1   "] {
+            assert_eq!(checksum(short), spec(short));
+        }
+    }
+
+    #[test]
+    fn long_name_layout() {
+        let short = short_name("bcm2711-rpi.dtb", 0);
+        let entries = long_name_entries("bcm2711-rpi.dtb", &short);
+        // 15 characters and the NUL take two entries, the last one first.
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0][0], 0x42);
+        assert_eq!(entries[1][0], 0x01);
+        for entry in &entries {
+            assert_eq!(entry[11], ATTR_LONG_NAME);
+            assert_eq!(entry[13], checksum(&short));
+            assert_eq!(u16_at(entry, 26), 0);
+        }
+        let chars = |entry: &[u8; 32]| -> Vec<u16> {
+            (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2))
+                .map(|at| u16_at(entry, at))
+                .collect()
+        };
+        let expected: Vec<u16> = "bcm2711-rpi.d".encode_utf16().collect();
+        assert_eq!(chars(&entries[1]), expected);
+        let mut expected: Vec<u16> = "tb".encode_utf16().collect();
+        expected.push(0);
+        expected.resize(13, 0xffff);
+        assert_eq!(chars(&entries[0]), expected);
+    }
+
+    #[test]
+    fn boot_partition() {
+        let kernel: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let nodes = [
+            Node::File("config.txt".to_string(), b"arm_64bit=1".to_vec()),
+            Node::File("kernel8.img".to_string(), kernel.clone()),
+            Node::Dir(
+                "overlays".to_string(),
+                vec![Node::File("README".to_string(), b"hi".to_vec())],
+            ),
+        ];
+        let path = temp("sdcard.img");
+        build(&nodes, SIZE, &path).unwrap();
+        let image = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let sectors = SIZE as usize / SECTOR;
+        assert_eq!(image.len(), (PARTITION_START + sectors) * SECTOR);
+
+        // The MBR
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+        assert_eq!(image[446], 0x80);
+        assert_eq!(image[450], 0x0c);
+        assert_eq!(u32_at(&image, 454), PARTITION_START as u32);
+        assert_eq!(u32_at(&image, 458), sectors as u32);
+
+        // The boot sector and the FS information sector, and their backups
+        let fs = &image[PARTITION_START * SECTOR..];
+        assert_eq!(u16_at(fs, 11), SECTOR as u16);
+        assert_eq!(fs[13], 1);
+        assert_eq!(u16_at(fs, 14), RESERVED_SECTORS as u16);
+        assert_eq!(fs[16], 2);
+        assert_eq!(u32_at(fs, 28), PARTITION_START as u32);
+        assert_eq!(u32_at(fs, 32), sectors as u32);
+        assert_eq!(u32_at(fs, 44), 2);
+        assert_eq!(u32_at(fs, 67), u32_at(&image, 440));
+        assert_eq!(&fs[71..82], b"BOOT       ");
+        assert_eq!(&fs[82..90], b"FAT32   ");
+        assert_eq!(&fs[510..512], &[0x55, 0xaa]);
+        assert_eq!(&fs[..2 * SECTOR], &fs[6 * SECTOR..8 * SECTOR]);
+        let info = &fs[SECTOR..2 * SECTOR];
+        assert_eq!(u32_at(info, 0), 0x4161_5252);
+        assert_eq!(u32_at(info, 484), 0x6141_7272);
+        assert_eq!(u32_at(info, 508), 0xaa55_0000);
+        let fat_sectors = u32_at(fs, 36) as usize;
+        let clusters = sectors - RESERVED_SECTORS - 2 * fat_sectors;
+        // The root, `config.txt`, three for `kernel8.img`, `overlays` and
+        // `README`
+        assert_eq!(u32_at(info, 488), (clusters - 7) as u32);
+
+        // The FAT chains, in both FATs
+        let chains = [
+            0x0fff_fff8,
+            END_OF_CHAIN,
+            END_OF_CHAIN,
+            END_OF_CHAIN,
+            5,
+            6,
+            END_OF_CHAIN,
+            END_OF_CHAIN,
+            END_OF_CHAIN,
+            0,
+        ];
+        for (cluster, &next) in chains.iter().enumerate() {
+            assert_eq!(fat_entry(fs, cluster), next, "cluster {}", cluster);
+        }
+        let fat = RESERVED_SECTORS * SECTOR..(RESERVED_SECTORS + fat_sectors) * SECTOR;
+        assert_eq!(
+            &fs[fat.clone()],
+            &fs[fat.start + fat_sectors * SECTOR..fat.end + fat_sectors * SECTOR]
+        );
+
+        // The root directory, with a long name before every short entry
+        let root = &fs[cluster_at(fs, 2)..];
+        let entry = |i: usize| &root[i * 32..(i + 1) * 32];
+        assert_eq!(entry(0)[0], 0x41);
+        assert_eq!(entry(0)[13], checksum(b"CONFIG~1TXT"));
+        assert_eq!(&entry(1)[..11], b"CONFIG~1TXT");
+        assert_eq!(entry(1)[11], ATTR_ARCHIVE);
+        assert_eq!(u16_at(entry(1), 26), 3);
+        assert_eq!(u32_at(entry(1), 28), 11);
+        assert_eq!(&entry(3)[..11], b"KERNEL~2IMG");
+        assert_eq!(u16_at(entry(3), 26), 4);
+        assert_eq!(u32_at(entry(3), 28), 1500);
+        assert_eq!(&entry(5)[..11], b"OVERLA~3   ");
+        assert_eq!(entry(5)[11], ATTR_DIRECTORY);
+        assert_eq!(u16_at(entry(5), 26), 7);
+        assert_eq!(entry(6)[0], 0);
+
+        // Files take consecutive clusters.
+        let at = cluster_at(fs, 3);
+        assert_eq!(&fs[at..at + 11], b"arm_64bit=1");
+        let at = cluster_at(fs, 4);
+        assert_eq!(&fs[at..at + 1500], &kernel[..]);
+
+        // `..` of a directory in the root refers to cluster 0.
+        let dir = &fs[cluster_at(fs, 7)..];
+        assert_eq!(&dir[..11], b".          ");
+        assert_eq!(u16_at(dir, 26), 7);
+        assert_eq!(&dir[32..43], b"..         ");
+        assert_eq!(u16_at(dir, 32 + 26), 0);
+        assert_eq!(&dir[96..107], b"README~1   ");
+        assert_eq!(u16_at(dir, 96 + 26), 8);
+    }
+
+    #[test]
+    fn empty() {
+        let path = temp("disk.img");
+        empty_disk(SIZE, &path).unwrap();
+        let fs = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(fs.len() as u64, SIZE);
+        assert_eq!(u32_at(&fs, 28), 0);
+        assert_eq!(&fs[71..82], b"NO NAME    ");
+        assert_eq!(&fs[..2 * SECTOR], &fs[6 * SECTOR..8 * SECTOR]);
+        assert_eq!(fat_entry(&fs, 2), END_OF_CHAIN);
+        assert_eq!(fat_entry(&fs, 3), 0);
+        assert!(fs[cluster_at(&fs, 2)..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn too_small() {
+        let path = temp("small.img");
+        assert!(empty_disk(1 << 20, &path).is_err());
+        assert!(!path.exists());
+        assert!(!crate::temp_path(&path).exists());
+    }
+}
@@ -0,0 +1,164 @@
+//! Running the kernel on a board for `--hardware`, with the same outcome as a
+//! run in QEMU so that CI can use boards and QEMU alike.
+//!
+//! Boards cannot report an exit status, so the console is watched for what
+//! ArceOS prints when it shuts down or panics.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::exit::GuestExit;
+
+/// Printed by ArceOS when it powers off.
+const SHUTDOWN_MARKER: &str = "Shutting down...";
+/// Printed by the panic handler of ArceOS.
+const PANIC_MARKER: &str = "panicked at";
+/// Printed by U-Boot before it boots on its own.
+const AUTOBOOT_MARKER: &str = "Hit any key to stop autoboot";
+/// The prompt of U-Boot, printed when a command has finished.
+const PROMPT: &str = "=> ";
+/// How long the board gets to get to the bootloader after powering on.
+const POWER_ON_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the bootloader gets to run a command that does not load anything.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the bootloader gets to load the image over TFTP.
+const TFTP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Runs `command` with the shell, like `--power-on`.
+pub fn shell(command: &str) -> anyhow::Result<()> {
+    crate::run_command(Command::new("sh").args(["-c", command]))
+        .with_context(|| format!("`{}` failed", command))
+}
+
+/// Copies `image` to the TFTP server directory `dir`.
+pub fn copy_to_tftp(image: &Path, dir: &Path) -> anyhow::Result<()> {
+    let target = dir.join(image.file_name().unwrap());
+    fs::copy(image, &target).with_context(|| {
+        format!(
+            "failed to copy `{}` to `{}`",
+            image.display(),
+            dir.display()
+        )
+    })?;
+    crate::info("Copied", format!("`{}` for TFTP", target.display()));
+    Ok(())
+}
+
+/// Loads the ELF `binary` with OpenOCD and the board config `config`, and
+/// starts it.
+pub fn openocd(config: &Path, binary: &Path) -> anyhow::Result<()> {
+    let mut command = Command::new("openocd");
+    command.arg("-f").arg(config).args([
+        "-c",
+        &format!("program {{{}}} verify reset exit", binary.display()),
+    ]);
+    crate::run_command(&mut command).context("failed to load the kernel with OpenOCD")
+}
+
+/// Waits until U-Boot offers to stop booting on its own, and stops it.
+pub fn stop_autoboot(mut port: &File) -> anyhow::Result<()> {
+    wait_for(port, AUTOBOOT_MARKER, POWER_ON_TIMEOUT)?;
+    port.write_all(b"\r")?;
+    Ok(())
+}
+
+/// Sends the U-Boot `command` that loads the image over TFTP, and waits for
+/// it to finish.
+pub fn tftp_load(mut port: &File, command: &str) -> anyhow::Result<()> {
+    write!(port, "{}\r", command)?;
+    // The bootloader does not read the console while it loads, so it would
+    // drop further input.
+    wait_for(port, PROMPT, TFTP_TIMEOUT)
+}
+
+/// Tells U-Boot the size of the compressed image before `boot_command` boots
+/// it with `booti`, which cannot decompress it otherwise.
+///
+/// `booti` also needs `kernel_comp_addr_r`, a free region to decompress
+/// into, which only the environment of the board can know.
+pub fn set_comp_size(mut port: &File, boot_command: &str, size: usize) -> anyhow::Result<()> {
+    if !boot_command.trim_start().starts_with("booti") {
+        return Ok(());
+    }
+    write!(port, "setenv kernel_comp_size {:#x}\r", size)?;
+    wait_for(port, PROMPT, COMMAND_TIMEOUT)
+}
+
+/// Prints the console of `port` until it prints `marker`.
+fn wait_for(mut port: &File, marker: &str, timeout: Duration) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let mut seen = String::new();
+    let mut buf = [0; 256];
+    while start.elapsed() < timeout {
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e.into()),
+        };
+        io::stdout().write_all(&buf[..n])?;
+        crate::logfile::console(&buf[..n]);
+        seen.push_str(&String::from_utf8_lossy(&buf[..n]));
+        if seen.contains(marker) {
+            return Ok(());
+        }
+        // Only the end can still be the start of the marker.
+        if seen.len() > 4 * marker.len() {
+            let keep = seen.floor_char_boundary(seen.len() - marker.len());
+            seen.drain(..keep);
+        }
+    }
+    anyhow::bail!(
+        "the bootloader did not print `{}` within {}s",
+        marker.trim(),
+        timeout.as_secs()
+    )
+}
+
+/// Prints the console of `port` until the kernel shuts down or panics, the
+/// runner is interrupted, or `timeout` passes.
+pub fn watch(mut port: impl Read, timeout: Option<Duration>) -> anyhow::Result<GuestExit> {
+    let start = Instant::now();
+    let mut line = String::new();
+    let mut buf = [0; 1024];
+    let mut stdout = io::stdout().lock();
+    loop {
+        if let Some(signal) = crate::signal::received() {
+            return Ok(GuestExit::Interrupted(signal));
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+            return Ok(GuestExit::TimedOut);
+        }
+
+        // The port returns from reads periodically.
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e).context("failed to read from the console"),
+        };
+        crate::logfile::console(&buf[..n]);
+        crate::harness::console(&buf[..n]);
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+
+        for c in String::from_utf8_lossy(&buf[..n]).chars() {
+            if c != '\n' {
+                line.push(c);
+                continue;
+            }
+            if line.contains(PANIC_MARKER) {
+                return Ok(GuestExit::Panic);
+            }
+            if line.contains(SHUTDOWN_MARKER) {
+                return Ok(GuestExit::Success);
+            }
+            line.clear();
+        }
+    }
+}
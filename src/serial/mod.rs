@@ -0,0 +1,669 @@
+mod board;
+mod modem;
+mod probe;
+
+use std::{
+    env,
+    ffi::OsString,
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use clap::{Args, builder::TypedValueParser};
+use console::style;
+use strum::VariantNames;
+
+use self::{
+    modem::Protocol,
+    probe::{Probe, ProbeProtocol},
+};
+use crate::{
+    exit::GuestExit,
+    image::ImageOptions,
+    platforms::{Arch, Platform},
+};
+
+/// Baud rates accepted by `--baud`.
+const BAUD_RATES: &[u32] = &[
+    1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1500000, 2000000,
+    3000000,
+];
+
+/// Byte sent from the host terminal to detach from the console (Ctrl-]).
+const DETACH: u8 = 0x1d;
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Serial Options")]
+#[command(group(clap::ArgGroup::new("board").multiple(true).args(["console_device", "probe"])))]
+pub struct SerialOptions {
+    /// Attach to a board's serial console instead of running QEMU
+    #[arg(long, value_name = "PATH")]
+    console_device: Option<PathBuf>,
+
+    /// Baud rate of the serial console
+    #[arg(long, default_value_t = 115200, value_parser = parse_baud, value_name = "RATE")]
+    baud: u32,
+
+    /// Data bits, parity (N, E or O) and stop bits of the serial console
+    #[arg(long, default_value = "8N1", value_name = "FORMAT")]
+    console_format: SerialFormat,
+
+    /// Upload the image to the bootloader over the serial console
+    #[arg(
+        long,
+        requires = "console_device",
+        group = "loader",
+        value_parser = enum_variants!(Protocol)
+    )]
+    upload: Option<Protocol>,
+
+    /// Copy the image to the directory of a TFTP server for the bootloader to
+    /// load with `--load-command` (e.g. `tftpboot 0x80080000 arceos.bin`)
+    #[arg(
+        long,
+        requires = "console_device",
+        group = "loader",
+        value_name = "DIR"
+    )]
+    tftp_dir: Option<PathBuf>,
+
+    /// Load the kernel with OpenOCD and the board config FILE, e.g. over
+    /// ST-Link, instead of through the bootloader
+    #[arg(
+        long,
+        requires = "console_device",
+        conflicts_with = "loader",
+        value_name = "FILE"
+    )]
+    openocd_config: Option<PathBuf>,
+
+    /// Flash the kernel onto CHIP, like `rk3588`, with probe-rs over a debug
+    /// probe and start it, reading the console from RTT unless
+    /// `--console-device` is given
+    #[arg(long, conflicts_with_all = ["loader", "openocd_config"], value_name = "CHIP")]
+    probe: Option<String>,
+
+    /// Debug probe to use, like `VID:PID[:SERIAL]` [default: the only one
+    /// attached]
+    #[arg(long, requires = "probe", value_name = "SELECTOR")]
+    probe_selector: Option<String>,
+
+    /// Protocol between the debug probe and the chip
+    #[arg(long, requires = "probe", default_value = "swd", value_parser = enum_variants!(ProbeProtocol))]
+    probe_protocol: ProbeProtocol,
+
+    /// Command that makes the bootloader receive the upload (e.g. `loady 0x80080000`)
+    #[arg(long, requires = "loader", value_name = "COMMAND")]
+    load_command: Option<String>,
+
+    /// Command that boots the uploaded image (e.g. `go 0x80080000`)
+    #[arg(long, requires = "loader", value_name = "COMMAND")]
+    boot_command: Option<String>,
+
+    /// Run on the board like in QEMU: power it on, load the kernel and print
+    /// the console until the kernel shuts down or panics, or `--timeout`
+    /// passes, and exit with the outcome
+    #[arg(long, requires = "board")]
+    hardware: bool,
+
+    /// Command that powers the board on, like one switching a relay
+    #[arg(long, requires = "hardware", value_name = "COMMAND")]
+    power_on: Option<String>,
+
+    /// Command that powers the board off after the run
+    #[arg(long, requires = "hardware", value_name = "COMMAND")]
+    power_off: Option<String>,
+}
+
+fn parse_baud(s: &str) -> Result<u32, String> {
+    let baud = s.parse::<u32>().map_err(|e| e.to_string())?;
+    if BAUD_RATES.contains(&baud) {
+        Ok(baud)
+    } else {
+        Err(format!(
+            "unsupported baud rate, expected one of {}",
+            BAUD_RATES
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Character format of a serial line, written like `8N1`.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialFormat {
+    data_bits: u8,
+    parity: Parity,
+    stop_bits: u8,
+}
+
+impl FromStr for SerialFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let &[data_bits, parity, stop_bits] = s.as_bytes() else {
+            return Err("expected a format like `8N1`".to_string());
+        };
+
+        let data_bits = match data_bits {
+            b'5'..=b'8' => data_bits - b'0',
+            _ => return Err("data bits should be between 5 and 8".to_string()),
+        };
+        let parity = match parity.to_ascii_uppercase() {
+            b'N' => Parity::None,
+            b'E' => Parity::Even,
+            b'O' => Parity::Odd,
+            _ => return Err("parity should be one of `N`, `E` or `O`".to_string()),
+        };
+        let stop_bits = match stop_bits {
+            b'1' | b'2' => stop_bits - b'0',
+            _ => return Err("stop bits should be 1 or 2".to_string()),
+        };
+
+        Ok(Self {
+            data_bits,
+            parity,
+            stop_bits,
+        })
+    }
+}
+
+impl fmt::Display for SerialFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Even => 'E',
+            Parity::Odd => 'O',
+        };
+        write!(f, "{}{}{}", self.data_bits, parity, self.stop_bits)
+    }
+}
+
+impl SerialOptions {
+    /// Returns whether the kernel runs on a board rather than in QEMU.
+    #[inline]
+    pub fn attached(&self) -> bool {
+        self.console_device.is_some() || self.probe.is_some()
+    }
+
+    /// Returns the files and directories that runs on the board write to.
+    pub fn output_paths(&self) -> Vec<PathBuf> {
+        self.tftp_dir.iter().cloned().collect()
+    }
+
+    /// Checks that the programs that the board is reached with are installed.
+    pub fn check_programs(&self) -> anyhow::Result<()> {
+        if self.probe.is_some() && crate::find_program("probe-rs").is_none() {
+            bail!(
+                "`probe-rs` is not found in PATH, install it with `cargo install probe-rs-tools`"
+            );
+        }
+        Ok(())
+    }
+
+    fn probe(&self) -> Option<Probe<'_>> {
+        Some(Probe {
+            chip: self.probe.as_deref()?,
+            selector: self.probe_selector.as_deref(),
+            protocol: self.probe_protocol,
+        })
+    }
+
+    pub fn runner_args(&self, runner: &mut Vec<OsString>) {
+        if let Some(device) = &self.console_device {
+            runner.push("--console-device".into());
+            runner.push(device.into());
+            runner.push("--baud".into());
+            runner.push(self.baud.to_string().into());
+            runner.push("--console-format".into());
+            runner.push(self.console_format.to_string().into());
+        }
+
+        if let Some(upload) = &self.upload {
+            runner.push("--upload".into());
+            runner.push(upload.as_ref().into());
+        }
+
+        if let Some(command) = &self.load_command {
+            runner.push("--load-command".into());
+            runner.push(command.into());
+        }
+
+        if let Some(command) = &self.boot_command {
+            runner.push("--boot-command".into());
+            runner.push(command.into());
+        }
+
+        if let Some(dir) = &self.tftp_dir {
+            runner.push("--tftp-dir".into());
+            runner.push(dir.into());
+        }
+
+        if let Some(config) = &self.openocd_config {
+            runner.push("--openocd-config".into());
+            runner.push(config.into());
+        }
+
+        if let Some(chip) = &self.probe {
+            runner.push("--probe".into());
+            runner.push(chip.into());
+            runner.push("--probe-protocol".into());
+            runner.push(self.probe_protocol.as_ref().into());
+        }
+
+        if let Some(selector) = &self.probe_selector {
+            runner.push("--probe-selector".into());
+            runner.push(selector.into());
+        }
+
+        if self.hardware {
+            runner.push("--hardware".into());
+        }
+
+        if let Some(command) = &self.power_on {
+            runner.push("--power-on".into());
+            runner.push(command.into());
+        }
+
+        if let Some(command) = &self.power_off {
+            runner.push("--power-off".into());
+            runner.push(command.into());
+        }
+    }
+
+    /// Loads `binary` onto the board and attaches to its console. With
+    /// `--hardware`, returns the exit code for the outcome of the run, which
+    /// ends after `timeout`.
+    pub fn execute(
+        self,
+        image_options: &ImageOptions,
+        binary: PathBuf,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<i32> {
+        let platform = Platform::from_str(&env::var("AX_PLATFORM")?)?;
+        if env::var_os(crate::qemu::APP_ARGS_ENV).is_some()
+            || env::var_os(crate::qemu::APP_ENV_ENV).is_some()
+        {
+            crate::warn_code(
+                crate::diagnostics::Code::IgnoredOption,
+                "the args and environment for the app are ignored on a board, put them in the command line of its bootloader",
+            );
+        }
+        if self.probe.is_some() && Arch::from(platform) != Arch::Aarch64 {
+            bail!(
+                "`--probe` only supports aarch64 boards, not platform `{}`",
+                platform
+            );
+        }
+        let Some(device) = &self.console_device else {
+            return self.execute_probe(binary, timeout);
+        };
+
+        let compressed = image_options.compression().is_some();
+        let image = crate::image::prepare(platform, image_options, binary.clone())?;
+        let image = crate::image::compress(image_options, image)?;
+        if let Some(dir) = &self.tftp_dir {
+            board::copy_to_tftp(&image, dir)?;
+        }
+        let port = sys::Port::open(device, self.baud, self.console_format)?;
+
+        if !self.hardware {
+            self.load(&port, &image, &binary, compressed, false)?;
+            port.console(device)?;
+            return Ok(0);
+        }
+
+        crate::signal::install();
+        let start = Instant::now();
+        let exit = self
+            .load(&port, &image, &binary, compressed, true)
+            .and_then(|_| board::watch(port.file(), timeout));
+        self.finish(exit, start)
+    }
+
+    /// Runs `binary` through the debug probe, with the console from RTT.
+    fn execute_probe(self, binary: PathBuf, timeout: Option<Duration>) -> anyhow::Result<i32> {
+        let probe = self.probe().unwrap();
+        if !self.hardware {
+            probe.attach(&binary)?;
+            return Ok(0);
+        }
+
+        crate::signal::install();
+        let start = Instant::now();
+        let exit = match &self.power_on {
+            Some(command) => board::shell(command),
+            None => Ok(()),
+        }
+        .and_then(|_| probe.run(&binary, timeout));
+        self.finish(exit, start)
+    }
+
+    /// Powers the board off after a run with `--hardware` that ended with
+    /// `exit`, and returns the exit code for it.
+    fn finish(&self, exit: anyhow::Result<GuestExit>, start: Instant) -> anyhow::Result<i32> {
+        let exit = exit.map(|exit| {
+            eprintln!();
+            crate::harness::finish(exit, start.elapsed())
+        });
+        if let Some(command) = &self.power_off {
+            board::shell(command)?;
+        }
+        let exit = exit?;
+        if exit != GuestExit::Success {
+            crate::warn(exit);
+        }
+        Ok(exit.code())
+    }
+
+    /// Powers the board on if `hardware`, and loads the kernel onto it.
+    fn load(
+        &self,
+        port: &sys::Port,
+        image: &Path,
+        binary: &Path,
+        compressed: bool,
+        hardware: bool,
+    ) -> anyhow::Result<()> {
+        let mut file = port.file();
+        if hardware && let Some(command) = &self.power_on {
+            board::shell(command)?;
+            if self.load_command.is_some() {
+                board::stop_autoboot(file)?;
+            }
+        }
+
+        if let Some(config) = &self.openocd_config {
+            return board::openocd(config, binary);
+        }
+        if let Some(probe) = self.probe() {
+            return probe.flash(binary);
+        }
+
+        if let Some(protocol) = self.upload {
+            if let Some(command) = &self.load_command {
+                write!(file, "{}\r", command)?;
+            }
+
+            let data = fs::read(image)
+                .with_context(|| format!("failed to read image `{}`", image.display()))?;
+            let name = image.file_name().unwrap().to_string_lossy();
+            crate::info(
+                "Uploading",
+                format!("`{}` with {}", image.display(), protocol.as_ref()),
+            );
+            modem::send(&mut file, protocol, &name, &data, |sent| {
+                eprint!(
+                    "\r{:>12} {}/{} KiB ({}%)",
+                    style("Sent").for_stderr().green().bold(),
+                    sent / 1024,
+                    data.len() / 1024,
+                    sent * 100 / data.len().max(1)
+                );
+            })
+            .inspect_err(|_| eprintln!())?;
+            eprintln!();
+
+            if let Some(command) = &self.boot_command {
+                if compressed {
+                    board::set_comp_size(file, command, data.len())?;
+                }
+                write!(file, "{}\r", command)?;
+            }
+        } else if self.tftp_dir.is_some() {
+            if let Some(command) = &self.load_command {
+                board::tftp_load(file, command)?;
+            }
+            if let Some(command) = &self.boot_command {
+                if compressed {
+                    board::set_comp_size(file, command, fs::metadata(image)?.len() as usize)?;
+                }
+                write!(file, "{}\r", command)?;
+            }
+        } else {
+            crate::info(
+                "Image",
+                format!("`{}` is ready to be loaded", image.display()),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{self, Read, Write},
+        mem::MaybeUninit,
+        os::{
+            fd::{AsRawFd, RawFd},
+            unix::fs::OpenOptionsExt,
+        },
+        path::Path,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread,
+    };
+
+    use anyhow::Context;
+
+    use super::{DETACH, Parity, SerialFormat};
+
+    /// Terminal settings of a file descriptor, restored when dropped.
+    struct Termios {
+        fd: RawFd,
+        saved: libc::termios,
+    }
+
+    impl Termios {
+        fn modify(fd: RawFd, f: impl FnOnce(&mut libc::termios)) -> io::Result<Self> {
+            let mut termios = MaybeUninit::uninit();
+            if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let saved = unsafe { termios.assume_init() };
+
+            let mut termios = saved;
+            f(&mut termios);
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self { fd, saved })
+        }
+    }
+
+    impl Drop for Termios {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) };
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn speed(baud: u32) -> libc::speed_t {
+        match baud {
+            1200 => libc::B1200,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            230400 => libc::B230400,
+            460800 => libc::B460800,
+            921600 => libc::B921600,
+            1500000 => libc::B1500000,
+            2000000 => libc::B2000000,
+            3000000 => libc::B3000000,
+            _ => unreachable!("baud rate is validated when parsing"),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn speed(baud: u32) -> libc::speed_t {
+        baud as libc::speed_t
+    }
+
+    fn configure(termios: &mut libc::termios, baud: u32, format: SerialFormat) {
+        unsafe {
+            libc::cfmakeraw(termios);
+            libc::cfsetspeed(termios, speed(baud));
+        }
+
+        termios.c_cflag &= !(libc::CSIZE | libc::PARENB | libc::PARODD | libc::CSTOPB);
+        termios.c_cflag |= libc::CLOCAL
+            | libc::CREAD
+            | match format.data_bits {
+                5 => libc::CS5,
+                6 => libc::CS6,
+                7 => libc::CS7,
+                _ => libc::CS8,
+            };
+        match format.parity {
+            Parity::None => {}
+            Parity::Even => termios.c_cflag |= libc::PARENB,
+            Parity::Odd => termios.c_cflag |= libc::PARENB | libc::PARODD,
+        }
+        if format.stop_bits == 2 {
+            termios.c_cflag |= libc::CSTOPB;
+        }
+
+        // Return from reads every 100ms so that detaching is noticed.
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 1;
+    }
+
+    /// A serial port configured for raw transmission.
+    pub struct Port {
+        file: File,
+        _termios: Termios,
+    }
+
+    impl Port {
+        pub fn open(path: &Path, baud: u32, format: SerialFormat) -> anyhow::Result<Self> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NOCTTY)
+                .open(path)
+                .with_context(|| format!("failed to open serial device `{}`", path.display()))?;
+            let termios = Termios::modify(file.as_raw_fd(), |t| configure(t, baud, format))
+                .with_context(|| {
+                    format!("failed to configure serial device `{}`", path.display())
+                })?;
+
+            crate::info(
+                "Opened",
+                format!("`{}` at {} baud {}", path.display(), baud, format),
+            );
+
+            Ok(Self {
+                file,
+                _termios: termios,
+            })
+        }
+
+        #[inline]
+        pub fn file(&self) -> &File {
+            &self.file
+        }
+
+        /// Forwards the port to the host terminal until the user detaches.
+        pub fn console(&self, path: &Path) -> anyhow::Result<()> {
+            let interactive = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+            let _stdin = if interactive {
+                // Forward every key press, but keep output post-processing so
+                // that line endings are still rendered properly.
+                Some(Termios::modify(libc::STDIN_FILENO, |t| {
+                    unsafe { libc::cfmakeraw(t) };
+                    t.c_oflag |= libc::OPOST;
+                })?)
+            } else {
+                None
+            };
+
+            if interactive {
+                crate::info("Attached", "press Ctrl-] to detach");
+            }
+
+            let detached = Arc::new(AtomicBool::new(false));
+            let mut input = self.file.try_clone()?;
+            thread::spawn({
+                let detached = detached.clone();
+                move || {
+                    let mut stdin = io::stdin().lock();
+                    let mut buf = [0; 256];
+                    while let Ok(n @ 1..) = stdin.read(&mut buf) {
+                        let pos = buf[..n].iter().position(|&b| b == DETACH);
+                        if input.write_all(&buf[..pos.unwrap_or(n)]).is_err() || pos.is_some() {
+                            break;
+                        }
+                    }
+                    if interactive {
+                        detached.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+
+            let mut output = &self.file;
+            let mut stdout = io::stdout().lock();
+            let mut buf = [0; 1024];
+            while !detached.load(Ordering::Relaxed) {
+                match output.read(&mut buf) {
+                    Ok(n) => {
+                        crate::logfile::console(&buf[..n]);
+                        stdout.write_all(&buf[..n])?;
+                        stdout.flush()?;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("failed to read from `{}`", path.display()));
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::{fs::File, path::Path};
+
+    use super::SerialFormat;
+
+    pub enum Port {}
+
+    impl Port {
+        pub fn open(_path: &Path, _baud: u32, _format: SerialFormat) -> anyhow::Result<Self> {
+            anyhow::bail!("serial consoles are only supported on Unix hosts")
+        }
+
+        pub fn file(&self) -> &File {
+            match *self {}
+        }
+
+        pub fn console(&self, _path: &Path) -> anyhow::Result<()> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Sender side of the XMODEM and YMODEM file transfer protocols, as accepted
+//! by bootloaders like U-Boot (`loadx` / `loady`).
+
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+const CRC: u8 = b'C';
+
+const RETRIES: usize = 10;
+const TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Protocol {
+    Xmodem,
+    Ymodem,
+}
+
+/// Reads a single byte, relying on the port returning from reads periodically.
+fn read_byte(port: &mut impl Read, timeout: Duration) -> io::Result<Option<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0];
+    while Instant::now() < deadline {
+        match port.read(&mut buf) {
+            Ok(1) => return Ok(Some(buf[0])),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// Waits for the receiver to start a transfer and returns whether it asked
+/// for CRC-16 instead of the arithmetic checksum.
+fn handshake(port: &mut impl Read) -> anyhow::Result<bool> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    while Instant::now() < deadline {
+        match read_byte(port, TIMEOUT)? {
+            Some(CRC) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN) => bail!("transfer cancelled by the receiver"),
+            // Skip whatever the bootloader echoes before it is ready.
+            _ => {}
+        }
+    }
+    bail!("receiver did not start the transfer")
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn send_block(
+    port: &mut (impl Read + Write),
+    crc: bool,
+    seq: u8,
+    data: &[u8],
+    size: usize,
+    pad: u8,
+) -> anyhow::Result<()> {
+    let mut packet = Vec::with_capacity(size + 5);
+    packet.push(if size == 1024 { STX } else { SOH });
+    packet.push(seq);
+    packet.push(!seq);
+    packet.extend_from_slice(data);
+    packet.resize(3 + size, pad);
+    if crc {
+        let crc = crc16(&packet[3..]);
+        packet.extend(crc.to_be_bytes());
+    } else {
+        let sum = packet[3..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        packet.push(sum);
+    }
+
+    for _ in 0..RETRIES {
+        port.write_all(&packet)?;
+        port.flush()?;
+        match read_byte(port, TIMEOUT)? {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => bail!("transfer cancelled by the receiver"),
+            _ => {}
+        }
+    }
+    bail!("block {} was not acknowledged", seq)
+}
+
+fn send_eot(port: &mut (impl Read + Write)) -> anyhow::Result<()> {
+    // YMODEM receivers NAK the first EOT on purpose, so simply retry.
+    for _ in 0..RETRIES {
+        port.write_all(&[EOT])?;
+        port.flush()?;
+        match read_byte(port, TIMEOUT)? {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => bail!("transfer cancelled by the receiver"),
+            _ => {}
+        }
+    }
+    bail!("end of transmission was not acknowledged")
+}
+
+/// Sends `data` as the file `name`, calling `progress` with the number of
+/// bytes transferred after each block.
+pub fn send(
+    port: &mut (impl Read + Write),
+    protocol: Protocol,
+    name: &str,
+    data: &[u8],
+    mut progress: impl FnMut(usize),
+) -> anyhow::Result<()> {
+    let mut crc = handshake(port)?;
+
+    let size = match protocol {
+        Protocol::Xmodem => 128,
+        Protocol::Ymodem => {
+            let mut header = name.as_bytes().to_vec();
+            header.push(0);
+            header.extend(data.len().to_string().as_bytes());
+            if header.len() > 128 {
+                bail!("file name `{}` is too long", name);
+            }
+            send_block(port, true, 0, &header, 128, 0)?;
+            crc = handshake(port)?;
+
+            1024
+        }
+    };
+
+    for (i, chunk) in data.chunks(size).enumerate() {
+        send_block(port, crc, (i + 1) as u8, chunk, size, SUB)?;
+        progress((i * size + chunk.len()).min(data.len()));
+    }
+    send_eot(port)?;
+
+    if let Protocol::Ymodem = protocol {
+        // An empty header ends the batch.
+        handshake(port)?;
+        send_block(port, true, 0, &[], 128, 0)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A receiver that answers with `replies`, one byte at a time, and
+    /// records what is sent.
+    struct Port {
+        replies: VecDeque<u8>,
+        sent: Vec<u8>,
+    }
+
+    impl Port {
+        fn new(replies: &[u8]) -> Self {
+            Port {
+                replies: replies.iter().copied().collect(),
+                sent: vec![],
+            }
+        }
+    }
+
+    impl Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.replies.pop_front() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                }
+                None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            }
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn crc16_check() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn block_1k() {
+        let mut port = Port::new(&[ACK]);
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        send_block(&mut port, true, 1, &data, 1024, SUB).unwrap();
+        let sent = &port.sent;
+        assert_eq!(sent.len(), 3 + 1024 + 2);
+        assert_eq!(&sent[..3], &[STX, 1, 0xfe]);
+        assert_eq!(&sent[3..1003], &data[..]);
+        assert!(sent[1003..1027].iter().all(|&b| b == SUB));
+        assert_eq!(sent[1027..], crc16(&sent[3..1027]).to_be_bytes());
+    }
+
+    #[test]
+    fn block_resent_until_acked() {
+        let mut port = Port::new(&[NAK, ACK]);
+        send_block(&mut port, false, 2, b"hi", 128, SUB).unwrap();
+        // Two copies with the arithmetic checksum
+        assert_eq!(port.sent.len(), 2 * (3 + 128 + 1));
+        assert_eq!(&port.sent[..3], &[SOH, 2, 0xfd]);
+        let sum = (b'h' as u32 + b'i' as u32 + 126 * SUB as u32) as u8;
+        assert_eq!(port.sent[131], sum);
+    }
+
+    #[test]
+    fn ymodem_header() {
+        // The handshake, block 0, the handshake, block 1, the EOT that is
+        // NAKed and resent, and the empty header that ends the batch
+        let mut port = Port::new(&[CRC, ACK, CRC, ACK, NAK, ACK, CRC, ACK]);
+        send(&mut port, Protocol::Ymodem, "kernel.bin", b"abc", |_| {}).unwrap();
+        let sent = &port.sent;
+
+        let header = &sent[..133];
+        assert_eq!(&header[..3], &[SOH, 0, 0xff]);
+        assert_eq!(&header[3..17], b"kernel.bin\x003\0\0");
+        assert!(header[17..131].iter().all(|&b| b == 0));
+        assert_eq!(header[131..], crc16(&header[3..131]).to_be_bytes());
+
+        let block = &sent[133..133 + 1029];
+        assert_eq!(&block[..6], &[STX, 1, 0xfe, b'a', b'b', b'c']);
+        let rest = &sent[133 + 1029..];
+        assert_eq!(&rest[..2], &[EOT, EOT]);
+        assert_eq!(&rest[2..5], &[SOH, 0, 0xff]);
+        assert!(rest[5..133].iter().all(|&b| b == 0));
+        assert_eq!(rest.len(), 2 + 133);
+    }
+}
@@ -0,0 +1,130 @@
+//! Running the kernel on the chip of a board through a debug probe with
+//! probe-rs, for `--probe`.
+//!
+//! The kernel is flashed over SWD or JTAG. Its console is the serial port of
+//! `--console-device` if given, or otherwise its RTT channel: a buffer in the
+//! memory of the kernel that probe-rs reads through the probe, found by the
+//! `_SEGGER_RTT` control block that the kernel has to set up.
+
+use std::{
+    io::{self, Read},
+    path::Path,
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use strum::{AsRefStr, EnumString, VariantNames};
+
+use crate::exit::GuestExit;
+
+#[derive(Debug, Clone, Copy, Default, EnumString, VariantNames, AsRefStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum ProbeProtocol {
+    #[default]
+    Swd,
+    Jtag,
+}
+
+/// A chip reached through a debug probe.
+pub struct Probe<'a> {
+    pub chip: &'a str,
+    /// The probe as `VID:PID[:SERIAL]`, or the only one attached.
+    pub selector: Option<&'a str>,
+    pub protocol: ProbeProtocol,
+}
+
+impl Probe<'_> {
+    fn command(&self, subcommand: &str) -> Command {
+        let mut command = Command::new("probe-rs");
+        command.args([subcommand, "--chip", self.chip]);
+        if let Some(selector) = self.selector {
+            command.args(["--probe", selector]);
+        }
+        command.args(["--protocol", self.protocol.as_ref()]);
+        command
+    }
+
+    /// Flashes the ELF `binary` and resets the chip to start it, for its
+    /// console to be read elsewhere.
+    pub fn flash(&self, binary: &Path) -> anyhow::Result<()> {
+        crate::run_command(self.command("download").arg(binary))
+            .context("failed to flash the kernel with probe-rs")?;
+        crate::run_command(&mut self.command("reset"))
+            .context("failed to reset the chip with probe-rs")
+    }
+
+    /// Flashes and starts `binary`, printing its RTT console until it is
+    /// detached with Ctrl-C.
+    pub fn attach(&self, binary: &Path) -> anyhow::Result<()> {
+        crate::run_command(self.command("run").arg(binary))
+            .context("failed to run the kernel with probe-rs")
+    }
+
+    /// Flashes and starts `binary`, and watches its RTT console like a serial
+    /// one for `--hardware`.
+    pub fn run(&self, binary: &Path, timeout: Option<Duration>) -> anyhow::Result<GuestExit> {
+        let mut command = self.command("run");
+        command.arg(binary).stdout(Stdio::piped());
+        crate::info("Running", format!("`{}`", crate::command_line(&command)));
+        let mut child = command.spawn().context("failed to run `probe-rs`")?;
+
+        let mut stdout = child.stdout.take().unwrap();
+        let (sender, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = stdout.read(&mut buf) {
+                if sender.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut console = Rtt {
+            chunks,
+            pending: vec![],
+            closed: false,
+        };
+        let exit = super::board::watch(&mut console, timeout);
+        if console.closed {
+            // probe-rs only stops by itself when the kernel exits through
+            // semihosting, with the status encoded like QEMU does.
+            let status = child
+                .wait()
+                .context("could not get probe-rs's exit status")?;
+            return Ok(GuestExit::new(status));
+        }
+        child.kill().ok();
+        child.wait().ok();
+        exit
+    }
+}
+
+/// The RTT console that probe-rs prints, read like a serial port that returns
+/// from reads periodically.
+struct Rtt {
+    chunks: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl Read for Rtt {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.chunks.recv_timeout(Duration::from_millis(100)) {
+                Ok(chunk) => self.pending = chunk,
+                Err(RecvTimeoutError::Timeout) => return Ok(0),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.closed = true;
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
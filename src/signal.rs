@@ -0,0 +1,37 @@
+//! Catching SIGINT and SIGTERM so that the runner can shut QEMU down instead
+//! of leaving it behind.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn handler(signal: libc::c_int) {
+    RECEIVED.store(signal, Ordering::SeqCst);
+}
+
+/// Starts recording SIGINT and SIGTERM instead of being terminated by them.
+pub fn install() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Returns the signal received since [`install`], if any.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Sends `signal` to the process `pid`.
+#[cfg(unix)]
+pub fn kill(pid: u32, signal: i32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, signal);
+    }
+}
@@ -0,0 +1,108 @@
+//! Checksum files and detached signatures of built kernels and images, for
+//! verifying what gets deployed.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use clap::{Args, builder::TypedValueParser};
+use strum::VariantNames;
+
+use crate::{
+    image::{Checksum, ImageOptions},
+    platforms::Platform,
+};
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "Signing Options")]
+pub struct SigningOptions {
+    /// Write a checksum file next to the image of each kernel, or the SD card
+    /// image, like `kernel.bin.sha256` in the format of `sha256sum`
+    #[arg(long, value_parser = enum_variants!(Checksum))]
+    checksum: Option<Checksum>,
+
+    /// Sign the image of each kernel, or the SD card image, with the private
+    /// key in KEY using OpenSSL, writing a detached signature next to it as
+    /// `.sig`
+    #[arg(long, value_name = "KEY")]
+    sign: Option<PathBuf>,
+}
+
+impl SigningOptions {
+    pub fn is_empty(&self) -> bool {
+        self.checksum.is_none() && self.sign.is_none()
+    }
+
+    /// Writes the checksum file and the signature of `path`.
+    pub fn apply(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(checksum) = self.checksum {
+            let data =
+                fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+            let digest = match checksum {
+                Checksum::Crc32 => format!("{:08x}", crate::checksum::crc32(&data)),
+                Checksum::Sha256 => crate::checksum::sha256(&data)
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect(),
+            };
+            let file = with_suffix(path, checksum.as_ref());
+            let name = path.file_name().unwrap().to_string_lossy();
+            crate::write_atomic(&file, format!("{}  {}\n", digest, name))
+                .with_context(|| format!("failed to write `{}`", file.display()))?;
+            crate::info("Checksum", format!("`{}`", file.display()));
+        }
+
+        if let Some(key) = &self.sign {
+            let signature = with_suffix(path, "sig");
+            let mut command = Command::new("openssl");
+            command
+                .args(["dgst", "-sha256", "-sign"])
+                .arg(key)
+                .arg("-out")
+                .arg(&signature)
+                .arg(path);
+            crate::run_command(&mut command)
+                .with_context(|| format!("failed to sign `{}`", path.display()))?;
+            crate::info("Signed", format!("`{}`", signature.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Signing the images of the kernels that a build makes.
+#[derive(Debug)]
+pub struct Signer {
+    pub options: SigningOptions,
+    pub platform: Platform,
+    /// How the images are made from the built executables.
+    pub image: ImageOptions,
+}
+
+impl Signer {
+    /// Writes the checksum files and signatures of the images of
+    /// `executables`, which are the images already if `collected` into
+    /// `--out-dir`.
+    pub fn apply(&self, executables: &[PathBuf], collected: bool) -> anyhow::Result<()> {
+        for executable in executables {
+            let image = if collected {
+                executable.clone()
+            } else {
+                let image = crate::image::prepare(self.platform, &self.image, executable.clone())?;
+                crate::image::compress(&self.image, image)?
+            };
+            self.options.apply(&image)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns `path` with `.suffix` appended, keeping its extension.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    path.into()
+}
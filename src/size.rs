@@ -0,0 +1,410 @@
+//! The sizes of a kernel and of the crates in it, for `cargo arceos size`.
+//!
+//! The sizes of the sections come from the ELF and those of the crates from
+//! its symbol table, where a symbol belongs to the crate that its demangled
+//! path starts with. Baselines are kept per platform and profile in the
+//! target directory, for `--diff` to report what grew since.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{Context, bail};
+use console::style;
+use serde::{Deserialize, Serialize};
+
+/// The header of the size columns.
+const HEADER: &str = "      text       data        bss      total  crate";
+
+/// The crate of symbols whose names have no path, like those of assembly.
+const UNKNOWN_CRATE: &str = "[unknown]";
+
+/// Text, data and BSS sizes in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Size {
+    pub text: u64,
+    pub data: u64,
+    pub bss: u64,
+}
+
+impl Size {
+    pub fn total(&self) -> u64 {
+        self.text + self.data + self.bss
+    }
+
+    fn add(&mut self, kind: SymbolKind, size: u64) {
+        match kind {
+            SymbolKind::Text => self.text += size,
+            SymbolKind::Data => self.data += size,
+            SymbolKind::Bss => self.bss += size,
+        }
+    }
+}
+
+/// The sizes of a kernel.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Sizes {
+    /// The allocated sections together.
+    pub sections: Size,
+    pub crates: BTreeMap<String, Size>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Text,
+    Data,
+    Bss,
+}
+
+/// A symbol of the kernel with a size.
+#[derive(Debug)]
+pub struct Symbol {
+    /// The demangled name.
+    pub name: String,
+    pub size: u64,
+    pub kind: SymbolKind,
+}
+
+impl Symbol {
+    /// Returns the crate that the symbol belongs to, which is the first
+    /// segment of its path, or the one of the type for trait methods like
+    /// `<alloc::vec::Vec<T> as core::fmt::Debug>::fmt`. Types without a path,
+    /// like `T` or `[u8]`, leave it to the trait.
+    pub fn crate_name(&self) -> &str {
+        // Some versions of nm leave names like `_$LT$alloc..vec..Vec...` of
+        // the legacy mangling half-demangled.
+        let name = self.name.strip_prefix("_$LT$").unwrap_or(&self.name);
+        first_segment(name)
+            .or_else(|| {
+                let (_, name) = name
+                    .split_once(" as ")
+                    .or_else(|| name.split_once("$u20$as$u20$"))?;
+                first_segment(name)
+            })
+            .unwrap_or(UNKNOWN_CRATE)
+    }
+}
+
+/// Returns the first segment of the path that `name` starts with, if it
+/// starts with one.
+fn first_segment(name: &str) -> Option<&str> {
+    let name = name.trim_start_matches(['<', '&', '*', '(', '[']);
+    let name = name.strip_prefix("mut ").unwrap_or(name);
+    let name = name.strip_prefix("dyn ").unwrap_or(name);
+    let end = name.find("::").into_iter().chain(name.find("..")).min()?;
+    Some(&name[..end])
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+/// Returns the symbols of `elf` with a size, as nm lists them.
+pub fn symbols(elf: &Path) -> anyhow::Result<Vec<Symbol>> {
+    let program = ["llvm-nm", "nm"]
+        .into_iter()
+        .find(|program| crate::find_program(program).is_some())
+        .context("nm is not found in PATH")?;
+
+    let output = Command::new(program)
+        .args(["--print-size", "--demangle", "--defined-only"])
+        .arg(elf)
+        .output()
+        .with_context(|| format!("failed to run `{}`", program))?;
+    if !output.status.success() {
+        bail!(
+            "`{}` failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // Each symbol is `address size type name`, without the size for symbols
+    // that have none.
+    let mut symbols = vec![];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.splitn(4, ' ');
+        let (Some(_), Some(size), Some(kind), Some(name)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let Ok(size) = u64::from_str_radix(size, 16) else {
+            continue;
+        };
+        let kind = match kind.to_ascii_lowercase().as_str() {
+            "t" | "w" => SymbolKind::Text,
+            "d" | "r" | "g" => SymbolKind::Data,
+            "b" | "s" => SymbolKind::Bss,
+            _ => continue,
+        };
+        symbols.push(Symbol {
+            name: name.to_string(),
+            size,
+            kind,
+        });
+    }
+    Ok(symbols)
+}
+
+/// Returns the sizes of the allocated sections of the kernel `elf`.
+pub fn measure_sections(elf: &Path) -> anyhow::Result<Size> {
+    let data = fs::read(elf).with_context(|| format!("failed to read `{}`", elf.display()))?;
+    let mut size = Size::default();
+    for section in crate::memmap::sections(&data)
+        .with_context(|| format!("failed to read sections of `{}`", elf.display()))?
+    {
+        let kind = if section.name.starts_with(".text") {
+            SymbolKind::Text
+        } else if section.name.contains("bss") {
+            SymbolKind::Bss
+        } else {
+            SymbolKind::Data
+        };
+        size.add(kind, section.size);
+    }
+    Ok(size)
+}
+
+/// Returns the sizes of the kernel `elf`.
+pub fn measure(elf: &Path) -> anyhow::Result<Sizes> {
+    let mut sizes = Sizes {
+        sections: measure_sections(elf)?,
+        ..Default::default()
+    };
+    for symbol in symbols(elf)? {
+        sizes
+            .crates
+            .entry(symbol.crate_name().to_string())
+            .or_default()
+            .add(symbol.kind, symbol.size);
+    }
+    Ok(sizes)
+}
+
+/// Returns where the baseline for `platform` and `profile` is kept.
+pub fn baseline_path(target_dir: &Path, platform: &str, profile: &str) -> PathBuf {
+    target_dir
+        .join("arceos-size")
+        .join(platform)
+        .join(format!("{}.json", profile))
+}
+
+pub fn save_baseline(path: &Path, sizes: &Sizes) -> anyhow::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    crate::write_atomic(path, serde_json::to_string_pretty(sizes)? + "\n")
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+    crate::info("Saved", format!("size baseline `{}`", path.display()));
+    Ok(())
+}
+
+pub fn load_baseline(path: &Path) -> anyhow::Result<Sizes> {
+    let json = fs::read_to_string(path).with_context(|| {
+        format!(
+            "no size baseline at `{}`, save one with `cargo arceos size --save-baseline`",
+            path.display()
+        )
+    })?;
+    serde_json::from_str(&json).with_context(|| format!("failed to parse `{}`", path.display()))
+}
+
+/// How much the kernel may grow over the baseline.
+#[derive(Debug, Clone, Copy)]
+pub enum Threshold {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl FromStr for Threshold {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|percent: &f64| *percent >= 0.0)
+                .map(Threshold::Percent)
+                .with_context(|| format!("invalid percentage `{}`", s)),
+            None => crate::image::parse_size(s).map(Threshold::Bytes),
+        }
+    }
+}
+
+impl Threshold {
+    fn allows(self, base: u64, growth: i64) -> bool {
+        match self {
+            Threshold::Bytes(bytes) => growth <= bytes as i64,
+            Threshold::Percent(percent) => growth as f64 <= base as f64 * percent / 100.0,
+        }
+    }
+}
+
+fn delta(current: u64, base: u64) -> i64 {
+    current as i64 - base as i64
+}
+
+/// Returns `delta` in a column, red if it grew and green if it shrank.
+fn signed(delta: i64) -> String {
+    let column = format!("{:>+10}", delta);
+    match delta.signum() {
+        1 => style(column).red().to_string(),
+        -1 => style(column).green().to_string(),
+        _ => column,
+    }
+}
+
+/// Prints the sizes of the sections and of the `top` largest crates.
+pub fn print(sizes: &Sizes, top: usize) {
+    let mut crates: Vec<_> = sizes.crates.iter().collect();
+    crates.sort_by_key(|(name, size)| (std::cmp::Reverse(size.total()), *name));
+    println!("{}", HEADER);
+    for (name, size) in crates.iter().take(top) {
+        println!(
+            "{:>10} {:>10} {:>10} {:>10}  {}",
+            size.text,
+            size.data,
+            size.bss,
+            size.total(),
+            name
+        );
+    }
+    if crates.len() > top {
+        println!("{:>43}  ({} more crates)", "", crates.len() - top);
+    }
+    let size = &sizes.sections;
+    println!(
+        "{:>10} {:>10} {:>10} {:>10}  {}",
+        size.text,
+        size.data,
+        size.bss,
+        size.total(),
+        style("sections").bold()
+    );
+}
+
+/// Prints how `sizes` differ from `base` for the `top` crates that changed
+/// most, and fails if the sections grew by more than `threshold`.
+pub fn print_diff(
+    sizes: &Sizes,
+    base: &Sizes,
+    top: usize,
+    threshold: Option<Threshold>,
+) -> anyhow::Result<()> {
+    let mut crates: Vec<(&str, Size, Size)> = sizes
+        .crates
+        .keys()
+        .chain(base.crates.keys())
+        .map(String::as_str)
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .map(|name| {
+            let size = |sizes: &Sizes| sizes.crates.get(name).copied().unwrap_or_default();
+            (name, size(sizes), size(base))
+        })
+        .filter(|(_, size, base)| size != base)
+        .collect();
+    crates.sort_by_key(|(name, size, base)| {
+        (
+            std::cmp::Reverse(delta(size.total(), base.total()).unsigned_abs()),
+            *name,
+        )
+    });
+
+    let row = |size: &Size, base: &Size, name: &dyn std::fmt::Display| {
+        println!(
+            "{} {} {} {}  {}",
+            signed(delta(size.text, base.text)),
+            signed(delta(size.data, base.data)),
+            signed(delta(size.bss, base.bss)),
+            signed(delta(size.total(), base.total())),
+            name
+        );
+    };
+    println!("{}", HEADER);
+    for (name, size, base) in crates.iter().take(top) {
+        row(size, base, name);
+    }
+    if crates.len() > top {
+        println!("{:>43}  ({} more crates changed)", "", crates.len() - top);
+    }
+    row(&sizes.sections, &base.sections, &style("sections").bold());
+
+    let (total, base_total) = (sizes.sections.total(), base.sections.total());
+    let growth = delta(total, base_total);
+    if let Some(threshold) = threshold
+        && !threshold.allows(base_total, growth)
+    {
+        bail!(
+            "the kernel grew by {} bytes to {} bytes, more than `--max-growth` allows",
+            growth,
+            total
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crate_of(name: &str) -> String {
+        Symbol {
+            name: name.to_string(),
+            size: 0,
+            kind: SymbolKind::Text,
+        }
+        .crate_name()
+        .to_string()
+    }
+
+    #[test]
+    fn crate_names() {
+        for (symbol, name) in [
+            ("axhal::arch::trap::handle_irq", "axhal"),
+            ("core::ptr::drop_in_place<axfs::File>", "core"),
+            ("<alloc::vec::Vec<T> as core::fmt::Debug>::fmt", "alloc"),
+            ("<&mut axfs::File as axio::Read>::read", "axfs"),
+            ("<dyn core::any::Any>::type_id", "core"),
+            ("<T as alloc::string::ToString>::to_string", "alloc"),
+            ("<[u8] as core::fmt::Debug>::fmt", "core"),
+            (
+                "_$LT$alloc..vec..Vec$LT$T$GT$$u20$as$u20$core..fmt..Debug$GT$::fmt",
+                "alloc",
+            ),
+            ("_$LT$T$u20$as$u20$axio..Write$GT$::flush", "axio"),
+            ("memcpy", UNKNOWN_CRATE),
+            ("<T as U>::f", UNKNOWN_CRATE),
+        ] {
+            assert_eq!(crate_of(symbol), name, "`{}`", symbol);
+        }
+    }
+
+    #[test]
+    fn thresholds() {
+        assert!(matches!("4K".parse(), Ok(Threshold::Bytes(4096))));
+        assert!(matches!("100".parse(), Ok(Threshold::Bytes(100))));
+        assert!(matches!("2.5%".parse(), Ok(Threshold::Percent(p)) if p == 2.5));
+        assert!(matches!(" 1 %".parse(), Ok(Threshold::Percent(p)) if p == 1.0));
+        for s in ["-1%", "x%", "%", "1.5K", "-4K", ""] {
+            assert!(s.parse::<Threshold>().is_err(), "`{}` parsed", s);
+        }
+    }
+
+    #[test]
+    fn threshold_allows() {
+        let bytes = Threshold::Bytes(100);
+        assert!(bytes.allows(1000, 100));
+        assert!(!bytes.allows(1000, 101));
+        assert!(bytes.allows(0, -500));
+
+        let percent = Threshold::Percent(1.0);
+        assert!(percent.allows(10_000, 100));
+        assert!(!percent.allows(10_000, 101));
+        assert!(percent.allows(10_000, -1));
+        // Nothing may grow from nothing.
+        assert!(!percent.allows(0, 1));
+    }
+}
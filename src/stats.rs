@@ -0,0 +1,274 @@
+//! Statistics of the builds, for `cargo arceos stats` to show how long builds
+//! take and how large the kernels get over time. Nothing leaves the machine:
+//! each successful `build` and `run` adds an entry to `arceos-stats.json` in
+//! the target directory, or in `--scratch-dir`, unless `--no-stats` is given.
+//!
+//! The build of `run` is recorded by the runner, as cargo starts it once the
+//! build is done.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use console::style;
+use serde::{Deserialize, Serialize};
+
+/// The statistics file that the runner records the build in.
+const STATS_ENV: &str = "CARGO_ARCEOS_STATS";
+
+/// How many builds are kept, the oldest are dropped first.
+const MAX_ENTRIES: usize = 1000;
+
+/// A recorded build.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// When the build finished, in seconds since the Unix epoch.
+    time: u64,
+    command: String,
+    platform: String,
+    profile: String,
+    /// How long cargo took, in seconds.
+    duration: f64,
+    binaries: Vec<Binary>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Binary {
+    name: String,
+    /// The allocated sections together, in bytes.
+    size: u64,
+}
+
+/// Returns where the statistics are kept in `dir`.
+pub fn path(dir: &Path) -> PathBuf {
+    dir.join("arceos-stats.json")
+}
+
+/// Locks the statistics in `path` against other builds recording theirs,
+/// until the returned file is dropped.
+fn lock(path: &Path) -> anyhow::Result<File> {
+    let lock_path = path.with_extension("lock");
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(&lock_path)
+        .and_then(|file| file.lock().map(|()| file))
+        .with_context(|| format!("failed to lock `{}`", lock_path.display()))?;
+    Ok(file)
+}
+
+fn load(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let Ok(json) = fs::read_to_string(path) else {
+        return Ok(vec![]);
+    };
+    serde_json::from_str(&json).with_context(|| format!("failed to parse `{}`", path.display()))
+}
+
+/// Records the builds of a platform and profile.
+#[derive(Debug)]
+pub struct Recorder {
+    pub path: PathBuf,
+    pub platform: String,
+    pub profile: String,
+}
+
+impl Recorder {
+    /// Has the runner spawned by `command` record the build instead.
+    pub fn apply(&self, command: &mut Command) {
+        command.env(STATS_ENV, &self.path);
+    }
+
+    /// Records that `command` built `executables` in `duration`. Failing to
+    /// record it does not fail the command.
+    pub fn record(&self, command: &str, duration: Duration, executables: &[PathBuf]) {
+        if let Err(e) = self.try_record(command, duration, executables) {
+            crate::warn(format!("could not record the build statistics: {:#}", e));
+        }
+    }
+
+    fn try_record(
+        &self,
+        command: &str,
+        duration: Duration,
+        executables: &[PathBuf],
+    ) -> anyhow::Result<()> {
+        let binaries = executables
+            .iter()
+            .map(|executable| {
+                Ok(Binary {
+                    name: executable
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    size: crate::size::measure_sections(executable)?.total(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        fs::create_dir_all(self.path.parent().unwrap())?;
+        // Builds may finish at the same time, and each adds its entry to what
+        // the others have written.
+        let _lock = lock(&self.path)?;
+        let mut entries = load(&self.path)?;
+        entries.push(Entry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            command: command.to_string(),
+            platform: self.platform.clone(),
+            profile: self.profile.clone(),
+            duration: duration.as_secs_f64(),
+            binaries,
+        });
+        let excess = entries.len().saturating_sub(MAX_ENTRIES);
+        entries.drain(..excess);
+
+        crate::write_atomic(&self.path, serde_json::to_string_pretty(&entries)? + "\n")
+            .with_context(|| format!("failed to write `{}`", self.path.display()))
+    }
+}
+
+/// Records the build of `binary` from the runner, if it was asked to.
+pub fn record_run(binary: &Path) {
+    let (Some(path), Some(duration)) = (env::var_os(STATS_ENV), crate::timings::since_started())
+    else {
+        return;
+    };
+    let (Ok(platform), Ok(profile)) = (env::var("AX_PLATFORM"), env::var("AX_MODE")) else {
+        return;
+    };
+    Recorder {
+        path: path.into(),
+        platform,
+        profile,
+    }
+    .record("run", duration, &[binary.to_path_buf()]);
+}
+
+/// The builds of a binary, oldest first.
+struct Trend {
+    durations: Vec<f64>,
+    first_size: u64,
+    last_size: u64,
+}
+
+/// Returns the median of `values`, which are not empty.
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Prints the last `last` builds in `path` that match `platform` and
+/// `profile`, and how the build time and the size of each binary changed
+/// over all of them.
+pub fn print(
+    path: &Path,
+    platform: Option<&str>,
+    profile: Option<&str>,
+    last: usize,
+) -> anyhow::Result<()> {
+    let entries: Vec<Entry> = load(path)?
+        .into_iter()
+        .filter(|entry| platform.is_none_or(|platform| entry.platform == platform))
+        .filter(|entry| profile.is_none_or(|profile| entry.profile == profile))
+        .collect();
+    if entries.is_empty() {
+        crate::info(
+            "Stats",
+            format!("no builds are recorded in `{}`", path.display()),
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        style(format!(
+            "{:<20}  {:<7}  {:<24}  {:<10}  {:>9}  {:>10}  binary",
+            "time", "command", "platform", "profile", "build", "size"
+        ))
+        .bold()
+    );
+    for entry in entries.iter().skip(entries.len().saturating_sub(last)) {
+        for binary in &entry.binaries {
+            println!(
+                "{:<20}  {:<7}  {:<24}  {:<10}  {:>9}  {:>10}  {}",
+                crate::options::utc_time(entry.time),
+                entry.command,
+                entry.platform,
+                entry.profile,
+                crate::timings::format(Duration::from_secs_f64(entry.duration)),
+                binary.size,
+                binary.name
+            );
+        }
+    }
+
+    // The builds of each binary, by platform, profile and name.
+    let mut trends: BTreeMap<(&str, &str, &str), Trend> = BTreeMap::new();
+    for entry in &entries {
+        for binary in &entry.binaries {
+            let trend = trends
+                .entry((&entry.platform, &entry.profile, &binary.name))
+                .or_insert(Trend {
+                    durations: vec![],
+                    first_size: binary.size,
+                    last_size: 0,
+                });
+            trend.durations.push(entry.duration);
+            trend.last_size = binary.size;
+        }
+    }
+    println!();
+    for ((platform, profile, name), mut trend) in trends {
+        let builds = trend.durations.len();
+        let last = *trend.durations.last().unwrap();
+        let median = median(&mut trend.durations);
+        let change = if median > 0.0 {
+            format!(" ({:+.1}%)", (last - median) / median * 100.0)
+        } else {
+            String::new()
+        };
+        println!(
+            "{} {} {}: {} build{}, median {}, last {}{}, size {} ({:+} since the first)",
+            style(platform).bold(),
+            profile,
+            name,
+            builds,
+            if builds == 1 { "" } else { "s" },
+            crate::timings::format(Duration::from_secs_f64(median)),
+            crate::timings::format(Duration::from_secs_f64(last)),
+            change,
+            trend.last_size,
+            trend.last_size as i64 - trend.first_size as i64
+        );
+    }
+    Ok(())
+}
+
+/// Forgets the builds recorded in `path`.
+pub fn clear(path: &Path) -> anyhow::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let _lock = lock(path)?;
+    match fs::remove_file(path) {
+        Ok(()) => {
+            crate::info("Removed", format!("`{}`", path.display()));
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("failed to remove `{}`", path.display())),
+    }
+}
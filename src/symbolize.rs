@@ -0,0 +1,98 @@
+//! Resolving the addresses that the guest prints in panics and backtraces to
+//! functions and source lines, for `cargo arceos symbolize`.
+
+use std::{collections::HashMap, path::Path, process::Command};
+
+use anyhow::Context;
+
+/// Addresses are only looked at from this many hex digits on, which leaves
+/// out most numbers that are not addresses.
+const MIN_DIGITS: usize = 8;
+
+/// Returns the hex addresses in `line`, like `0xffffffc080200000`.
+fn addresses(line: &str) -> impl Iterator<Item = u64> + '_ {
+    line.match_indices("0x").filter_map(|(i, _)| {
+        let digits: &str = &line[i + 2..];
+        let len = digits
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(digits.len());
+        if !(MIN_DIGITS..=16).contains(&len) {
+            return None;
+        }
+        u64::from_str_radix(&digits[..len], 16).ok()
+    })
+}
+
+/// Resolves `addresses` with addr2line, returning the frames of each address
+/// it knows, innermost first.
+fn resolve(elf: &Path, addresses: &[u64]) -> anyhow::Result<HashMap<u64, Vec<String>>> {
+    let program = ["llvm-addr2line", "addr2line"]
+        .into_iter()
+        .find(|program| crate::find_program(program).is_some())
+        .context("addr2line is not found in PATH")?;
+
+    let output = Command::new(program)
+        .arg("-e")
+        .arg(elf)
+        .args(["-a", "-f", "-i", "-p", "-C"])
+        .args(addresses.iter().map(|addr| format!("{:#x}", addr)))
+        .output()
+        .with_context(|| format!("failed to run `{}`", program))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "`{}` failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // Each address starts with `0x...: function at file:line`, followed by a
+    // `(inlined by) ...` line for each function it is inlined into.
+    let mut frames: HashMap<u64, Vec<String>> = HashMap::new();
+    let mut current = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim();
+        if let Some((addr, frame)) = line.split_once(": ")
+            && let Some(addr) = addr.strip_prefix("0x")
+            && let Ok(addr) = u64::from_str_radix(addr, 16)
+        {
+            current = Some(addr);
+            if !frame.starts_with("??") {
+                frames.entry(addr).or_default().push(frame.to_string());
+            }
+        } else if let Some(frame) = line.strip_prefix("(inlined by) ")
+            && let Some(addr) = current
+            && let Some(frames) = frames.get_mut(&addr)
+        {
+            frames.push(frame.to_string());
+        }
+    }
+    Ok(frames)
+}
+
+/// Prints `input` with the frames of every address that `elf` knows below the
+/// line it is on.
+pub fn print(elf: &Path, input: &str) -> anyhow::Result<()> {
+    let mut all: Vec<u64> = input.lines().flat_map(addresses).collect();
+    all.sort_unstable();
+    all.dedup();
+    let frames = if all.is_empty() {
+        HashMap::new()
+    } else {
+        resolve(elf, &all)?
+    };
+
+    for line in input.lines() {
+        println!("{}", line);
+        for addr in addresses(line) {
+            for (i, frame) in frames.get(&addr).into_iter().flatten().enumerate() {
+                if i == 0 {
+                    println!("    {}", frame);
+                } else {
+                    println!("    inlined into {}", frame);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+//! Wall time of the phases of `cargo arceos run`, summarized by the runner.
+
+use std::{
+    env,
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// When cargo was started, in milliseconds since the Unix epoch.
+const STARTED_ENV: &str = "CARGO_ARCEOS_STARTED";
+
+/// Records the current time as the start of the build done by `command`.
+pub fn mark_started(command: &mut Command) {
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        command.env(STARTED_ENV, now.as_millis().to_string());
+    }
+}
+
+/// Returns the time since the build was started, which is how long the build
+/// took when called as the runner starts.
+pub fn since_started() -> Option<Duration> {
+    let started = Duration::from_millis(env::var(STARTED_ENV).ok()?.parse().ok()?);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .checked_sub(started)
+}
+
+pub fn format(duration: Duration) -> String {
+    format!("{:.2}s", duration.as_secs_f64())
+}
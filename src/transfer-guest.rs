@@ -0,0 +1,75 @@
+//! The guest side of `cargo arceos push` and `cargo arceos pull`, printed by
+//! `cargo arceos push --guest-code`.
+//!
+//! Copy it into an app that depends on `axstd` with the `net` and `fs`
+//! features, call `serve()` from a thread of its own, and run the app with
+//! `cargo arceos run --net`, which forwards port 5555 of the host to the guest.
+
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::net::{TcpListener, TcpStream};
+use std::string::String;
+use std::vec::Vec;
+
+const ADDR: (&str, u16) = ("0.0.0.0", 5555);
+
+/// Serves files to the host until the listener fails.
+pub fn serve() -> io::Result<()> {
+    let listener = TcpListener::bind(ADDR)?;
+    loop {
+        let (mut stream, _) = listener.accept()?;
+        if let Err(e) = handle(&mut stream) {
+            println!("file transfer failed: {:?}", e);
+        }
+    }
+}
+
+fn read_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while stream.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Handles `PUT <SIZE> <PATH>` followed by the contents, or `GET <PATH>`,
+/// answering with `OK`, `OK <SIZE>` followed by the contents, or
+/// `ERR <MESSAGE>`.
+fn handle(stream: &mut TcpStream) -> io::Result<()> {
+    let line = read_line(stream)?;
+    if let Some((size, path)) = line.strip_prefix("PUT ").and_then(|rest| rest.split_once(' ')) {
+        let Ok(mut left) = size.parse::<usize>() else {
+            return stream.write_all(b"ERR invalid size\n");
+        };
+        // The contents are read even if the file cannot be written, so that
+        // the host gets the answer.
+        let mut file = File::create(path);
+        let mut buf = [0; 4096];
+        while left > 0 {
+            let n = stream.read(&mut buf[..left.min(4096)])?;
+            if n == 0 {
+                break;
+            }
+            if let Ok(file) = &mut file {
+                file.write_all(&buf[..n])?;
+            }
+            left -= n;
+        }
+        match file {
+            Ok(_) => stream.write_all(b"OK\n"),
+            Err(e) => writeln!(stream, "ERR cannot create `{}`: {:?}", path, e),
+        }
+    } else if let Some(path) = line.strip_prefix("GET ") {
+        let mut data = Vec::new();
+        match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+            Ok(_) => {
+                writeln!(stream, "OK {}", data.len())?;
+                stream.write_all(&data)
+            }
+            Err(e) => writeln!(stream, "ERR cannot read `{}`: {:?}", path, e),
+        }
+    } else {
+        stream.write_all(b"ERR unknown request\n")
+    }
+}
@@ -0,0 +1,116 @@
+//! Copying files between the host and a running guest, for `cargo arceos
+//! push` and `cargo arceos pull`.
+//!
+//! The guest serves files over TCP on the port that QEMU forwards with
+//! `--net`. A request is a line, `PUT <SIZE> <PATH>` followed by the contents
+//! or `GET <PATH>`, answered by `OK`, `OK <SIZE>` followed by the contents, or
+//! `ERR <MESSAGE>`. The guest side is in `transfer-guest.rs`.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+
+/// The port that QEMU forwards to the guest with `--net`.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:5555";
+/// How long the guest gets to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The server for the guest, to copy into the app.
+pub const GUEST_CODE: &str = include_str!("transfer-guest.rs");
+
+fn connect(addr: &str) -> anyhow::Result<TcpStream> {
+    let addr = addr
+        .to_socket_addrs()
+        .with_context(|| format!("invalid address `{}`", addr))?
+        .next()
+        .with_context(|| format!("`{}` has no address", addr))?;
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT).with_context(|| {
+        format!(
+            "failed to connect to the guest at {}, is it running with `--net` and serving files?",
+            addr
+        )
+    })?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Reads the answer of the guest, returning what follows `OK`.
+fn answer(reader: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .context("the guest did not answer")?;
+    let line = line.trim_end();
+    if let Some(message) = line.strip_prefix("ERR ") {
+        bail!("the guest failed: {}", message);
+    }
+    match line.strip_prefix("OK") {
+        Some(rest) => Ok(rest.trim_start().to_string()),
+        None if line.is_empty() => bail!("the guest closed the connection"),
+        None => bail!("unexpected answer from the guest: `{}`", line),
+    }
+}
+
+fn check_path(path: &str) -> anyhow::Result<()> {
+    if path.is_empty() || path.contains('\n') {
+        bail!("invalid guest path `{}`", path.escape_debug());
+    }
+    Ok(())
+}
+
+/// Copies the file `local` to `remote` in the guest at `addr`.
+pub fn push(addr: &str, local: &Path, remote: &str) -> anyhow::Result<()> {
+    check_path(remote)?;
+    let data = fs::read(local).with_context(|| format!("failed to read `{}`", local.display()))?;
+    let mut stream = connect(addr)?;
+    writeln!(stream, "PUT {} {}", data.len(), remote)?;
+    stream
+        .write_all(&data)
+        .context("failed to send the file to the guest")?;
+    answer(&mut BufReader::new(&stream))?;
+    crate::info(
+        "Pushed",
+        format!(
+            "`{}` to `{}` in the guest, {} bytes",
+            local.display(),
+            remote,
+            data.len()
+        ),
+    );
+    Ok(())
+}
+
+/// Copies `remote` in the guest at `addr` to the file `local`.
+pub fn pull(addr: &str, remote: &str, local: &Path) -> anyhow::Result<()> {
+    check_path(remote)?;
+    let mut stream = connect(addr)?;
+    writeln!(stream, "GET {}", remote)?;
+    let mut reader = BufReader::new(&stream);
+    let size = answer(&mut reader)?;
+    let size: usize = size
+        .parse()
+        .with_context(|| format!("unexpected size `{}` from the guest", size))?;
+    let mut data = vec![0; size];
+    reader
+        .read_exact(&mut data)
+        .context("failed to receive the file from the guest")?;
+    crate::write_atomic(local, &data)
+        .with_context(|| format!("failed to write `{}`", local.display()))?;
+    crate::info(
+        "Pulled",
+        format!(
+            "`{}` from the guest to `{}`, {} bytes",
+            remote,
+            local.display(),
+            size
+        ),
+    );
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Detection of `std` features that cargo's feature unification enables for
+//! the bare-metal target.
+//!
+//! Cargo unifies the features of a package across everything in the build
+//! that depends on it, so a single dependent asking for `std` enables it for
+//! all of them. With resolver 1 this even includes build scripts and proc
+//! macros, which run on the host and usually want `std`.
+
+use std::collections::{HashMap, HashSet};
+
+use cargo_metadata::{DependencyKind, Metadata, Node, Package, PackageId};
+
+/// Warns about every package built for `target` that has its `std` feature
+/// enabled, naming the dependents that enable it.
+pub fn check(metadata: &Metadata, target: &str) {
+    let mut command = cargo_metadata::MetadataCommand::new();
+    command
+        .manifest_path(metadata.workspace_root.join("Cargo.toml"))
+        .other_options(vec!["--filter-platform".to_string(), target.to_string()]);
+    // Problems with the dependencies are reported by the build itself.
+    let Ok(metadata) = command.exec() else {
+        return;
+    };
+    let Some(resolve) = &metadata.resolve else {
+        return;
+    };
+    let nodes: HashMap<&PackageId, &Node> =
+        resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+    let host_unified = resolver(&metadata) == "1";
+
+    // The packages built for the target are those that the workspace members
+    // reach through normal dependencies. Proc macros are built for the host.
+    let mut on_target = HashSet::new();
+    let mut queue: Vec<&PackageId> = metadata.workspace_members.iter().collect();
+    while let Some(id) = queue.pop() {
+        if !on_target.insert(id) {
+            continue;
+        }
+        for dep in &nodes[id].deps {
+            if is_normal(&dep.dep_kinds) && !is_proc_macro(&metadata[&dep.pkg]) {
+                queue.push(&dep.pkg);
+            }
+        }
+    }
+
+    for &id in &on_target {
+        if !nodes[id].features.iter().any(|f| f == "std") {
+            continue;
+        }
+        let package = &metadata[id];
+
+        let mut culprits = vec![];
+        let mut from_host = false;
+        for parent in &resolve.nodes {
+            for dep in parent.deps.iter().filter(|dep| dep.pkg == *id) {
+                let host = !on_target.contains(&parent.id) || !is_normal(&dep.dep_kinds);
+                // With resolver 2, the host gets its own copy of the features.
+                if host && !host_unified {
+                    continue;
+                }
+                if dep
+                    .dep_kinds
+                    .iter()
+                    .all(|kind| kind.kind == DependencyKind::Development)
+                {
+                    continue;
+                }
+
+                let parent_package = &metadata[&parent.id];
+                if enables_std(parent, parent_package, &dep.name, package) {
+                    from_host |= host;
+                    culprits.push(if host {
+                        format!("`{}` (for the host)", parent_package.name)
+                    } else {
+                        format!("`{}`", parent_package.name)
+                    });
+                }
+            }
+        }
+
+        if !culprits.is_empty() {
+            let mut message = format!(
+                "feature `std` of `{}` is enabled for `{}` by {}",
+                package.name,
+                target,
+                culprits.join(", ")
+            );
+            if from_host {
+                message.push_str(
+                    "; resolver 1 shares features between the host and the target, set `resolver = \"2\"` in the workspace to keep them apart",
+                );
+            }
+            crate::warn_code(crate::diagnostics::Code::UnifiedStd, message);
+        }
+    }
+}
+
+fn is_normal(kinds: &[cargo_metadata::DepKindInfo]) -> bool {
+    kinds.iter().any(|kind| kind.kind == DependencyKind::Normal)
+}
+
+fn is_proc_macro(package: &Package) -> bool {
+    package.targets.iter().any(|target| target.is_proc_macro())
+}
+
+/// Whether `parent` enables `std` on its dependency `package`, whose library
+/// is known to it as `lib_name`, either in the dependency declaration or
+/// through one of its own enabled features.
+fn enables_std(parent: &Node, parent_package: &Package, lib_name: &str, package: &Package) -> bool {
+    let default_has_std = package
+        .features
+        .get("default")
+        .is_some_and(|default| default.iter().any(|f| f == "std"));
+
+    parent_package
+        .dependencies
+        .iter()
+        .filter(|dep| dep.rename.as_ref().unwrap_or(&dep.name).replace('-', "_") == lib_name)
+        .any(|dep| {
+            let name = dep.rename.as_ref().unwrap_or(&dep.name);
+            dep.features.iter().any(|f| f == "std")
+                || (dep.uses_default_features && default_has_std)
+                || parent.features.iter().any(|feature| {
+                    parent_package.features.get(feature).is_some_and(|values| {
+                        values.iter().any(|value| {
+                            *value == format!("{}/std", name) || *value == format!("{}?/std", name)
+                        })
+                    })
+                })
+        })
+}
+
+/// Returns the feature resolver version of the workspace.
+fn resolver(metadata: &Metadata) -> String {
+    let manifest = std::fs::read_to_string(metadata.workspace_root.join("Cargo.toml"))
+        .ok()
+        .and_then(|manifest| manifest.parse::<toml_edit::DocumentMut>().ok());
+    let explicit = manifest.as_ref().and_then(|manifest| {
+        manifest
+            .get("workspace")
+            .and_then(|workspace| workspace.get("resolver"))
+            .or_else(|| manifest.get("package")?.get("resolver"))
+            .and_then(|resolver| resolver.as_str())
+            .map(str::to_string)
+    });
+    if let Some(resolver) = explicit {
+        return resolver;
+    }
+
+    // Without an explicit resolver, the edition of the root package decides.
+    let edition = metadata
+        .root_package()
+        .map(|package| package.edition.as_str().to_string());
+    match edition.as_deref() {
+        Some("2015" | "2018") => "1".to_string(),
+        Some("2021") => "2".to_string(),
+        Some(_) => "3".to_string(),
+        // A virtual workspace without a resolver uses resolver 1.
+        None => "1".to_string(),
+    }
+}
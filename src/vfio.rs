@@ -0,0 +1,138 @@
+//! Passing host PCI devices through to the guest with VFIO.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+
+/// The address of a host PCI device, like `0000:01:00.0`.
+#[derive(Debug, Clone)]
+pub struct PciAddress {
+    domain: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl FromStr for PciAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || -> Option<PciAddress> {
+            let (rest, function) = s.rsplit_once('.')?;
+            let mut parts = rest.rsplitn(3, ':');
+            let device = parts.next()?;
+            let bus = parts.next()?;
+            let domain = parts.next().unwrap_or("0");
+            let address = PciAddress {
+                domain: u16::from_str_radix(domain, 16).ok()?,
+                bus: u8::from_str_radix(bus, 16).ok()?,
+                device: u8::from_str_radix(device, 16).ok()?,
+                function: u8::from_str_radix(function, 16).ok()?,
+            };
+            (address.device < 32 && address.function < 8).then_some(address)
+        };
+        parse()
+            .ok_or_else(|| anyhow::anyhow!("expected `[DOMAIN:]BUS:DEVICE.FUNCTION`, got `{}`", s))
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.domain, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Checks that the device at `address` can be passed through: the IOMMU is
+/// enabled, the device is bound to `vfio-pci`, and its IOMMU group can be
+/// opened.
+#[cfg(target_os = "linux")]
+pub fn check(address: &PciAddress) -> anyhow::Result<()> {
+    use std::{fs, path::Path};
+
+    let has_groups = fs::read_dir("/sys/kernel/iommu_groups")
+        .map(|mut groups| groups.next().is_some())
+        .unwrap_or(false);
+    if !has_groups {
+        bail!("the IOMMU is not enabled, boot the host with `intel_iommu=on` or `amd_iommu=on`");
+    }
+
+    let device = Path::new("/sys/bus/pci/devices").join(address.to_string());
+    if !device.exists() {
+        bail!("there is no PCI device `{}`", address);
+    }
+
+    let driver = fs::read_link(device.join("driver")).ok();
+    let driver = driver
+        .as_deref()
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned());
+    if driver.as_deref() != Some("vfio-pci") {
+        bail!(
+            "PCI device `{}` is bound to {} instead of `vfio-pci`, rebind it with `echo {} > /sys/bus/pci/devices/{}/driver/unbind` and `echo vfio-pci > /sys/bus/pci/devices/{}/driver_override` followed by `echo {} > /sys/bus/pci/drivers_probe`",
+            address,
+            driver.map_or("no driver".to_string(), |driver| format!("`{}`", driver)),
+            address,
+            address,
+            address,
+            address
+        );
+    }
+
+    let group = fs::read_link(device.join("iommu_group"))?;
+    let group = Path::new("/dev/vfio").join(group.file_name().unwrap());
+    if let Err(e) = fs::OpenOptions::new().read(true).write(true).open(&group) {
+        bail!(
+            "cannot open `{}` of PCI device `{}`: {}",
+            group.display(),
+            address,
+            e
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check(_address: &PciAddress) -> anyhow::Result<()> {
+    bail!("PCI passthrough is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Option<String> {
+        s.parse::<PciAddress>()
+            .ok()
+            .map(|address| address.to_string())
+    }
+
+    #[test]
+    fn with_domain() {
+        assert_eq!(parse("0000:01:00.0").as_deref(), Some("0000:01:00.0"));
+        assert_eq!(parse("10000:ff:1f.7"), None);
+        assert_eq!(parse("abcd:FF:1f.7").as_deref(), Some("abcd:ff:1f.7"));
+        assert_eq!(parse(":01:00.0"), None);
+        assert_eq!(parse("0:0000:01:00.0"), None);
+    }
+
+    #[test]
+    fn without_domain() {
+        assert_eq!(parse("01:00.0").as_deref(), Some("0000:01:00.0"));
+        assert_eq!(parse("3:4.1").as_deref(), Some("0000:03:04.1"));
+        assert_eq!(parse("01:00"), None);
+        assert_eq!(parse("01.0"), None);
+        assert_eq!(parse("100:00.0"), None);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(parse("00:1f.0").as_deref(), Some("0000:00:1f.0"));
+        assert_eq!(parse("00:20.0"), None);
+        assert_eq!(parse("00:00.8"), None);
+        assert_eq!(parse("00:00.10"), None);
+    }
+}
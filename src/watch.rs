@@ -0,0 +1,125 @@
+//! Rebuilding and rerunning the kernel when its sources change, for
+//! `cargo arceos watch`.
+//!
+//! The source tree is polled for changed modification times, as file system
+//! notifications differ between platforms and miss changes on some mounts.
+//! The target directory and the files that the options write to, like
+//! `--disk` and `--log-file`, are left out, so runs do not restart
+//! themselves.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How often the source tree is scanned.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long to wait for more changes after one, as editors and `git` write
+/// several files in a row.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// The cargo process running the current iteration, which becomes the runner
+/// once the build has finished.
+static RUNNING: Mutex<Option<u32>> = Mutex::new(None);
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Records that cargo is running as `pid`, or no longer runs.
+pub fn set_running(pid: Option<u32>) {
+    *RUNNING.lock().unwrap() = pid;
+}
+
+type Snapshot = BTreeMap<PathBuf, SystemTime>;
+
+/// Records the modification times of the files below `dir`, except for
+/// those in `ignore` and hidden directories like `.git`.
+fn scan(dir: &Path, ignore: &[PathBuf], snapshot: &mut Snapshot) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if ignore.iter().any(|ignored| path.starts_with(ignored)) {
+            continue;
+        }
+        if metadata.is_dir() {
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                scan(&path, ignore, snapshot);
+            }
+        } else if let Ok(modified) = metadata.modified() {
+            snapshot.insert(path, modified);
+        }
+    }
+}
+
+/// Watches `root` in the background, except for the files and directories
+/// in `ignore`, like the target directory. On a change, the running
+/// iteration is stopped.
+pub fn start(root: PathBuf, ignore: Vec<PathBuf>) {
+    // Paths below `root` are found as it is joined with their names.
+    let ignore: Vec<PathBuf> = ignore
+        .into_iter()
+        .filter_map(|path| {
+            let path = std::path::absolute(path).ok()?;
+            Some(path.canonicalize().unwrap_or(path))
+        })
+        .collect();
+    let root = root.canonicalize().unwrap_or(root);
+    let snapshot = move || {
+        let mut snapshot = Snapshot::new();
+        scan(&root, &ignore, &mut snapshot);
+        snapshot
+    };
+    let mut last = snapshot();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let mut current = snapshot();
+            if current == last {
+                continue;
+            }
+            loop {
+                thread::sleep(SETTLE_TIME);
+                let settled = snapshot();
+                if settled == current {
+                    break;
+                }
+                current = settled;
+            }
+            last = current;
+
+            CHANGED.store(true, Ordering::SeqCst);
+            if let Some(pid) = *RUNNING.lock().unwrap() {
+                crate::info("Restarting", "after changes to the sources");
+                // The runner powers the guest off like on Ctrl-C.
+                #[cfg(unix)]
+                crate::signal::kill(pid, libc::SIGTERM);
+                #[cfg(not(unix))]
+                let _ = pid;
+            }
+        }
+    });
+}
+
+/// Waits until the sources change, returning `false` if the runner has been
+/// interrupted meanwhile.
+pub fn wait() -> bool {
+    loop {
+        if crate::signal::received().is_some() {
+            return false;
+        }
+        if CHANGED.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}